@@ -0,0 +1,179 @@
+//! Allocator benchmarks.
+//!
+//! The workloads run against the in-memory disk simulator, and report their throughput in pages per
+//! second. Run with `cargo bench --features testing`. Each workload is also run once by the
+//! `bench_workloads` test of the allocator.
+
+extern crate byteorder;
+#[macro_use]
+extern crate criterion;
+extern crate slog;
+extern crate tfs;
+
+use byteorder::{ByteOrder, LittleEndian};
+use criterion::{Criterion, Throughput};
+use tfs::{ChecksumAlgorithm, ChecksumWidth, CompressionAlgorithm, Config, DedupPolicy, Disk, DiskHeader, Driver,
+          FreelistConstruction, Manager, MemSim, SECTOR_SIZE, SectorBuf};
+
+/// The number of sectors of the simulated disk.
+const SECTORS: usize = 256;
+/// The number of pages processed per iteration.
+///
+/// This is kept below the number of free clusters of the simulated disk, so that every iteration
+/// fits on a fresh disk.
+const PAGES: usize = 128;
+
+/// Format a manager on a fresh simulated disk.
+fn manager(config: Config) -> Manager {
+    let mut disk = MemSim::new(SECTORS);
+    disk.write(0, &DiskHeader::default().encode()).unwrap();
+
+    Manager::format(Driver::open(slog::Discard, disk, b"").unwrap(), None, config, FreelistConstruction::Bulk)
+        .unwrap()
+}
+
+/// Generate the `n`'th page of some benchmark.
+///
+/// If `compressible` is set, the page is highly repetitive. Otherwise, it is filled with
+/// pseudorandom bytes.
+fn page(n: usize, compressible: bool) -> SectorBuf {
+    let mut buf = [0; SECTOR_SIZE];
+
+    // Make the page unique by stamping its number in.
+    LittleEndian::write_u64(&mut buf, n as u64);
+
+    if !compressible {
+        // Fill it with pseudorandom bytes.
+        let mut x = n as u64 ^ 0x6eed0e9da4d94a4f;
+        for byte in &mut buf[8..] {
+            x = x.wrapping_mul(0x6eed0e9da4d94a4f);
+            x ^= x >> 32;
+            *byte = x as u8;
+        }
+    }
+
+    buf
+}
+
+/// Get the configuration with LZ4 compression.
+fn lz4() -> Config {
+    Config {
+        compression_algorithm: CompressionAlgorithm::Lz4,
+        .. Default::default()
+    }
+}
+
+/// Allocate `PAGES` pages generated by `gen` on a fresh disk.
+fn alloc_pages<F>(config: Config, gen: F)
+    where F: Fn(usize) -> SectorBuf {
+    let manager = manager(config);
+
+    for n in 0..PAGES {
+        manager.alloc(&gen(n)).unwrap().execute();
+    }
+}
+
+/// Allocate `PAGES` pages, freeing each right away.
+fn churn(manager: &mut Manager) {
+    for n in 0..PAGES {
+        // Compression is disabled, so every page occupies its own cluster, which is freed right
+        // away.
+        let page = manager.alloc(&page(n, false)).unwrap().execute();
+        if let Some(transaction) = manager.free(page).unwrap() {
+            transaction.execute();
+        }
+    }
+}
+
+/// Checksum some pages with some algorithm.
+///
+/// The checksums are returned combined, so they aren't optimized out.
+fn checksum(pages: &[SectorBuf], algorithm: ChecksumAlgorithm) -> u64 {
+    pages.iter().fold(0, |acc, page| acc ^ algorithm.hash(page))
+}
+
+/// Run the allocation benchmarks.
+fn alloc(c: &mut Criterion) {
+    let mut group = c.benchmark_group("alloc");
+    group.throughput(Throughput::Elements(PAGES as u64));
+
+    group.bench_function("unique", |b| b.iter(|| alloc_pages(Config::default(), |n| page(n, false))));
+    // Every page is the same, so every allocation but the first hits the deduplication table.
+    group.bench_function("duplicate", |b| b.iter(|| alloc_pages(Config::default(), |_| page(0, false))));
+    // Like `duplicate`, but trusting the wide checksums instead of verifying the candidates (once
+    // verification gets expensive).
+    group.bench_function("duplicate_trusted", |b| {
+        b.iter(|| alloc_pages(Config {
+            checksum_width: ChecksumWidth::Wide,
+            dedup_policy: DedupPolicy::Adaptive,
+            .. Default::default()
+        }, |_| page(0, false)))
+    });
+    group.bench_function("duplicate_skipped", |b| {
+        b.iter(|| alloc_pages(Config {
+            dedup_policy: DedupPolicy::Disabled,
+            .. Default::default()
+        }, |_| page(0, false)))
+    });
+    group.bench_function("compressible", |b| b.iter(|| alloc_pages(lz4(), |n| page(n, true))));
+    group.bench_function("incompressible", |b| b.iter(|| alloc_pages(lz4(), |n| page(n, false))));
+    let bufs: Vec<_> = (0..PAGES).map(|n| page(n, true)).collect();
+    group.bench_function("many_compressible", |b| {
+        b.iter(|| {
+            for page in manager(lz4()).alloc_many(&bufs).unwrap() {
+                page.execute();
+            }
+        })
+    });
+    let mut churned = manager(Config::default());
+    group.bench_function("free_churn", |b| b.iter(|| churn(&mut churned)));
+
+    group.finish();
+}
+
+/// Run the read benchmarks.
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Elements(PAGES as u64));
+
+    // Store `PAGES` pages, which are compressible, unless `incompressible` returns `true` for their
+    // number.
+    let stored = |incompressible: fn(usize) -> bool| {
+        let manager = manager(lz4());
+        let pages: Vec<_> = (0..PAGES).map(|n| manager.alloc(&page(n, !incompressible(n))).unwrap().execute())
+            .collect();
+        (manager, pages)
+    };
+
+    // Read the pages in a pseudorandom order. Since `PAGES` is a power of two, multiplying by an
+    // odd number permutes the indices.
+    let (manager, pages) = stored(|n| n % 2 == 0);
+    group.bench_function("random", |b| {
+        b.iter(|| {
+            for n in 0..PAGES {
+                manager.read(pages[n.wrapping_mul(0x9E3779B9) % PAGES]).unwrap();
+            }
+        })
+    });
+    // Mix compressible and incompressible pages, spreading them over many clusters.
+    let (manager, pages) = stored(|n| n % 4 == 3);
+    group.bench_function("batch_one_worker", |b| b.iter(|| manager.read_batch(&pages, 1).unwrap()));
+    group.bench_function("batch_four_workers", |b| b.iter(|| manager.read_batch(&pages, 4).unwrap()));
+
+    group.finish();
+}
+
+/// Run the checksum benchmarks.
+fn checksums(c: &mut Criterion) {
+    let mut group = c.benchmark_group("checksum");
+    group.throughput(Throughput::Elements(PAGES as u64));
+
+    let pages: Vec<_> = (0..PAGES).map(|n| page(n, false)).collect();
+    group.bench_function("seahash", |b| b.iter(|| checksum(&pages, ChecksumAlgorithm::SeaHash)));
+    group.bench_function("crc32c", |b| b.iter(|| checksum(&pages, ChecksumAlgorithm::Crc32c)));
+
+    group.finish();
+}
+
+criterion_group!(benches, alloc, read, checksums);
+criterion_main!(benches);
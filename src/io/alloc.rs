@@ -6,10 +6,105 @@
 
 /// The atomic ordering used in the allocator.
 const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
+/// The capacity (in bytes) of a compressed cluster.
+///
+/// This is the maximal number of bytes that a cluster can contain decompressed.
+pub const CLUSTER_CAPACITY: usize = 512 * 2048;
+/// The maximal number of clusters in the trash.
+///
+/// When more clusters are freed to the trash, the oldest are evicted to the freelist.
+const TRASH_CAPACITY: usize = 64;
+/// The maximal number of free clusters in a metacluster.
+///
+/// The metacluster starts with the checksum of and the pointer to the next metacluster, and the
+/// rest is filled with pointers to free clusters.
+///
+/// This is for single-sector metaclusters. See `max_free`.
+const MAX_FREE: usize = disk::SECTOR_SIZE / cluster::POINTER_SIZE - 2;
+/// The compression algorithms tried by automatic selection.
+///
+/// These are ordered by speed, fastest first. The first algorithm is always eligible.
+const AUTO_ALGORITHMS: [CompressionAlgorithm; 2] = [CompressionAlgorithm::Lz4, CompressionAlgorithm::Zstd];
+/// The range of valid Zstandard compression levels.
+///
+/// Levels outside of it are clamped into it.
+const ZSTD_LEVELS: (i32, i32) = (1, 22);
+/// The minimal speed (in bytes per second) for an algorithm to be chosen by automatic selection.
+const AUTO_SPEED_FLOOR: usize = 32 * 1024 * 1024;
+/// The minimal difference in write counts for two clusters to be swapped by rebalancing.
+const WEAR_THRESHOLD: u64 = 16;
+/// The maximal number of corruption warnings kept for health checks.
+///
+/// When more are recorded, the oldest are dropped.
+const WARNING_CAPACITY: usize = 64;
+/// The age (in seconds) up to which a corruption warning degrades the health.
+const RECENT_WARNING_AGE: u64 = 300;
+/// The number of free clusters below which the device is considered low on space.
+const LOW_SPACE_CLUSTERS: usize = 64;
 
 quick_error! {
     /// A page management error.
     enum Error {
+        /// The device is not formatted.
+        ///
+        /// The state block is blank (all zeros), so the device has to be formatted before it can
+        /// be opened.
+        NotFormatted {
+            description("The device is not formatted.")
+        }
+        /// The deduplication table cannot be persisted.
+        ///
+        /// No region is reserved for it in the disk header.
+        DedupPersistenceDisabled {
+            description("No region is reserved for the deduplication table.")
+        }
+        /// The compressed clusters are not tagged with their compression algorithm.
+        ///
+        /// Changing the compression algorithm would make the existing clusters unreadable.
+        UntaggedClusters {
+            description("The compression algorithm cannot be changed without cluster tags.")
+        }
+        /// Multi-sector metaclusters are combined with an option they don't support.
+        ///
+        /// The sectors following the first one are stored in free clusters of the data device,
+        /// which must neither be discarded (`trim_on_free`) nor be separated from the first
+        /// sector, which a separate metadata device would hold.
+        UnsupportedMetaclusterSize {
+            /// The size (in sectors) of the metaclusters.
+            sectors: usize,
+        } {
+            display("Metaclusters of {} sectors support neither `trim_on_free` nor a metadata device.", sectors)
+            description("Unsupported metacluster size.")
+        }
+        /// The live page index is incomplete.
+        ///
+        /// The pages allocated before the manager was opened are unknown, so they cannot be
+        /// covered (see `rebuild_refcounts`).
+        IndexIncomplete {
+            description("The live page index is incomplete.")
+        }
+        /// A cluster cannot be written to.
+        ///
+        /// It is in use, in the trash, or free, but not at the head of the freelist.
+        ClusterUnavailable {
+            /// The cluster.
+            cluster: cluster::Pointer,
+        } {
+            display("Cluster {} is unavailable.", cluster)
+            description("Cluster unavailable.")
+        }
+        /// The snapshot doesn't exist.
+        UnknownSnapshot {
+            description("Unknown snapshot.")
+        }
+        /// The page didn't exist at the time of the snapshot.
+        PageNotInSnapshot {
+            /// The page.
+            page: page::Pointer,
+        } {
+            display("Page {} is not part of the snapshot.", page)
+            description("Page not part of the snapshot.")
+        }
         /// No clusters left in the freelist.
         ///
         /// This is the equivalent to OOM, but with disk space.
@@ -25,12 +120,33 @@ quick_error! {
             /// The page with the mismatching checksum.
             page: page::Pointer,
             /// The actual checksum of the page.
-            found: u32,
+            ///
+            /// This is truncated to the configured checksum width.
+            found: u64,
         } {
             display("Mismatching checksums in {} - expected {:x}, found {:x}.",
                     page, page.checksum, found)
             description("Mismatching checksum in page.")
         }
+        /// A page did not match an externally supplied checksum.
+        ///
+        /// The page passed the verification against the checksum of its pointer, but not against
+        /// the full checksum supplied by the caller.
+        ///
+        /// This indicates data corruption, which slipped through the (possibly narrow) checksum
+        /// of the page pointer.
+        ExternalChecksumMismatch {
+            /// The page with the mismatching checksum.
+            page: page::Pointer,
+            /// The externally supplied checksum.
+            expected: u64,
+            /// The actual checksum of the page.
+            found: u64,
+        } {
+            display("Mismatching external checksum in {} - expected {:x}, found {:x}.",
+                    page, expected, found)
+            description("Mismatching external checksum in page.")
+        }
         /// A metacluster checksum did not match.
         ///
         /// The checksum of the metacluster and the checksum stored in the previous metacluster
@@ -49,6 +165,18 @@ quick_error! {
                     cluster, expected.checksum, found)
             description("Mismatching checksum in metacluster.")
         }
+        /// The freelist head counter is invalid.
+        ///
+        /// The counter stored in the state block exceeds the capacity of a metacluster.
+        ///
+        /// This indicates some form of data corruption in the state block.
+        InvalidFreelistCounter {
+            /// The invalid counter.
+            counter: u8,
+        } {
+            display("Freelist head counter {} exceeds the capacity of a metacluster.", counter)
+            description("Invalid freelist head counter.")
+        }
         /// The compressed data is invalid and cannot be decompressed.
         ///
         /// Multiple reasons exists for this to happen:
@@ -58,17 +186,246 @@ quick_error! {
         /// 3. There is a bug in compression or decompression.
         InvalidCompression {
             cluster: cluster::Pointer,
+            /// The part of the cluster, which is corrupt.
+            kind: CompressionCorruption,
         } {
-            display("Unable to decompress data from cluster {}.", cluster)
+            display("Unable to decompress data from cluster {} ({:?}).", cluster, kind)
             description("Unable to decompress data.")
         }
+        /// The page could not be undeleted.
+        ///
+        /// Its cluster is neither in the trash nor in use, so it might have been overwritten.
+        PageNotInTrash {
+            /// The page.
+            page: page::Pointer,
+        } {
+            display("Page {} is not in the trash.", page)
+            description("Page not in the trash.")
+        }
+        /// A cluster holding freelist metadata was about to be freed.
+        ///
+        /// This indicates a bug in the caller, as no page is stored in such a cluster.
+        ClusterInUseAsMetadata {
+            /// The cluster.
+            cluster: cluster::Pointer,
+        } {
+            display("Cluster {} holds freelist metadata and cannot be freed.", cluster)
+            description("Cluster holds freelist metadata.")
+        }
+        /// The freelist contains a cycle.
+        ///
+        /// The metacluster chain was longer than the device could possibly hold.
+        FreelistCycle {
+            /// The metacluster at which the walk was given up.
+            cluster: cluster::Pointer,
+        } {
+            display("The freelist cycles (detected at metacluster {}).", cluster)
+            description("Cycle in the freelist.")
+        }
+        /// Large pages are not enabled in the disk header.
+        LargePagesDisabled {
+            description("Large pages are disabled.")
+        }
+        /// The pages of a run could not be packed into a single cluster.
+        ///
+        /// This happens when the pages don't compress to the size of a cluster or less.
+        RunTooLarge {
+            /// The number of pages in the run.
+            pages: usize,
+        } {
+            display("Unable to pack {} pages into a single cluster.", pages)
+            description("Unable to pack pages into a single cluster.")
+        }
+        /// No run of contiguous free clusters was found.
+        NoContiguousClusters {
+            /// The number of clusters requested.
+            clusters: usize,
+        } {
+            display("Unable to find {} contiguous free clusters.", clusters)
+            description("Unable to find contiguous free clusters.")
+        }
+        /// A page pointer points outside the data clusters of the device.
+        ClusterOutOfBounds {
+            /// The invalid page pointer.
+            page: page::Pointer,
+        } {
+            display("Page {} points outside the data clusters.", page)
+            description("Page points outside the data clusters.")
+        }
+        /// A page pointer has an offset which no cluster can hold, or which is past the pages
+        /// stored in its cluster.
+        ImplausibleOffset {
+            /// The invalid page pointer.
+            page: page::Pointer,
+        } {
+            display("Page {} has an implausible offset.", page)
+            description("Page has an implausible offset.")
+        }
+        /// A compressed page was read with compression disabled.
+        ///
+        /// The page has an offset, so it is compressed, but the configured compression algorithm
+        /// is `Identity`, and the cluster isn't tagged with the algorithm it was compressed with.
+        /// This usually indicates a misconfiguration.
+        CompressionDisabledButPageCompressed {
+            /// The compressed page.
+            page: page::Pointer,
+        } {
+            display("Page {} is compressed, but compression is disabled.", page)
+            description("Compressed page read with compression disabled.")
+        }
+        /// The offset of a page pointer overflows the address space.
+        ///
+        /// This can only happen on targets where `usize` is narrower than the offset allows for.
+        InvalidPageOffset {
+            /// The invalid page pointer.
+            page: page::Pointer,
+        } {
+            display("The offset of page {} overflows.", page)
+            description("Page offset overflows.")
+        }
+        /// A metacluster claims more free clusters than it can hold.
+        InvalidMetacluster {
+            /// The cluster of the metacluster.
+            cluster: cluster::Pointer,
+        } {
+            display("Metacluster {} holds more free clusters than fit.", cluster)
+            description("Invalid metacluster.")
+        }
+        /// A page pointer points to a cluster which isn't allocated.
+        UnallocatedCluster {
+            /// The invalid page pointer.
+            page: page::Pointer,
+        } {
+            display("Page {} points to an unallocated cluster.", page)
+            description("Page points to an unallocated cluster.")
+        }
+        /// A cluster isn't in use.
+        ///
+        /// The live page index is complete, but the cluster isn't in it.
+        ClusterNotInUse {
+            /// The cluster.
+            cluster: cluster::Pointer,
+        } {
+            display("Cluster {} is not in use.", cluster)
+            description("Cluster not in use.")
+        }
+        /// The journal does not match the freelist.
+        ///
+        /// A popped cluster recorded in the journal couldn't be found at the head of the freelist
+        /// during replay. This indicates data corruption in the journal or the freelist.
+        JournalMismatch {
+            /// The cluster recorded in the journal.
+            cluster: cluster::Pointer,
+        } {
+            display("Journaled cluster {} is not at the head of the freelist.", cluster)
+            description("Journal does not match the freelist.")
+        }
+        /// A state block error.
+        StateBlock(err: state_block::Error) {
+            from()
+            description("State block error.")
+            display("State block error: {}", err)
+        }
+        /// A configuration conflict.
+        Config(err: state_block::ConfigError) {
+            from()
+            description("Conflicting configuration options.")
+            display("Conflicting configuration options: {}", err)
+        }
+        /// A journal error.
+        Journal(err: journal::Error) {
+            from()
+            description("Journal error.")
+            display("Journal error: {}", err)
+        }
         /// A disk error.
         Disk(err: disk::Error) {
             from()
             description("Disk I/O error.")
             display("Disk I/O error: {}", err)
         }
+        /// Writing a dump failed.
+        Dump(err: std::io::Error) {
+            from()
+            description("Failed to write dump.")
+            display("Failed to write dump: {}", err)
+        }
+    }
+}
+
+/// Get the maximal number of free clusters in a metacluster spanning some number of sectors.
+///
+/// This generalizes `MAX_FREE` to metaclusters of `sectors` sectors. Only the first sector starts
+/// with the checksum of and the pointer to the next metacluster, so the other sectors are filled
+/// with pointers to free clusters.
+fn max_free(sectors: usize) -> usize {
+    sectors * disk::SECTOR_SIZE / cluster::POINTER_SIZE - 2
+}
+
+/// Make sure that the metacluster size is supported along with the other options.
+///
+/// Metaclusters of more than one sector are rejected with `Error::UnsupportedMetaclusterSize`, if
+/// `config` discards freed clusters, or if there is a separate metadata device (`metadata`).
+fn check_metacluster_size(header: &header::DiskHeader, config: &state_block::Config, metadata: bool)
+    -> Result<(), Error> {
+    let sectors = header.metacluster_sectors();
+    if sectors > 1 && (config.trim_on_free || metadata) {
+        return Err(Error::UnsupportedMetaclusterSize {
+            sectors: sectors,
+        });
+    }
+
+    Ok(())
+}
+
+/// Get the number of sectors of a metacluster holding some number of free clusters.
+///
+/// These are the sectors spanned by the active region of a metacluster with `free` free clusters.
+/// The sectors following them are unused.
+fn active_sectors(free: usize) -> usize {
+    ((free + 2) * cluster::POINTER_SIZE + disk::SECTOR_SIZE - 1) / disk::SECTOR_SIZE
+}
+
+/// Convert a duration to nanoseconds, saturating on overflow.
+fn nanos(duration: Duration) -> usize {
+    duration.as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(duration.subsec_nanos() as u64) as usize
+}
+
+/// The size (in bytes) of the length prefix of a compressed cluster.
+const LENGTH_PREFIX_SIZE: usize = 2;
+
+/// Pad some compressed data into a cluster.
+///
+/// The cluster starts with the length of the data as a little-endian `u16`, followed by the data,
+/// and the rest of the cluster is zeroed. `unpad` slices exactly that many bytes, so the data is
+/// recovered exactly, whatever bytes it ends in.
+///
+/// `None` is returned if the length prefix and the data don't fit into a cluster.
+fn pad(data: &[u8]) -> Option<disk::SectorBuf> {
+    if LENGTH_PREFIX_SIZE + data.len() > disk::SECTOR_SIZE {
+        return None;
+    }
+
+    let mut buf = disk::SectorBuf::default();
+    LittleEndian::write_u16(&mut buf, data.len() as u16);
+    buf[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + data.len()].copy_from_slice(data);
+
+    Some(buf)
+}
+
+/// Strip the padding from a cluster padded by `pad`.
+///
+/// `None` is returned if the length prefix exceeds the cluster, meaning that it is corrupt. The
+/// padding following the data is ignored.
+fn unpad(buf: &disk::SectorBuf) -> Option<&[u8]> {
+    let len = LittleEndian::read_u16(buf) as usize;
+    if LENGTH_PREFIX_SIZE + len > disk::SECTOR_SIZE {
+        return None;
     }
+
+    Some(&buf[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + len])
 }
 
 /// The state of some cluster.
@@ -84,12 +441,361 @@ struct ClusterState {
     /// and then compressing it to see if it fits into the cluster. If it fails to fit, the vector
     /// is reset and a new cluster is allocated.
     uncompressed: Vec<u8>,
+    /// The compression algorithm of the cluster.
+    ///
+    /// This is the configured algorithm, unless automatic selection is enabled, in which case it
+    /// is chosen when the cluster is allocated, and then reused for every page appended to it.
+    algorithm: CompressionAlgorithm,
+}
+
+/// A cluster packed by a batch allocation.
+///
+/// This is used by `Manager::alloc_many` to lay out the pages of the batch, before any cluster is
+/// popped from the freelist.
+struct PackedCluster {
+    /// The pointer to the cluster, if it is the last allocated cluster being extended.
+    cluster: Option<cluster::Pointer>,
+    /// The cluster uncompressed.
+    ///
+    /// This is empty if the cluster holds a single page uncompressed.
+    uncompressed: Vec<u8>,
+    /// The compression algorithm of the cluster, or `None` if it is uncompressed.
+    algorithm: Option<CompressionAlgorithm>,
+    /// The data to write to the cluster.
+    data: disk::SectorBuf,
+    /// The indices (into the batch) of the pages stored in the cluster, in order of offset.
+    pages: Vec<usize>,
+}
+
+/// The contiguity of a run of pages.
+///
+/// This is used in `Manager::alloc_run` to choose how the pages of the run are laid out.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Contiguity {
+    /// Pack every page into the same cluster.
+    ///
+    /// This requires the pages to compress to the size of a cluster or less.
+    SameCluster,
+    /// Store the pages, uncompressed, in clusters with consecutive addresses.
+    ContiguousClusters,
+}
+
+/// Statistics on how densely pages are packed into clusters.
+///
+/// This is returned by `Manager::packing_stats` and quantifies the effectiveness of compression.
+#[derive(PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct PackingStats {
+    /// The average number of pages in the live compressed clusters.
+    ///
+    /// This is zero if there are no live compressed clusters.
+    pages_per_compressed_cluster: f64,
+    /// The ratio between the logical bytes (the live pages) and the physical bytes (the live
+    /// clusters).
+    ///
+    /// This is zero if there are no live clusters.
+    logical_to_physical: f64,
+}
+
+/// Statistics on the allocator.
+///
+/// This is returned by `Manager::allocator_stats`, for exporting to monitoring systems. Like the
+/// live page index, the live pages and clusters only cover the pages allocated since the manager
+/// was opened.
+#[derive(PartialEq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct AllocatorStats {
+    /// The number of free clusters.
+    ///
+    /// See `Manager::free_clusters`.
+    pub free_clusters: u64,
+    /// The number of live pages.
+    ///
+    /// See `Manager::live_page_count`.
+    pub live_pages: usize,
+    /// The number of clusters holding live pages.
+    pub live_clusters: usize,
+    /// The fraction of the stored pages of the live compressed clusters, which were freed.
+    ///
+    /// See `Manager::fragmentation`.
+    pub fragmentation: f64,
+    /// The number of compression attempts.
+    pub compressions: usize,
+    /// The number of metacluster checksums computed while traversing the freelist.
+    pub metacluster_hashes: usize,
+    /// The statistics of the deduplication table.
+    pub dedup: dedup::Stats,
+}
+
+/// The placement of an allocated page.
+///
+/// This is returned by `Manager::alloc_placed`, and tells if the page started a new cluster,
+/// such that callers can co-locate related pages.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Placement {
+    /// The page was deduplicated against an already stored page.
+    Duplicate,
+    /// The page was packed into the last allocated cluster.
+    Extended,
+    /// The page was stored in a freshly allocated cluster.
+    Fresh,
+}
+
+/// The kind of corruption of a compressed cluster.
+///
+/// This is carried by `Error::InvalidCompression`, and tells which part of the cluster failed to
+/// decode, for diagnosing the failure mode.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum CompressionCorruption {
+    /// The compressed data is corrupt.
+    ///
+    /// The data failed to decompress, or decompressed to a stream too short to hold the page.
+    DataCorrupt,
+    /// The length prefix is corrupt.
+    ///
+    /// The cluster starts with the length of the compressed data. Either the recorded length
+    /// exceeds `SECTOR_SIZE` (along with the prefix itself), or the algorithm tag at the recorded
+    /// end of the data is invalid, suggesting that the length is off.
+    LengthPrefixCorrupt,
+}
+
+/// A corruption warning.
+///
+/// The most recent warnings are kept by the manager, and reported by `Manager::health_check`.
+/// They're recorded on the read, write, allocation and freeing paths alike.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Warning {
+    /// A page failed to match its checksum.
+    ChecksumMismatch(page::Pointer),
+    /// A compressed cluster failed to decode.
+    InvalidCompression(cluster::Pointer),
+    /// A metacluster failed to match its checksum.
+    MetaclusterChecksumMismatch(cluster::Pointer),
+    /// The disk reported a sector as corrupt.
+    CorruptSector(disk::Sector),
+    /// A sector failed to be written to the disk.
+    WriteFailed(disk::Sector),
+    /// A corrupt cluster was repaired through the redundancy of the vdev stack.
+    ///
+    /// The read was served from the good copy, e.g. the other leg of a mirror, which overwrote
+    /// the corrupt one.
+    Healed(cluster::Pointer),
+}
+
+/// The reason the health of a manager is degraded.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum DegradedReason {
+    /// Few free clusters are left.
+    LowSpace {
+        /// The number of free clusters left.
+        free: usize,
+    },
+    /// Corruption (or a failed write) was encountered recently.
+    ///
+    /// See `RECENT_WARNING_AGE`.
+    RecentCorruption {
+        /// The number of recent corruption warnings.
+        warnings: usize,
+    },
+}
+
+/// The health of a manager.
+///
+/// This is returned by `Manager::health_check`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+enum HealthStatus {
+    /// Everything is fine.
+    Healthy,
+    /// The manager works, but something needs attention.
+    Degraded {
+        /// The reasons, in no particular order.
+        reasons: Vec<DegradedReason>,
+    },
+    /// The manager cannot allocate anymore, as no free clusters are left.
+    Failing,
+}
+
+/// The physical location of a page.
+///
+/// This is returned by `Manager::read_located`, and tells which device the page lives on, such
+/// that tooling can reason about placement.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+struct PhysicalLocation {
+    /// The index of the device holding the cluster of the page.
+    ///
+    /// The data device has index `0`, and the separate metadata device (if any) index `1`.
+    device: usize,
+    /// The address of the cluster on the device.
+    sector: disk::Sector,
+}
+
+/// A policy for automatic compaction.
+///
+/// This is set through `Manager::set_auto_compaction`. Automatic compaction is off by default.
+#[derive(PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct CompactionPolicy {
+    /// The fragmentation at which compaction is triggered.
+    ///
+    /// See `Manager::fragmentation`.
+    threshold: f64,
+    /// The maximal number of clusters compacted in a single pass.
+    ///
+    /// This bounds the time a single pass stalls the foreground.
+    budget: usize,
+    /// The minimal number of allocations and frees between two passes.
+    interval: usize,
+}
+
+/// The number of buckets of the fragmentation histogram.
+///
+/// See `Manager::fragmentation_histogram`.
+const HISTOGRAM_BUCKETS: usize = 10;
+
+/// Hints for presizing the in-memory structures of a manager.
+///
+/// See `Manager::open_with_capacity`.
+#[derive(Default, PartialEq, Eq, Clone, Copy)]
+struct CapacityHints {
+    /// The expected number of sectors in the working set.
+    ///
+    /// The cache is presized to hold this many sectors.
+    working_set: usize,
+    /// The expected number of scratch buffers in use at a time.
+    ///
+    /// This many buffers for compression and decompression are preallocated in the pool, bounded
+    /// by its maximal size.
+    buffers: usize,
+    /// The expected number of live pages.
+    ///
+    /// The live page index and the deduplication table are presized to hold this many pages. If
+    /// zero, the deduplication table gets its default size.
+    live_pages: usize,
+}
+
+/// The way the initial freelist is constructed when formatting.
+///
+/// See `Manager::format`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum FreelistConstruction {
+    /// Write fully populated metaclusters covering the device directly.
+    ///
+    /// Every metacluster is written once, so this is fast even on large devices.
+    Bulk,
+    /// Push the clusters to the freelist one by one.
+    ///
+    /// This updates the head metacluster and the state block for every cluster, and is thus slow
+    /// on large devices.
+    Incremental,
+}
+
+impl Default for FreelistConstruction {
+    fn default() -> FreelistConstruction {
+        FreelistConstruction::Bulk
+    }
+}
+
+/// The order in which free clusters are handed out.
+///
+/// See `Manager::set_allocation_strategy`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum AllocationStrategy {
+    /// Hand out the most recently freed cluster first.
+    Lifo,
+    /// Hand out the lowest free cluster first.
+    ///
+    /// This keeps the allocated clusters packed towards the start of the device. The free
+    /// clusters are kept sorted along the whole chain, so freeing a cluster may rewrite several
+    /// metaclusters. The clusters holding the metaclusters themselves are handed out once
+    /// exhausted, regardless of their address.
+    LowestFirst,
+}
+
+impl Default for AllocationStrategy {
+    fn default() -> AllocationStrategy {
+        AllocationStrategy::Lifo
+    }
+}
+
+/// The identifier of a snapshot.
+///
+/// See `Manager::snapshot`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+struct SnapshotId(u64);
+
+/// A background flushing policy.
+///
+/// See `Manager::spawn_flusher`.
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct FlushPolicy {
+    /// The maximal number of dirty sectors flushed at a time.
+    sectors: usize,
+    /// The pause between flushes.
+    interval: Duration,
+}
+
+/// A background flusher.
+///
+/// This trickles dirty sectors to the disk in a background thread. It is stopped when dropped.
+struct Flusher {
+    /// Should the flusher stop?
+    stop: Arc<AtomicBool>,
+    /// The flushing thread.
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // Stop the thread, and wait for it to finish its current flush.
+        self.stop.store(true, ORDERING);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// A report of the repairs made by `Manager::verify_and_repair`.
+#[derive(PartialEq, Eq, Clone, Copy, Default)]
+struct RepairReport {
+    /// The number of metaclusters rewritten, because their counter or checksum drifted.
+    metaclusters_rewritten: usize,
+    /// The number of duplicate entries removed from the freelist.
+    duplicates_removed: usize,
+    /// The number of leaked clusters reclaimed.
+    ///
+    /// This covers both clusters which were neither free nor referenced, and clusters left
+    /// without live pages when the reference counts were rebuilt.
+    clusters_reclaimed: usize,
+}
+
+/// The live pages of some cluster.
+struct LivePages {
+    /// The allocation sequence number of the cluster.
+    ///
+    /// This increases monotonically with every cluster taken into use, and thus reflects the
+    /// order in which the clusters were written.
+    sequence: u64,
+    /// The live pages stored in the cluster, in order of allocation.
+    pages: Vec<page::Pointer>,
+    /// The number of duplicates handed out of the live pages.
+    ///
+    /// Every duplicate handed out through deduplication is another reference to the very same
+    /// page, so the reference count of a page is one more than this. A page is only removed from
+    /// `pages` when its last reference is freed. Pages without duplicates are left out.
+    duplicates: HashMap<page::Pointer, usize>,
+    /// The number of pages stored in the cluster, including the freed ones.
+    stored: usize,
 }
 
 /// A metacluster.
 ///
 /// Metaclusters points to other free clusters, and possibly a metacluster. Metacluters can be seen
 /// as nodes of the unrolled linked list of free blocks.
+///
+/// If the disk header configures metaclusters spanning multiple sectors, the first sector is
+/// stored in the cluster of the metacluster, and every following sector in one of its first free
+/// clusters (see `Manager::write_metacluster`).
 struct Metacluster {
     /// Checksum of the next metacluster.
     next_checksum: u64,
@@ -100,12 +806,43 @@ struct Metacluster {
 }
 
 impl Metacluster {
-    /// Encode the metacluster.
+    /// Decode a metacluster.
+    ///
+    /// This decodes the binary representation `buf`, of which only the first `counter` free
+    /// cluster pointers are active. `buf` might span multiple sectors.
+    ///
+    /// If the active region doesn't fit in `buf`, `None` is returned.
+    fn decode(buf: &[u8], counter: u8) -> Option<Metacluster> {
+        // Make sure that the active region fits in the buffer.
+        match (counter as usize).checked_mul(cluster::POINTER_SIZE).and_then(|len| len.checked_add(16)) {
+            Some(end) if end <= buf.len() => (),
+            _ => return None,
+        }
+
+        Some(Metacluster {
+            // Read the checksum of the next metacluster.
+            next_checksum: LittleEndian::read(buf),
+            // Read the pointer to the next metacluster.
+            next: cluster::Pointer::new(LittleEndian::read(&buf[8..])),
+            // Read the active pointers of the freelist.
+            free: (0..counter as usize).filter_map(|n| {
+                cluster::Pointer::new(LittleEndian::read(&buf[cluster::POINTER_SIZE * n + 16..]))
+            }).collect(),
+        })
+    }
+
+    /// Encode the metacluster into some number of sectors.
+    ///
+    /// This encodes the metacluster into its binary representation, spanning `sectors` sectors.
     ///
-    /// This encodes the metacluster into its binary representation.
-    fn encode(&self) -> [u8; disk::SECTOR_SIZE] {
+    /// # Panics
+    ///
+    /// This will panic if the free clusters don't fit in `sectors` sectors.
+    fn encode_sectors(&self, sectors: usize) -> Vec<u8> {
+        assert!(self.free.len() <= max_free(sectors), "Metacluster overflows its sectors.");
+
         // Start with an all-null buffer.
-        let mut buf = [0; disk::SECTOR_SIZE];
+        let mut buf = vec![0; sectors * disk::SECTOR_SIZE];
 
         // Write the checksum of the next metacluster.
         LittleEndian::write(&mut buf, self.next_checksum);
@@ -113,8 +850,8 @@ impl Metacluster {
         LittleEndian::write(&mut buf[8..], self.next.map_or(0, |x| x.into()));
 
         // Write every pointer of the freelist into the buffer.
-        for (n, i) in self.head_metacluster.free.iter().enumerate() {
-            LittleEndian::write(&mut buf[cluster::POINTER_SIZE * i + 8..], i);
+        for (n, &i) in self.free.iter().enumerate() {
+            LittleEndian::write(&mut buf[cluster::POINTER_SIZE * n + 16..], i);
         }
 
         buf
@@ -124,9 +861,82 @@ impl Metacluster {
     ///
     /// This calculates the checksum of the non-empty part of its serialization with algorithm
     /// `algorithm`.
+    ///
+    /// Since only the active part is hashed, the checksum doesn't depend on the size of the
+    /// metacluster.
     fn checksum(&self, algorithm: header::ChecksumAlgorithm) -> u64 {
+        // Encode into just enough sectors to hold the free clusters.
+        let buf = self.encode_sectors(active_sectors(self.free.len()));
+
         // Only hash the initialized/active part of the metacluster.
-        algorithm.hash(self.encode()[..(self.free + 1) * cluster::POINTER_SIZE + 8])
+        algorithm.hash(&buf[..(self.free.len() + 1) * cluster::POINTER_SIZE + 8])
+    }
+}
+
+/// An iterator over the free clusters.
+///
+/// This lazily walks the metacluster chain. See `Manager::iter_free_clusters`.
+struct FreeClusters<'a> {
+    /// The manager.
+    manager: &'a Manager,
+    /// The free clusters of the current metacluster, which are yet to be yielded.
+    ///
+    /// The last one is yielded first, as it is popped first.
+    free: Vec<cluster::Pointer>,
+    /// The current metacluster, if it is yet to be yielded.
+    metacluster: Option<cluster::Pointer>,
+    /// The next metacluster.
+    next: Option<cluster::Pointer>,
+    /// The number of metaclusters loaded so far.
+    ///
+    /// This bounds the walk, should the freelist cycle.
+    metaclusters: usize,
+}
+
+impl<'a> Iterator for FreeClusters<'a> {
+    type Item = cluster::Pointer;
+
+    fn next(&mut self) -> Option<cluster::Pointer> {
+        loop {
+            // Yield the free clusters of the current metacluster.
+            if let Some(cluster) = self.free.pop() {
+                return Some(cluster);
+            }
+
+            // Then yield the metacluster itself, unless it lives on the metadata device.
+            if let Some(metacluster) = self.metacluster.take() {
+                if !self.manager.cache.is_metadata(metacluster.into()) {
+                    return Some(metacluster);
+                }
+            }
+
+            // Go to the next metacluster, if any.
+            let next = match self.next.take() {
+                Some(next) => next,
+                None => return None,
+            };
+            self.metaclusters += 1;
+            if self.metaclusters > self.manager.driver.number_of_sectors() {
+                warn!(self.manager, "freelist cycles, stopping iteration"; "subsystem" => subsystem::FREELIST,
+                      "metacluster" => next);
+                return None;
+            }
+
+            // Metaclusters are only linked to when they're full.
+            match self.manager.read_metacluster(next, self.manager.max_free() as u8, |_| Ok(())) {
+                Ok(metacluster) => {
+                    self.free = metacluster.free;
+                    self.metacluster = Some(next);
+                    self.next = metacluster.next;
+                },
+                Err(err) => {
+                    warn!(self.manager, "failed to read metacluster, stopping iteration"; "subsystem" => subsystem::FREELIST,
+                          "metacluster" => next,
+                          "error" => err);
+                    return None;
+                },
+            }
+        }
     }
 }
 
@@ -142,38 +952,436 @@ struct Manager {
     /// This is the state as stored in the state block. The reason we do not store the whole state
     /// block in one is that, we want to avoid the lock when reading the static parts of the state
     /// block (e.g. configuration).
+    ///
+    /// When locked together with `head_metacluster`, this is always locked first. See
+    /// `lock_freelist`.
     state: Mutex<state_block::State>,
     /// The configuration options.
     ///
     /// This is the configuration part of the state block. We don't need a lock, since we won't
     /// mutate it while the system is initialized.
     config: state_block::Config,
+    /// The compression level.
+    ///
+    /// This is resolved from the compression profile, which also determines the compression
+    /// algorithm of `config`.
+    compression_level: i32,
     /// The first metacluster of the freelist.
     ///
     /// This list is used as the allocation primitive of TFS. It is a simple freelist-based cluster
     /// allocation system, but there is one twist: To optimize the data locality, the list is
     /// unrolled.
+    ///
+    /// The freelist head of `state` covers this, so when both are updated, they're locked
+    /// together through `lock_freelist`.
     head_metacluster: Mutex<Metacluster>,
+    /// The number of free clusters in the head metacluster.
+    ///
+    /// This mirrors the counter of the freelist head, so it can be read without locking. It is
+    /// updated whenever the state block is flushed, and is thus only approximately consistent.
+    head_free: AtomicUsize,
+    /// The number of metaclusters following the head metacluster.
+    ///
+    /// This is tracked as metaclusters are linked into and switched out of the freelist, and set
+    /// whenever the freelist is walked (in particular when opening). It is thus exact, unless the
+    /// freelist is changed behind the manager's back.
+    tail_metaclusters: AtomicUsize,
     /// The last allocated cluster.
     ///
     /// If possible, newly allocated pages will be appended to this cluster. When it is filled
     /// (i.e. the pages cannot compress to the cluster size or less), a new cluster will be
     /// allocated.
-    last_cluster: AtomicOption<ClusterState>,
+    ///
+    /// The lock is held for the whole duration of storing a page, so concurrent allocations
+    /// cooperate on the same cluster, instead of racing to replace it (which would silently drop
+    /// the pages packed by one of them). To avoid deadlocks, it must be acquired before `state`.
+    last_cluster: Mutex<Option<ClusterState>>,
     /// The deduplication table.
     ///
     /// This table allows the allocator for searching for candidates to use instead of allocating a
     /// new cluster. In particular, it searches for duplicates of the allocated page.
     dedup_table: dedup::Table,
-}
-
-impl Manager {
+    /// The live pages of every used cluster.
+    ///
+    /// This maps clusters to the pages stored in them. The number of live pages of a cluster is its
+    /// reference count. If persistence is enabled in the disk header, it is persisted when syncing
+    /// the metadata, and loaded when opening. Otherwise, it merely covers the pages allocated since
+    /// the manager was opened (see `index_complete`).
+    live: Mutex<HashMap<cluster::Pointer, LivePages>>,
+    /// Does the live page index cover every page in use?
+    ///
+    /// This is false, if the manager was opened without a current persisted index (e.g. because
+    /// persistence is disabled, or the index went stale in a crash), until the index is rebuilt
+    /// through `rebuild_refcounts`. An incomplete index is never persisted.
+    index_complete: bool,
+    /// The generation of the last persisted live page index.
+    ///
+    /// The index of generation `n` is stored in slot `n % 2`. See `flush_live_index`.
+    index_generation: u64,
+    /// The sequence number of the next cluster taken into use.
+    next_sequence: AtomicUsize,
+    /// The backup generation in which every cluster in use was last written.
+    ///
+    /// This is persisted along with `live`, so it only covers the clusters written before the
+    /// manager was opened if the live page index is complete.
+    changed: Mutex<BTreeMap<cluster::Pointer, u64>>,
+    /// Should the write counts of the clusters be tracked?
+    track_wear: bool,
+    /// The number of writes to every cluster.
+    ///
+    /// This is only maintained if `track_wear` is set, and is used for rebalancing the wear of the
+    /// clusters. Every page stored counts as a write to its cluster. Like `changed`, this is
+    /// persisted along with `live`, and covers free clusters too.
+    writes: Mutex<BTreeMap<cluster::Pointer, u64>>,
+    /// The automatic compaction policy, if any.
+    ///
+    /// The callback is called for every page moved by automatic compaction, like the `remap`
+    /// argument of `compact`.
+    ///
+    /// The policy is taken out for the duration of a pass, so concurrent allocations don't start
+    /// another one.
+    auto_compaction: Mutex<Option<(CompactionPolicy, Box<dyn FnMut(page::Pointer, page::Pointer) + Send>)>>,
+    /// The number of allocations and frees since the last automatic compaction pass.
+    operations_since_compaction: AtomicUsize,
+    /// The measured costs of deduplication.
+    ///
+    /// This is used by the adaptive deduplication policy.
+    dedup_cost: dedup::Cost,
+    /// The clock timing the compression and deduplication.
+    ///
+    /// This is `Instant::now`, unless replaced through `set_clock`.
+    clock: Box<dyn Fn() -> Instant + Send + Sync>,
+    /// The pool of scratch buffers for compression and decompression.
+    pool: pool::Pool,
+    /// The trash.
+    ///
+    /// This holds the clusters evacuated by `free_to_trash`, oldest first. Their data is kept
+    /// until they're evicted to the freelist, so the pages can be undeleted. Like `changed`, this
+    /// is persisted along with `live`.
+    trash: Mutex<VecDeque<cluster::Pointer>>,
+    /// The most recent corruption warnings, oldest first.
+    ///
+    /// This is bounded by `WARNING_CAPACITY`.
+    warnings: Mutex<VecDeque<(Instant, Warning)>>,
+    /// Has the manager been shut down?
+    ///
+    /// This is set by `shutdown`, so `Drop` won't flush a second time.
+    shut_down: bool,
+    /// Should metaclusters be verified when traversing the freelist?
+    ///
+    /// If false, the checksum stored in the preceding metacluster is trusted when switching to
+    /// the next one, rather than recomputed. This trades integrity for speed, and is only sound
+    /// on trusted hardware. Checksums are still computed when metaclusters are written.
+    verify_metaclusters: bool,
+    /// The order in which free clusters are handed out.
+    strategy: AllocationStrategy,
+    /// The number of times a page read is retried on a checksum mismatch.
+    ///
+    /// Mismatches can be transient (e.g. a flaky bus), so the cluster is dropped from the cache and
+    /// read from the disk again, before the error is surfaced.
+    checksum_retries: usize,
+    /// The number of metacluster checksums computed while traversing the freelist.
+    metacluster_hashes: AtomicUsize,
+    /// The number of compression attempts.
+    compressions: AtomicUsize,
+    /// The allocation metadata journal.
+    ///
+    /// This is `None` if journaling is disabled in the disk header. It holds the records since
+    /// the last checkpoint, mirroring the journal cluster.
+    journal: Option<Mutex<journal::Journal>>,
+    /// The live pages at the time of every snapshot.
+    ///
+    /// Like `changed`, this is persisted along with `live`, so snapshots only survive reopening
+    /// the manager if the live page index is complete.
+    snapshots: Mutex<BTreeMap<SnapshotId, Vec<page::Pointer>>>,
+    /// The identifier of the next snapshot.
+    next_snapshot: u64,
+    /// The clusters, which were freed, but are retained for some snapshot.
+    ///
+    /// They're pushed to the freelist, when no snapshot refers to them anymore. Like `snapshots`,
+    /// this is persisted along with `live`.
+    retained: Mutex<BTreeSet<cluster::Pointer>>,
+    /// The generation of the last persisted deduplication table.
+    ///
+    /// The table of generation `n` is stored in slot `n % 2`. See `flush_dedup_table`.
+    dedup_generation: u64,
+}
+
+impl Manager {
     /// Open the manager from some driver.
     ///
     /// This loads the state page and other things from a vdev driver `driver`. If it fails, an
     /// error is returned.
-    fn open(driver: vdev::Driver) -> Result<Manager, Error> {
-        unimplemented!();
+    ///
+    /// If `metadata` is set, the state block and the metaclusters are stored on this driver,
+    /// while the page clusters are stored on `driver`.
+    ///
+    /// If `progress` is set, it is called with the progress of validating the freelist, which can
+    /// take a while on large devices. See `walk_freelist`.
+    ///
+    /// If the journal is enabled, it is replayed, bringing the freelist up to date after a crash.
+    ///
+    /// If a deduplication table was persisted, the newest intact one is loaded. So is the live page
+    /// index, unless it went stale, in which case it only covers the pages allocated from here on,
+    /// until it is rebuilt through `rebuild_refcounts`.
+    ///
+    /// If `trim` is set, the free clusters beyond the end of the device (e.g. after it was
+    /// truncated) are dropped from the freelist. See `trim_freelist_to_device_bounds`.
+    ///
+    /// If the state block is blank, `Error::NotFormatted` is returned.
+    fn open(driver: vdev::Driver, metadata: Option<vdev::Driver>, trim: bool,
+            progress: Option<&mut dyn FnMut(f64)>) -> Result<Manager, Error> {
+        Manager::open_with_capacity(driver, metadata, trim, progress, CapacityHints::default())
+    }
+
+    /// Open the manager with presized in-memory structures.
+    ///
+    /// This is like `open`, but the cache, the buffer pool, the live page index and the
+    /// deduplication table are sized up front according to `hints`, avoiding reallocation while
+    /// they fill up after opening. The behavior is otherwise identical.
+    pub fn open_with_capacity(driver: vdev::Driver, metadata: Option<vdev::Driver>, trim: bool,
+                              progress: Option<&mut dyn FnMut(f64)>, hints: CapacityHints)
+        -> Result<Manager, Error> {
+        {
+            // The state block lives on the metadata device, if any.
+            let state_driver = metadata.as_ref().unwrap_or(&driver);
+
+            // Make sure that the device is formatted. A blank state block would otherwise fail with
+            // a confusing checksum mismatch.
+            if state_driver.read(state_driver.header.state_block_address)?.iter().all(|&x| x == 0) {
+                return Err(Error::NotFormatted);
+            }
+        }
+
+        // Read the state block.
+        let block = state_block::read(metadata.as_ref().unwrap_or(&driver))?;
+        let mut config = block.config;
+        // Reject conflicting options.
+        config.validate()?;
+        check_metacluster_size(&driver.header, &config, metadata.is_some())?;
+        // Resolve the compression profile.
+        let compression_level = config.resolve_compression();
+
+        let cache = Cache::with_capacity(driver, metadata, hints.working_set);
+        let mut manager = Manager::new(cache, config, compression_level, block.state);
+        info!(manager, "opening the page manager"; "subsystem" => subsystem::ALLOC);
+        // Preallocate the scratch buffers.
+        manager.pool = pool::Pool::with_buffers(hints.buffers, CLUSTER_CAPACITY);
+        // Presize the live page index and the deduplication table.
+        manager.live = Mutex::new(HashMap::with_capacity(hints.live_pages));
+        if hints.live_pages != 0 {
+            manager.dedup_table = dedup::Table::with_capacity(hints.live_pages);
+        }
+
+        // Load the head metacluster, which validates the freelist head counter.
+        if let Some(freelist_head) = block.state.freelist_head {
+            manager.head_metacluster = Mutex::new(manager.load_head_metacluster(freelist_head)?);
+            manager.head_free.store(freelist_head.counter as usize, ORDERING);
+        }
+
+        // Replay the journal, if enabled.
+        if manager.journal.is_some() {
+            manager.replay_journal()?;
+        }
+
+        // Load the persisted deduplication table, if any.
+        if manager.driver.header.dedup_sectors != 0 {
+            manager.load_dedup_table()?;
+        }
+
+        // Load the persisted live page index, if it is current.
+        manager.index_complete = false;
+        if manager.driver.header.index_sectors != 0 {
+            manager.load_live_index()?;
+        }
+
+        // Drop the free clusters beyond the end of the device, if requested.
+        if trim {
+            manager.trim_freelist_to_device_bounds()?;
+        }
+
+        // Validate the freelist.
+        if let Some(mut progress) = progress {
+            manager.walk_freelist(&mut progress)?;
+        } else {
+            manager.walk_freelist(&mut |_| ())?;
+        }
+
+        // Drop the persisted deduplication candidates, whose pages were freed (and their clusters
+        // possibly reused) after the table was flushed. Candidates of clusters outside an
+        // incomplete index are kept, but never handed out (see `find_duplicate`).
+        if manager.driver.header.dedup_sectors != 0 {
+            let removed = manager.compact_dedup_table();
+            if removed != 0 {
+                info!(manager, "dropped stale persisted deduplication candidates"; "subsystem" => subsystem::ALLOC,
+                      "removed" => removed);
+            }
+        }
+
+        Ok(manager)
+    }
+
+    /// Format a device, and create a manager on it.
+    ///
+    /// This writes a fresh state block with configuration `config` through `driver` (or
+    /// `metadata`, if set, like `open`), sets aside the reserve configured by
+    /// `config.reserve_clusters` at the end of the device, and puts every other data cluster on
+    /// the freelist, constructed as chosen by `construction`. The allocation metadata is synced
+    /// before the manager is returned, so the device can be opened right away.
+    ///
+    /// The disk header must already be written, as it determines the layout.
+    pub fn format(driver: vdev::Driver, metadata: Option<vdev::Driver>, mut config: state_block::Config,
+                  construction: FreelistConstruction) -> Result<Manager, Error> {
+        // Reject conflicting options.
+        config.validate()?;
+        check_metacluster_size(&driver.header, &config, metadata.is_some())?;
+        // Resolve the compression profile.
+        let compression_level = config.resolve_compression();
+
+        let mut manager = Manager::new(Cache::with_metadata(driver, metadata), config, compression_level,
+                                       state_block::State::default());
+        info!(manager, "formatting"; "subsystem" => subsystem::ALLOC,
+              "construction" => format!("{:?}", construction));
+
+        // Set aside the reserve, and put every other data cluster on the freelist.
+        let mut clusters: Vec<_> = (manager.first_data_cluster()..manager.driver.number_of_sectors())
+            .map(|cluster| cluster::Pointer::new(cluster as u64).unwrap())
+            .collect();
+        let reserved = clusters.len().saturating_sub(manager.config.reserve_clusters as usize);
+        manager.state.lock().reserve = clusters.split_off(reserved);
+        match construction {
+            FreelistConstruction::Bulk => manager.build_freelist(&clusters),
+            FreelistConstruction::Incremental => {
+                for &cluster in &clusters {
+                    manager.freelist_push(cluster).execute();
+                }
+            },
+        }
+
+        // Write the metadata to the disk.
+        manager.sync_metadata()?;
+
+        Ok(manager)
+    }
+
+    /// Build the freelist from scratch.
+    ///
+    /// This replaces the freelist by `clusters`, writing fully populated metaclusters directly,
+    /// rather than pushing the clusters one by one. The chain is built from the tail towards the
+    /// head, so only the head metacluster is partially filled, and the chain follows the order of
+    /// `clusters`.
+    ///
+    /// Like when pushing, the metaclusters are stacked on the metadata device, if any, as long as
+    /// it has room left, each holding as many free clusters as fit (see `max_free`). The rest are
+    /// the first cluster of every group of one more cluster. See `new_metacluster`.
+    ///
+    /// The state block isn't flushed, but it depends on the metaclusters written.
+    fn build_freelist(&mut self, clusters: &[cluster::Pointer]) {
+        debug!(self, "building freelist"; "subsystem" => subsystem::FREELIST, "clusters" => clusters.len());
+
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+        // The old chain is replaced, including the metaclusters stacked on the metadata device.
+        state.metaclusters = 0;
+        if clusters.is_empty() {
+            state.freelist_head = None;
+            return;
+        }
+
+        // Write the metaclusters from the tail towards the head, as every metacluster stores the
+        // checksum of its successor. Each write depends on the previous, so the chain hits the disk
+        // before the state block pointing to it.
+        let mut rest = clusters;
+        let mut next = None;
+        let mut next_checksum = 0;
+        let mut metaclusters = 0;
+        let mut transaction = cache::Transacting::no_transaction(());
+        let max_free = self.max_free();
+        while !rest.is_empty() {
+            // Take the free clusters of the metacluster from the end of the remaining clusters.
+            let (cluster, free) = if let Some(cluster) = self.metadata_metacluster(&state) {
+                state.metaclusters += 1;
+                let (head, free) = rest.split_at(rest.len().saturating_sub(max_free));
+                rest = head;
+
+                (cluster, free)
+            } else {
+                let (head, group) = rest.split_at(rest.len().saturating_sub(max_free + 1));
+                rest = head;
+
+                (group[0], &group[1..])
+            };
+
+            let metacluster = Metacluster {
+                next_checksum: next_checksum,
+                next: next,
+                free: free.to_vec(),
+            };
+            next = Some(cluster);
+            next_checksum = self.metacluster_checksum(&metacluster);
+            metaclusters += 1;
+
+            let write = self.write_metacluster(&metacluster, cluster);
+            transaction = transaction.and(cache::Transacting::new((), Some(write)));
+            *head_metacluster = metacluster;
+        }
+        self.tail_metaclusters.store(metaclusters - 1, ORDERING);
+
+        // Point the state block to the head metacluster.
+        state.freelist_head = Some(state_block::FreelistHead {
+            cluster: next.unwrap(),
+            checksum: next_checksum,
+            counter: head_metacluster.free.len() as u8,
+        });
+        transaction.then(self.flush_state_block(&state)).execute();
+    }
+
+    /// Create a manager on top of some cache.
+    ///
+    /// The head metacluster is left empty, and so are the in-memory structures (like the
+    /// deduplication table), which cover the pages allocated from here on.
+    fn new(cache: Cache, config: state_block::Config, compression_level: i32, state: state_block::State)
+        -> Manager {
+        // Set up the journal, if enabled.
+        let journal = if cache.driver.header.journal {
+            Some(Mutex::new(journal::Journal::default()))
+        } else { None };
+
+        Manager {
+            cache: cache,
+            state: Mutex::new(state),
+            config: config,
+            compression_level: compression_level,
+            head_metacluster: Mutex::new(Metacluster::default()),
+            head_free: AtomicUsize::new(0),
+            tail_metaclusters: AtomicUsize::new(0),
+            last_cluster: Mutex::new(None),
+            dedup_table: dedup::Table::default(),
+            live: Mutex::new(HashMap::new()),
+            index_complete: true,
+            index_generation: 0,
+            next_sequence: AtomicUsize::new(0),
+            changed: Mutex::new(BTreeMap::new()),
+            track_wear: false,
+            writes: Mutex::new(BTreeMap::new()),
+            auto_compaction: Mutex::new(None),
+            operations_since_compaction: AtomicUsize::new(0),
+            dedup_cost: dedup::Cost::default(),
+            clock: Box::new(Instant::now),
+            pool: pool::Pool::default(),
+            trash: Mutex::new(VecDeque::new()),
+            warnings: Mutex::new(VecDeque::new()),
+            shut_down: false,
+            verify_metaclusters: true,
+            strategy: AllocationStrategy::default(),
+            checksum_retries: 0,
+            metacluster_hashes: AtomicUsize::new(0),
+            compressions: AtomicUsize::new(0),
+            journal: journal,
+            snapshots: Mutex::new(BTreeMap::new()),
+            next_snapshot: 0,
+            retained: Mutex::new(BTreeSet::new()),
+            dedup_generation: 0,
+        }
     }
 
     /// Allocate a page.
@@ -182,46 +1390,217 @@ impl Manager {
     ///
     /// The algorithm works greedily by fitting as many pages as possible into the most recently
     /// used cluster.
-    pub fn alloc(&mut self, buf: disk::SectorBuf) -> Result<cache::Transacting<page::Pointer>, Error> {
-        // TODO: The variables are named things like `ptr`, which kinda contradicts the style of
-        //       the rest of the code.
+    pub fn alloc(&self, buf: &disk::SectorBuf) -> Result<cache::Transacting<page::Pointer>, Error> {
+        Ok(self.alloc_placed(buf)?.map(|(page, _)| page))
+    }
 
-        /// The capacity (in bytes) of a compressed cluster.
-        ///
-        /// This is the maximal number of bytes that a cluster can contain decompressed.
-        const CLUSTER_CAPACITY: usize = 512 * 2048;
+    /// Allocate a page, and report where it was placed.
+    ///
+    /// This is like `alloc`, but the placement of the page is returned along with the pointer.
+    /// When it is `Placement::Fresh`, the page started a new cluster, into which the following
+    /// pages are packed (as long as they fit), so related pages allocated right after it are
+    /// co-located.
+    pub fn alloc_placed(&self, buf: &disk::SectorBuf)
+        -> Result<cache::Transacting<(page::Pointer, Placement)>, Error> {
+        // Calculate the checksum of the buffer, truncated to the width stored in the page pointer.
+        let cksum = self.checksum_page(buf);
+        self.alloc_checksummed(buf, cksum)
+    }
+
+    /// Allocate a page with a precomputed checksum.
+    ///
+    /// This is like `alloc`, but the checksum of `buf` under the configured checksum algorithm
+    /// is supplied by the caller (e.g. because it already hashed the buffer for an index of its
+    /// own), so it isn't calculated again. It is truncated to the configured width.
+    ///
+    /// The checksum is trusted. A wrong checksum makes the page fail verification when read.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, this panics if the checksum doesn't match `buf`.
+    pub fn alloc_with_checksum(&self, buf: &disk::SectorBuf, checksum: u64)
+        -> Result<cache::Transacting<page::Pointer>, Error> {
+        let cksum = self.config.checksum_width.truncate(checksum);
+        debug_assert!(cksum == self.checksum_page(buf), "Supplied checksum doesn't match the page.");
 
-        // Calculate the checksum of the buffer. We'll use this later.
-        let cksum = self.checksum(buf) as u32;
-        debug!(self, "allocating page"; "checksum" => cksum);
+        Ok(self.alloc_checksummed(buf, cksum)?.map(|(page, _)| page))
+    }
 
-        // Check if duplicate exists.
-        if let Some(page) = self.dedup_table.dedup(buf, cksum) {
-            debug!(self, "found duplicate page"; "page" => page);
-            // Deduplicate and simply use the already stored page. No transaction where required.
+    /// Allocate a page uncompressed.
+    ///
+    /// This is like `alloc`, but the page is stored uncompressed in a cluster of its own,
+    /// regardless of the compression algorithm, without attempting to compress it. This is
+    /// meant for data known to be incompressible (e.g. already compressed media), where the
+    /// compression attempt would be wasted.
+    ///
+    /// The page is still checksummed and deduplicated.
+    pub fn alloc_raw(&self, buf: &disk::SectorBuf) -> Result<cache::Transacting<page::Pointer>, Error> {
+        // Calculate the checksum of the buffer, truncated to the width stored in the page pointer.
+        let cksum = self.checksum_page(buf);
+        debug!(self, "allocating raw page"; "subsystem" => subsystem::ALLOC, "checksum" => cksum);
+
+        // Use the duplicate, if any.
+        if let Some(page) = self.find_duplicate(buf, cksum) {
             return Ok(cache::Transacting::no_transaction(page));
         }
 
-        // Handle the case where compression is disabled.
+        Ok(self.store_uncompressed(buf, cksum)?.map(|(page, _)| page))
+    }
+
+    /// Allocate a page in a cluster of its own.
+    ///
+    /// This is like `alloc`, but the page is compressed into a fresh cluster, and no other pages
+    /// are ever packed into it. The last allocated cluster is neither extended nor replaced, so
+    /// it stays available for packing the pages of other callers. This is meant for independent
+    /// pages, such as those of a large one-shot write, where packing provides no benefit.
+    ///
+    /// The page is still deduplicated.
+    pub fn alloc_isolated(&self, buf: &disk::SectorBuf) -> Result<cache::Transacting<page::Pointer>, Error> {
+        // Calculate the checksum of the buffer, truncated to the width stored in the page pointer.
+        let cksum = self.checksum_page(buf);
+        debug!(self, "allocating isolated page"; "subsystem" => subsystem::ALLOC, "checksum" => cksum);
+
+        // Use the duplicate, if any.
+        if let Some(page) = self.find_duplicate(buf, cksum) {
+            return Ok(cache::Transacting::no_transaction(page));
+        }
+
+        self.store_with(|| self.store_isolated(buf, cksum))
+    }
+
+    /// Store a page in a cluster of its own, bypassing deduplication.
+    ///
+    /// This stores `buf`, whose checksum is `cksum`, like `alloc_isolated`.
+    fn store_isolated(&self, buf: &disk::SectorBuf, cksum: u64)
+        -> Result<cache::Transacting<page::Pointer>, Error> {
+        // Without compression, every page is isolated anyway.
         if self.config.compression_algorithm == CompressionAlgorithm::Identity {
-            // Pop a cluster from the freelist.
-            let cluster = self.freelist_pop()?;
+            return Ok(self.store_uncompressed(buf, cksum)?.map(|(page, _)| page));
+        }
 
-            let ptr = page::Pointer {
-                cluster: cluster,
-                offset: None,
-                checksum: cksum,
-            };
+        // Pop a cluster from the freelist, and compress the page into it, if possible.
+        let cluster = self.freelist_pop()?;
+        let (data, offset) = match self.compress_new_cluster(buf) {
+            Some((_, compressed)) => (compressed, Some(0)),
+            None => (*buf, None),
+        };
+        let ptr = page::Pointer {
+            cluster: cluster,
+            offset: offset,
+            checksum: cksum,
+        };
+
+        // Register the page as live, and allow future use as duplicate.
+        self.register(buf, ptr);
+
+        // Write the cluster, and return the transaction monad.
+        Ok(cluster.then(self.cache.write(cluster, data)).wrap(ptr))
+    }
+
+    /// Allocate a page, whose checksum is known.
+    ///
+    /// `cksum` is the checksum of `buf`, truncated to the configured width.
+    fn alloc_checksummed(&self, buf: &disk::SectorBuf, cksum: u64)
+        -> Result<cache::Transacting<(page::Pointer, Placement)>, Error> {
+        debug!(self, "allocating page"; "subsystem" => subsystem::ALLOC, "checksum" => cksum);
+
+        if let Some(page) = self.find_duplicate(buf, cksum) {
+            // Deduplicate and simply use the already stored page. No transaction where required.
+            return Ok(cache::Transacting::no_transaction((page, Placement::Duplicate)));
+        }
+
+        // No duplicate exists, so the page must be stored.
+        self.store_with(|| self.store(buf, cksum))
+    }
+
+    /// Store a page, which has no duplicate.
+    ///
+    /// This calls closure `store`, which stores the page. Before that, the allocation is counted
+    /// towards the automatic compaction policy, so more space isn't taken up when a pass is due.
+    /// The time it takes is measured, to compare it with the cost of deduplication.
+    fn store_with<T, F>(&self, store: F) -> Result<T, Error>
+        where F: FnOnce() -> Result<T, Error> {
+        // Compact automatically before taking up more space, if the policy calls for it.
+        self.tick_compaction()?;
+
+        // Measure how long storing takes.
+        let start = (self.clock)();
+        let ret = store();
+        self.dedup_cost.record_store(nanos((self.clock)() - start));
+
+        ret
+    }
+
+    /// Find a stored duplicate of a page.
+    ///
+    /// This looks up `buf`, whose checksum is `cksum`, in the deduplication table, in the way
+    /// chosen by the deduplication policy. The reference count of the duplicate found is
+    /// incremented, so it must be freed like a newly allocated page.
+    fn find_duplicate(&self, buf: &disk::SectorBuf, cksum: u64) -> Option<page::Pointer> {
+        // Skip the lookup for high-entropy pages, as they rarely have duplicates. Otherwise, the
+        // deduplication policy chooses the mode.
+        let threshold = self.config.dedup_entropy_threshold as usize;
+        let mode = if threshold != 0 && dedup::distinct_bytes(buf) > threshold {
+            dedup::Mode::Skip
+        } else {
+            self.dedup_cost.mode(self.config.dedup_policy, self.config.checksum_width)
+        };
+
+        // Check if duplicate exists, in the way chosen.
+        let duplicate = match mode {
+            dedup::Mode::Verify => {
+                // Look up and verify the candidate, and measure how long it takes.
+                let start = (self.clock)();
+                // Imported candidates are verified against the stored page.
+                let duplicate = self.dedup_table.dedup_with(buf, cksum, |page| self.read(page).ok());
+                self.dedup_cost.record_verify(nanos((self.clock)() - start));
+
+                duplicate
+            },
+            dedup::Mode::Trust => self.dedup_table.dedup_trusted(cksum),
+            dedup::Mode::Skip => {
+                trace!(self, "skipping deduplication"; "subsystem" => subsystem::ALLOC);
+                None
+            },
+        };
+        let page = duplicate?;
 
-            // Insert the page pointer into the deduplication table to allow future use as
-            // duplicate.
-            self.dedup_table.insert(buf, ptr);
+        // Take another reference to the duplicate, so it isn't freed while still in use. Pages
+        // outside the live page index cannot be reference counted, so they aren't used, and are
+        // dropped from the table.
+        if !self.reference(page) {
+            debug!(self, "dropping untracked duplicate candidate"; "subsystem" => subsystem::ALLOC,
+                   "page" => page);
+            self.dedup_table.remove(page);
 
-            // Write the cluster with the raw, uncompressed data, and return the transaction monad.
-            return Ok(cluster.then(self.cache.write(cluster, buf)).wrap(ptr));
+            return None;
         }
 
-        if let Some(state) = self.last_cluster.take(ORDERING) {
+        debug!(self, "found duplicate page"; "subsystem" => subsystem::ALLOC, "page" => page);
+        Some(page)
+    }
+
+    /// Store a page, bypassing deduplication.
+    ///
+    /// This stores `buf`, whose checksum is `cksum`, in a cluster. It is used by `alloc` after it
+    /// has failed to find a duplicate.
+    ///
+    /// The algorithm works greedily by fitting as many pages as possible into the most recently
+    /// used cluster.
+    fn store(&self, buf: &disk::SectorBuf, cksum: u64)
+        -> Result<cache::Transacting<(page::Pointer, Placement)>, Error> {
+        // TODO: The variables are named things like `ptr`, which kinda contradicts the style of
+        //       the rest of the code.
+
+        // Handle the case where compression is disabled.
+        if self.config.compression_algorithm == CompressionAlgorithm::Identity {
+            return self.store_uncompressed(buf, cksum);
+        }
+
+        // Lock the last allocated cluster until the page is stored.
+        let mut last_cluster = self.last_cluster.lock();
+
+        if let Some(mut state) = last_cluster.take() {
             // We have earlier allocated a cluster, meaning that we can potentially append more
             // pages into the cluster.
 
@@ -229,33 +1608,38 @@ impl Manager {
             // allocate a new cluster. This limit exists to avoid unbounded memory use which can be
             // exploited by a malicious party to force an OOM crash.
             if state.uncompressed.len() < CLUSTER_CAPACITY {
-                trace!(self, "extending existing cluster";
+                trace!(self, "extending existing cluster"; "subsystem" => subsystem::ALLOC,
                        "old length" => state.uncompressed.len());
 
+                // Calculate the offset into the decompressed buffer, where the page will be
+                // stored. This is the number of pages preceding it, so it is calculated before
+                // extending, which also rules out underflows. The capacity check above keeps it
+                // from overflowing.
+                let offset = (state.uncompressed.len() / disk::SECTOR_SIZE) as u32;
+
                 // Extend the buffer of uncompressed data in the last allocated cluster.
                 state.uncompressed.extend_from_slice(buf);
 
-                // Check if we can compress the extended buffer into a single cluster.
-                if let Some(compressed) = self.compress(state.uncompressed) {
-                    // Put back the "last cluster", as it might be possible to fit in even more
-                    // pages later on.
-                    self.last_cluster.swap(Some(state), ORDERING);
-
-                    let ptr = Ok(page::Pointer {
+                // Check if we can compress the extended buffer into a single cluster. The cluster's
+                // algorithm is reused.
+                if let Some(compressed) = self.compress(state.algorithm, &state.uncompressed) {
+                    let ptr = page::Pointer {
                         cluster: state.cluster,
-                        // Calculate the offset into the decompressed buffer, where the page is
-                        // stored.
-                        offset: Some(state.uncompressed / disk::SECTOR_SIZE - 1),
+                        offset: Some(offset),
                         checksum: cksum,
-                    });
+                    };
+
+                    // Register the page as live, and allow future use as duplicate.
+                    self.register(buf, ptr);
 
-                    // Insert the page pointer into the deduplication table to allow future use as
-                    // duplicate.
-                    self.dedup_table.insert(buf, ptr);
+                    // It succeeded! Write the compressed data into the cluster.
+                    let transaction = self.cache.write(state.cluster, compressed);
+                    // Put back the "last cluster", as it might be possible to fit in even more
+                    // pages later on.
+                    *last_cluster = Some(state);
 
-                    // It succeeded! Write the compressed data into the cluster. Wrap the pointer
-                    // in the transaction and return it.
-                    return self.cache.write(state.cluster, compressed).wrap(ptr);
+                    // Wrap the pointer in the transaction and return it.
+                    return Ok(transaction.wrap((ptr, Placement::Extended)));
                 }
             }
         }
@@ -266,8 +1650,9 @@ impl Manager {
 
         // Pop the cluster from the freelist.
         let cluster = self.freelist_pop()?;
-        let ptr = if let Some(compressed) = self.compress(buf) {
-            trace!(self, "storing compressible page in cluster"; "cluster" => cluster);
+        let ptr = if let Some((algorithm, compressed)) = self.compress_new_cluster(buf) {
+            trace!(self, "storing compressible page in cluster"; "subsystem" => subsystem::ALLOC,
+                   "cluster" => cluster);
 
             // We were able to compress the page to fit into the cluster. At first, compressing the
             // first page seems unnecessary as it is guaranteed to fit in without compression, but
@@ -277,11 +1662,12 @@ impl Manager {
             // there is no change in how the other pages are read.
 
             // Make the "last cluster" the newly allocated cluster.
-            self.last_cluster.swap(Some(ClusterState {
+            *last_cluster = Some(ClusterState {
                 cluster: cluster,
                 // So far, it only contains one page.
                 uncompressed: buf.as_vec(),
-            }), ORDERING);
+                algorithm: algorithm,
+            });
 
             // Write the compressed data into the cluster.
             cluster.then(self.cache.write(cluster, compressed)).wrap(page::Pointer {
@@ -290,7 +1676,8 @@ impl Manager {
                 checksum: cksum,
             })
         } else {
-            trace!(self, "storing incompressible page in cluster"; "cluster" => cluster);
+            trace!(self, "storing incompressible page in cluster"; "subsystem" => subsystem::ALLOC,
+                   "cluster" => cluster);
 
             // We were not able to compress the page into a single cluster. We work under the
             // assumption, that we cannot do so either when new data is added. This makes the
@@ -309,307 +1696,9362 @@ impl Manager {
             })
         };
 
-        // Insert the page pointer into the deduplication table to allow future use as
-        // duplicate.
-        self.dedup_table.insert(buf, ptr);
+        // Register the page as live, and allow future use as duplicate.
+        self.register(buf, ptr);
 
-        Ok(ptr)
+        Ok(ptr.map(|page| (page, Placement::Fresh)))
     }
 
-    /// Read/dereference a page.
+    /// Store a page uncompressed in a cluster of its own.
     ///
-    /// This reads page `page` and returns the content.
-    pub fn read(&self, page: page::Pointer) -> Result<disk::SectorBuf, Error> {
-        trace!(self, "reading page"; "page" => page);
+    /// This stores `buf`, whose checksum is `cksum`, in a fresh cluster, bypassing both
+    /// deduplication and compression.
+    fn store_uncompressed(&self, buf: &disk::SectorBuf, cksum: u64)
+        -> Result<cache::Transacting<(page::Pointer, Placement)>, Error> {
+        // Pop a cluster from the freelist.
+        let cluster = self.freelist_pop()?;
 
-        // Read the cluster in which the page is stored.
-        self.cache.read_then(page.cluster, |cluster| {
-            // Decompress if necessary.
-            let buf = if let Some(offset) = page.offset {
-                // The page is compressed, decompress it and read at some offset `offset` (in pages).
+        let ptr = page::Pointer {
+            cluster: cluster,
+            offset: None,
+            checksum: cksum,
+        };
 
-                // Decompress the cluster.
-                let decompressed = self.decompress(cluster)?;
+        // Register the page as live, and allow future use as duplicate.
+        self.register(buf, ptr);
 
-                // Read the decompressed stream from some offset, into a sector buffer.
-                let mut tmp = disk::SectorBuf::default();
-                // TODO: Find a way to eliminate this memcpy.
-                tmp.copy_from_slice(decompressed[offset * disk::SECTOR_SIZE..][..disk::SECTOR_SIZE]);
+        // Write the cluster with the raw, uncompressed data, and return the transaction monad.
+        Ok(cluster.then(self.cache.write(cluster, buf)).wrap((ptr, Placement::Fresh)))
+    }
 
-                tmp
-            } else {
-                // The page was not compressed so we can just use the cluster directly.
-                cluster
-            };
+    /// Allocate a run of pages.
+    ///
+    /// This allocates a page for every buffer of `bufs`, laid out according to `contiguity`.
+    /// Unlike `alloc`, no deduplication is done, as that would break the contiguity. If the
+    /// contiguity cannot be satisfied, an error is returned, and nothing is allocated.
+    pub fn alloc_run(&mut self, bufs: &[disk::SectorBuf], contiguity: Contiguity)
+        -> Result<cache::Transacting<Vec<page::Pointer>>, Error> {
+        debug!(self, "allocating run"; "subsystem" => subsystem::ALLOC, "pages" => bufs.len());
 
-            // Check the data against the stored checksum.
-            let cksum = self.checksum(buf) as u32;
-            if cksum as u32 != page.checksum {
-                // The checksums mismatched, thrown an error.
-                return Err(Error::PageChecksumMismatch {
-                    page: page,
-                    found: cksum,
-                });
-            }
+        // Nothing to allocate.
+        if bufs.is_empty() {
+            return Ok(cache::Transacting::no_transaction(Vec::new()));
+        }
 
-            Ok(ret)
-        })
-    }
+        // Calculate the checksums of the buffers, truncated to the width stored in the page
+        // pointers.
+        let cksums: Vec<_> = bufs.iter()
+            .map(|buf| self.checksum_page(buf))
+            .collect();
 
-    /// Calculate the checksum of some buffer, based on the user configuration.
-    fn checksum(&self, buf: &[u8]) -> u64 {
-        trace!(self, "calculating checksum");
+        match contiguity {
+            Contiguity::SameCluster => {
+                // Find the data of the cluster, and the offsets of the pages in it.
+                let (data, offsets) = if self.config.compression_algorithm == CompressionAlgorithm::Identity {
+                    // With compression disabled, only a single page fits in a cluster.
+                    if bufs.len() > 1 {
+                        return Err(Error::RunTooLarge {
+                            pages: bufs.len(),
+                        });
+                    }
 
-        self.driver.header.hash(buf)
-    }
+                    (bufs[0], vec![None])
+                } else {
+                    // Concatenate the pages and compress them together, like `store` would do
+                    // when packing them one by one.
+                    let mut uncompressed = Vec::with_capacity(bufs.len() * disk::SECTOR_SIZE);
+                    for buf in bufs {
+                        uncompressed.extend_from_slice(buf);
+                    }
 
-    /// Compress some data based on the compression configuration option.
-    ///
-    /// # Panics
-    ///
-    /// This will panic if compression is disabled.
-    fn compress(&self, input: &[u8]) -> Option<disk::SectorBuf> {
-        trace!(self, "compressing data");
+                    let algorithm = self.choose_algorithm(&bufs[0]);
+                    let compressed = if uncompressed.len() <= CLUSTER_CAPACITY {
+                        self.compress(algorithm, &uncompressed)
+                    } else {
+                        None
+                    };
 
-        // Compress the input.
-        let compressed = match self.config.compression_algorithm {
-            // We'll panic if compression is disabled, as it is assumed that the caller handles
-            // this case.
-            CompressionAlgorithm::Identity => panic!("Compression was disabled."),
-            // Compress via LZ4.
-            CompressionAlgorithm::Lz4 => lz4_compress::compress(input),
-        };
+                    (compressed.ok_or(Error::RunTooLarge {
+                        pages: bufs.len(),
+                    })?, (0..bufs.len() as u32).map(Some).collect())
+                };
 
-        if compressed.len() < disk::SECTOR_SIZE {
-            // We were able to compress the input into at least one cluster. Now, we apply padding.
+                // Pop the cluster from the freelist.
+                let cluster = self.freelist_pop()?;
 
-            // Write a delimiter to make the padding distinguishable from the actual data (e.g. if
-            // it ends in zero).
-            // TODO: This is not bijective. Very bad! FAKE NEWS
-            compressed.push(0xFF);
+                let pages: Vec<_> = offsets.into_iter().zip(cksums).map(|(offset, cksum)| page::Pointer {
+                    cluster: cluster,
+                    offset: offset,
+                    checksum: cksum,
+                }).collect();
+                // Register the pages as live, and allow future use as duplicates.
+                for (buf, &page) in bufs.iter().zip(&pages) {
+                    self.register(buf, page);
+                }
 
-            // Convert it to type `disk::SectorBuf`.
-            let mut buf = disk::SectorBuf::default();
-            // TODO: Find a way to eliminate this memcpy.
-            buf[..compressed.len()].copy_from_slice(&compressed);
-        } else {
-            // We were unable to compress the input into one cluster.
-            None
-        }
-    }
+                // Write the data into the cluster.
+                Ok(cluster.then(self.cache.write(cluster, data)).wrap(pages))
+            },
+            Contiguity::ContiguousClusters => {
+                // Pop the run of clusters from the freelist.
+                let (start, mut transaction) = self.freelist_pop_run(bufs.len())?;
+                let start: u64 = start.into();
 
-    /// Decompress some data based on the compression configuration option.
-    ///
-    /// # Panics
-    ///
-    /// This will panic if compression is disabled.
-    fn decompress(&self, cluster: disk::SectorBuf) -> Result<Box<[u8]>, Error> {
-        trace!(self, "decompressing data");
-
-        // Find the padding delimited (i.e. the last non-zero byte).
-        if let Some((len, _)) = cluster.enumerate().rev().find(|(_, x)| x != 0) {
-            // We found the delimiter and can now distinguish padding from data.
-            Ok(match self.config.compression_algorithm {
-                // We'll panic if compression is disabled, as it is assumed that the caller handles
-                // this case.
-                CompressionAlgorithm::Identity => panic!("Compression was disabled."),
-                // Decompress the non-padding section from LZ4.
-                CompressionAlgorithm::Lz4 => lz4_compress::decompress(source[..len])?,
-            })
-        } else {
-            // No delimiter was found, indicating data corruption.
-            // TODO: Use a special error for this.
-            Err(Error::InvalidCompression)
+                let mut pages = Vec::with_capacity(bufs.len());
+                for (n, (buf, cksum)) in bufs.iter().zip(cksums).enumerate() {
+                    let page = page::Pointer {
+                        // The address is non-zero, since `start` is.
+                        cluster: cluster::Pointer::new(start + n as u64).unwrap(),
+                        offset: None,
+                        checksum: cksum,
+                    };
+
+                    // Register the page as live, and allow future use as duplicate.
+                    self.register(buf, page);
+                    // Write the page into its cluster, uncompressed.
+                    transaction = transaction.then(self.cache.write(page.cluster, buf));
+
+                    pages.push(page);
+                }
+
+                Ok(transaction.wrap(pages))
+            },
         }
     }
 
-    /// Flush the state block.
+    /// Allocate many pages.
     ///
-    /// This flushes the state block (not to the disk, but to the cache), and returns the
-    /// transaction.
+    /// This allocates a page for every buffer of `bufs`, like calling `alloc` on each of them in
+    /// order, and returns the pointers in the same order. Every page is still deduplicated, also
+    /// against the preceding pages of the batch. The rest are packed into the last allocated
+    /// cluster, and then into as few new clusters as possible, each of which is written once.
     ///
-    /// It takes a state in order to avoid re-acquiring the lock.
-    fn flush_state_block(&mut self, state: &state_block::State) -> cache::Transaction {
-        trace!(self, "flushing the state block to the cache");
+    /// The clusters are popped at once, flushing the state block once. Every cluster write
+    /// depends on it, and is carried by the transaction of the first page stored in the cluster.
+    /// The other pages (i.e. the rest of the cluster and the duplicates) carry no transaction, so
+    /// the pointers are only valid once every transaction is executed. They're meant to be
+    /// combined (with `and`), such that the batch is committed atomically.
+    ///
+    /// If the freelist runs out, nothing is allocated.
+    pub fn alloc_many(&mut self, bufs: &[disk::SectorBuf])
+        -> Result<Vec<cache::Transacting<page::Pointer>>, Error> {
+        debug!(self, "allocating pages"; "subsystem" => subsystem::ALLOC, "pages" => bufs.len());
 
-        // Do it, motherfucker.
-        self.cache.write(self.driver.header.state_block_address, state_block::StateBlock {
-            config: self.config,
-            state: state,
-        }.encode())
-    }
+        // Nothing to allocate.
+        if bufs.is_empty() {
+            return Ok(Vec::new());
+        }
 
-    /// Write the head metacluster to some cluster.
-    ///
-    /// The cache transaction is returned.
-    fn write_head_metacluster(&mut self, cluster: cluster::Pointer) -> cache::Transaction {
-        trace!(self, "writing the head metacluster"; "target cluster" => cluster);
+        // Calculate the checksums of the buffers, truncated to the width stored in the page
+        // pointers.
+        let cksums: Vec<_> = bufs.iter()
+            .map(|buf| self.checksum_page(buf))
+            .collect();
 
-        self.cache.write(cluster, self.head_metacluster.encode());
-    }
+        // Resolve the duplicates within the batch to the first copy, as the batch isn't in the
+        // deduplication table yet.
+        let mut copies = Vec::with_capacity(bufs.len());
+        let mut firsts: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for (n, (buf, &cksum)) in bufs.iter().zip(&cksums).enumerate() {
+            let first = firsts.get(&cksum)
+                .and_then(|candidates| candidates.iter().cloned().find(|&m| bufs[m] == *buf));
+            if first.is_none() {
+                firsts.entry(cksum).or_insert_with(Vec::new).push(n);
+            }
 
-    /// Pop from the freelist.
-    ///
-    /// The returned pointer is wrapped in a cache transaction, representing the operations done in
-    /// order to pop it.
-    ///
-    /// The algorithm works as follows: If the head metacluster contains more free clusters, simply
-    /// pop and return the pointer. If not, make the next metacluster the head metacluster and
-    /// return the old metacluster.
-    fn freelist_pop(&mut self) -> Result<cache::Transacting<cluster::Pointer>, Error> {
-        trace!(self, "popping from freelist");
+            copies.push(first);
+        }
 
-        // Lock the state.
-        let state = self.state.lock();
+        // Count the first copies towards the automatic compaction policy, like `alloc` does. This
+        // is done before looking up the duplicates, since a compaction pass might move them, so
+        // the pages turning out to have a duplicate are counted as well.
+        for _ in copies.iter().filter(|copy| copy.is_none()) {
+            self.tick_compaction()?;
+        }
 
-        if let Some(freelist_head) = state.freelist_head.take() {
-            if let Some(free) = self.head_metacluster.free.pop() {
-                // There were one or more free clusters in the head metacluster, we pop the last
-                // free cluster in the metacluster.
-
-                // Decrement the cluster counter to "truncate" the metacluster. This trick saves us
-                // from passing through an inconsistent state as we can update the checksum and the
-                // counter in the same sector write.
-                freelist_head.counter -= 1;
-                // Update the checksum to reflect the change made to the metacluster.
-                freelist_head.checksum = self.head_metacluster.checksum();
-
-                // Put back the freelist head into the state block.
-                state.freelist_head = freelist_head;
-
-                // Flush the state block to reflect the changes above. Because both the checksum
-                // and counter are updated, this will be atomic and consistent. Wrap the output in
-                // the transaction.
-                Ok(self.flush_state_block(&state).wrap(free))
+        // Look up the duplicates before anything is written, since verifying a duplicate might
+        // read the last allocated cluster.
+        let mut pages: Vec<Option<page::Pointer>> = bufs.iter().zip(&cksums).zip(&copies)
+            .map(|((buf, &cksum), copy)| if copy.is_none() {
+                self.find_duplicate(buf, cksum)
             } else {
-                // There were no free clusters, but there might be additional metaclusters. The
-                // outline of the algorithm is to update the freelist head pointer to point to the
-                // next metacluster, if any, and then use the current, exhausted metacluster as the
-                // allocated cluster.
-
-                // The head metacluster is now empty, update the head to the next metacluster, if
-                // it exist.
-                let transaction = if let Some(next_metacluster) = self.head_metacluster.next_metacluster.take() {
-                    // A new metacluster existed.
-                    debug!(self, "switching metacluster"; "new metacluster" => next_metacluster);
-
-                    // Read and decode the metacluster.
-                    if let Ok(metacluster) = self.cache.read_then(next_metacluster.into()?, |buf| {
-                        // Decode the new metacluster.
-                        let metacluter = Metacluster::decode(buf);
-                        // Calculate the checksum.
-                        // TODO: This can be done much more efficiently, as we already have the
-                        //       decoded buffer. No need for re-decoding it.
-                        let checksum = metacluster.checksum();
-
-                        // Check the metacluster against the checksum stored in the older block.
-                        if checksum != self.head_metacluster.next_checksum {
-                            // Everything suceeded.
-                            Ok(metacluster)
-                        } else {
-                            // Checksum mismatched; throw an error.
-                            Err(Error::ChecksumMismatch {
-                                cluster: next_metacluster,
-                                // This was the stored checksum.
-                                expected: self.head_metacluster.next_checksum,
-                                // And the actual checksum.
-                                found: checksum,
-                            })
-                        }
-                    }) {
-                        // Update the head metacluster to the decoded cluster.
-                        self.head_metacluster = metacluster;
-                        // Update the state block with the data from the newly decoded metacluster.
-                        state.freelist_head = Some(state_block::FreelistHead {
-                            // The pointer should point towards the new metacluster.
-                            cluster: next_metacluster,
-                            checksum: checksum,
-                            // Since the cluster can at most contain 63 < 256 clusters, casting to u8
-                            // won't cause overflow.
-                            counter: self.head_metacluster.free.len() as u8,
-                        });
+                None
+            })
+            .collect();
 
-                        // We flush the state block flush to write down our changes to the state block.
-                        Some(self.flush_state_block(&state))
-                    } else { None }
-                } else { None };
+        // Measure how long storing the pages takes, to compare it with the cost of deduplication.
+        let start = (self.clock)();
 
-                // Use _the old_ head metacluster as the allocated cluster, and wrap it in the
-                // potential transaction from updating the metacluster head.
-                Ok(cache::Transacting::new(freelist_head.cluster, transaction))
-            }
-        } else {
-            // There is no freelist head, rendering the freelist empty, hence there is no cluster
-            // to allocate. Return an error.
-            Err(Error::OutOfClusters)
-        }
-    }
+        // Lock the last allocated cluster until the pages are stored.
+        let mut last_cluster = self.last_cluster.lock();
 
-    /// Push to the freelist.
-    ///
-    /// This pushes `cluster` to the freelist and returns the cache transaction, or an error.
-    ///
-    /// The algorithm works as follows: If the metacluster is full, the pushed cluster is used as
-    /// the new, empty head metacluster, which is linked to the old head metacluster. If not, the
-    /// free cluster is simply pushed.
-    fn freelist_push(&mut self, cluster: cluster::Pointer) -> cache::Transaction {
-        trace!(self, "pushing to freelist"; "cluster" => cluster);
+        // Lay out the pages to store. Like `store`, this packs pages greedily into the current
+        // cluster, starting with the last allocated one, and moves on to a new cluster once a page
+        // doesn't fit.
+        let mut packed = Vec::new();
+        let mut current = if self.config.compression_algorithm == CompressionAlgorithm::Identity {
+            None
+        } else {
+            last_cluster.as_ref().map(|state| PackedCluster {
+                cluster: Some(state.cluster),
+                uncompressed: state.uncompressed.clone(),
+                algorithm: Some(state.algorithm),
+                data: disk::SectorBuf::default(),
+                pages: Vec::new(),
+            })
+        };
+        for n in (0..bufs.len()).filter(|&n| pages[n].is_none() && copies[n].is_none()) {
+            let buf = &bufs[n];
 
-        // Lock the state.
-        let state = self.state.lock();
+            // Try to extend the current cluster.
+            if let Some(ref mut cluster) = current {
+                if cluster.uncompressed.len() < CLUSTER_CAPACITY {
+                    let len = cluster.uncompressed.len();
+                    cluster.uncompressed.extend_from_slice(buf);
 
-        if let Some(freelist_head) = state.freelist_head {
-            if self.head_metacluster.free.len() + 2 == disk::SECTOR_SIZE / cluster::POINTER_SIZE {
-                // The head metacluster is full, so we will use the cluster to create a new
-                // head metacluster.
-                debug!(self, "creating new metacluster"; "cluster" => cluster);
+                    if let Some(compressed) = self.compress(cluster.algorithm.unwrap(), &cluster.uncompressed) {
+                        cluster.data = compressed;
+                        cluster.pages.push(n);
+                        continue;
+                    }
 
-                // Clear the free clusters to make ensure that there isn't duplicates.
-                self.head_metacluster.free.clear();
-                // Update the head metacluster's next pointer to point to the old head metacluster.
-                self.head_metacluster.next = Some(freelist.cluster);
-                // Update the head metacluster's next metacluster checksum to be the checksum of
-                // the old metacluster as stored in the state block, since the old metacluster will
-                // become the new metacluster's next. This simple trick is allows us to bypass
-                // recalculation of the checksum. Small optimization, but hey, it works.
-                self.head_metacluster.next_checksum = freelist_head.next_checksum;
-                // Update the state block freelist head metadata to point to the new head
-                // metacluster.
-                state.freelist_head = Some(state_block::FreelistHead {
-                    cluster: cluster,
-                    // Calculate the checksum of the new head metacluster.
-                    checksum: self.head_metacluster.checksum(),
-                    // Currently, no free clusters are stored in the new head metacluster, so the
-                    // counter is 0.
-                    counter: 0,
+                    // It didn't fit, so undo the extension.
+                    cluster.uncompressed.truncate(len);
+                }
+            }
+
+            // The page starts a new cluster. Only clusters gaining pages need to be written.
+            packed.extend(current.take().filter(|cluster| !cluster.pages.is_empty()));
+            if self.config.compression_algorithm == CompressionAlgorithm::Identity {
+                packed.push(PackedCluster {
+                    cluster: None,
+                    uncompressed: Vec::new(),
+                    algorithm: None,
+                    data: *buf,
+                    pages: vec![n],
+                });
+            } else if let Some((algorithm, compressed)) = self.compress_new_cluster(buf) {
+                current = Some(PackedCluster {
+                    cluster: None,
+                    uncompressed: buf.as_vec(),
+                    algorithm: Some(algorithm),
+                    data: compressed,
+                    pages: vec![n],
                 });
-                // Write the metacluster to `cluster`. This won't leave the system in an
-                // inconsistent state, as only `cluster`, which is free, will be changed.
-                self.write_head_metacluster(cluster).then(
-                    // Flush the state block. This won't leave the system in an inconsistent state
-                    // either, as a new, valid metacluster is stored at `cluster`.
-                    self.flush_state_block(&state)
-                )
             } else {
-                // There is more space in the head metacluster.
+                // As in `store`, incompressible pages are stored in a cluster of their own, and
+                // aren't extended further.
+                packed.push(PackedCluster {
+                    cluster: None,
+                    uncompressed: Vec::new(),
+                    algorithm: None,
+                    data: *buf,
+                    pages: vec![n],
+                });
+            }
+        }
+        // The cluster packed last is kept as the last allocated cluster.
+        let last = current.map(|cluster| {
+            packed.push(cluster);
+            packed.len() - 1
+        });
 
-                // Push the new free cluster.
-                self.head_metacluster.free.push(cluster);
-                // Flush. Woosh!
-                self.flush_state_block(&state)
+        // Pop the clusters at once, so the state block is flushed once, ahead of the cluster
+        // writes.
+        let needed = packed.iter().filter(|cluster| cluster.cluster.is_none()).count();
+        let popped = match self.freelist_pop_many(needed) {
+            Ok(popped) => popped,
+            Err(err) => {
+                // Nothing is allocated, so give back the references taken to the duplicates,
+                // which are the only pages resolved so far.
+                for &page in pages.iter().flat_map(|page| page) {
+                    self.unreference(page);
+                }
+
+                return Err(err);
+            },
+        };
+        let popped = popped.map(|clusters| {
+            let unassigned = packed.iter_mut().filter(|cluster| cluster.cluster.is_none());
+            for (cluster, pointer) in unassigned.zip(clusters) {
+                cluster.cluster = Some(pointer);
+            }
+        });
+
+        // Write the clusters, and register the pages as live, allowing future use as duplicates.
+        let mut writes = Vec::with_capacity(packed.len());
+        for cluster in &packed {
+            let pointer = cluster.cluster.unwrap();
+            if cluster.pages.is_empty() {
+                // The last allocated cluster didn't gain any pages.
+                continue;
+            }
+
+            trace!(self, "writing packed cluster"; "subsystem" => subsystem::ALLOC, "cluster" => pointer,
+                   "pages" => cluster.pages.len());
+            writes.push((cluster.pages[0], self.cache.write(pointer, cluster.data)));
+
+            // Pages appended to the last allocated cluster follow the pages already in it. Uncompressed
+            // clusters hold a single page, and no stream.
+            let base = cluster.algorithm.map(|_| cluster.uncompressed.len() / disk::SECTOR_SIZE - cluster.pages.len());
+            for (offset, &n) in cluster.pages.iter().enumerate() {
+                let page = page::Pointer {
+                    cluster: pointer,
+                    offset: base.map(|base| (base + offset) as u32),
+                    checksum: cksums[n],
+                };
+
+                self.register(&bufs[n], page);
+                pages[n] = Some(page);
+            }
+        }
+
+        // Make every cluster write depend on the popping. The first write takes over its
+        // transaction, which is thus executed along with the batch.
+        let mut transactions: Vec<_> = (0..bufs.len()).map(|_| None).collect();
+        let mut writes = writes.into_iter();
+        if let Some((first, write)) = writes.next() {
+            for (n, write) in writes {
+                transactions[n] = Some(popped.precede(write));
             }
+            transactions[first] = Some(popped.then(write));
         } else {
-            // The freelist is empty, so we set the cluster up as an empty metacluster as the
-            // head metacluster.
-            state.freelist_head = Some(state_block::FreelistHead {
-                cluster: cluster,
-                checksum: 0,
-                counter: 0,
+            // Every page had a duplicate, so nothing was popped.
+            popped.execute();
+        }
+
+        // Update the last allocated cluster, unless compression is disabled, in which case it is
+        // left alone.
+        if let Some(index) = last {
+            let cluster = &packed[index];
+            *last_cluster = Some(ClusterState {
+                cluster: cluster.cluster.unwrap(),
+                uncompressed: cluster.uncompressed.clone(),
+                algorithm: cluster.algorithm.unwrap(),
             });
-            // Flush the state block to add the new cluster.
-            self.flush_state_block(&state)
+        } else if self.config.compression_algorithm != CompressionAlgorithm::Identity {
+            // The batch ended with an incompressible page, so there is no extendible cluster.
+            *last_cluster = None;
+        }
+        drop(last_cluster);
+
+        // Record the cost of storing, spread evenly over the pages stored, like `alloc` records
+        // it for every page.
+        let stored: usize = packed.iter().map(|cluster| cluster.pages.len()).sum();
+        if stored != 0 {
+            let cost = nanos((self.clock)() - start) / stored;
+            for _ in 0..stored {
+                self.dedup_cost.record_store(cost);
+            }
         }
+
+        // Resolve the duplicates within the batch, and wrap the pointers in their transactions.
+        // Every copy takes another reference to the first one, which was just registered.
+        Ok(transactions.into_iter().enumerate().map(|(n, transaction)| {
+            let page = match copies[n] {
+                Some(m) => {
+                    let page = pages[m].unwrap();
+                    self.reference(page);
+                    page
+                },
+                None => pages[n].unwrap(),
+            };
+
+            cache::Transacting::new(page, transaction)
+        }).collect())
     }
-}
 
-delegate_log!(Manager.cache);
+    /// Register a newly stored page.
+    ///
+    /// This inserts page `page` with content `buf` into the deduplication table and the live page
+    /// index.
+    fn register(&self, buf: &disk::SectorBuf, page: page::Pointer) {
+        // Insert the page pointer into the deduplication table to allow future use as duplicate.
+        self.dedup_table.insert(buf, page);
+        self.track(page);
+    }
+
+    /// Track a stored page.
+    ///
+    /// This is like `register`, but it doesn't make the page available for deduplication.
+    fn track(&self, page: page::Pointer) {
+        self.touch_index();
+
+        // Add the page to the live pages of its cluster. If the cluster was not in use, it is
+        // stamped with the next sequence number.
+        let mut live = self.live.lock();
+        let live_pages = live.entry(page.cluster).or_insert_with(|| LivePages {
+            sequence: self.next_sequence.fetch_add(1, ORDERING) as u64,
+            pages: Vec::new(),
+            duplicates: HashMap::new(),
+            stored: 0,
+        });
+        live_pages.pages.push(page);
+        live_pages.stored += 1;
+        drop(live);
+
+        // Stamp the cluster with the current backup generation.
+        let generation = self.state.lock().backup_generation;
+        self.changed.lock().insert(page.cluster, generation);
+        // Count the write to the cluster.
+        if self.track_wear {
+            *self.writes.lock().entry(page.cluster).or_insert(0) += 1;
+        }
+    }
+
+    /// Take another reference to a live page.
+    ///
+    /// This increments the reference count of `page`, so it is only freed once every reference is.
+    /// If the page isn't in the live page index, nothing is changed, and `false` is returned.
+    fn reference(&self, page: page::Pointer) -> bool {
+        self.touch_index();
+
+        let mut live = self.live.lock();
+        match live.get_mut(&page.cluster) {
+            Some(live_pages) if live_pages.pages.contains(&page) => {
+                *live_pages.duplicates.entry(page).or_insert(0) += 1;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Drop a reference taken by `reference`.
+    ///
+    /// This gives back a reference to `page`, which ended up not being handed out. Since it isn't
+    /// the last reference, the page itself stays live.
+    fn unreference(&self, page: page::Pointer) {
+        self.touch_index();
+
+        if let Some(live_pages) = self.live.lock().get_mut(&page.cluster) {
+            if let Some(duplicates) = live_pages.duplicates.get_mut(&page) {
+                *duplicates -= 1;
+                if *duplicates == 0 {
+                    live_pages.duplicates.remove(&page);
+                }
+            }
+        }
+    }
+
+    /// Set the number of duplicates of a live page.
+    ///
+    /// This carries the references of a moved page over to its new pointer.
+    fn set_duplicates(&self, page: page::Pointer, duplicates: usize) {
+        if duplicates == 0 {
+            return;
+        }
+
+        if let Some(live_pages) = self.live.lock().get_mut(&page.cluster) {
+            live_pages.duplicates.insert(page, duplicates);
+        }
+    }
+
+    /// Allocate a large page.
+    ///
+    /// This allocates a page spanning multiple sectors, with content `buf`, whose length must be a
+    /// non-zero multiple of the sector size. It is stored uncompressed in consecutive clusters, and
+    /// never deduplicated. If large pages are not enabled in the disk header,
+    /// `Error::LargePagesDisabled` is returned.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the length of `buf` is not a non-zero multiple of the sector size.
+    pub fn alloc_large(&mut self, buf: &[u8]) -> Result<cache::Transacting<page::LargePointer>, Error> {
+        if !self.driver.header.large_pages {
+            return Err(Error::LargePagesDisabled);
+        }
+
+        assert!(!buf.is_empty() && buf.len() % disk::SECTOR_SIZE == 0,
+                "The length of a large page must be a non-zero multiple of the sector size.");
+        let sectors = buf.len() / disk::SECTOR_SIZE;
+        debug!(self, "allocating large page"; "subsystem" => subsystem::ALLOC, "sectors" => sectors);
+
+        // Pop the run of clusters from the freelist.
+        let (start, mut transaction) = self.freelist_pop_run(sectors)?;
+        let ptr = page::LargePointer {
+            first: page::Pointer {
+                cluster: start,
+                offset: None,
+                // The checksum covers the whole page.
+                checksum: self.checksum_page(buf),
+            },
+            sectors: sectors as u32,
+        };
+
+        for (page, data) in ptr.sectors().zip(buf.chunks(disk::SECTOR_SIZE)) {
+            // Track the cluster as live.
+            self.track(page);
+            // Write the sector into its cluster.
+            let mut sector = disk::SectorBuf::default();
+            sector.copy_from_slice(data);
+            transaction = transaction.then(self.cache.write(page.cluster, sector));
+        }
+
+        Ok(transaction.wrap(ptr))
+    }
+
+    /// Read a large page.
+    ///
+    /// This reads the sectors of large page `page`, and checks the whole page against its
+    /// checksum.
+    pub fn read_large(&self, page: page::LargePointer) -> Result<Vec<u8>, Error> {
+        trace!(self, "reading large page"; "subsystem" => subsystem::ALLOC,
+               "page" => page.first, "sectors" => page.sectors);
+
+        let mut buf = Vec::with_capacity(page.sectors as usize * disk::SECTOR_SIZE);
+        for sector in page.sectors() {
+            self.cache.read_then(sector.cluster, |data| {
+                buf.extend_from_slice(data);
+                Ok(())
+            })?;
+        }
+
+        // Check the data against the checksum.
+        let cksum = self.checksum_page(&buf);
+        if cksum != page.first.checksum {
+            return Err(Error::PageChecksumMismatch {
+                page: page.first,
+                found: cksum,
+            });
+        }
+
+        Ok(buf)
+    }
+
+    /// Free a large page.
+    ///
+    /// This frees the clusters of large page `page`, like `free_range`.
+    pub fn free_large(&mut self, page: page::LargePointer) -> Result<Option<cache::Transaction>, Error> {
+        self.free_range(&page.sectors().collect::<Vec<_>>())
+    }
+
+    /// Mark a backup checkpoint.
+    ///
+    /// This bumps the backup generation stored in the state block, and returns the new generation
+    /// wrapped in the cache transaction. Clusters written from now on are reported by
+    /// `changed_clusters_since` with this generation.
+    pub fn bump_backup_generation(&mut self) -> cache::Transacting<u64> {
+        // Lock the state.
+        let mut state = self.state.lock();
+        state.backup_generation += 1;
+
+        info!(self, "bumping the backup generation"; "subsystem" => subsystem::ALLOC,
+              "generation" => state.backup_generation);
+
+        self.flush_state_block(&state).wrap(state.backup_generation)
+    }
+
+    /// Get the clusters changed since some backup generation.
+    ///
+    /// This returns the clusters, which are still in use and were written in generation
+    /// `generation` or later, ordered by address. Only these need to be copied by an incremental
+    /// backup following a backup of `generation - 1`.
+    ///
+    /// The generations are persisted with the live page index. If the index is incomplete (see
+    /// `rebuild_refcounts`), the clusters written before the manager was opened are unaccounted
+    /// for, so `None` is returned, and a full backup is needed.
+    pub fn changed_clusters_since(&self, generation: u64) -> Option<Vec<cluster::Pointer>> {
+        if !self.index_complete {
+            warn!(self, "changed clusters unknown before the manager was opened; a full backup is needed";
+                  "subsystem" => subsystem::ALLOC);
+            return None;
+        }
+
+        Some(self.changed.lock().iter()
+            .filter(|&(_, &written)| written >= generation)
+            .map(|(&cluster, _)| cluster)
+            .collect())
+    }
+
+    /// Iterate over the live pages in write order.
+    ///
+    /// This yields every live page along with its content, ordered by when their clusters were
+    /// taken into use (and within a cluster, by when the pages were allocated). This
+    /// approximates the order in which the pages were written, which is useful for consistent
+    /// backups.
+    ///
+    /// The set of pages is snapshotted when this is called, but their content is read lazily.
+    pub fn iter_pages_in_write_order(&self)
+        -> impl Iterator<Item = Result<(page::Pointer, disk::SectorBuf), Error>> + '_ {
+        // Collect the live pages, ordered by the sequence number of their cluster.
+        let mut clusters: Vec<_> = self.live.lock().values()
+            .map(|live_pages| (live_pages.sequence, live_pages.pages.clone()))
+            .collect();
+        clusters.sort_by_key(|&(sequence, _)| sequence);
+
+        // Read the pages one by one.
+        clusters.into_iter()
+            .flat_map(|(_, pages)| pages)
+            .map(move |page| self.read(page).map(|buf| (page, buf)))
+    }
+
+    /// Free a page.
+    ///
+    /// This drops a reference to page `page`. Every allocation handing out the page (including
+    /// duplicates found through deduplication) takes a reference, and the page is only removed
+    /// from the live pages of its cluster, when the last one is dropped. If no live pages are left
+    /// in the cluster, it is pushed to the freelist, and the cache transaction is returned.
+    ///
+    /// If the page points to a cluster holding freelist metadata, nothing is freed, and
+    /// `Error::ClusterInUseAsMetadata` is returned.
+    ///
+    /// If the free triggers automatic compaction, the transaction is executed before the pass,
+    /// and `None` is returned. See `free_range`.
+    pub fn free(&mut self, page: page::Pointer) -> Result<Option<cache::Transaction>, Error> {
+        trace!(self, "freeing page"; "subsystem" => subsystem::ALLOC, "page" => page);
+
+        self.free_range(&[page])
+    }
+
+    /// Free a range of pages.
+    ///
+    /// This is like calling `free` on each page of `pages` (e.g. the pages of some object), but
+    /// the pages are grouped by cluster, and the evacuated clusters are pushed to the freelist in
+    /// one batch, with a single state block flush. The transaction is returned, if any cluster
+    /// was freed.
+    ///
+    /// If automatic compaction is enabled and a pass is due, the transaction is executed first,
+    /// so the pass can reuse the freed clusters, and `None` is returned, even if clusters were
+    /// freed. See `set_auto_compaction`.
+    pub fn free_range(&mut self, pages: &[page::Pointer]) -> Result<Option<cache::Transaction>, Error> {
+        debug!(self, "freeing pages"; "subsystem" => subsystem::ALLOC, "pages" => pages.len());
+
+        // Release the pages, and push the evacuated clusters to the freelist.
+        let empty = self.release(pages)?;
+        let transaction = if empty.is_empty() {
+            None
+        } else {
+            debug!(self, "freeing clusters"; "subsystem" => subsystem::ALLOC, "clusters" => empty.len());
+
+            Some(self.freelist_push_batch(&empty))
+        };
+
+        // Compact automatically, if the policy calls for it.
+        if self.auto_compaction.lock().is_some() {
+            self.operations_since_compaction.fetch_add(1, ORDERING);
+            if self.compaction_due() {
+                // Complete the freeing first, so the compaction can reuse the clusters.
+                if let Some(transaction) = transaction {
+                    transaction.execute();
+                }
+                self.compact_if_needed()?;
+
+                return Ok(None);
+            }
+        }
+
+        Ok(transaction)
+    }
+
+    /// Free a page to the trash.
+    ///
+    /// This is like `free`, but if the cluster is evacuated, it is moved to the trash instead of
+    /// the freelist, so its data is kept around, and the page can be brought back by `undelete`.
+    /// The trash is bounded by `TRASH_CAPACITY`, and the oldest clusters are evicted (truly freed)
+    /// when it overflows, or when the freelist runs empty.
+    ///
+    /// The cache transaction of evicting clusters is returned, if any.
+    pub fn free_to_trash(&mut self, page: page::Pointer) -> Result<Option<cache::Transaction>, Error> {
+        trace!(self, "freeing page to the trash"; "subsystem" => subsystem::ALLOC, "page" => page);
+
+        // Release the page, and move the evacuated clusters to the trash.
+        let empty = self.release(&[page])?;
+        let evicted: Vec<_> = {
+            let mut trash = self.trash.lock();
+            trash.extend(empty);
+
+            // Evict the oldest clusters, if the trash is full.
+            let overflow = trash.len().saturating_sub(TRASH_CAPACITY);
+            trash.drain(..overflow).collect()
+        };
+
+        if evicted.is_empty() {
+            Ok(None)
+        } else {
+            debug!(self, "evicting clusters from the trash"; "subsystem" => subsystem::ALLOC,
+                   "clusters" => evicted.len());
+
+            Ok(Some(self.freelist_push_batch(&evicted)))
+        }
+    }
+
+    /// Undelete a page.
+    ///
+    /// This brings back page `page`, which was freed through `free_to_trash`. If its cluster has
+    /// been evicted from the trash, and reused since, the page is lost, and an error is returned.
+    pub fn undelete(&mut self, page: page::Pointer) -> Result<(), Error> {
+        debug!(self, "undeleting page"; "subsystem" => subsystem::ALLOC, "page" => page);
+
+        self.touch_index();
+        // Take the cluster out of the trash, if it's there.
+        let trashed = {
+            let mut trash = self.trash.lock();
+            let position = trash.iter().position(|&cluster| cluster == page.cluster);
+            position.map(|n| trash.remove(n)).is_some()
+        };
+
+        // If it isn't in the trash, the cluster must still be in use for the page to be intact.
+        if !trashed && !self.live.lock().contains_key(&page.cluster) {
+            return Err(Error::PageNotInTrash {
+                page: page,
+            });
+        }
+
+        // Make sure that the data is intact, by reading and verifying it.
+        let buf = match self.read(page) {
+            Ok(buf) => buf,
+            Err(err) => {
+                // Put back the cluster in the trash.
+                if trashed {
+                    self.trash.lock().push_front(page.cluster);
+                }
+
+                return Err(err);
+            },
+        };
+
+        // Restore the page, unless it is already live.
+        let live = self.live.lock().get(&page.cluster).map_or(false, |live_pages| live_pages.pages.contains(&page));
+        if !live {
+            self.register(&buf, page);
+        }
+
+        Ok(())
+    }
+
+    /// Rebuild the live page index.
+    ///
+    /// The number of live pages of a cluster is its reference count. If it drifts (e.g. after a
+    /// crash, or because of a bug), clusters are either leaked or freed prematurely. This
+    /// recomputes the index from `live`, the authoritative set of live pages (usually supplied
+    /// by the caller's own index), overwriting the old one. A page is supplied once for every
+    /// reference to it, i.e. duplicates handed out by deduplication are supplied as well.
+    ///
+    /// Clusters which were in use, but hold no live pages according to `live`, are leaked, so
+    /// they're pushed to the freelist, and the cache transaction is returned.
+    ///
+    /// The rebuilt index is complete, so if persistence is enabled, it is persisted (along with
+    /// the rest of the allocation metadata) before the leaked clusters are pushed. A crash in
+    /// between merely leaks them again.
+    pub fn rebuild_refcounts<I>(&mut self, live: I) -> Result<Option<cache::Transaction>, Error>
+        where I: Iterator<Item = page::Pointer> {
+        info!(self, "rebuilding the live page index"; "subsystem" => subsystem::ALLOC);
+
+        self.touch_index();
+        let (dead, leaked) = {
+            let mut old = self.live.lock();
+
+            // Build the new index, keeping the sequence numbers of the clusters already in use.
+            let mut new = HashMap::with_capacity(old.len());
+            for page in live {
+                let live_pages = new.entry(page.cluster).or_insert_with(|| LivePages {
+                    sequence: old.get(&page.cluster).map_or_else(|| {
+                        self.next_sequence.fetch_add(1, ORDERING) as u64
+                    }, |live_pages| live_pages.sequence),
+                    pages: Vec::new(),
+                    duplicates: HashMap::new(),
+                    stored: old.get(&page.cluster).map_or(0, |live_pages| live_pages.stored),
+                });
+
+                // A page supplied several times is referenced several times.
+                if live_pages.pages.contains(&page) {
+                    *live_pages.duplicates.entry(page).or_insert(0) += 1;
+                } else {
+                    live_pages.pages.push(page);
+                    live_pages.stored = live_pages.stored.max(live_pages.pages.len());
+                }
+            }
+
+            // Find the pages which are no longer live, and the clusters left without live pages.
+            let dead: Vec<_> = old.values()
+                .flat_map(|live_pages| live_pages.pages.iter().cloned())
+                .filter(|page| new.get(&page.cluster).map_or(true, |live_pages| !live_pages.pages.contains(page)))
+                .collect();
+            let leaked: Vec<_> = old.keys().filter(|cluster| !new.contains_key(cluster)).cloned().collect();
+
+            *old = new;
+
+            (dead, leaked)
+        };
+
+        // Make sure the dead pages aren't handed out as duplicates anymore.
+        for page in dead {
+            self.dedup_table.remove(page);
+        }
+
+        // The clusters written before the manager was opened are of unknown age, so they are
+        // stamped with the current backup generation, making the next incremental backup cover
+        // them. The leaked clusters hold nothing worth backing up.
+        {
+            let generation = self.state.lock().backup_generation;
+            let clusters: Vec<_> = self.live.lock().keys().cloned().collect();
+            let mut changed = self.changed.lock();
+            for cluster in clusters {
+                changed.entry(cluster).or_insert(generation);
+            }
+            for cluster in &leaked {
+                changed.remove(cluster);
+            }
+        }
+
+        // Persist the rebuilt index.
+        self.index_complete = true;
+        if self.driver.header.index_sectors != 0 {
+            self.sync_metadata()?;
+        }
+
+        if leaked.is_empty() {
+            return Ok(None);
+        }
+
+        warn!(self, "reclaiming leaked clusters"; "subsystem" => subsystem::ALLOC,
+              "clusters" => leaked.len());
+
+        // Stop packing pages into the last allocated cluster, if it was leaked.
+        {
+            let mut last_cluster = self.last_cluster.lock();
+            if last_cluster.as_ref().map_or(false, |state| leaked.contains(&state.cluster)) {
+                *last_cluster = None;
+            }
+        }
+
+        Ok(Some(self.freelist_push_batch(&leaked)))
+    }
+
+    /// Verify the allocation metadata, and repair what is recoverable.
+    ///
+    /// This is meant to be run offline, with `live` yielding every page in use. It does the
+    /// following, in order:
+    ///
+    /// 1. Rebuild the reference counts (see `rebuild_refcounts`).
+    /// 2. Remove duplicate entries from the freelist.
+    /// 3. Rewrite the metaclusters whose counter or checksum drifted.
+    /// 4. Reclaim the clusters which are neither free nor referenced by `live`.
+    ///
+    /// The metaclusters are rewritten in place, from the tail towards the head, and the state
+    /// block is written last. This is not crash-safe: a crash halfway through can leave a
+    /// metacluster whose checksum doesn't match the one stored in its predecessor, so the
+    /// freelist fails to load, until it is repaired again. Hence, this must be run offline, with
+    /// nothing else relying on the device. Everything is synced to the disk before returning.
+    pub fn verify_and_repair<I>(&mut self, live: I) -> Result<RepairReport, Error>
+        where I: Iterator<Item = page::Pointer> {
+        info!(self, "verifying and repairing the allocation metadata"; "subsystem" => subsystem::ALLOC);
+
+        let mut report = RepairReport::default();
+
+        // Rebuild the reference counts, and count the clusters left without live pages.
+        let live: Vec<_> = live.collect();
+        let before: Vec<_> = self.live.lock().keys().cloned().collect();
+        if let Some(transaction) = self.rebuild_refcounts(live.iter().cloned())? {
+            transaction.execute();
+        }
+        report.clusters_reclaimed += before.iter().filter(|cluster| !self.live.lock().contains_key(cluster)).count();
+
+        // Lock the freelist, and load the metacluster chain following the head metacluster.
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+        let mut chain = self.load_metacluster_chain(&head_metacluster)?;
+
+        // The clusters seen in the freelist so far, starting with the metaclusters.
+        let mut seen = BTreeSet::new();
+        seen.extend(state.freelist_head.map(|freelist_head| freelist_head.cluster));
+        seen.extend(chain.iter().map(|&(cluster, _, _)| cluster));
+
+        // Remove the duplicates from the head metacluster.
+        let head_len = head_metacluster.free.len();
+        head_metacluster.free.retain(|&cluster| seen.insert(cluster));
+        report.duplicates_removed += head_len - head_metacluster.free.len();
+        let mut head_changed = head_len != head_metacluster.free.len();
+
+        // The other metaclusters must remain full, so their duplicates are replaced by clusters
+        // taken from the head metacluster.
+        for &mut (cluster, ref mut metacluster, ref mut changed) in &mut chain {
+            for n in 0..metacluster.free.len() {
+                if seen.insert(metacluster.free[n]) {
+                    continue;
+                }
+
+                if let Some(replacement) = head_metacluster.free.pop() {
+                    metacluster.free[n] = replacement;
+                    report.duplicates_removed += 1;
+                    *changed = true;
+                    head_changed = true;
+                } else {
+                    warn!(self, "unable to remove duplicate from full metacluster"; "subsystem" => subsystem::ALLOC,
+                          "metacluster" => cluster, "duplicate" => metacluster.free[n]);
+                }
+            }
+        }
+
+        // Rewrite the metaclusters which changed or drifted.
+        let (transaction, rewritten) = self.rewrite_metacluster_chain(&mut state, &mut head_metacluster, &mut chain, head_changed);
+        report.metaclusters_rewritten += rewritten;
+        transaction.then(self.flush_state_block(&state)).execute();
+
+        // Find the clusters, which are neither free, live, in the trash, retained for a snapshot,
+        // nor reserved.
+        let used: BTreeSet<_> = live.iter().map(|page| page.cluster)
+            .chain(self.trash.lock().iter().cloned())
+            .chain(self.retained.lock().iter().cloned())
+            .chain(state.reserve.iter().cloned())
+            .collect();
+        let leaked: Vec<_> = (self.first_data_cluster()..self.driver.number_of_sectors())
+            .filter(|&sector| !self.cache.is_metadata(sector))
+            .filter_map(|sector| cluster::Pointer::new(sector as u64))
+            .filter(|cluster| !seen.contains(cluster) && !used.contains(cluster))
+            .collect();
+        drop(state);
+        drop(head_metacluster);
+
+        // Reclaim them.
+        if !leaked.is_empty() {
+            warn!(self, "reclaiming unreferenced clusters"; "subsystem" => subsystem::ALLOC,
+                  "clusters" => leaked.len());
+
+            report.clusters_reclaimed += leaked.len();
+            self.freelist_push_batch(&leaked).execute();
+        }
+
+        // Make the repairs durable.
+        self.sync_metadata()?;
+
+        Ok(report)
+    }
+
+    /// Trim the freelist to the bounds of the device.
+    ///
+    /// If the device was shrunk out-of-band (e.g. a truncated image), the freelist might still
+    /// refer to clusters beyond its end, and handing those out would cause out-of-bounds writes.
+    /// This drops every such cluster from the freelist, and returns how many were dropped.
+    ///
+    /// The metaclusters following the head metacluster must remain full, so their dropped entries
+    /// are replaced by clusters taken from the head metacluster. If it runs dry, the chain is cut,
+    /// and the free clusters following the cut are pushed anew. If the chain links to a
+    /// metacluster beyond the end, it is cut there, and the clusters held by the lost metaclusters
+    /// are leaked (they can be reclaimed by `verify_and_repair`).
+    ///
+    /// The head metacluster itself is assumed to be within the device, as it was loaded already.
+    /// Reserved clusters beyond the end are dropped as well.
+    pub fn trim_freelist_to_device_bounds(&mut self) -> Result<usize, Error> {
+        debug!(self, "trimming the freelist to the device bounds"; "subsystem" => subsystem::FREELIST);
+
+        // The end of the device.
+        let end = self.driver.number_of_sectors() as u64;
+        let mut dropped = 0;
+
+        // Lock the freelist.
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+
+        // Load the metacluster chain following the head metacluster, stopping at the end of the
+        // device.
+        let mut chain = Vec::new();
+        let mut next = head_metacluster.next;
+        while let Some(cluster) = next {
+            // Guard against cycles.
+            if chain.len() > self.driver.number_of_sectors() {
+                return Err(Error::FreelistCycle {
+                    cluster: cluster,
+                });
+            }
+
+            // Stop at metaclusters beyond the end of the device, or with sectors beyond it. The
+            // sectors following the first are stored in its first free clusters.
+            let beyond = u64::from(cluster) >= end || self.cache.read_then(cluster.into(), |buf| {
+                Ok::<_, Error>(Metacluster::decode(buf, self.metacluster_sectors() as u8 - 1))
+            })?.map_or(false, |metacluster| metacluster.free.iter().any(|&sector| u64::from(sector) >= end));
+            if beyond {
+                warn!(self, "freelist links to metacluster beyond the device"; "subsystem" => subsystem::FREELIST,
+                      "metacluster" => cluster);
+                dropped += 1;
+                break;
+            }
+
+            // Metaclusters are only linked to when they're full.
+            let metacluster = self.read_metacluster(cluster, self.max_free() as u8, |_| Ok(()))?;
+            next = metacluster.next;
+            chain.push((cluster, metacluster, false));
+        }
+        // If the walk stopped early, the chain must be cut after the last metacluster loaded.
+        let mut cut = next.is_some();
+
+        // Drop the reserved clusters beyond the end.
+        let reserve_len = state.reserve.len();
+        state.reserve.retain(|&cluster| u64::from(cluster) < end);
+        dropped += reserve_len - state.reserve.len();
+
+        // Drop the clusters beyond the end from the head metacluster.
+        let head_len = head_metacluster.free.len();
+        head_metacluster.free.retain(|&cluster| u64::from(cluster) < end);
+        dropped += head_len - head_metacluster.free.len();
+        let mut head_changed = head_len != head_metacluster.free.len();
+
+        // Replace the clusters beyond the end in the other metaclusters.
+        let mut orphans = Vec::new();
+        for n in 0..chain.len() {
+            let exhausted = {
+                let (_, ref mut metacluster, ref mut changed) = chain[n];
+                for m in 0..metacluster.free.len() {
+                    if u64::from(metacluster.free[m]) < end {
+                        continue;
+                    }
+
+                    if let Some(replacement) = head_metacluster.free.pop() {
+                        metacluster.free[m] = replacement;
+                        dropped += 1;
+                        *changed = true;
+                        head_changed = true;
+                    }
+                }
+
+                metacluster.free.iter().any(|&cluster| u64::from(cluster) >= end)
+            };
+
+            // If the head metacluster ran dry, cut the chain here, and keep the free clusters
+            // following the cut (including the metaclusters), which are within the device.
+            if exhausted {
+                for (cluster, metacluster, _) in chain.drain(n..) {
+                    let len = metacluster.free.len();
+                    let kept = orphans.len();
+                    orphans.extend(metacluster.free.into_iter().filter(|&cluster| u64::from(cluster) < end));
+                    dropped += len - (orphans.len() - kept);
+                    orphans.push(cluster);
+                }
+                cut = true;
+                break;
+            }
+        }
+
+        // Terminate the chain at the cut.
+        if cut {
+            if let Some(&mut (_, ref mut metacluster, ref mut changed)) = chain.last_mut() {
+                metacluster.next = None;
+                *changed = true;
+            } else {
+                head_metacluster.next = None;
+                head_changed = true;
+            }
+        }
+
+        // Rewrite the metaclusters which changed, then flush the state block.
+        let (transaction, _) = self.rewrite_metacluster_chain(&mut state, &mut head_metacluster, &mut chain, head_changed);
+        transaction.then(self.flush_state_block(&state)).execute();
+        drop(state);
+        drop(head_metacluster);
+
+        // Push the free clusters following the cut anew.
+        if !orphans.is_empty() {
+            self.freelist_push_batch(&orphans).execute();
+        }
+
+        if dropped > 0 {
+            warn!(self, "dropped free clusters beyond the device"; "subsystem" => subsystem::FREELIST,
+                  "clusters" => dropped);
+        }
+
+        Ok(dropped)
+    }
+
+    /// Coalesce the free clusters.
+    ///
+    /// This reorganizes the freelist to group physically adjacent clusters: The free clusters are
+    /// redistributed among the metaclusters, such that the longest runs of consecutive addresses
+    /// end up in the head metacluster, where `alloc_run` with `Contiguity::ContiguousClusters`
+    /// looks for them. Within each metacluster, the clusters are popped in ascending order, which
+    /// improves the locality of sequential writes (notably on spinning disks).
+    ///
+    /// The metaclusters stay in place, and so does the number of free clusters in each of them.
+    /// Hence, the head metacluster can only hold as much of a run as it has entries. Like
+    /// `verify_and_repair`, this rewrites the whole freelist, so it is meant as an occasional
+    /// maintenance operation. It gives up the order kept by `AllocationStrategy::LowestFirst`
+    /// until the strategy is set again.
+    ///
+    /// The length of the longest run in the head metacluster is returned.
+    pub fn coalesce(&mut self) -> Result<usize, Error> {
+        info!(self, "coalescing the free clusters"; "subsystem" => subsystem::FREELIST);
+
+        // Lock the freelist, and load the metacluster chain following the head metacluster.
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+        let mut chain = self.load_metacluster_chain(&head_metacluster)?;
+
+        // Gather the free clusters, and sort them by address.
+        let mut free: Vec<u64> = head_metacluster.free.iter()
+            .chain(chain.iter().flat_map(|&(_, ref metacluster, _)| metacluster.free.iter()))
+            .map(|&cluster| cluster.into())
+            .collect();
+        free.sort();
+
+        // Split them into runs of consecutive addresses.
+        let mut runs = Vec::new();
+        let mut start = 0;
+        for n in 1..free.len() + 1 {
+            if n == free.len() || free[n] != free[n - 1] + 1 {
+                runs.push(&free[start..n]);
+                start = n;
+            }
+        }
+        // Order the runs longest first. The sort is stable, so equally long runs remain in the
+        // order of their addresses.
+        runs.sort_by(|a, b| b.len().cmp(&a.len()));
+
+        let head_len = head_metacluster.free.len();
+        let longest = runs.first().map_or(0, |run| run.len().min(head_len));
+        debug!(self, "found runs of free clusters"; "subsystem" => subsystem::FREELIST,
+               "runs" => runs.len(), "longest" => longest);
+
+        // Deal the clusters out to the metaclusters, starting with the head metacluster. The
+        // clusters of a metacluster are stored in descending order, as the last one is popped
+        // first.
+        let mut clusters = runs.into_iter()
+            .flat_map(|run| run.iter())
+            .map(|&cluster| cluster::Pointer::new(cluster).unwrap());
+        let mut deal = |len| {
+            let mut free: Vec<_> = clusters.by_ref().take(len).collect();
+            free.sort_by(|a, b| b.cmp(a));
+            free
+        };
+        head_metacluster.free = deal(head_len);
+        for &mut (_, ref mut metacluster, ref mut changed) in &mut chain {
+            metacluster.free = deal(metacluster.free.len());
+            *changed = true;
+        }
+
+        // Rewrite the metaclusters, then flush the state block.
+        let (transaction, _) = self.rewrite_metacluster_chain(&mut state, &mut head_metacluster, &mut chain, true);
+        transaction.then(self.flush_state_block(&state)).execute();
+
+        Ok(longest)
+    }
+
+    /// Sort the free clusters.
+    ///
+    /// This redistributes the free clusters among the metaclusters in ascending order, starting
+    /// with the head metacluster, as kept by `AllocationStrategy::LowestFirst`. Like `coalesce`,
+    /// the metaclusters stay in place, and so does the number of free clusters in each of them,
+    /// but only the metaclusters whose free clusters change are rewritten.
+    fn sort_freelist(&mut self) -> Result<(), Error> {
+        debug!(self, "sorting the free clusters"; "subsystem" => subsystem::FREELIST);
+
+        // Lock the freelist, and load the metacluster chain following the head metacluster.
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+        let mut chain = self.load_metacluster_chain(&head_metacluster)?;
+
+        // Gather the free clusters, and sort them by address.
+        let mut free: Vec<cluster::Pointer> = head_metacluster.free.iter()
+            .chain(chain.iter().flat_map(|&(_, ref metacluster, _)| metacluster.free.iter()))
+            .cloned()
+            .collect();
+        free.sort();
+
+        // Deal them out to the metaclusters, starting with the head metacluster. The clusters of
+        // a metacluster are stored in descending order, as the last one is popped first.
+        let mut clusters = free.into_iter();
+        let mut deal = |metacluster: &mut Metacluster| {
+            let mut free: Vec<_> = clusters.by_ref().take(metacluster.free.len()).collect();
+            free.sort_by(|a, b| b.cmp(a));
+            let changed = free != metacluster.free;
+            metacluster.free = free;
+            changed
+        };
+        let head_changed = deal(&mut head_metacluster);
+        for &mut (_, ref mut metacluster, ref mut changed) in &mut chain {
+            *changed = deal(metacluster);
+        }
+
+        // Rewrite the metaclusters which changed, then flush the state block.
+        let (transaction, _) = self.rewrite_metacluster_chain(&mut state, &mut head_metacluster, &mut chain, head_changed);
+        transaction.then(self.flush_state_block(&state)).execute();
+
+        Ok(())
+    }
+
+    /// Load the metacluster chain following the head metacluster.
+    ///
+    /// The metaclusters are returned in order, each flagged as unchanged, as expected by
+    /// `rewrite_metacluster_chain`. The checksums are not verified. If the chain is longer than
+    /// the device could hold, it must cycle, and `Error::FreelistCycle` is returned.
+    fn load_metacluster_chain(&self, head_metacluster: &Metacluster) -> Result<Vec<(cluster::Pointer, Metacluster, bool)>, Error> {
+        let mut chain = Vec::new();
+        let mut next = head_metacluster.next;
+        while let Some(cluster) = next {
+            // Guard against cycles.
+            if chain.len() > self.driver.number_of_sectors() {
+                return Err(Error::FreelistCycle {
+                    cluster: cluster,
+                });
+            }
+
+            // Metaclusters are only linked to when they're full.
+            let metacluster = self.read_metacluster(cluster, self.max_free() as u8, |_| Ok(()))?;
+            next = metacluster.next;
+            chain.push((cluster, metacluster, false));
+        }
+
+        Ok(chain)
+    }
+
+    /// Rewrite the metacluster chain of the freelist.
+    ///
+    /// `chain` holds the metaclusters following the head metacluster, each flagged if its free
+    /// clusters were changed in memory, and `head_changed` flags the head metacluster. `chain` may
+    /// stop short of the tail, in which case the rest of the chain is left as it is. The
+    /// checksums are recalculated from the tail towards the head, and the metaclusters which
+    /// changed, or whose checksum of the next metacluster drifted, are rewritten, in that order.
+    /// The freelist head of `state` is updated, but the state block is left to the caller.
+    ///
+    /// The metaclusters are overwritten in place, so this isn't crash-safe. See
+    /// `verify_and_repair`.
+    ///
+    /// The transaction writing the metaclusters and the number of metaclusters rewritten are
+    /// returned.
+    fn rewrite_metacluster_chain(&self, state: &mut state_block::State, head_metacluster: &mut Metacluster,
+                                 chain: &mut [(cluster::Pointer, Metacluster, bool)], mut head_changed: bool)
+        -> (cache::Transacting<()>, usize) {
+        let mut rewritten = 0;
+
+        // Recalculate the checksums, from the tail towards the head, and rewrite the metaclusters
+        // which drifted.
+        let mut transaction = cache::Transacting::no_transaction(());
+        // If `chain` is merely a prefix of the chain, its last metacluster keeps the checksum of
+        // the one following it.
+        let mut next_checksum = chain.last()
+            .filter(|&&(_, ref metacluster, _)| metacluster.next.is_some())
+            .map_or(0, |&(_, ref metacluster, _)| metacluster.next_checksum);
+        // The next metacluster, whose checksum is stored in the current one.
+        let mut next: Option<&Metacluster> = None;
+        for &mut (cluster, ref mut metacluster, changed) in chain.iter_mut().rev() {
+            let drifted = next.map_or(false, |next| !self.metacluster_checksum_matches(next, metacluster.next_checksum));
+            if changed || drifted {
+                debug!(self, "rewriting metacluster"; "subsystem" => subsystem::FREELIST,
+                       "metacluster" => cluster);
+
+                metacluster.next_checksum = next_checksum;
+                transaction = cache::Transacting::new((), Some(transaction.then(self.write_metacluster(metacluster, cluster))));
+                rewritten += 1;
+            }
+
+            next_checksum = self.metacluster_checksum(metacluster);
+            next = Some(metacluster);
+        }
+
+        // Finally the head metacluster, which is covered by the state block.
+        if let Some(freelist_head) = state.freelist_head {
+            if next.map_or(false, |next| !self.metacluster_checksum_matches(next, head_metacluster.next_checksum)) {
+                head_metacluster.next_checksum = next_checksum;
+                head_changed = true;
+            }
+
+            // A checksum stored under the previous algorithm is still valid (see
+            // `migrate_checksum`), so only a mismatching one is repaired.
+            let stale = freelist_head.counter as usize != head_metacluster.free.len()
+                || !self.metacluster_checksum_matches(head_metacluster, freelist_head.checksum);
+            if head_changed || stale {
+                let repaired = state_block::FreelistHead {
+                    cluster: freelist_head.cluster,
+                    checksum: self.metacluster_checksum(head_metacluster),
+                    counter: head_metacluster.free.len() as u8,
+                };
+                debug!(self, "rewriting head metacluster"; "subsystem" => subsystem::FREELIST,
+                       "metacluster" => freelist_head.cluster);
+
+                state.freelist_head = Some(repaired);
+                transaction = cache::Transacting::new((), Some(transaction.then(self.write_head_metacluster(head_metacluster, freelist_head.cluster))));
+                rewritten += 1;
+            }
+        }
+
+        (transaction, rewritten)
+    }
+
+    /// Release some pages.
+    ///
+    /// This drops a reference to each of `pages`. Pages losing their last reference are removed
+    /// from the deduplication table and the live pages of their clusters. The clusters left
+    /// without live pages (which will no longer be packed into) are returned.
+    ///
+    /// If the live page index is complete, pages of clusters not in it were already freed, so
+    /// they're ignored. Otherwise, such clusters were allocated before the manager was opened:
+    /// Uncompressed ones hold only the page, and are returned, while compressed ones are leaked.
+    ///
+    /// If any of the pages points to a cluster holding freelist metadata, nothing is released,
+    /// and an error is returned.
+    fn release(&self, pages: &[page::Pointer]) -> Result<Vec<cluster::Pointer>, Error> {
+        // Make sure that no page points into the metadata, before changing anything.
+        let clusters: Vec<_> = pages.iter().map(|page| page.cluster).collect();
+        if let Err(err) = self.check_not_metadata(&clusters) {
+            // Keep track of the corruption for health checks.
+            self.note_corruption(&err);
+
+            return Err(err);
+        }
+
+        // The clusters left without live pages.
+        let mut empty = Vec::new();
+        self.touch_index();
+        {
+            // Lock the live page index once for the whole range.
+            let mut live = self.live.lock();
+            for &page in pages {
+                // Drop a reference to the page. Only the last reference frees the page.
+                if let Some(live_pages) = live.get_mut(&page.cluster) {
+                    if let Some(duplicates) = live_pages.duplicates.get_mut(&page) {
+                        *duplicates -= 1;
+                        if *duplicates == 0 {
+                            live_pages.duplicates.remove(&page);
+                        }
+
+                        continue;
+                    }
+                }
+
+                // Make sure the page isn't handed out as a duplicate anymore.
+                self.dedup_table.remove(page);
+
+                // Remove the page from the live pages of its cluster, and check if the cluster is
+                // now empty.
+                if let Some(live_pages) = live.get_mut(&page.cluster) {
+                    live_pages.pages.retain(|&x| x != page);
+                    if live_pages.pages.is_empty() {
+                        live.remove(&page.cluster);
+                        empty.push(page.cluster);
+                    }
+                } else if self.index_complete {
+                    // The index covers every cluster in use, so the page was already freed.
+                    warn!(self, "ignoring page of unallocated cluster"; "subsystem" => subsystem::ALLOC,
+                          "page" => page);
+                } else if page.offset.is_none() {
+                    // The cluster isn't tracked, as it was allocated before the manager was
+                    // opened, but an uncompressed cluster holds only this page.
+                    empty.push(page.cluster);
+                } else {
+                    // A compressed cluster might hold other live pages, so we cannot free it.
+                    warn!(self, "leaking untracked compressed cluster"; "subsystem" => subsystem::ALLOC,
+                          "cluster" => page.cluster);
+                }
+            }
+        }
+
+        // The evacuated clusters hold nothing worth backing up anymore.
+        {
+            let mut changed = self.changed.lock();
+            for cluster in &empty {
+                changed.remove(cluster);
+            }
+        }
+
+        // Stop packing pages into the last allocated cluster, if it was evacuated.
+        let mut last_cluster = self.last_cluster.lock();
+        if last_cluster.as_ref().map_or(false, |state| empty.contains(&state.cluster)) {
+            *last_cluster = None;
+        }
+
+        // Retain the clusters, which some snapshot refers to, rather than freeing them.
+        self.retain_for_snapshots(&mut empty);
+
+        Ok(empty)
+    }
+
+    /// Retain the clusters, which some snapshot refers to.
+    ///
+    /// This moves the clusters of `clusters` holding pages of a snapshot to the retained clusters,
+    /// which are only freed when the snapshot is dropped. The remaining clusters can be freed.
+    fn retain_for_snapshots(&self, clusters: &mut Vec<cluster::Pointer>) {
+        let snapshots = self.snapshots.lock();
+        if snapshots.is_empty() {
+            return;
+        }
+
+        let mut retained = self.retained.lock();
+        clusters.retain(|&cluster| {
+            let pinned = snapshots.values().any(|pages| pages.iter().any(|page| page.cluster == cluster));
+            if pinned {
+                trace!(self, "retaining cluster for snapshot"; "subsystem" => subsystem::ALLOC,
+                       "cluster" => cluster);
+                retained.insert(cluster);
+            }
+
+            !pinned
+        });
+    }
+
+    /// Relocate a page to some cluster.
+    ///
+    /// This moves `page` into cluster `target`, compressing it if possible, and returns the new
+    /// pointer. The old page is freed, releasing its cluster if it becomes empty.
+    ///
+    /// `target` must either be free and in the head metacluster, in which case it is taken from
+    /// the freelist, or be reserved by the caller, i.e. neither free, in use, in the trash,
+    /// retained for a snapshot, nor in the reserve for metaclusters.
+    /// Otherwise, `Error::ClusterUnavailable` is returned. If `target` isn't a data cluster within
+    /// the device (like in `validate_page_pointer`), `Error::ClusterOutOfBounds` is returned,
+    /// naming the pointer the page would get. This is meant for tooling, which rebalances or
+    /// repairs.
+    pub fn relocate_page(&mut self, page: page::Pointer, target: cluster::Pointer) -> Result<page::Pointer, Error> {
+        debug!(self, "relocating page"; "subsystem" => subsystem::ALLOC, "page" => page, "target" => target);
+
+        // Make sure that the target follows the state block (and the journal), and lies within the
+        // data device, so neither the metadata nor anything past the end is overwritten.
+        let sector = target.into() as disk::Sector;
+        if sector < self.first_data_cluster()
+            || sector >= self.driver.number_of_sectors()
+            || self.cache.is_metadata(sector) {
+            return Err(Error::ClusterOutOfBounds {
+                page: page::Pointer {
+                    cluster: target,
+                    offset: None,
+                    checksum: page.checksum,
+                },
+            });
+        }
+
+        // Make sure that the target can be written.
+        if self.live.lock().contains_key(&target)
+            || self.trash.lock().contains(&target)
+            || self.retained.lock().contains(&target)
+            || self.state.lock().reserve.contains(&target) {
+            return Err(Error::ClusterUnavailable {
+                cluster: target,
+            });
+        }
+        self.check_not_metadata(&[target])?;
+
+        // Read (and validate) the page, then take the target from the freelist, if it is there.
+        let buf = self.read(page)?;
+        self.freelist_take(target)?;
+
+        // Write the page into the target, compressed, if possible. The cluster isn't extended
+        // with more pages.
+        let compressed = if self.config.compression_algorithm == CompressionAlgorithm::Identity {
+            None
+        } else {
+            self.compress_new_cluster(&buf)
+        };
+        let new = if let Some((_, compressed)) = compressed {
+            self.cache.write(target, compressed).execute();
+
+            page::Pointer {
+                cluster: target,
+                offset: Some(0),
+                checksum: page.checksum,
+            }
+        } else {
+            self.cache.write(target, buf).execute();
+
+            page::Pointer {
+                cluster: target,
+                offset: None,
+                checksum: page.checksum,
+            }
+        };
+        // Make the page durable before anything refers to it.
+        self.flush_sector(target.into())?;
+
+        // Register the new page, and free the old one.
+        self.register(&buf, new);
+        if let Some(transaction) = self.free(page)? {
+            transaction.execute();
+        }
+
+        Ok(new)
+    }
+
+    /// Take a snapshot.
+    ///
+    /// This records the live pages, and retains their clusters until the snapshot is dropped,
+    /// even if the pages are freed (e.g. overwritten through `atomic_swap`). Thus, the pages can
+    /// be read as they were at the time of the snapshot through `read_at`.
+    ///
+    /// Snapshots only cover the pages in the live page index (so unless it is complete, only those
+    /// allocated since the manager was opened). They're persisted along with the index, and can be
+    /// listed through `snapshots` after reopening the manager.
+    pub fn snapshot(&mut self) -> SnapshotId {
+        self.touch_index();
+
+        let id = SnapshotId(self.next_snapshot);
+        self.next_snapshot += 1;
+        info!(self, "taking snapshot"; "subsystem" => subsystem::ALLOC, "snapshot" => id.0);
+
+        // Record the live pages.
+        let pages = self.live.lock().values().flat_map(|live_pages| live_pages.pages.iter().cloned()).collect();
+        self.snapshots.lock().insert(id, pages);
+
+        id
+    }
+
+    /// Drop a snapshot.
+    ///
+    /// The retained clusters, which no other snapshot refers to, are pushed to the freelist, and
+    /// the transaction is returned.
+    pub fn drop_snapshot(&mut self, snapshot: SnapshotId) -> Result<Option<cache::Transaction>, Error> {
+        info!(self, "dropping snapshot"; "subsystem" => subsystem::ALLOC, "snapshot" => snapshot.0);
+
+        self.touch_index();
+        let mut snapshots = self.snapshots.lock();
+        snapshots.remove(&snapshot).ok_or(Error::UnknownSnapshot)?;
+
+        // Release the retained clusters, which are no longer referred to.
+        let released: Vec<_> = {
+            let mut retained = self.retained.lock();
+            let released: Vec<_> = retained.iter().cloned().filter(|&cluster| {
+                !snapshots.values().any(|pages| pages.iter().any(|page| page.cluster == cluster))
+            }).collect();
+            for cluster in &released {
+                retained.remove(cluster);
+            }
+
+            released
+        };
+        drop(snapshots);
+
+        if released.is_empty() {
+            Ok(None)
+        } else {
+            debug!(self, "releasing retained clusters"; "subsystem" => subsystem::ALLOC,
+                   "clusters" => released.len());
+
+            Ok(Some(self.freelist_push_batch(&released)))
+        }
+    }
+
+    /// Get the snapshots, oldest first.
+    pub fn snapshots(&self) -> Vec<SnapshotId> {
+        self.snapshots.lock().keys().cloned().collect()
+    }
+
+    /// Read a page as it was at the time of some snapshot.
+    ///
+    /// If the snapshot doesn't exist, `Error::UnknownSnapshot` is returned. If the page wasn't
+    /// live at the time of the snapshot, `Error::PageNotInSnapshot` is returned.
+    pub fn read_at(&self, snapshot: SnapshotId, page: page::Pointer) -> Result<disk::SectorBuf, Error> {
+        trace!(self, "reading page at snapshot"; "subsystem" => subsystem::ALLOC,
+               "snapshot" => snapshot.0, "page" => page);
+
+        // Make sure that the page is part of the snapshot.
+        if !self.snapshots.lock().get(&snapshot).ok_or(Error::UnknownSnapshot)?.contains(&page) {
+            return Err(Error::PageNotInSnapshot {
+                page: page,
+            });
+        }
+
+        // Since the cluster of the page is retained, the page is intact.
+        self.read(page)
+    }
+
+    /// Make sure that some clusters don't hold metadata.
+    ///
+    /// Freeing (or overwriting) a cluster holding metadata would corrupt the allocator, so this
+    /// returns `Error::ClusterInUseAsMetadata` if any of `clusters` precedes the data clusters
+    /// (i.e. is the disk header, the state block, the journal or one of the table slots), holds a
+    /// metacluster of the freelist, or lives on the separate metadata device.
+    ///
+    /// The metacluster chain is walked at most once, and only if some cluster passes the other
+    /// checks. If it can't be read, the error is returned.
+    fn check_not_metadata(&self, clusters: &[cluster::Pointer]) -> Result<(), Error> {
+        let mut metaclusters = None;
+        for &cluster in clusters {
+            let sector = cluster.into() as disk::Sector;
+            let metadata = sector < self.first_data_cluster() || self.cache.is_metadata(sector) || {
+                if metaclusters.is_none() {
+                    metaclusters = Some(self.metacluster_clusters()?);
+                }
+                metaclusters.as_ref().unwrap().contains(&cluster)
+            };
+
+            if metadata {
+                warn!(self, "refusing to free metadata cluster"; "subsystem" => subsystem::ALLOC,
+                      "cluster" => cluster);
+
+                return Err(Error::ClusterInUseAsMetadata {
+                    cluster: cluster,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the clusters holding the metaclusters of the freelist.
+    ///
+    /// This walks the whole metacluster chain. The checksums are not verified.
+    fn metacluster_clusters(&self) -> Result<BTreeSet<cluster::Pointer>, Error> {
+        let (head, head_metacluster) = {
+            let (state, head_metacluster) = self.lock_freelist();
+            (state.freelist_head.map(|freelist_head| freelist_head.cluster), head_metacluster.clone())
+        };
+
+        let mut clusters: BTreeSet<_> = head.into_iter().collect();
+        if head.is_some() {
+            clusters.extend(self.load_metacluster_chain(&head_metacluster)?.into_iter().map(|(cluster, _, _)| cluster));
+        }
+
+        Ok(clusters)
+    }
+
+    /// Atomically replace a page with new content.
+    ///
+    /// This allocates a page with content `new_content` in a fresh cluster (see `alloc_isolated`),
+    /// makes it durable, and only then frees `old`. Packing it into the last allocated cluster
+    /// instead would rewrite a cluster which might hold `old`, so a torn write could destroy both. A crash at any point leaves at least one of the two pages fully valid on the disk,
+    /// so it can be used as a copy-on-write update primitive (e.g. for updating a root pointer).
+    ///
+    /// If deduplication gives back an existing page (possibly `old` itself), that page gains a
+    /// reference, so freeing `old` afterwards never takes away the new page.
+    ///
+    /// The pointer to the new page is returned.
+    pub fn atomic_swap(&mut self, old: page::Pointer, new_content: &disk::SectorBuf) -> Result<page::Pointer, Error> {
+        debug!(self, "swapping page"; "subsystem" => subsystem::ALLOC, "old" => old);
+
+        // Allocate the new page, and flush it along with the allocation metadata, such that it is
+        // durable before the old page goes away.
+        let new = self.alloc_isolated(new_content)?.execute();
+        self.flush_sector(new.cluster)?;
+        self.sync_metadata()?;
+
+        // Free the old page. If the new page is a duplicate of it, this merely drops the
+        // reference gained above.
+        if let Some(transaction) = self.free(old)? {
+            transaction.execute();
+        }
+
+        Ok(new)
+    }
+
+    /// Compact the pages into as few clusters as possible.
+    ///
+    /// This reads every live page of the compressed clusters, and repacks them greedily into new
+    /// clusters, which are then filled to their limit. For every moved page, `remap` is called
+    /// with the old and the new pointer, so external indices can be updated.
+    ///
+    /// The clusters are taken from the live page index, so if it is incomplete (see
+    /// `rebuild_refcounts`), the clusters allocated before the manager was opened are left as is.
+    ///
+    /// The pages are repacked in the order of `order`, if given, so pages which are read together
+    /// end up in the same clusters. Pages not in `order` follow in cluster order, and so does
+    /// everything if no order is given. Pointers in `order` which aren't compacted are ignored.
+    ///
+    /// The old clusters are freed as the very last step, after the new data has been flushed and
+    /// the pages have been remapped, so a crash in the middle of the compaction leaves the old
+    /// pages intact. The old clusters, which some snapshot refers to, are retained instead.
+    pub fn compact<F>(&mut self, order: Option<&[page::Pointer]>, remap: &mut F) -> Result<(), Error>
+        where F: FnMut(page::Pointer, page::Pointer) {
+        info!(self, "compacting clusters"; "subsystem" => subsystem::ALLOC);
+
+        // Abandon the last allocated cluster, as it is about to be compacted itself, and we
+        // don't want to pack new pages into it.
+        *self.last_cluster.lock() = None;
+
+        // Take out the live pages of the compressed clusters. Uncompressed clusters contain only a
+        // single page, so there is nothing to gain from moving them.
+        self.touch_index();
+        let old: Vec<(cluster::Pointer, LivePages)> = {
+            let mut live = self.live.lock();
+            let clusters: Vec<_> = live.iter()
+                .filter(|&(_, live_pages)| live_pages.pages.iter().all(|page| page.offset.is_some()))
+                .map(|(&cluster, _)| cluster)
+                .collect();
+
+            clusters.into_iter().map(|cluster| (cluster, live.remove(&cluster).unwrap())).collect()
+        };
+
+        self.repack(old, order.unwrap_or(&[]), remap)
+    }
+
+    /// Set the automatic compaction policy.
+    ///
+    /// When the live compressed clusters are fragmented beyond `policy.threshold`, a compaction
+    /// pass of at most `policy.budget` clusters is run automatically, no more often than every
+    /// `policy.interval` allocations and frees. The check is made when freeing pages, and when
+    /// allocating pages through `alloc` (before a new page is stored, not for duplicates). For
+    /// every moved page, `remap` is called with the old and the new pointer, from whichever
+    /// thread triggered the pass.
+    pub fn set_auto_compaction<F>(&mut self, policy: CompactionPolicy, remap: F)
+        where F: FnMut(page::Pointer, page::Pointer) + Send + 'static {
+        *self.auto_compaction.lock() = Some((policy, Box::new(remap)));
+        self.operations_since_compaction.store(0, ORDERING);
+    }
+
+    /// Disable automatic compaction.
+    pub fn disable_auto_compaction(&mut self) {
+        *self.auto_compaction.lock() = None;
+    }
+
+    /// Get the fragmentation histogram of the compressed clusters.
+    ///
+    /// This buckets the live compressed clusters by their occupancy, i.e. the fraction of the
+    /// pages stored in them which are still live. Bucket `n` counts the clusters with an
+    /// occupancy of at least `n / HISTOGRAM_BUCKETS`, but less than `(n + 1) / HISTOGRAM_BUCKETS`,
+    /// except that fully occupied clusters are counted in the last bucket.
+    pub fn fragmentation_histogram(&self) -> [usize; HISTOGRAM_BUCKETS] {
+        let mut histogram = [0; HISTOGRAM_BUCKETS];
+        for live_pages in self.live.lock().values() {
+            // Only compressed clusters have pages with offsets.
+            if live_pages.pages.iter().any(|page| page.offset.is_some()) {
+                let bucket = live_pages.pages.len() * HISTOGRAM_BUCKETS / live_pages.stored;
+                histogram[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+            }
+        }
+
+        histogram
+    }
+
+    /// Get the fragmentation of the compressed clusters.
+    ///
+    /// This is the fraction of the pages stored in the live compressed clusters which have since
+    /// been freed, and thus waste space until the cluster is compacted. It is zero if there are
+    /// no live compressed clusters.
+    pub fn fragmentation(&self) -> f64 {
+        let (mut stored, mut live) = (0, 0);
+        for live_pages in self.live.lock().values() {
+            // Only compressed clusters have pages with offsets.
+            if live_pages.pages.iter().any(|page| page.offset.is_some()) {
+                stored += live_pages.stored;
+                live += live_pages.pages.len();
+            }
+        }
+
+        if stored == 0 {
+            0.0
+        } else {
+            (stored - live) as f64 / stored as f64
+        }
+    }
+
+    /// Is an automatic compaction pass due?
+    fn compaction_due(&self) -> bool {
+        self.auto_compaction.lock().as_ref().map_or(false, |&(policy, _)| {
+            self.operations_since_compaction.load(ORDERING) >= policy.interval
+                && self.fragmentation() >= policy.threshold
+        })
+    }
+
+    /// Count an allocation towards the automatic compaction policy.
+    ///
+    /// If a pass is due, it is run.
+    fn tick_compaction(&self) -> Result<(), Error> {
+        if self.auto_compaction.lock().is_some() {
+            self.operations_since_compaction.fetch_add(1, ORDERING);
+            self.compact_if_needed()?;
+        }
+
+        Ok(())
+    }
+
+    /// Run an automatic compaction pass, if one is due.
+    ///
+    /// This compacts the most fragmented compressed clusters, within the budget of the policy,
+    /// and returns whether a pass was run. It is called automatically when allocating and freeing
+    /// pages. If another pass is already running, `false` is returned.
+    pub fn compact_if_needed(&self) -> Result<bool, Error> {
+        if !self.compaction_due() {
+            return Ok(false);
+        }
+
+        // Take out the policy, so the callback can be borrowed alongside the manager, and no other
+        // pass is started meanwhile. If it is gone, another pass got ahead of us.
+        let (policy, mut remap) = match self.auto_compaction.lock().take() {
+            Some(auto_compaction) => auto_compaction,
+            None => return Ok(false),
+        };
+        self.operations_since_compaction.store(0, ORDERING);
+
+        info!(self, "compacting automatically"; "subsystem" => subsystem::ALLOC,
+              "fragmentation" => self.fragmentation());
+
+        // Abandon the last allocated cluster, as it might be compacted itself.
+        *self.last_cluster.lock() = None;
+
+        // Take out the live pages of the most fragmented compressed clusters.
+        self.touch_index();
+        let old: Vec<(cluster::Pointer, LivePages)> = {
+            let mut live = self.live.lock();
+            let mut clusters = Manager::fragmented_clusters(&live);
+            clusters.truncate(policy.budget);
+
+            clusters.into_iter().map(|cluster| (cluster, live.remove(&cluster).unwrap())).collect()
+        };
+
+        let res = self.repack(old, &[], &mut *remap);
+        // Put back the policy.
+        *self.auto_compaction.lock() = Some((policy, remap));
+        res.map(|_| true)
+    }
+
+    /// Compact the most fragmented clusters until some number of clusters is freed.
+    ///
+    /// This repacks the compressed clusters with the most freed pages first, like automatic
+    /// compaction, until `target` clusters (net of the new clusters the pages are packed into)
+    /// have been freed, or there are no fragmented clusters left. For every moved page, `remap`
+    /// is called with the old and the new pointer.
+    ///
+    /// The number of clusters actually freed is returned, which might fall short of `target`,
+    /// or exceed it slightly.
+    pub fn compact_until<F>(&mut self, target: usize, remap: &mut F) -> Result<usize, Error>
+        where F: FnMut(page::Pointer, page::Pointer) {
+        info!(self, "compacting until enough clusters are freed"; "subsystem" => subsystem::ALLOC,
+              "target" => target);
+
+        let mut freed = 0;
+        while freed < target {
+            // Abandon the last allocated cluster, as it might be compacted itself.
+            *self.last_cluster.lock() = None;
+
+            let before = self.live.lock().len();
+            // Take out the live pages of the most fragmented clusters, just enough of them to free
+            // the remaining clusters, if the pages packed perfectly. Otherwise, another pass
+            // follows.
+            self.touch_index();
+            let old: Vec<(cluster::Pointer, LivePages)> = {
+                let mut live = self.live.lock();
+                let max_pages = self.max_pages_per_cluster();
+
+                let mut pages = 0;
+                let mut taken = 0;
+                let clusters: Vec<_> = Manager::fragmented_clusters(&live).into_iter().take_while(|cluster| {
+                    // Stop once the clusters taken so far suffice.
+                    if taken - (pages + max_pages - 1) / max_pages >= target - freed {
+                        return false;
+                    }
+
+                    pages += live[cluster].pages.len();
+                    taken += 1;
+                    true
+                }).collect();
+
+                clusters.into_iter().map(|cluster| (cluster, live.remove(&cluster).unwrap())).collect()
+            };
+
+            // Stop when there is nothing left to compact.
+            if old.is_empty() {
+                break;
+            }
+
+            self.repack(old, &[], remap)?;
+
+            // Count the clusters freed by the pass. The repacked clusters are no longer
+            // fragmented, so a pass not freeing anything would be the last one anyway.
+            let after = self.live.lock().len();
+            if after >= before {
+                break;
+            }
+            freed += before - after;
+        }
+
+        debug!(self, "freed clusters by compaction"; "subsystem" => subsystem::ALLOC, "clusters" => freed);
+
+        Ok(freed)
+    }
+
+    /// Get the fragmented compressed clusters of a live page index.
+    ///
+    /// The clusters are ordered by the number of freed pages, most first.
+    fn fragmented_clusters(live: &HashMap<cluster::Pointer, LivePages>) -> Vec<cluster::Pointer> {
+        let mut clusters: Vec<_> = live.iter()
+            .filter(|&(_, live_pages)| live_pages.pages.iter().all(|page| page.offset.is_some()))
+            .filter(|&(_, live_pages)| live_pages.pages.len() < live_pages.stored)
+            .map(|(&cluster, live_pages)| (live_pages.stored - live_pages.pages.len(), cluster))
+            .collect();
+        // Most freed pages first.
+        clusters.sort_by(|a, b| b.cmp(a));
+
+        clusters.into_iter().map(|(_, cluster)| cluster).collect()
+    }
+
+    /// Repack pages into new clusters.
+    ///
+    /// `old` holds clusters, whose pages have been taken out of the live page index. The pages are
+    /// stored again (keeping their duplicates), calling `remap` for every page moved, and the
+    /// clusters are freed as the very last step.
+    ///
+    /// The pages in `order` are stored first, in that order, followed by the rest in the order of
+    /// `old`.
+    fn repack<F>(&self, old: Vec<(cluster::Pointer, LivePages)>, order: &[page::Pointer], remap: &mut F)
+        -> Result<(), Error>
+        where F: FnMut(page::Pointer, page::Pointer) + ?Sized {
+        // Look up the position of every hinted page. If a page is hinted several times, its first
+        // position is used.
+        let mut positions = HashMap::with_capacity(order.len());
+        for (position, &page) in order.iter().enumerate() {
+            positions.entry(page).or_insert(position);
+        }
+
+        // Sort the pages by their position in the order. The sort is stable, so the pages not in
+        // the order keep the order of `old`.
+        let mut pages: Vec<_> = old.iter()
+            .flat_map(|&(_, ref live_pages)| live_pages.pages.iter().cloned())
+            .map(|page| (positions.get(&page).cloned().unwrap_or(order.len()), page))
+            .collect();
+        pages.sort_by_key(|&(position, _)| position);
+
+        // Repack every page into new clusters.
+        let mut moved = Vec::new();
+        for (_, page) in pages {
+            trace!(self, "moving page"; "subsystem" => subsystem::ALLOC, "page" => page);
+
+            // Read the old page and store it again. Deduplication is bypassed, as it would simply
+            // give us back the old page.
+            let buf = self.read(page)?;
+            let (new, _) = self.store(&buf, page.checksum)?.execute();
+            // Carry over the references to the page.
+            let duplicates = old.iter()
+                .find(|&&(cluster, _)| cluster == page.cluster)
+                .and_then(|&(_, ref live_pages)| live_pages.duplicates.get(&page).cloned());
+            self.set_duplicates(new, duplicates.unwrap_or(0));
+
+            moved.push((page, new));
+        }
+
+        // Flush the new clusters, so the pages are durable before anything refers to them.
+        for &(_, new) in &moved {
+            self.flush_sector(new.cluster)?;
+        }
+
+        // Let the caller update their pointers.
+        for (page, new) in moved {
+            remap(page, new);
+        }
+
+        // Finally, free the old clusters, unless some snapshot refers to them.
+        let mut clusters: Vec<_> = old.into_iter().map(|(cluster, _)| cluster).collect();
+        self.retain_for_snapshots(&mut clusters);
+        for cluster in clusters {
+            self.freelist_push(cluster).execute();
+        }
+
+        Ok(())
+    }
+
+    /// Set whether the write counts of the clusters should be tracked.
+    ///
+    /// This must be enabled for `rebalance` to have any effect.
+    pub fn set_wear_tracking(&mut self, track: bool) {
+        self.track_wear = track;
+    }
+
+    /// Spread the wear evenly over the clusters.
+    ///
+    /// This pairs the most written live clusters with the least written ones, and swaps their data
+    /// as long as their write counts differ by at least `WEAR_THRESHOLD`. Pages are never
+    /// rewritten in place, so the cold data will rest in the worn clusters, while the data of the
+    /// worn clusters moves to less worn ones. For every moved page, `remap` is called with the old
+    /// and the new pointer, so external indices can be updated.
+    ///
+    /// Every move goes into a freshly popped cluster, which is made durable before the pages are
+    /// remapped, and the old cluster is only freed afterwards, so a crash leaves every page intact
+    /// under either its old or its new pointer (see `relocate`).
+    pub fn rebalance<F>(&mut self, remap: &mut F) -> Result<(), Error>
+        where F: FnMut(page::Pointer, page::Pointer) {
+        // Without the write counts, there is nothing to go by.
+        if !self.track_wear {
+            return Ok(());
+        }
+
+        info!(self, "rebalancing the wear of the clusters"; "subsystem" => subsystem::ALLOC);
+
+        // Abandon the last allocated cluster, as its data might be moved.
+        *self.last_cluster.lock() = None;
+
+        // Order the live clusters by their write counts, least written first.
+        let mut clusters: Vec<(u64, cluster::Pointer)> = {
+            let writes = self.writes.lock();
+            self.live.lock().keys()
+                .map(|&cluster| (writes.get(&cluster).cloned().unwrap_or(0), cluster))
+                .collect()
+        };
+        clusters.sort();
+
+        // Swap the hottest clusters with the coldest.
+        for n in 0..clusters.len() / 2 {
+            let (cold_writes, cold) = clusters[n];
+            let (hot_writes, hot) = clusters[clusters.len() - 1 - n];
+            if hot_writes - cold_writes < WEAR_THRESHOLD {
+                break;
+            }
+
+            debug!(self, "swapping clusters"; "subsystem" => subsystem::ALLOC, "hot" => hot, "cold" => cold);
+
+            // Move the cold data out of the way, into a spare cluster. The freelist is last in,
+            // first out, so every following move lands in the cluster freed by the one before
+            // (unless a snapshot retains it).
+            let spare = self.relocate(cold, remap)?;
+            // Move the hot data into the cold cluster.
+            self.relocate(hot, remap)?;
+            // Move the cold data into the hot cluster, giving back the spare cluster.
+            self.relocate(spare, remap)?;
+        }
+
+        Ok(())
+    }
+
+    /// Move the data of a cluster to a fresh cluster.
+    ///
+    /// This pops a cluster from the freelist, copies cluster `from` as is to it, and makes both
+    /// the copy and the pop durable. Then the live pages of `from` are moved to the new cluster,
+    /// calling `remap` for every page moved, and only then is `from` freed (or retained, if some
+    /// snapshot refers to it).
+    ///
+    /// The new cluster is returned.
+    fn relocate<F>(&mut self, from: cluster::Pointer, remap: &mut F) -> Result<cluster::Pointer, Error>
+        where F: FnMut(page::Pointer, page::Pointer) {
+        // Copy the cluster into a fresh one, and make it durable before anything refers to it.
+        let buf = self.cache.read_then(from.into(), |buf| Ok(*buf))?;
+        let to = self.freelist_pop()?.execute();
+        trace!(self, "relocating cluster"; "subsystem" => subsystem::ALLOC, "from" => from, "to" => to);
+        self.cache.write(to.into(), buf).execute();
+        self.flush_sector(to.into())?;
+        self.sync_metadata()?;
+
+        // Move the live pages. Since the cluster is copied as is, the offsets are unchanged.
+        self.touch_index();
+        let (pages, duplicates) = self.live.lock().remove(&from).map_or_else(|| (Vec::new(), HashMap::new()), |live_pages| {
+            (live_pages.pages, live_pages.duplicates)
+        });
+        for page in pages {
+            let new = page::Pointer {
+                cluster: to,
+                .. page
+            };
+
+            // Register the page under its new pointer, and make sure that the old pointer isn't
+            // handed out as a duplicate anymore.
+            let content = self.read(new)?;
+            self.dedup_table.remove(page);
+            self.register(&content, new);
+            self.set_duplicates(new, duplicates.get(&page).cloned().unwrap_or(0));
+
+            remap(page, new);
+        }
+
+        // Free the old cluster, now that no live page refers to it, unless some snapshot does.
+        self.changed.lock().remove(&from);
+        let mut freed = vec![from];
+        self.retain_for_snapshots(&mut freed);
+        if !freed.is_empty() {
+            self.freelist_push(from).execute();
+        }
+
+        Ok(to)
+    }
+
+    /// Estimate the number of clusters needed to store some number of pages.
+    ///
+    /// This calculates how many clusters `pages` pages take up, given that they compress with an
+    /// estimated ratio of `estimated_ratio` (uncompressed size over compressed size). Since pages
+    /// are packed whole, a cluster holds `estimated_ratio` pages rounded down (but at least one),
+    /// and no more than what the cluster capacity permits.
+    ///
+    /// It ignores deduplication and the clusters used by the freelist.
+    pub fn clusters_for_page_count(pages: usize, estimated_ratio: f64) -> usize {
+        // Calculate the number of pages packed into every cluster. Incompressible pages (ratio
+        // below 1) are stored one per cluster.
+        let pages_per_cluster = (estimated_ratio.floor() as usize)
+            .max(1)
+            .min(page::MAX_PAGES_PER_CLUSTER);
+
+        // Round up, as a partially filled cluster still occupies a whole cluster.
+        (pages + pages_per_cluster - 1) / pages_per_cluster
+    }
+
+    /// Estimate the number of free bytes.
+    ///
+    /// This estimates how many bytes of pages can still be stored, given that they compress with
+    /// an estimated ratio of `expected_ratio` (uncompressed size over compressed size). It covers
+    /// the free clusters (not counting the metaclusters, so the estimate errs on the low side) and
+    /// the room left in the last allocated cluster.
+    ///
+    /// Counting the free clusters requires walking the freelist, which can fail.
+    pub fn estimate_free_bytes(&self, expected_ratio: f64) -> Result<u64, Error> {
+        let free = self.walk_freelist(&mut |_| ())?;
+
+        // The number of bytes a cluster is expected to hold, which is bounded by the capacity.
+        let per_cluster = (disk::SECTOR_SIZE as f64 * expected_ratio).min(CLUSTER_CAPACITY as f64);
+        // The room left in the last allocated cluster.
+        let last = self.last_cluster.lock().as_ref().map_or(0.0, |state| {
+            (per_cluster - state.uncompressed.len() as f64).max(0.0)
+        });
+
+        Ok((free as f64 * per_cluster + last) as u64)
+    }
+
+    /// Get the maximal number of pages a cluster can hold with the configured compression.
+    ///
+    /// Without compression, every page occupies a cluster of its own. Otherwise, this is bounded by
+    /// the decompressed cluster capacity, but how many pages actually fit depends on how well they
+    /// compress.
+    pub fn max_pages_per_cluster(&self) -> usize {
+        if self.config.compression_algorithm == state_block::CompressionAlgorithm::Identity {
+            1
+        } else {
+            page::MAX_PAGES_PER_CLUSTER
+        }
+    }
+
+    /// Set whether metaclusters should be verified when traversing the freelist.
+    ///
+    /// Disabling verification makes switching metaclusters cheaper, but corruption of the
+    /// freelist will go unnoticed. It should only be done on trusted hardware.
+    pub fn set_verify_metaclusters(&mut self, verify: bool) {
+        self.verify_metaclusters = verify;
+    }
+
+    /// Set the maximal number of sectors read ahead on a cache miss.
+    ///
+    /// Sequential reads make the cache prefetch the following sectors, with a window growing up
+    /// to `sectors`. Setting it to zero disables the read-ahead.
+    pub fn set_max_readahead(&mut self, sectors: usize) {
+        self.cache.set_max_readahead(sectors);
+    }
+
+    /// Get the active compression level.
+    ///
+    /// This is the level resolved from the compression profile and level of the configuration.
+    /// Levels out of range of the compression algorithm are clamped into it when compressing, and
+    /// algorithms without levels ignore it.
+    pub fn compression_level(&self) -> i32 {
+        self.compression_level
+    }
+
+    /// Count the free clusters.
+    ///
+    /// This is the number of clusters which can be allocated, i.e. the free clusters and the
+    /// metaclusters on the data device (which are handed out, once emptied). It walks the whole
+    /// freelist, reading and verifying every metacluster, so the cost grows with the number of
+    /// free clusters. See `approx_free_clusters` for a cheap alternative.
+    pub fn free_clusters(&self) -> Result<u64, Error> {
+        Ok(self.count_freelist(&mut |_| ())?.1 as u64)
+    }
+
+    /// Approximate the number of free clusters without touching the disk.
+    ///
+    /// This counts the free clusters of the head metacluster, and assumes that every following
+    /// metacluster is full (which they are, except after a crash or repair). The metaclusters
+    /// themselves aren't counted. See `free_clusters` for an exact count.
+    pub fn approx_free_clusters(&self) -> u64 {
+        (self.head_free_count() + self.tail_metaclusters.load(ORDERING) * self.max_free()) as u64
+    }
+
+    /// Get the number of free clusters in the head metacluster.
+    ///
+    /// This is lock-free, and thus cheap enough for frequent monitoring, but it is only
+    /// approximately consistent with concurrent pushes and pops.
+    pub fn head_free_count(&self) -> usize {
+        self.head_free.load(ORDERING)
+    }
+
+    /// Set the maximal number of sectors held by the cache.
+    ///
+    /// Shrinking the cache below its current size evicts blocks (flushing dirty ones) until it
+    /// fits, before returning. Growing it merely raises the bound.
+    pub fn set_cache_size(&mut self, max_sectors: usize) -> Result<(), Error> {
+        self.cache.set_max_sectors(max_sectors)?;
+
+        Ok(())
+    }
+
+    /// Set the order in which free clusters are handed out.
+    ///
+    /// Under `AllocationStrategy::LowestFirst`, the free clusters are kept in ascending order
+    /// along the whole freelist, such that the lowest free cluster is handed out first. Switching
+    /// to it sorts the freelist (see `sort_freelist`), which may fail.
+    pub fn set_allocation_strategy(&mut self, strategy: AllocationStrategy) -> Result<(), Error> {
+        if strategy == AllocationStrategy::LowestFirst {
+            self.sort_freelist()?;
+        }
+        self.strategy = strategy;
+
+        Ok(())
+    }
+
+    /// Replace the clock timing the compression and deduplication.
+    ///
+    /// The measured durations drive the automatic compression algorithm selection and the
+    /// adaptive deduplication policy, so a fake clock makes their choices deterministic.
+    fn set_clock<F>(&mut self, clock: F)
+        where F: Fn() -> Instant + Send + Sync + 'static {
+        self.clock = Box::new(clock);
+    }
+
+    /// Set the number of times a read is retried on a page checksum mismatch.
+    ///
+    /// Before surfacing `Error::PageChecksumMismatch`, the cluster is evicted from the cache and
+    /// read from the disk again, up to `retries` times. Zero (the default) disables the retries.
+    pub fn set_checksum_retries(&mut self, retries: usize) {
+        self.checksum_retries = retries;
+    }
+
+    /// Get statistics on the packing of pages into clusters.
+    ///
+    /// This is derived from the live page index, so unless the index is complete, it only covers
+    /// the pages allocated since the manager was opened, and might be approximate.
+    pub fn packing_stats(&self) -> PackingStats {
+        // The number of live clusters and pages, in total and in compressed clusters.
+        let (mut clusters, mut pages) = (0, 0);
+        let (mut compressed_clusters, mut compressed_pages) = (0, 0);
+        for live_pages in self.live.lock().values() {
+            clusters += 1;
+            pages += live_pages.pages.len();
+
+            // Only compressed clusters have pages with offsets.
+            if live_pages.pages.iter().any(|page| page.offset.is_some()) {
+                compressed_clusters += 1;
+                compressed_pages += live_pages.pages.len();
+            }
+        }
+
+        PackingStats {
+            pages_per_compressed_cluster: if compressed_clusters == 0 {
+                0.0
+            } else {
+                compressed_pages as f64 / compressed_clusters as f64
+            },
+            // Pages and clusters share the same size, so the byte ratio is the count ratio.
+            logical_to_physical: if clusters == 0 {
+                0.0
+            } else {
+                pages as f64 / clusters as f64
+            },
+        }
+    }
+
+    /// Get the number of live pages.
+    ///
+    /// This sums the reference counts (i.e. the numbers of live pages) of the clusters in the live
+    /// page index, which is persisted with the allocation metadata, if enabled in the disk header.
+    /// Otherwise, like `packing_stats`, it only covers the pages allocated since the manager was
+    /// opened (or supplied to `rebuild_refcounts`). Duplicates found through deduplication are the
+    /// very same page, and thus only counted once.
+    pub fn live_page_count(&self) -> usize {
+        self.live.lock().values().map(|live_pages| live_pages.pages.len()).sum()
+    }
+
+    /// Get the statistics of the deduplication table.
+    ///
+    /// This helps deciding whether deduplication is worth its memory. Lookups skipped by the
+    /// deduplication policy are neither counted as hits nor misses.
+    pub fn dedup_stats(&self) -> dedup::Stats {
+        self.dedup_table.stats()
+    }
+
+    /// Get the statistics of the allocator.
+    ///
+    /// This counts the free clusters through `free_clusters`, so it walks the freelist.
+    pub fn allocator_stats(&self) -> Result<AllocatorStats, Error> {
+        Ok(AllocatorStats {
+            free_clusters: self.free_clusters()?,
+            live_pages: self.live_page_count(),
+            live_clusters: self.live.lock().len(),
+            fragmentation: self.fragmentation(),
+            compressions: self.compressions.load(ORDERING),
+            metacluster_hashes: self.metacluster_hashes.load(ORDERING),
+            dedup: self.dedup_stats(),
+        })
+    }
+
+    /// Get the metrics of the cache.
+    pub fn cache_metrics(&self) -> cache::CacheMetrics {
+        self.cache.metrics()
+    }
+
+    /// Remove stale entries from the deduplication table.
+    ///
+    /// Freeing a page normally removes it from the deduplication table, but entries can still go
+    /// stale, e.g. when they're restored from a persisted table. This drops every entry, whose
+    /// cluster is free (or in the trash), or whose page is no longer among the live pages of its
+    /// cluster. If the live page index is complete, entries of clusters not in it are dropped as
+    /// well. Otherwise, they're kept, as they might be in use.
+    ///
+    /// The number of entries removed is returned.
+    pub fn compact_dedup_table(&mut self) -> usize {
+        debug!(self, "compacting the deduplication table"; "subsystem" => subsystem::ALLOC);
+
+        // Collect the clusters, which are not in use.
+        let mut unused: BTreeSet<_> = self.iter_free_clusters().collect();
+        unused.extend(self.trash.lock().iter().cloned());
+        unused.extend(self.state.lock().reserve.iter().cloned());
+
+        let complete = self.index_complete;
+        let live = self.live.lock();
+        let removed = self.dedup_table.retain(|page| {
+            !unused.contains(&page.cluster)
+                && live.get(&page.cluster).map_or(!complete, |live_pages| live_pages.pages.contains(&page))
+        });
+
+        debug!(self, "removed stale deduplication entries"; "subsystem" => subsystem::ALLOC,
+               "removed" => removed);
+
+        removed
+    }
+
+    /// Change the compression algorithm.
+    ///
+    /// This sets the compression algorithm of the clusters allocated from here on, and persists it
+    /// in the state block (the returned transaction). The existing clusters remain readable, as
+    /// they're decompressed through the algorithm they're tagged with. Consequently, cluster
+    /// tagging is enabled, unless compression stays disabled.
+    ///
+    /// If compression is enabled, but the clusters aren't tagged, the algorithm the existing
+    /// clusters were compressed with is unknown, so `Error::UntaggedClusters` is returned.
+    pub fn set_compression_algorithm(&mut self, algorithm: CompressionAlgorithm) -> Result<cache::Transaction, Error> {
+        info!(self, "changing the compression algorithm"; "subsystem" => subsystem::COMPRESSION,
+              "algorithm" => algorithm as u16);
+
+        // Make sure that the existing clusters remain readable.
+        if !self.config.tags_clusters()
+            && self.config.compression_algorithm != CompressionAlgorithm::Identity
+            && algorithm != self.config.compression_algorithm {
+            return Err(Error::UntaggedClusters);
+        }
+
+        // Tag the clusters from here on, so the algorithm can be changed again.
+        if algorithm != CompressionAlgorithm::Identity {
+            self.config.cluster_tags = true;
+        }
+        // Override the compression profile, which would otherwise choose the algorithm.
+        self.config.compression_profile = state_block::CompressionProfile::Custom;
+        self.config.compression_algorithm = algorithm;
+        self.compression_level = self.config.resolve_compression();
+
+        // Stop packing pages into the last allocated cluster, which uses the old algorithm.
+        *self.last_cluster.lock() = None;
+
+        // Persist the configuration.
+        let state = self.state.lock();
+        Ok(self.flush_state_block(&state))
+    }
+
+    /// Seed the deduplication table from an external catalog.
+    ///
+    /// `entries` are pairs of the checksum of a page (under the configured checksum algorithm)
+    /// and the pointer to the page, e.g. from an index of existing content, so the manager
+    /// deduplicates against them right away. The imported pages are tracked as live.
+    ///
+    /// Unless the candidates are trusted on their checksum alone, an imported page is read and
+    /// compared to the allocated page, when it is first matched. If it doesn't match, it is
+    /// dropped from the table.
+    ///
+    /// Entries whose checksum doesn't match their pointer, or whose pointer is out of bounds, are
+    /// skipped. So are entries whose cluster isn't allocated (like in `validate_page_pointer`), or
+    /// whose page doesn't match the checksum, which is verified by reading it. The number of
+    /// imported entries is returned.
+    pub fn import_dedup_entries<I>(&mut self, entries: I) -> usize
+        where I: IntoIterator<Item = (u64, page::Pointer)> {
+        // Collect the clusters, which are not allocated. If the live page index is complete, the
+        // allocated clusters are exactly those in it.
+        let complete = self.index_complete;
+        let mut unused = BTreeSet::new();
+        if !complete {
+            unused.extend(self.iter_free_clusters());
+            unused.extend(self.trash.lock().iter().cloned());
+            unused.extend(self.retained.lock().iter().cloned());
+            unused.extend(self.state.lock().reserve.iter().cloned());
+        }
+
+        let mut imported = 0;
+        for (checksum, page) in entries {
+            // Skip entries which are inconsistent or point outside the data clusters.
+            let sector = page.cluster.into() as disk::Sector;
+            if self.config.checksum_width.truncate(checksum) != page.checksum
+                || sector < self.first_data_cluster()
+                || sector >= self.driver.number_of_sectors()
+                || self.cache.is_metadata(sector)
+                || page.offset.map_or(false, |offset| offset as usize >= page::MAX_PAGES_PER_CLUSTER) {
+                warn!(self, "skipping invalid deduplication entry"; "subsystem" => subsystem::ALLOC,
+                      "page" => page);
+                continue;
+            }
+
+            // Skip entries whose cluster isn't allocated, as it might be overwritten at any time.
+            let allocated = if complete {
+                self.live.lock().contains_key(&page.cluster)
+            } else {
+                !unused.contains(&page.cluster)
+            };
+            if !allocated {
+                warn!(self, "skipping deduplication entry of an unallocated cluster"; "subsystem" => subsystem::ALLOC,
+                      "page" => page);
+                continue;
+            }
+
+            // Skip entries, which don't match the page stored.
+            if let Err(err) = self.check_page_stored(page) {
+                warn!(self, "skipping deduplication entry not matching the page"; "subsystem" => subsystem::ALLOC,
+                      "page" => page, "error" => err);
+                continue;
+            }
+
+            self.dedup_table.import(page);
+            // Track the page, unless it is already.
+            if !self.live.lock().get(&page.cluster).map_or(false, |live_pages| live_pages.pages.contains(&page)) {
+                self.track(page);
+            }
+            imported += 1;
+        }
+
+        info!(self, "imported deduplication entries"; "subsystem" => subsystem::ALLOC,
+              "entries" => imported);
+
+        imported
+    }
+
+    /// Migrate to another checksum algorithm.
+    ///
+    /// Page pointers hold the checksum of their page, so every page (both the live pages and the
+    /// pages of the snapshots) is read and verified, and gets a new pointer with the checksum
+    /// under `to`. For every page, `remap` is called with the old and the new pointer, so external
+    /// indices can be updated. The page data itself stays in place. The pages in the trash are
+    /// unknown, so the trash is evicted first.
+    ///
+    /// The migration cannot be atomic, so the disk header switches first, and keeps the previous
+    /// algorithm, under which the metadata and the page pointers are still accepted. Then the
+    /// metadata (the metaclusters, the state block, the journal, the deduplication table and the
+    /// live page index) is rewritten under `to`. A crash at any point hence leaves a disk, which
+    /// opens with both the old and the new pointers valid, and the migration can simply be
+    /// retried. The previous algorithm is dropped by the next migration, so the remapped pointers
+    /// must be persisted before migrating again.
+    ///
+    /// If the live page index is incomplete (see `rebuild_refcounts`), `Error::IndexIncomplete`
+    /// is returned, as the pages allocated before the manager was opened are unknown.
+    pub fn migrate_checksum<F>(&mut self, to: header::ChecksumAlgorithm, remap: &mut F) -> Result<(), Error>
+        where F: FnMut(page::Pointer, page::Pointer) {
+        info!(self, "migrating the checksum algorithm"; "subsystem" => subsystem::ALLOC,
+              "algorithm" => to as u16);
+
+        if !self.index_complete {
+            return Err(Error::IndexIncomplete);
+        }
+
+        // If an earlier migration was interrupted, some metadata might still be checksummed under
+        // the algorithm before the current one, which is about to be dropped, so rewrite it first.
+        let from = self.driver.header.checksum_algorithm;
+        if self.driver.header.previous_checksum_algorithm.map_or(false, |previous| previous != from) {
+            self.rewrite_checksummed_metadata()?;
+        }
+
+        // The live page index is about to change, so mark the persisted one stale.
+        self.touch_index();
+
+        // Evict the trash, as its pages can't be remapped.
+        let trash: Vec<_> = self.trash.lock().drain(..).collect();
+        if !trash.is_empty() {
+            debug!(self, "evicting the trash"; "subsystem" => subsystem::ALLOC,
+                   "clusters" => trash.len());
+            self.freelist_push_batch(&trash).execute();
+        }
+
+        // Read and verify every live page and every page of the snapshots. The pages are keyed by
+        // their location, so the pages shared with the snapshots are read once.
+        let live: BTreeMap<_, _> = self.live.lock().values()
+            .flat_map(|live_pages| live_pages.pages.iter().map(|&page| ((page.cluster, page.offset), page)))
+            .collect();
+        let pages: Vec<page::Pointer> = {
+            let mut pages = live.clone();
+            for snapshot in self.snapshots.lock().values() {
+                pages.extend(snapshot.iter().map(|&page| ((page.cluster, page.offset), page)));
+            }
+            pages.into_iter().map(|(_, page)| page).collect()
+        };
+        let mut bufs = Vec::with_capacity(pages.len());
+        for &page in &pages {
+            bufs.push(self.read(page)?);
+        }
+
+        // Switch the algorithm, keeping the old one (unless this retries an interrupted migration
+        // to the same algorithm), and flush the disk headers before anything is rewritten.
+        for header in Some(&mut self.cache.driver.header).into_iter()
+            .chain(self.cache.metadata.as_mut().map(|metadata| &mut metadata.driver.header)) {
+            if from != to {
+                header.previous_checksum_algorithm = Some(from);
+            }
+            header.checksum_algorithm = to;
+        }
+        if let Some(ref mut metadata) = self.cache.metadata {
+            metadata.driver.flush_header()?;
+        }
+        self.cache.driver.flush_header()?;
+
+        // Recalculate the checksums of the pages. The deduplication table is indexed by the old
+        // checksums, so it is rebuilt from scratch (from the live pages only).
+        let mut new_pages = Vec::with_capacity(pages.len());
+        self.dedup_table = dedup::Table::default();
+        for (&old, buf) in pages.iter().zip(&bufs) {
+            let new = page::Pointer {
+                checksum: self.checksum_page(buf),
+                .. old
+            };
+            if live.contains_key(&(old.cluster, old.offset)) {
+                self.dedup_table.insert(buf, new);
+            }
+            new_pages.push(new);
+        }
+        // Map the location of every page to its old and new pointer.
+        let remapped: BTreeMap<_, _> = pages.iter().zip(&new_pages)
+            .map(|(&old, &new)| ((old.cluster, old.offset), (old, new)))
+            .collect();
+        let remap_page = |page: &mut page::Pointer| match remapped.get(&(page.cluster, page.offset)) {
+            Some(&(old, new)) if old == *page => *page = new,
+            _ => (),
+        };
+
+        // Update the live page index and the snapshots.
+        for live_pages in self.live.lock().values_mut() {
+            for page in &mut live_pages.pages {
+                let old = *page;
+                remap_page(page);
+                // Rekey the duplicates of the page.
+                if let Some(duplicates) = live_pages.duplicates.remove(&old) {
+                    live_pages.duplicates.insert(*page, duplicates);
+                }
+            }
+        }
+        for pages in self.snapshots.lock().values_mut() {
+            for page in pages {
+                remap_page(page);
+            }
+        }
+        // Update the superpage pointer.
+        if let Some(ref mut superpage) = self.state.lock().superpage {
+            remap_page(superpage);
+        }
+
+        // Rewrite the metadata under the new algorithm.
+        self.rewrite_checksummed_metadata()?;
+
+        // Let the caller update the external indices.
+        for (old, new) in pages.into_iter().zip(new_pages) {
+            remap(old, new);
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the metadata checksummed under the checksum algorithm.
+    ///
+    /// This rewrites every metacluster, so the chained checksums are recalculated, and the
+    /// persisted deduplication table, if any (abandoning its other slot). Then the state block,
+    /// the journal and the live page index are flushed by syncing the metadata.
+    fn rewrite_checksummed_metadata(&mut self) -> Result<(), Error> {
+        debug!(self, "rewriting the checksummed metadata"; "subsystem" => subsystem::ALLOC);
+
+        {
+            // Lock the freelist.
+            let (mut state, mut head_metacluster) = self.lock_freelist();
+
+            // Rewrite every metacluster.
+            let mut chain = self.load_metacluster_chain(&head_metacluster)?;
+            for entry in &mut chain {
+                entry.2 = true;
+            }
+            self.rewrite_metacluster_chain(&mut state, &mut head_metacluster, &mut chain, true).0.execute();
+        }
+
+        // Rewrite the persisted deduplication table, if any.
+        if self.driver.header.dedup_sectors != 0 {
+            self.flush_dedup_table()?.execute();
+        }
+
+        // Flush everything.
+        self.sync_metadata()?;
+        self.cache.trim(0)?;
+
+        Ok(())
+    }
+
+    /// Persist the deduplication table.
+    ///
+    /// This serializes the deduplication table into its reserved region, so it is warm when the
+    /// manager is opened again. It can be called on a schedule, or before shutting down.
+    ///
+    /// The table is double-buffered: It is written to the slot not holding the newest persisted
+    /// table, and it is checksummed as a whole. Hence, a torn or failed flush leaves the previous
+    /// table intact. The candidates not fitting in a slot are dropped.
+    ///
+    /// If no region is reserved in the disk header, `Error::DedupPersistenceDisabled` is
+    /// returned.
+    pub fn flush_dedup_table(&mut self) -> Result<cache::Transaction, Error> {
+        let sectors = self.driver.header.dedup_sectors as disk::Sector;
+        if sectors == 0 {
+            return Err(Error::DedupPersistenceDisabled);
+        }
+
+        // Serialize the table into the next slot.
+        self.dedup_generation += 1;
+        debug!(self, "flushing the deduplication table"; "subsystem" => subsystem::ALLOC,
+               "generation" => self.dedup_generation);
+        let buf = self.dedup_table.persisted(self.dedup_generation)
+            .encode(sectors * disk::SECTOR_SIZE, self.driver.header.checksum_algorithm);
+        let start = self.dedup_table_address() + (self.dedup_generation % 2) as disk::Sector * sectors;
+
+        // Write the slot sector by sector, chaining the transactions. The slot spans at least one
+        // sector.
+        let mut writes = buf.chunks(disk::SECTOR_SIZE).enumerate().map(|(n, chunk)| {
+            let mut sector = disk::SectorBuf::default();
+            sector.copy_from_slice(chunk);
+            self.cache.write(start + n, sector)
+        });
+        let mut transaction = writes.next().unwrap();
+        for write in writes {
+            transaction = transaction.then(write);
+        }
+
+        Ok(transaction)
+    }
+
+    /// Load the persisted deduplication table.
+    ///
+    /// This reads both slots, and restores the candidates of the newest intact table into the
+    /// deduplication table. The number of candidates restored is returned.
+    fn load_dedup_table(&mut self) -> Result<usize, Error> {
+        let sectors = self.driver.header.dedup_sectors as disk::Sector;
+
+        // Read and decode the slots, ignoring torn ones.
+        let mut newest: Option<dedup::Persisted> = None;
+        for slot in 0..2 {
+            let start = self.dedup_table_address() + slot * sectors;
+            let mut buf = Vec::with_capacity(sectors * disk::SECTOR_SIZE);
+            for sector in start..start + sectors {
+                self.cache.read_then(sector, |data| {
+                    buf.extend_from_slice(data);
+                    Ok(())
+                })?;
+            }
+
+            // Accept the previous checksum algorithm after an interrupted migration.
+            let decoded = self.driver.header.checksum_algorithms()
+                .filter_map(|algorithm| dedup::Persisted::decode(&buf, algorithm))
+                .next();
+            match decoded {
+                Some(persisted) => if newest.as_ref().map_or(true, |newest| persisted.generation > newest.generation) {
+                    newest = Some(persisted);
+                },
+                None => warn!(self, "ignoring damaged deduplication table slot"; "subsystem" => subsystem::ALLOC,
+                              "slot" => slot),
+            }
+        }
+
+        let persisted = match newest {
+            Some(persisted) => persisted,
+            None => return Ok(0),
+        };
+        let candidates = persisted.candidates.len();
+        info!(self, "loading the persisted deduplication table"; "subsystem" => subsystem::ALLOC,
+              "generation" => persisted.generation,
+              "candidates" => candidates);
+
+        // Continue after the loaded generation.
+        self.dedup_generation = persisted.generation;
+        self.dedup_table.restore(persisted);
+
+        Ok(candidates)
+    }
+
+    /// Persist the live page index.
+    ///
+    /// This writes the live page index through to the slot not holding the current persisted
+    /// index, and points the state block (in memory) to it, so it must be followed by flushing the
+    /// state block. Like the deduplication table, a torn write leaves the previous index intact.
+    /// Along with the live pages, the index holds the backup generations of the clusters in use,
+    /// the write counts of the clusters, the trash, and the snapshots.
+    ///
+    /// Nothing is written, if the persisted index is current, if persistence is disabled, or if
+    /// the index is incomplete. If the index doesn't fit in a slot, it is left stale, and a warning
+    /// is logged.
+    fn flush_live_index(&mut self) -> Result<(), Error> {
+        let sectors = self.driver.header.index_sectors as disk::Sector;
+        if sectors == 0 || !self.index_complete || self.state.lock().live_index != 0 {
+            return Ok(());
+        }
+
+        // Serialize the index.
+        let generation = self.index_generation + 1;
+        debug!(self, "flushing the live page index"; "subsystem" => subsystem::ALLOC,
+               "generation" => generation);
+        let backup_generation = self.state.lock().backup_generation;
+        let writes: Vec<_> = self.writes.lock().iter().map(|(&cluster, &writes)| (cluster, writes)).collect();
+        let trash: Vec<_> = self.trash.lock().iter().cloned().collect();
+        let snapshots: Vec<_> = self.snapshots.lock().iter().map(|(id, pages)| (id.0, pages.clone())).collect();
+        let retained: Vec<_> = self.retained.lock().iter().cloned().collect();
+        let buf = {
+            let live = self.live.lock();
+            let changed = self.changed.lock();
+            index::Persisted {
+                generation: generation,
+                next_sequence: self.next_sequence.load(ORDERING) as u64,
+                clusters: live.iter().map(|(&cluster, live_pages)| index::Cluster {
+                    cluster: cluster,
+                    sequence: live_pages.sequence,
+                    stored: live_pages.stored,
+                    // Every cluster of a complete index is stamped, but err on the side of
+                    // backing it up.
+                    written: changed.get(&cluster).cloned().unwrap_or(backup_generation),
+                    pages: live_pages.pages.iter().map(|page| {
+                        (*page, live_pages.duplicates.get(page).cloned().unwrap_or(0))
+                    }).collect(),
+                }).collect(),
+                writes: writes,
+                next_snapshot: self.next_snapshot,
+                trash: trash,
+                retained: retained,
+                snapshots: snapshots,
+            }.encode(sectors * disk::SECTOR_SIZE, self.driver.header.checksum_algorithm)
+        };
+        let buf = match buf {
+            Some(buf) => buf,
+            None => {
+                warn!(self, "live page index doesn't fit in its slot; leaving it stale";
+                      "subsystem" => subsystem::ALLOC, "clusters" => self.live.lock().len());
+                return Ok(());
+            },
+        };
+
+        // Write the next slot through to the disk, before the state block points to it.
+        self.index_generation = generation;
+        let start = self.live_index_address() + (generation % 2) as disk::Sector * sectors;
+        for (n, chunk) in buf.chunks(disk::SECTOR_SIZE).enumerate() {
+            let mut sector = disk::SectorBuf::default();
+            sector.copy_from_slice(chunk);
+            self.cache.write(start + n, sector).execute();
+            self.flush_sector(start + n)?;
+        }
+
+        self.state.lock().live_index = generation;
+
+        Ok(())
+    }
+
+    /// Load the persisted live page index.
+    ///
+    /// This reads both slots, and restores the index whose generation is the one stored in the
+    /// state block. If there is none (the index went stale, or its slot is damaged), a warning is
+    /// logged, and the index is left incomplete. Whether the index was loaded is returned.
+    fn load_live_index(&mut self) -> Result<bool, Error> {
+        let sectors = self.driver.header.index_sectors as disk::Sector;
+        let current = self.state.lock().live_index;
+
+        // Read and decode the slots, ignoring torn ones.
+        let mut loaded = None;
+        for slot in 0..2 {
+            let start = self.live_index_address() + slot * sectors;
+            let mut buf = Vec::with_capacity(sectors * disk::SECTOR_SIZE);
+            for sector in start..start + sectors {
+                self.cache.read_then(sector, |data| {
+                    buf.extend_from_slice(data);
+                    Ok(())
+                })?;
+            }
+
+            // Accept the previous checksum algorithm after an interrupted migration.
+            let decoded = self.driver.header.checksum_algorithms()
+                .filter_map(|algorithm| index::Persisted::decode(&buf, algorithm))
+                .next();
+            if let Some(persisted) = decoded {
+                // Continue after the newest generation, so the current slot is never overwritten by
+                // an older generation.
+                self.index_generation = self.index_generation.max(persisted.generation);
+                if current != 0 && persisted.generation == current {
+                    loaded = Some(persisted);
+                }
+            }
+        }
+
+        let persisted = match loaded {
+            Some(persisted) => persisted,
+            None => {
+                warn!(self, "the persisted live page index is stale; rebuild it to track the existing pages";
+                      "subsystem" => subsystem::ALLOC, "generation" => current);
+                return Ok(false);
+            },
+        };
+        info!(self, "loading the persisted live page index"; "subsystem" => subsystem::ALLOC,
+              "generation" => persisted.generation,
+              "clusters" => persisted.clusters.len());
+
+        self.next_sequence.store(persisted.next_sequence as usize, ORDERING);
+        self.changed.lock().extend(persisted.clusters.iter().map(|cluster| (cluster.cluster, cluster.written)));
+        self.writes.lock().extend(persisted.writes);
+        self.next_snapshot = persisted.next_snapshot;
+        self.trash.lock().extend(persisted.trash);
+        self.retained.lock().extend(persisted.retained);
+        self.snapshots.lock().extend(persisted.snapshots.into_iter().map(|(id, pages)| (SnapshotId(id), pages)));
+        // Extend the index rather than replacing it, keeping its presized capacity.
+        self.live.lock().extend(persisted.clusters.into_iter().map(|cluster| (cluster.cluster, LivePages {
+            sequence: cluster.sequence,
+            pages: cluster.pages.iter().map(|&(page, _)| page).collect(),
+            duplicates: cluster.pages.iter().filter(|&&(_, duplicates)| duplicates != 0).cloned().collect(),
+            stored: cluster.stored,
+        })));
+        self.index_complete = true;
+
+        Ok(true)
+    }
+
+    /// Mark the persisted live page index stale.
+    ///
+    /// This must be called before the live page index is changed. On the first change since the
+    /// index was persisted, the state block is flushed with the index marked stale, so a crash
+    /// before the next sync doesn't leave an outdated index to be loaded. A failed flush is
+    /// recorded as a warning (see `flush_sector`).
+    ///
+    /// The state is locked, so `state` must not be held by the caller.
+    fn touch_index(&self) {
+        let mut state = self.state.lock();
+        if state.live_index == 0 {
+            return;
+        }
+
+        debug!(self, "marking the persisted live page index stale"; "subsystem" => subsystem::ALLOC,
+               "generation" => state.live_index);
+        state.live_index = 0;
+        self.flush_state_block(&state).execute();
+        drop(state);
+        // A failure is recorded by `flush_sector`.
+        self.flush_sector(self.state_block_address()).ok();
+    }
+
+    /// Get the number of dirty sectors in the cache.
+    ///
+    /// This is roughly the amount of work left for a sync.
+    pub fn dirty_sectors(&self) -> usize {
+        self.cache.dirty_blocks()
+    }
+
+    /// Trickle some dirty sectors to the disk.
+    ///
+    /// This flushes up to `sectors` dirty sectors (and the sectors they depend on), keeping them
+    /// in the cache. The number of sectors chosen is returned. See `spawn_flusher`.
+    pub fn trickle(&self, sectors: usize) -> Result<usize, Error> {
+        Ok(self.cache.flush_some(sectors)?)
+    }
+
+    /// Set whether transactions are deferred.
+    ///
+    /// In deferred mode, the writes of executed transactions are queued in the cache rather than
+    /// applied, so nothing hits the device, not even on syncs, until `drain_transactions` is
+    /// called. This lets callers batch many transactions under their own scheduling.
+    ///
+    /// The queued writes stay in the cache, so they're bounded by its size (see
+    /// `set_cache_size`). Once they fill it, deferred mode is left, and the queued transactions
+    /// are applied, rather than letting the cache grow without bound.
+    ///
+    /// Leaving deferred mode drains the queued transactions.
+    pub fn set_deferred_transactions(&mut self, deferred: bool) -> Result<(), Error> {
+        self.cache.set_deferring(deferred);
+
+        if !deferred {
+            self.drain_transactions()?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply the queued deferred transactions.
+    ///
+    /// This writes every transaction queued in deferred mode to the device, in order. This only
+    /// guarantees the order, not atomicity: If it crashes in the middle, the transactions applied
+    /// form a prefix of the queue. Deferred mode stays enabled.
+    pub fn drain_transactions(&mut self) -> Result<(), Error> {
+        debug!(self, "draining deferred transactions"; "subsystem" => subsystem::ALLOC,
+               "sectors" => self.cache.deferred_sectors());
+
+        Ok(self.cache.drain_deferred()?)
+    }
+
+    /// Spawn a background flusher.
+    ///
+    /// This continuously trickles dirty sectors to the disk according to `policy`, so the cache
+    /// doesn't pile up dirty sectors, and syncs have little to do. Each round only holds the lock
+    /// for a bounded number of sectors, keeping the latency of other operations bounded as well.
+    ///
+    /// The flusher runs until the returned handle is dropped. Errors are logged and otherwise
+    /// ignored, as a later sync will surface them.
+    pub fn spawn_flusher(manager: Arc<Mutex<Manager>>, policy: FlushPolicy) -> Flusher {
+        let stop = Arc::new(AtomicBool::new(false));
+
+        Flusher {
+            stop: stop.clone(),
+            thread: Some(thread::spawn(move || {
+                while !stop.load(ORDERING) {
+                    {
+                        let manager = manager.lock();
+                        if let Err(err) = manager.trickle(policy.sectors) {
+                            warn!(manager, "background flush failed"; "subsystem" => subsystem::ALLOC,
+                                  "error" => err);
+                        }
+                    }
+
+                    thread::sleep(policy.interval);
+                }
+            })),
+        }
+    }
+
+    /// Read some pages into the cache.
+    ///
+    /// This reads and validates `pages`, discarding the data, purely to warm the cache, so later
+    /// reads of the pages don't hit the disk. This is useful for keeping the latency predictable
+    /// after opening, by warming the cache with hot pages.
+    ///
+    /// If a page fails to read or validate, the error is returned.
+    pub fn read_and_cache(&self, pages: &[page::Pointer]) -> Result<(), Error> {
+        debug!(self, "warming the cache"; "subsystem" => subsystem::ALLOC, "pages" => pages.len());
+
+        let mut buf = disk::SectorBuf::default();
+        for &page in pages {
+            self.read_into(page, &mut buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read/dereference a page.
+    ///
+    /// This reads page `page` and returns the content.
+    ///
+    /// If the cluster fails verification when fetched from the disk, it is healed through the
+    /// redundancy of the vdev stack, if any, and verified again. With a mirror vdev, the corrupt
+    /// copy is overwritten by the mirrored copy, so the good data is returned and repaired at
+    /// once. A successful repair is recorded as a `Warning::Healed` for health checks, while a
+    /// failed one is returned as an error.
+    pub fn read(&self, page: page::Pointer) -> Result<disk::SectorBuf, Error> {
+        // Read the page into a fresh buffer.
+        let mut buf = disk::SectorBuf::default();
+        self.read_into(page, &mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// Read/dereference a page, and report where it is stored.
+    ///
+    /// This is like `read`, but the physical location of the page's cluster is returned along
+    /// with the content.
+    pub fn read_located(&self, page: page::Pointer) -> Result<(disk::SectorBuf, PhysicalLocation), Error> {
+        let buf = self.read(page)?;
+        let (device, sector) = self.cache.locate(page.cluster.into());
+
+        Ok((buf, PhysicalLocation {
+            device: device,
+            sector: sector,
+        }))
+    }
+
+    /// Read/dereference a page into some buffer.
+    ///
+    /// This reads page `page` into `out`, which is usually owned by some I/O framework. The page
+    /// is decompressed and validated directly in `out`, so if the page is uncompressed, no
+    /// intermediate buffer is used at all.
+    ///
+    /// If checksum retries are enabled (see `set_checksum_retries`), a checksum mismatch causes
+    /// the cluster to be read from the disk again, before the error is returned.
+    pub fn read_into(&self, page: page::Pointer, out: &mut disk::SectorBuf) -> Result<(), Error> {
+        trace!(self, "reading page"; "subsystem" => subsystem::ALLOC, "page" => page);
+
+        let mut retries = self.checksum_retries;
+        loop {
+            match self.read_into_once(page, out) {
+                // The mismatch might be transient, so evict the cached cluster and try again. Dirty
+                // clusters cannot be evicted, but they aren't read from the disk either, so there
+                // is nothing to retry.
+                Err(Error::PageChecksumMismatch { .. }) if retries > 0 && self.cache.evict(page.cluster) => {
+                    warn!(self, "page checksum mismatch; retrying read"; "subsystem" => subsystem::ALLOC,
+                          "page" => page, "retries left" => retries);
+
+                    retries -= 1;
+                },
+                res => {
+                    // Keep track of the corruption for health checks.
+                    if let Err(ref err) = res {
+                        self.note_corruption(err);
+                    }
+
+                    return res;
+                },
+            }
+        }
+    }
+
+    /// Record a corruption warning.
+    ///
+    /// The oldest warning is dropped, if there are more than `WARNING_CAPACITY` of them.
+    fn record_warning(&self, warning: Warning) {
+        let mut warnings = self.warnings.lock();
+        if warnings.len() == WARNING_CAPACITY {
+            warnings.pop_front();
+        }
+        warnings.push_back((Instant::now(), warning));
+    }
+
+    /// Record the corruption reported by an error, if any.
+    ///
+    /// Errors which don't stem from corruption are ignored.
+    fn note_corruption(&self, err: &Error) {
+        let warning = match *err {
+            Error::PageChecksumMismatch { page, .. } => Warning::ChecksumMismatch(page),
+            Error::InvalidCompression { cluster, .. } => Warning::InvalidCompression(cluster),
+            Error::MetacluterChecksumMismatch { cluster, .. } => Warning::MetaclusterChecksumMismatch(cluster),
+            Error::Disk(disk::Error::CorruptSector { sector }) => Warning::CorruptSector(sector),
+            _ => return,
+        };
+
+        self.record_warning(warning);
+    }
+
+    /// Flush a sector to the disk.
+    ///
+    /// Failures are recorded as warnings for health checks, before they're returned.
+    fn flush_sector(&self, sector: disk::Sector) -> Result<(), Error> {
+        self.cache.flush(sector).map_err(|err| {
+            warn!(self, "failed to flush sector"; "subsystem" => subsystem::ALLOC, "sector" => sector,
+                  "error" => err);
+            self.record_warning(Warning::WriteFailed(sector));
+
+            err.into()
+        })
+    }
+
+    /// Get the recorded corruption warnings.
+    ///
+    /// This returns the most recent warnings (up to `WARNING_CAPACITY`), oldest first.
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.warnings.lock().iter().map(|&(_, warning)| warning).collect()
+    }
+
+    /// Check the health of the manager.
+    ///
+    /// The health is degraded when the device is low on free clusters (see `LOW_SPACE_CLUSTERS`),
+    /// or corruption was encountered recently (see `RECENT_WARNING_AGE`), and failing when no
+    /// free clusters are left.
+    ///
+    /// The free clusters are counted by `free_clusters`, which walks the metacluster chain. The
+    /// metaclusters are usually cached, so this is cheap enough for readiness and liveness probes.
+    pub fn health_check(&self) -> HealthStatus {
+        let mut reasons = Vec::new();
+
+        // Count the free clusters. A freelist failing to load is corrupt, which is recorded like
+        // any other corruption.
+        match self.free_clusters() {
+            // Nothing can be allocated.
+            Ok(0) => return HealthStatus::Failing,
+            Ok(free) if (free as usize) < LOW_SPACE_CLUSTERS => reasons.push(DegradedReason::LowSpace {
+                free: free as usize,
+            }),
+            Ok(_) => (),
+            Err(err) => self.note_corruption(&err),
+        }
+
+        // Count the recent corruption warnings.
+        let recent = self.warnings.lock().iter()
+            .filter(|&&(at, _)| at.elapsed() < Duration::from_secs(RECENT_WARNING_AGE))
+            .count();
+        if recent > 0 {
+            reasons.push(DegradedReason::RecentCorruption {
+                warnings: recent,
+            });
+        }
+
+        if reasons.is_empty() {
+            HealthStatus::Healthy
+        } else {
+            HealthStatus::Degraded {
+                reasons: reasons,
+            }
+        }
+    }
+
+    /// Read a page into some buffer, without retrying.
+    ///
+    /// This is the workhorse of `read_into`.
+    fn read_into_once(&self, page: page::Pointer, out: &mut disk::SectorBuf) -> Result<(), Error> {
+        // Did the cluster fail verification?
+        let failed = Cell::new(false);
+
+        // Read the cluster in which the page is stored.
+        let res = self.cache.read_then(page.cluster, |cluster| {
+            let res = self.extract_page(page, cluster, out);
+            if res.is_err() {
+                failed.set(true);
+            }
+
+            res
+        });
+
+        // If the cluster failed verification, but the read succeeded anyway, the cache healed it.
+        if res.is_ok() && failed.get() {
+            warn!(self, "healed corrupt cluster"; "subsystem" => subsystem::ALLOC, "page" => page);
+            self.record_warning(Warning::Healed(page.cluster));
+        }
+
+        res
+    }
+
+    /// Extract and verify a page from its cluster.
+    ///
+    /// This decompresses `cluster`, if needed, copies page `page` into `out`, and checks it
+    /// against its checksum.
+    fn extract_page(&self, page: page::Pointer, cluster: &disk::SectorBuf, out: &mut disk::SectorBuf)
+        -> Result<(), Error> {
+        // Decompress if necessary.
+        if let Some(offset) = page.offset {
+            // The page is compressed, decompress it and read at some offset `offset` (in pages).
+
+            // Without cluster tags, the algorithm is only known if compression is enabled.
+            if self.config.compression_algorithm == CompressionAlgorithm::Identity && !self.config.tags_clusters() {
+                return Err(Error::CompressionDisabledButPageCompressed {
+                    page: page,
+                });
+            }
+
+            // Decompress the cluster into a pooled buffer.
+            let mut decompressed = self.pool.get();
+            self.decompress(page.cluster, cluster, &mut decompressed)?;
+
+            // Find the page in the decompressed stream. A corrupted offset might overflow,
+            // notably on 32-bit targets.
+            let start = (offset as usize).checked_mul(disk::SECTOR_SIZE).ok_or(Error::InvalidPageOffset {
+                page: page,
+            })?;
+            let end = start.checked_add(disk::SECTOR_SIZE).ok_or(Error::InvalidPageOffset {
+                page: page,
+            })?;
+
+            // Make sure that the decompressed stream actually contains the page.
+            if decompressed.len() < end {
+                return Err(Error::InvalidCompression {
+                    cluster: page.cluster,
+                    kind: CompressionCorruption::DataCorrupt,
+                });
+            }
+
+            // Copy the page out of the decompressed stream.
+            out.copy_from_slice(&decompressed[start..end]);
+        } else {
+            // The page was not compressed so we can just copy the cluster directly.
+            *out = *cluster;
+        }
+
+        // Check the data against the stored checksum, truncated to the configured width.
+        if !self.page_checksum_matches(out, page.checksum) {
+            // The checksums mismatched, thrown an error.
+            return Err(Error::PageChecksumMismatch {
+                page: page,
+                found: self.checksum_page(out),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Verify a page without returning its content.
+    ///
+    /// This reads and validates `page` like `read`, including healing and checksum retries, but
+    /// the content is dropped rather than handed to the caller. This is meant for scrubbers,
+    /// which only care about whether the page is intact.
+    pub fn verify_page(&self, page: page::Pointer) -> Result<(), Error> {
+        trace!(self, "verifying page"; "subsystem" => subsystem::ALLOC, "page" => page);
+
+        // The page is read into a scratch buffer on the stack, so nothing is allocated.
+        let mut buf = disk::SectorBuf::default();
+        self.read_into(page, &mut buf)
+    }
+
+    /// Read a batch of pages, decompressing distinct clusters in parallel.
+    ///
+    /// This reads `pages` and returns their contents in the same order. Decompression is
+    /// CPU-bound, so the distinct compressed clusters of the batch are spread over `workers`
+    /// threads. Every cluster is decompressed once, however many of the pages it holds. The
+    /// clusters themselves are still fetched through the cache one by one.
+    ///
+    /// The workers verify the checksums of the pages they extract. The clusters which fail to
+    /// decompress or validate are evicted from the cache, and their pages are read again through
+    /// `read_into`, so the usual healing and checksum retries apply, and the error is returned if
+    /// it persists.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `workers` is zero.
+    pub fn read_batch(&self, pages: &[page::Pointer], workers: usize) -> Result<Vec<disk::SectorBuf>, Error> {
+        assert!(workers > 0, "Batch reads need at least one worker.");
+
+        debug!(self, "reading batch"; "subsystem" => subsystem::ALLOC, "pages" => pages.len(),
+               "workers" => workers);
+
+        // Fetch the distinct compressed clusters with the pages read from them, unless compression
+        // is disabled, in which case `read_into` reports the compressed pages. Every page of the
+        // batch remembers where its content will be found.
+        let mut index = BTreeMap::new();
+        let mut clusters = Vec::new();
+        let mut slots = vec![None; pages.len()];
+        if self.config.compression_algorithm != CompressionAlgorithm::Identity || self.config.tags_clusters() {
+            for (slot, page) in slots.iter_mut().zip(pages) {
+                if page.offset.is_some() {
+                    let n = match index.get(&page.cluster) {
+                        Some(&n) => n,
+                        None => {
+                            let buf = self.cache.read_then(page.cluster, |buf| Ok::<_, Error>(*buf))?;
+                            index.insert(page.cluster, clusters.len());
+                            clusters.push((page.cluster, buf, Vec::new()));
+
+                            clusters.len() - 1
+                        },
+                    };
+
+                    let cluster_pages: &mut Vec<_> = &mut clusters[n].2;
+                    *slot = Some((n, cluster_pages.len()));
+                    cluster_pages.push(*page);
+                }
+            }
+        }
+
+        // Decompress the clusters, and extract and verify their pages, spreading the clusters
+        // evenly over the workers. If a cluster fails to decompress, or some of its pages don't
+        // match their checksums, the cached cluster is likely corrupt, so it is evicted, and its
+        // pages are left to the fallback below, which fetches it again (healing it, if needed).
+        let chunk_size = ((clusters.len() + workers - 1) / workers).max(1);
+        let extracted: Vec<Option<Vec<disk::SectorBuf>>> = thread::scope(|scope| {
+            let workers: Vec<_> = clusters.chunks(chunk_size).map(|chunk| scope.spawn(move || {
+                chunk.iter().map(|&(cluster, ref buf, ref cluster_pages)| {
+                    let mut stream = Vec::new();
+                    let bufs = self.decompress(cluster, buf, &mut stream).ok().and_then(|()| {
+                        cluster_pages.iter().map(|page| {
+                            let start = page.offset? as usize * disk::SECTOR_SIZE;
+                            if stream.len() < start + disk::SECTOR_SIZE {
+                                return None;
+                            }
+
+                            let mut buf = disk::SectorBuf::default();
+                            buf.copy_from_slice(&stream[start..start + disk::SECTOR_SIZE]);
+                            if self.page_checksum_matches(&buf, page.checksum) {
+                                Some(buf)
+                            } else {
+                                None
+                            }
+                        }).collect::<Option<Vec<_>>>()
+                    });
+
+                    if bufs.is_none() {
+                        warn!(self, "batch read failed verification"; "subsystem" => subsystem::ALLOC,
+                              "cluster" => cluster);
+                        self.cache.evict(cluster);
+                    }
+
+                    bufs
+                }).collect::<Vec<_>>()
+            })).collect();
+
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+        });
+
+        // Assemble the pages in order.
+        let mut bufs = Vec::with_capacity(pages.len());
+        for (&page, &slot) in pages.iter().zip(&slots) {
+            // Take the page out of its extracted cluster, if any.
+            let buf = match slot.and_then(|(n, k)| extracted[n].as_ref().map(|bufs| bufs[k])) {
+                Some(buf) => buf,
+                // Read uncompressed pages and the pages that couldn't be extracted the usual way.
+                None => {
+                    let mut buf = disk::SectorBuf::default();
+                    self.read_into(page, &mut buf)?;
+
+                    buf
+                },
+            };
+
+            bufs.push(buf);
+        }
+
+        Ok(bufs)
+    }
+
+    /// Read the whole decompressed content of a cluster.
+    ///
+    /// This returns the decompressed stream of `cluster` (without the padding), regardless of
+    /// how many pages it holds, exposing how the pages are packed. Uncompressed clusters are
+    /// returned as-is.
+    ///
+    /// Whether the cluster is compressed is looked up in the live page index. If the index is
+    /// complete, clusters not in it aren't in use, and `Error::ClusterNotInUse` is returned.
+    /// Otherwise, they might have been allocated before the manager was opened, and they're
+    /// assumed to be compressed, unless compression is disabled.
+    pub fn read_cluster_decompressed(&self, cluster: cluster::Pointer) -> Result<Box<[u8]>, Error> {
+        trace!(self, "reading decompressed cluster"; "subsystem" => subsystem::ALLOC, "cluster" => cluster);
+
+        // Find out if the cluster is compressed.
+        let compressed = match self.live.lock().get(&cluster) {
+            Some(live_pages) => live_pages.pages.iter().any(|page| page.offset.is_some()),
+            None if self.index_complete => return Err(Error::ClusterNotInUse {
+                cluster: cluster,
+            }),
+            None => true,
+        } && (self.config.compression_algorithm != CompressionAlgorithm::Identity
+              || self.config.tags_clusters());
+
+        self.cache.read_then(cluster, |buf| {
+            if compressed {
+                // Decompress the cluster.
+                let mut decompressed = Vec::new();
+                self.decompress(cluster, buf, &mut decompressed)?;
+
+                Ok(decompressed.into_boxed_slice())
+            } else {
+                // The cluster is stored raw.
+                Ok(buf.to_vec().into_boxed_slice())
+            }
+        })
+    }
+
+    /// Validate a page pointer from an untrusted source.
+    ///
+    /// Dereferencing a page pointer received from e.g. the network could otherwise read arbitrary
+    /// sectors. This checks that:
+    ///
+    /// 1. The cluster is a data cluster within the device.
+    /// 2. The offset, if any, is within the number of pages a cluster can hold.
+    /// 3. The cluster is allocated. If the live page index is complete, the cluster must be in it.
+    ///    Otherwise, it must be neither free (which walks the freelist), in the trash, retained
+    ///    for a snapshot, nor reserved.
+    /// 4. The page is stored in the cluster: The cluster is read and decompressed (if the page is
+    ///    compressed), the offset must be within the pages stored, and the page must match the
+    ///    checksum of the pointer.
+    ///
+    /// Unlike reading, a mismatch isn't recorded as corruption, since the pointer is untrusted.
+    pub fn validate_page_pointer(&self, page: page::Pointer) -> Result<(), Error> {
+        trace!(self, "validating page pointer"; "subsystem" => subsystem::ALLOC, "page" => page);
+
+        // Make sure that the cluster follows the state block (and the journal), and lies within the
+        // data device.
+        let sector = page.cluster.into() as disk::Sector;
+        if sector < self.first_data_cluster()
+            || sector >= self.driver.number_of_sectors()
+            || self.cache.is_metadata(sector) {
+            return Err(Error::ClusterOutOfBounds {
+                page: page,
+            });
+        }
+
+        // Make sure that the offset is within the decompressed cluster.
+        if let Some(offset) = page.offset {
+            if offset as usize >= page::MAX_PAGES_PER_CLUSTER {
+                return Err(Error::ImplausibleOffset {
+                    page: page,
+                });
+            }
+        }
+
+        // Make sure that the cluster is allocated.
+        let allocated = if self.index_complete {
+            self.live.lock().contains_key(&page.cluster)
+        } else {
+            // The pages allocated before the manager was opened aren't in the index.
+            !self.trash.lock().contains(&page.cluster)
+                && !self.retained.lock().contains(&page.cluster)
+                && !self.state.lock().reserve.contains(&page.cluster)
+                && self.iter_free_clusters().all(|cluster| cluster != page.cluster)
+        };
+        if !allocated {
+            return Err(Error::UnallocatedCluster {
+                page: page,
+            });
+        }
+
+        self.check_page_stored(page)
+    }
+
+    /// Make sure that a page is stored in its cluster.
+    ///
+    /// The cluster is read and decompressed (if the page is compressed), the offset must be within
+    /// the pages stored, and the page must match the checksum of the pointer. The cluster and the
+    /// offset must have been bounds checked.
+    fn check_page_stored(&self, page: page::Pointer) -> Result<(), Error> {
+        self.cache.read_then(page.cluster, |cluster| {
+            let mut decompressed = self.pool.get();
+            let buf = if let Some(offset) = page.offset {
+                // Decompress the cluster, and make sure that the stream contains the page. The
+                // offset was checked above, so it doesn't overflow.
+                self.decompress(page.cluster, cluster, &mut decompressed)?;
+                let start = offset as usize * disk::SECTOR_SIZE;
+                if decompressed.len() < start + disk::SECTOR_SIZE {
+                    return Err(Error::ImplausibleOffset {
+                        page: page,
+                    });
+                }
+
+                &decompressed[start..start + disk::SECTOR_SIZE]
+            } else {
+                &cluster[..]
+            };
+
+            if !self.page_checksum_matches(buf, page.checksum) {
+                return Err(Error::PageChecksumMismatch {
+                    page: page,
+                    found: self.checksum_page(buf),
+                });
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Read a page, and verify it against an externally supplied checksum.
+    ///
+    /// This is like `read`, but additionally compares the full (untruncated) checksum of the
+    /// page's content against `expected`, which is usually stored in an external index. This
+    /// serves as a defense in depth, catching corruption that slipped past the page pointer's
+    /// checksum.
+    pub fn read_verified(&self, page: page::Pointer, expected: u64) -> Result<disk::SectorBuf, Error> {
+        let buf = self.read(page)?;
+
+        // Check the data against the external checksum.
+        let found = self.checksum(&buf);
+        if found != expected {
+            return Err(Error::ExternalChecksumMismatch {
+                page: page,
+                expected: expected,
+                found: found,
+            });
+        }
+
+        Ok(buf)
+    }
+
+    /// Calculate the checksum of a page, as stored in its page pointer.
+    ///
+    /// This uses the configured checksum algorithm, and truncates the checksum to the configured
+    /// width, so it can be computed externally (e.g. for indexing) consistently with the
+    /// checksums of the page pointers returned by `alloc`.
+    pub fn checksum_page(&self, buf: &[u8]) -> u64 {
+        self.config.checksum_width.truncate(self.checksum(buf))
+    }
+
+    /// Check a page against the checksum of its pointer.
+    ///
+    /// Pointers handed out before a checksum migration hold the checksum under the previous
+    /// algorithm, which is accepted as well until the next migration (see `migrate_checksum`).
+    fn page_checksum_matches(&self, buf: &[u8], checksum: u64) -> bool {
+        self.driver.header.checksum_algorithms()
+            .any(|algorithm| self.config.checksum_width.truncate(algorithm.hash(buf)) == checksum)
+    }
+
+    /// Calculate the checksum of some buffer, based on the user configuration.
+    fn checksum(&self, buf: &[u8]) -> u64 {
+        trace!(self, "calculating checksum"; "subsystem" => subsystem::ALLOC);
+
+        self.driver.header.hash(buf)
+    }
+
+    /// Choose the compression algorithm of a new cluster.
+    ///
+    /// This returns the configured algorithm, unless automatic selection is enabled. In that case,
+    /// every algorithm of `AUTO_ALGORITHMS` is tried on `input` (the first page of the cluster),
+    /// and the one giving the best ratio, while compressing faster than `AUTO_SPEED_FLOOR`, is
+    /// chosen.
+    fn choose_algorithm(&self, input: &[u8]) -> CompressionAlgorithm {
+        if self.config.compression_algorithm != CompressionAlgorithm::Auto {
+            return self.config.compression_algorithm;
+        }
+
+        // The best algorithm so far and the length of its output.
+        let mut best = None;
+        for (n, &algorithm) in AUTO_ALGORITHMS.iter().enumerate() {
+            // Compress the input, and measure how long it takes.
+            let mut compressed = self.pool.get();
+            let start = (self.clock)();
+            self.compress_into(algorithm, input, &mut compressed);
+            let nanos = nanos((self.clock)() - start);
+
+            // Skip the algorithm if it is too slow. The first (fastest) algorithm is always
+            // eligible, so there is something to fall back to.
+            if n > 0 && input.len() as u64 * 1_000_000_000 > AUTO_SPEED_FLOOR as u64 * nanos as u64 {
+                trace!(self, "compression algorithm below speed floor"; "subsystem" => subsystem::COMPRESSION,
+                       "nanoseconds" => nanos);
+                continue;
+            }
+
+            // Ties are won by the faster algorithm.
+            if best.map_or(true, |(_, len)| compressed.len() < len) {
+                best = Some((algorithm, compressed.len()));
+            }
+        }
+
+        let (algorithm, _) = best.unwrap();
+        debug!(self, "chose compression algorithm"; "subsystem" => subsystem::COMPRESSION,
+               "algorithm" => algorithm as u16);
+
+        algorithm
+    }
+
+    /// Compress the first page of a new cluster.
+    ///
+    /// This compresses `input` with the algorithm chosen for the new cluster. If it doesn't fit,
+    /// the secondary algorithm (if enabled) is tried instead. The algorithm which succeeded is
+    /// returned along with the compressed cluster, or `None`, if the page has to be stored
+    /// uncompressed.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if compression is disabled.
+    fn compress_new_cluster(&self, input: &[u8]) -> Option<(CompressionAlgorithm, disk::SectorBuf)> {
+        // Try the chosen algorithm.
+        let algorithm = self.choose_algorithm(input);
+        if let Some(compressed) = self.compress(algorithm, input) {
+            return Some((algorithm, compressed));
+        }
+
+        // Fall back to the secondary algorithm, if enabled.
+        let secondary = self.config.secondary_compression_algorithm;
+        if secondary == CompressionAlgorithm::Identity || secondary == algorithm {
+            return None;
+        }
+        trace!(self, "falling back to secondary compression algorithm"; "subsystem" => subsystem::COMPRESSION,
+               "algorithm" => secondary as u16);
+
+        self.compress(secondary, input).map(|compressed| (secondary, compressed))
+    }
+
+    /// Compress some data through some algorithm, appending it to a buffer.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `algorithm` is `Identity` or `Auto`.
+    fn compress_into(&self, algorithm: CompressionAlgorithm, input: &[u8], out: &mut Vec<u8>) {
+        match algorithm {
+            // We'll panic if compression is disabled, as it is assumed that the caller handles
+            // this case.
+            CompressionAlgorithm::Identity => panic!("Compression was disabled."),
+            // Automatic selection must be resolved by the caller.
+            CompressionAlgorithm::Auto => panic!("Compression algorithm was not chosen."),
+            // Compress via LZ4.
+            CompressionAlgorithm::Lz4 => lz4_compress::compress_into(input, out),
+            // Compress via Zstandard, at a valid level. The frame carries a checksum of its
+            // content, so corruption is detected on decompression.
+            CompressionAlgorithm::Zstd => {
+                let level = self.compression_level.max(ZSTD_LEVELS.0).min(ZSTD_LEVELS.1);
+                let mut compressor = zstd::bulk::Compressor::new(level)
+                    .expect("Failed to set up the Zstandard compressor.");
+                compressor.set_parameter(zstd::zstd_safe::CParameter::ChecksumFlag(true))
+                    .expect("Failed to enable the Zstandard content checksum.");
+                out.extend_from_slice(&compressor.compress(input)
+                    .expect("Zstandard failed to compress an in-memory buffer."))
+            },
+        }
+    }
+
+    /// Compress some data with some algorithm into a cluster.
+    ///
+    /// If cluster tagging is enabled (see `state_block::Config::tags_clusters`), the cluster is
+    /// tagged with `algorithm`, so it can be decompressed later on.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if compression is disabled.
+    fn compress(&self, algorithm: CompressionAlgorithm, input: &[u8]) -> Option<disk::SectorBuf> {
+        trace!(self, "compressing data"; "subsystem" => subsystem::COMPRESSION);
+        self.compressions.fetch_add(1, ORDERING);
+
+        // Compress the input into a pooled buffer.
+        let mut compressed = self.pool.get();
+        self.compress_into(algorithm, input, &mut compressed);
+
+        // Tag the cluster with the algorithm, if enabled.
+        if self.config.tags_clusters() {
+            compressed.push(algorithm as u8);
+        }
+
+        // Pad the compressed data into a cluster, if it fits. The length prefix makes the padding
+        // distinguishable from the actual data (e.g. if it ends in zero).
+        // TODO: Find a way to eliminate this memcpy.
+        pad(&compressed)
+    }
+
+    /// Decompress some data based on the compression configuration option.
+    ///
+    /// The data `buf` of cluster `cluster` is decompressed and appended to `out`. If cluster
+    /// tagging is enabled, the algorithm is read from the cluster's tag.
+    ///
+    /// Any failure is reported as `Error::InvalidCompression` naming `cluster`, so the corruption
+    /// can be located.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if compression is disabled.
+    fn decompress(&self, cluster: cluster::Pointer, buf: &disk::SectorBuf, out: &mut Vec<u8>) -> Result<(), Error> {
+        trace!(self, "decompressing data"; "subsystem" => subsystem::COMPRESSION, "cluster" => cluster);
+
+        // Construct the error returned on failure, naming the corrupt part.
+        let invalid = |kind| Error::InvalidCompression {
+            cluster: cluster,
+            kind: kind,
+        };
+        let data_corrupt = || invalid(CompressionCorruption::DataCorrupt);
+        let length_prefix_corrupt = || invalid(CompressionCorruption::LengthPrefixCorrupt);
+
+        // Strip the padding. If the length prefix written by `compress` exceeds the cluster, it was
+        // corrupted.
+        let data = unpad(buf).ok_or_else(length_prefix_corrupt)?;
+        let mut len = data.len();
+
+        // Read the tag ending the data, if enabled.
+        let algorithm = if self.config.tags_clusters() {
+            len = len.checked_sub(1).ok_or_else(length_prefix_corrupt)?;
+            match CompressionAlgorithm::try_from(data[len] as u16) {
+                Ok(CompressionAlgorithm::Lz4) => CompressionAlgorithm::Lz4,
+                Ok(CompressionAlgorithm::Zstd) => CompressionAlgorithm::Zstd,
+                // The tag is invalid, indicating data corruption.
+                _ => return Err(length_prefix_corrupt()),
+            }
+        } else {
+            self.config.compression_algorithm
+        };
+
+        let start = out.len();
+        match algorithm {
+            // We'll panic if compression is disabled, as it is assumed that the caller handles
+            // this case.
+            CompressionAlgorithm::Identity => panic!("Compression was disabled."),
+            // The tag was resolved above.
+            CompressionAlgorithm::Auto => unreachable!(),
+            // Decompress the non-padding section from LZ4 into `out`.
+            CompressionAlgorithm::Lz4 => lz4_compress::decompress_into(&data[..len], out)
+                .map_err(|_| data_corrupt())?,
+            // Decompress the non-padding section from Zstandard into `out`. This verifies the
+            // content checksum of the frame, if any.
+            CompressionAlgorithm::Zstd => {
+                let decompressed = zstd::bulk::decompress(&data[..len], CLUSTER_CAPACITY)
+                    .map_err(|_| data_corrupt())?;
+                out.extend_from_slice(&decompressed);
+            },
+        }
+
+        // Clusters hold at least one page, and only whole pages, so anything else means that the
+        // data was decoded wrongly, e.g. by another algorithm than the one it was compressed with,
+        // or that the cluster was wiped.
+        if out.len() == start || out.len() % disk::SECTOR_SIZE != 0 {
+            return Err(data_corrupt());
+        }
+
+        Ok(())
+    }
+
+    /// Flush the state block.
+    ///
+    /// This flushes the state block (not to the disk, but to the cache), and returns the
+    /// transaction.
+    ///
+    /// It takes a state in order to avoid re-acquiring the lock.
+    fn flush_state_block(&self, state: &state_block::State) -> cache::Transaction {
+        trace!(self, "flushing the state block to the cache"; "subsystem" => subsystem::ALLOC);
+
+        // Mirror the counter of the freelist head.
+        self.head_free.store(state.freelist_head.map_or(0, |freelist_head| freelist_head.counter as usize),
+                             ORDERING);
+
+        // Do it, motherfucker.
+        self.cache.write(self.state_block_address(), state_block::StateBlock {
+            config: self.config,
+            state: state,
+        }.encode())
+    }
+
+    /// Flush the allocation metadata to the disk.
+    ///
+    /// This writes the live page index (if persistence is enabled), the head metacluster and the
+    /// state block through to the disk, making the allocation decisions made so far durable. Unlike a full sync, dirty page clusters are left
+    /// in the cache, unless the metadata depends on them.
+    pub fn sync_metadata(&mut self) -> Result<(), Error> {
+        info!(self, "syncing the allocation metadata"; "subsystem" => subsystem::ALLOC);
+
+        // Persist the live page index, so the state block written below points to it.
+        self.flush_live_index()?;
+
+        // Lock the freelist.
+        let (state, head_metacluster) = self.lock_freelist();
+
+        if let Some(freelist_head) = state.freelist_head {
+            // Write the head metacluster, and make the state block depend on it, so the state block
+            // never points to a stale metacluster on the disk.
+            self.write_head_metacluster(&head_metacluster, freelist_head.cluster)
+                .then(self.flush_state_block(&state))
+                .execute();
+            // Flush the head metacluster.
+            self.flush_sector(freelist_head.cluster)?;
+        } else {
+            // The freelist is empty, so only the state block needs to be written.
+            self.flush_state_block(&state).execute();
+        }
+
+        // Flush the state block.
+        self.flush_sector(self.state_block_address())?;
+
+        // Checkpoint the journal, if enabled, as the records are now covered by the metadata on
+        // the disk.
+        if let Some(ref journal) = self.journal {
+            let mut journal = journal.lock();
+            journal.clear();
+            self.cache.write(self.journal_address(), journal.encode(self.driver.header.checksum_algorithm))
+                .execute();
+            self.flush_sector(self.journal_address())?;
+        }
+
+        Ok(())
+    }
+
+    /// Shut down the manager.
+    ///
+    /// This flushes the allocation metadata and every dirty cached block to the disk. Unlike
+    /// dropping the manager, errors are returned, so the caller can react to data not making it to
+    /// the disk.
+    pub fn shutdown(mut self) -> Result<(), Error> {
+        info!(self, "shutting down the page manager"; "subsystem" => subsystem::ALLOC);
+
+        // Whatever happens, don't try to flush again in `Drop`.
+        self.shut_down = true;
+
+        // Flush the allocation metadata.
+        self.sync_metadata()?;
+        // Flush the rest of the cache.
+        self.cache.trim(0)?;
+
+        Ok(())
+    }
+
+    /// Get the address of the state block.
+    ///
+    /// With a separate metadata device, the state block follows the device's disk header.
+    fn state_block_address(&self) -> disk::Sector {
+        self.cache.metadata_start().map_or(self.driver.header.state_block_address, |start| start + 1)
+    }
+
+    /// Get the address of the journal.
+    ///
+    /// The journal occupies the cluster following the state block on the data device. This is only
+    /// meaningful if the journal is enabled.
+    fn journal_address(&self) -> disk::Sector {
+        self.driver.header.state_block_address + 1
+    }
+
+    /// Get the address of the first slot of the persisted deduplication table.
+    ///
+    /// The two slots follow the journal, if enabled.
+    fn dedup_table_address(&self) -> disk::Sector {
+        self.journal_address() + self.journal.is_some() as disk::Sector
+    }
+
+    /// Get the address of the first slot of the persisted live page index.
+    ///
+    /// The two slots follow those of the persisted deduplication table, if enabled.
+    fn live_index_address(&self) -> disk::Sector {
+        self.dedup_table_address() + 2 * self.driver.header.dedup_sectors as disk::Sector
+    }
+
+    /// Get the address of the first data cluster.
+    ///
+    /// The data clusters follow the state block, the journal, and the slots of the persisted
+    /// deduplication table and live page index, if enabled.
+    fn first_data_cluster(&self) -> disk::Sector {
+        self.live_index_address() + 2 * self.driver.header.index_sectors as disk::Sector
+    }
+
+    /// Journal some freelist operations.
+    ///
+    /// This appends `records` to the journal, and makes `transaction`, which carries out the
+    /// operations, depend on the journal write. Hence, the records always hit the disk before the
+    /// metadata they cover.
+    ///
+    /// If the records don't fit, the journal is checkpointed instead: The records are dropped, and
+    /// the journal is cleared after `transaction`, as the metadata written by then covers every
+    /// operation journaled so far. Since replaying is idempotent, crashing in between is harmless.
+    ///
+    /// If the journal is disabled, `transaction` is returned as is.
+    fn journaled(&self, records: &[journal::Record], transaction: cache::Transaction) -> cache::Transaction {
+        let journal = match self.journal {
+            Some(ref journal) => journal,
+            None => return transaction,
+        };
+
+        let mut journal = journal.lock();
+        if journal.has_room(records.len()) {
+            // Append the records, and write the journal ahead of the metadata.
+            for &record in records {
+                journal.push(record);
+            }
+            self.cache.write(self.journal_address(), journal.encode(self.driver.header.checksum_algorithm))
+                .then(transaction)
+        } else {
+            debug!(self, "journal full, checkpointing"; "subsystem" => subsystem::ALLOC);
+
+            // The journaled transactions end with the state block, so flushing it makes the
+            // metadata covered by the journal (and by `transaction`) durable. Only then can the
+            // records be dropped, as a crash would lose the operations otherwise.
+            transaction.execute();
+            if let Err(err) = self.flush_sector(self.state_block_address()) {
+                // Keep the records, and leave the operation unjournaled. Should we crash before
+                // the metadata is flushed, it is lost, just like unflushed data is.
+                warn!(self, "failed to checkpoint the full journal"; "subsystem" => subsystem::ALLOC,
+                      "error" => err);
+            } else {
+                journal.clear();
+            }
+
+            self.cache.write(self.journal_address(), journal.encode(self.driver.header.checksum_algorithm))
+        }
+    }
+
+    /// Replay the journal.
+    ///
+    /// This applies the freelist operations recorded in the journal, bringing the freelist up to
+    /// date after a crash, and then checkpoints the journal. The number of replayed records is
+    /// returned.
+    ///
+    /// Since the metadata might have hit the disk for some of the operations, replaying is
+    /// idempotent: A popped cluster, which isn't free, and a pushed cluster, which is already
+    /// free, are skipped. As the last record of a cluster determines whether it is free, this
+    /// leaves the freelist in the state it was in before the crash.
+    fn replay_journal(&mut self) -> Result<usize, Error> {
+        // Read the journal.
+        let records = self.cache.read_then(self.journal_address(), |buf| {
+            // Accept the previous checksum algorithm after an interrupted migration.
+            let res = journal::Journal::decode(buf, self.driver.header.checksum_algorithm);
+            match self.driver.header.previous_checksum_algorithm {
+                Some(previous) if res.is_err() => journal::Journal::decode(buf, previous),
+                _ => res,
+            }
+        })?.records;
+        info!(self, "replaying the journal"; "subsystem" => subsystem::ALLOC, "records" => records.len());
+
+        // Collect the free clusters, so we can tell which records are already reflected in the
+        // freelist. The freelist is validated later on by `walk_freelist`. Reserved clusters were
+        // pushed and then moved to the reserve, so they count as free.
+        let mut free: BTreeSet<_> = self.iter_free_clusters().collect();
+        free.extend(self.state.lock().reserve.iter().cloned());
+
+        // Disable journaling while replaying, since the journal is checkpointed afterwards anyway.
+        let journal = self.journal.take();
+
+        for &record in &records {
+            match record {
+                journal::Record::Pop(cluster) => {
+                    if !free.remove(&cluster) {
+                        // The cluster was already popped.
+                        continue;
+                    }
+
+                    let (in_head, exhausted_head) = {
+                        let (state, head_metacluster) = self.lock_freelist();
+                        let head = state.freelist_head.map(|freelist_head| freelist_head.cluster);
+                        (head_metacluster.free.contains(&cluster),
+                         head == Some(cluster) && head_metacluster.free.is_empty())
+                    };
+                    if in_head {
+                        // Remove the cluster from the head metacluster.
+                        self.freelist_take(cluster)?;
+                    } else if exhausted_head {
+                        // The exhausted head metacluster itself was popped.
+                        self.freelist_pop()?.execute();
+                    } else {
+                        // The cluster is buried in the freelist, so it cannot have been popped.
+                        return Err(Error::JournalMismatch {
+                            cluster: cluster,
+                        });
+                    }
+                },
+                journal::Record::Push(cluster) => if free.insert(cluster) {
+                    self.freelist_push(cluster).execute();
+                },
+            }
+        }
+
+        // Re-enable journaling, and checkpoint.
+        self.journal = journal;
+        self.sync_metadata()?;
+
+        Ok(records.len())
+    }
+
+    /// Write the head metacluster to some cluster.
+    ///
+    /// The cache transaction is returned.
+    fn write_head_metacluster(&self, head_metacluster: &Metacluster, cluster: cluster::Pointer) -> cache::Transaction {
+        trace!(self, "writing the head metacluster"; "subsystem" => subsystem::FREELIST,
+               "target cluster" => cluster);
+
+        self.write_metacluster(head_metacluster, cluster)
+    }
+
+    /// Lock the freelist.
+    ///
+    /// This locks the state, and then the head metacluster. Every path locking both goes
+    /// through this, such that the locks are always acquired in the same order, ruling out
+    /// deadlocks.
+    fn lock_freelist(&self) -> (MutexGuard<state_block::State>, MutexGuard<Metacluster>) {
+        let state = self.state.lock();
+        let head_metacluster = self.head_metacluster.lock();
+
+        (state, head_metacluster)
+    }
+
+    /// Pop from the freelist.
+    ///
+    /// The returned pointer is wrapped in a cache transaction, representing the operations done in
+    /// order to pop it.
+    ///
+    /// The algorithm works as follows: If the head metacluster contains more free clusters, simply
+    /// pop and return the pointer. If not, make the next metacluster the head metacluster and
+    /// return the old metacluster.
+    fn freelist_pop(&self) -> Result<cache::Transacting<cluster::Pointer>, Error> {
+        trace!(self, "popping from freelist"; "subsystem" => subsystem::FREELIST);
+
+        // Pop a batch of one, so the state block is flushed once the cluster is popped.
+        Ok(self.freelist_pop_many(1)?.map(|clusters| clusters[0]))
+    }
+
+    /// Pop several clusters from the freelist at once.
+    ///
+    /// This is equivalent to popping `n` clusters one by one, but the state block is only flushed
+    /// once, after every cluster is popped. Hence, the returned transaction can head a chain of
+    /// writes to the clusters, without the pops holding the state block in between.
+    ///
+    /// If the freelist runs out, it is left as it was, and `Error::OutOfClusters` is returned.
+    fn freelist_pop_many(&self, n: usize) -> Result<cache::Transacting<Vec<cluster::Pointer>>, Error> {
+        trace!(self, "popping from freelist"; "subsystem" => subsystem::FREELIST, "clusters" => n);
+
+        // Nothing to pop.
+        if n == 0 {
+            return Ok(cache::Transacting::no_transaction(Vec::new()));
+        }
+
+        // Lock the state and the head metacluster for the whole batch.
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+        // Keep the freelist as it was, so it can be restored if it runs out.
+        let saved = (state.clone(), head_metacluster.clone(), self.tail_metaclusters.load(ORDERING));
+
+        let mut popped = Vec::with_capacity(n);
+        let mut evicted = Vec::new();
+        while popped.len() < n {
+            match self.freelist_pop_unflushed(&mut state, &mut head_metacluster, &mut evicted) {
+                Ok(cluster) => popped.push(cluster),
+                Err(err) => {
+                    // Keep track of the corruption for health checks.
+                    self.note_corruption(&err);
+
+                    // Restore the freelist and the trash. Nothing was written yet.
+                    *state = saved.0;
+                    *head_metacluster = saved.1;
+                    self.tail_metaclusters.store(saved.2, ORDERING);
+                    let mut trash = self.trash.lock();
+                    for cluster in evicted.into_iter().rev() {
+                        trash.push_front(cluster);
+                    }
+
+                    return Err(err);
+                },
+            }
+        }
+        drop(head_metacluster);
+
+        // Evicting from the trash changes the persisted live page index, so it is marked stale
+        // along with the pops (see `touch_index`).
+        if !evicted.is_empty() {
+            state.live_index = 0;
+        }
+
+        // Flush the state block, and journal the pops. The clusters taken from the trash never
+        // entered the freelist, so they aren't journaled.
+        let records: Vec<_> = popped.iter()
+            .filter(|cluster| !evicted.contains(cluster))
+            .map(|&cluster| journal::Record::Pop(cluster))
+            .collect();
+        let transaction = self.flush_state_block(&state);
+
+        Ok(self.journaled(&records, transaction).wrap(popped))
+    }
+
+    /// Pop a cluster from the freelist without flushing the state block.
+    ///
+    /// This updates `state` and `head_metacluster` like `freelist_pop`, leaving the state block
+    /// to the caller. Since the clusters are written after the state block, metaclusters switched
+    /// out of the freelist are never overwritten before the state block stops pointing to them.
+    ///
+    /// If the freelist is empty, the oldest cluster of the trash is taken instead, and added to
+    /// `evicted` (pushing it to the empty freelist and popping it again would leave the freelist
+    /// as it was).
+    fn freelist_pop_unflushed(&self, state: &mut state_block::State, head_metacluster: &mut Metacluster,
+                              evicted: &mut Vec<cluster::Pointer>) -> Result<cluster::Pointer, Error> {
+        let freelist_head = match state.freelist_head {
+            Some(freelist_head) => freelist_head,
+            None => {
+                // The freelist is empty, so evict the oldest cluster from the trash, if any.
+                let cluster = self.trash.lock().pop_front().ok_or(Error::OutOfClusters)?;
+                warn!(self, "freelist empty, evicting cluster from the trash"; "subsystem" => subsystem::FREELIST,
+                      "cluster" => cluster);
+                evicted.push(cluster);
+
+                return Ok(cluster);
+            },
+        };
+
+        if let Some(free) = head_metacluster.free.pop() {
+            // Truncate the head metacluster, updating the counter and checksum of the freelist head.
+            state.freelist_head = Some(state_block::FreelistHead {
+                cluster: freelist_head.cluster,
+                checksum: self.metacluster_checksum(head_metacluster),
+                counter: head_metacluster.free.len() as u8,
+            });
+
+            return Ok(free);
+        }
+
+        // The head metacluster is exhausted, so switch to the next metacluster, if any.
+        if let Some(next) = head_metacluster.next {
+            debug!(self, "switching metacluster"; "subsystem" => subsystem::FREELIST,
+                   "new metacluster" => next);
+
+            // Metaclusters are only linked to when they're full, and the checksum is stored in the
+            // exhausted metacluster.
+            let next_head = state_block::FreelistHead {
+                cluster: next,
+                checksum: head_metacluster.next_checksum,
+                counter: self.max_free() as u8,
+            };
+            *head_metacluster = if self.verify_metaclusters {
+                self.metacluster_hashes.fetch_add(1, ORDERING);
+                self.load_head_metacluster(next_head)?
+            } else {
+                // Verification is disabled, so trust the checksum stored in the exhausted
+                // metacluster instead of recomputing it.
+                self.read_metacluster(next, next_head.counter, |_| Ok(()))?
+            };
+            self.tail_metaclusters.fetch_sub(1, ORDERING);
+            state.freelist_head = Some(next_head);
+        } else {
+            state.freelist_head = None;
+        }
+
+        if self.cache.is_metadata(freelist_head.cluster.into()) {
+            // The old head metacluster lives on the metadata device, so it cannot hold data.
+            // Instead, pop it from the metacluster stack, and allocate from the new head.
+            state.metaclusters -= 1;
+            self.freelist_pop_unflushed(state, head_metacluster, evicted)
+        } else {
+            // Use the old head metacluster as the allocated cluster.
+            Ok(freelist_head.cluster)
+        }
+    }
+
+    /// Pop a run of contiguous clusters from the freelist.
+    ///
+    /// This searches the head metacluster for `n` free clusters with consecutive addresses, and
+    /// removes them from it. The first cluster of the run is returned along with the cache
+    /// transaction.
+    ///
+    /// This is best effort: Only the head metacluster is searched, so an error might be returned,
+    /// even though such a run exists further down the freelist.
+    fn freelist_pop_run(&self, n: usize) -> Result<(cluster::Pointer, cache::Transaction), Error> {
+        trace!(self, "popping run from freelist"; "subsystem" => subsystem::FREELIST, "clusters" => n);
+
+        // Lock the freelist.
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+        let freelist_head = state.freelist_head.ok_or(Error::OutOfClusters)?;
+
+        // Sort the free clusters by address, and find a run of consecutive addresses.
+        let mut free: Vec<u64> = head_metacluster.free.iter().map(|&x| x.into()).collect();
+        free.sort();
+        let start = free.windows(n)
+            .find(|run| run[n - 1] - run[0] == n as u64 - 1)
+            .map(|run| run[0])
+            .ok_or(Error::NoContiguousClusters {
+                clusters: n,
+            })?;
+
+        // Remove the run from the head metacluster.
+        head_metacluster.free.retain(|&x| {
+            let x: u64 = x.into();
+            x < start || x >= start + n as u64
+        });
+        // Update the counter and checksum of the freelist head to reflect the change.
+        state.freelist_head = Some(state_block::FreelistHead {
+            cluster: freelist_head.cluster,
+            checksum: self.metacluster_checksum(&head_metacluster),
+            counter: head_metacluster.free.len() as u8,
+        });
+
+        // Write the head metacluster, then flush the state block.
+        let transaction = self.write_head_metacluster(&head_metacluster, freelist_head.cluster)
+            .then(self.flush_state_block(&state));
+        // Journal the popped clusters.
+        let records: Vec<_> = (start..start + n as u64)
+            .map(|cluster| journal::Record::Pop(cluster::Pointer::new(cluster).unwrap()))
+            .collect();
+
+        Ok((cluster::Pointer::new(start).unwrap(), self.journaled(&records, transaction)))
+    }
+
+    /// Take a specific cluster out of the freelist.
+    ///
+    /// If `cluster` is in the head metacluster, it is removed from it, and `true` is returned. If
+    /// it is free, but buried deeper in the freelist, `Error::ClusterUnavailable` is returned, as
+    /// taking it would require rewriting the metacluster chain. Otherwise, the cluster isn't free,
+    /// nothing is done, and `false` is returned.
+    ///
+    /// Note that the latter cases walk the whole freelist.
+    fn freelist_take(&self, cluster: cluster::Pointer) -> Result<bool, Error> {
+        trace!(self, "taking cluster from freelist"; "subsystem" => subsystem::FREELIST,
+               "cluster" => cluster);
+
+        // Lock the freelist.
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+
+        if let Some(index) = head_metacluster.free.iter().position(|&x| x == cluster) {
+            let freelist_head = state.freelist_head.unwrap();
+
+            // Remove the cluster from the head metacluster, and update the counter and checksum of
+            // the freelist head to reflect the change.
+            head_metacluster.free.remove(index);
+            state.freelist_head = Some(state_block::FreelistHead {
+                cluster: freelist_head.cluster,
+                checksum: self.metacluster_checksum(&head_metacluster),
+                counter: head_metacluster.free.len() as u8,
+            });
+
+            // Write the head metacluster, then flush the state block, and journal it all.
+            let transaction = self.write_head_metacluster(&head_metacluster, freelist_head.cluster)
+                .then(self.flush_state_block(&state));
+            self.journaled(&[journal::Record::Pop(cluster)], transaction).execute();
+
+            Ok(true)
+        } else {
+            drop(state);
+            drop(head_metacluster);
+
+            if self.iter_free_clusters().any(|x| x == cluster) {
+                Err(Error::ClusterUnavailable {
+                    cluster: cluster,
+                })
+            } else {
+                Ok(false)
+            }
+        }
+    }
+
+    /// Calculate the checksum of a metacluster under the current checksum algorithm.
+    ///
+    /// This is the checksum stored alongside new or rewritten metaclusters. Use
+    /// `metacluster_checksum_matches` to check a stored checksum.
+    fn metacluster_checksum(&self, metacluster: &Metacluster) -> u64 {
+        metacluster.checksum(self.driver.header.checksum_algorithm)
+    }
+
+    /// Check a metacluster against a stored checksum.
+    ///
+    /// After an interrupted checksum migration, the metacluster might still be checksummed under
+    /// the previous algorithm, which is accepted as well (see `migrate_checksum`).
+    fn metacluster_checksum_matches(&self, metacluster: &Metacluster, checksum: u64) -> bool {
+        self.driver.header.checksum_algorithms().any(|algorithm| metacluster.checksum(algorithm) == checksum)
+    }
+
+    /// Load the head metacluster.
+    ///
+    /// This reads the metacluster pointed to by `freelist_head`, and validates it against the
+    /// counter and checksum of `freelist_head`. The counter determines the active region of the
+    /// metacluster, so if it was corrupted, `freelist_pop` would hand out garbage clusters. Since
+    /// the checksum covers exactly the active region, a wrong counter is caught by it.
+    fn load_head_metacluster(&self, freelist_head: state_block::FreelistHead) -> Result<Metacluster, Error> {
+        trace!(self, "loading the head metacluster"; "subsystem" => subsystem::FREELIST,
+               "cluster" => freelist_head.cluster);
+
+        // Make sure that the counter is within the capacity of a metacluster.
+        if freelist_head.counter as usize > self.max_free() {
+            return Err(Error::InvalidFreelistCounter {
+                counter: freelist_head.counter,
+            });
+        }
+
+        // Decode the active region of the metacluster, and check it against the checksum stored
+        // in the state block.
+        self.read_metacluster(freelist_head.cluster, freelist_head.counter, |metacluster| {
+            if self.metacluster_checksum_matches(metacluster, freelist_head.checksum) {
+                Ok(())
+            } else {
+                Err(Error::MetacluterChecksumMismatch {
+                    cluster: freelist_head.cluster,
+                    expected: freelist_head.checksum,
+                    found: self.metacluster_checksum(metacluster),
+                })
+            }
+        })
+    }
+
+    /// Read a metacluster.
+    ///
+    /// This reads the metacluster in `cluster`, of which the first `counter` free clusters are
+    /// active, and checks it with `verify`. A metacluster spanning multiple sectors keeps the
+    /// sectors following the first one in its first free clusters (see `write_metacluster`), so
+    /// only the sectors covering the active region are read.
+    ///
+    /// If the active region doesn't fit in a metacluster, `Error::InvalidMetacluster` is returned.
+    /// A metacluster failing verification is healed through the vdev's redundancy, if it fits in
+    /// a single sector (see `Cache::read_then`).
+    fn read_metacluster<F>(&self, cluster: cluster::Pointer, counter: u8, verify: F) -> Result<Metacluster, Error>
+        where F: Fn(&Metacluster) -> Result<(), Error> {
+        // Make sure that the active region fits in a metacluster.
+        if counter as usize > self.max_free() {
+            return Err(Error::InvalidMetacluster {
+                cluster: cluster,
+            });
+        }
+
+        let decode = |buf: &[u8]| {
+            let metacluster = Metacluster::decode(buf, counter).ok_or(Error::InvalidMetacluster {
+                cluster: cluster,
+            })?;
+            verify(&metacluster)?;
+
+            Ok(metacluster)
+        };
+        let sectors = active_sectors(counter as usize);
+        if sectors == 1 {
+            return self.cache.read_then(cluster.into(), |buf| decode(&buf[..]));
+        }
+
+        // Read the first sector, and then the following ones from the free clusters it starts
+        // with.
+        let mut buf = self.cache.read_then(cluster.into(), |buf| Ok::<_, Error>(buf.to_vec()))?;
+        let extensions = Metacluster::decode(&buf, sectors as u8 - 1).map_or(Vec::new(), |metacluster| metacluster.free);
+        if extensions.len() != sectors - 1 {
+            return Err(Error::InvalidMetacluster {
+                cluster: cluster,
+            });
+        }
+        for extension in extensions {
+            buf.extend_from_slice(&self.cache.read_then(extension.into(), |buf| Ok::<_, Error>(*buf))?);
+        }
+
+        decode(&buf[..])
+    }
+
+    /// Write a metacluster to some cluster.
+    ///
+    /// This writes the sectors covering the active region of `metacluster`: The first sector to
+    /// `cluster`, and every following sector to one of the first free clusters of the metacluster.
+    /// These are popped last, so they are only handed out once the sector they hold is no longer
+    /// active. As the sectors move along with the free clusters, every change of the first free
+    /// clusters must rewrite the metacluster.
+    ///
+    /// The sectors are written from the last to the first, and the cache transaction is returned.
+    fn write_metacluster(&self, metacluster: &Metacluster, cluster: cluster::Pointer) -> cache::Transaction {
+        let buf = metacluster.encode_sectors(active_sectors(metacluster.free.len()));
+
+        // Write the sectors, chaining the transactions.
+        let mut writes = buf.chunks(disk::SECTOR_SIZE).enumerate().rev().map(|(n, chunk)| {
+            let mut sector = disk::SectorBuf::default();
+            sector.copy_from_slice(chunk);
+            let target = if n == 0 { cluster } else { metacluster.free[n - 1] };
+            self.cache.write(target.into(), sector)
+        });
+        let mut transaction = writes.next().unwrap();
+        for write in writes {
+            transaction = transaction.then(write);
+        }
+
+        transaction
+    }
+
+    /// Get the size of the metaclusters (in sectors).
+    fn metacluster_sectors(&self) -> usize {
+        self.driver.header.metacluster_sectors()
+    }
+
+    /// Get the maximal number of free clusters in a metacluster.
+    ///
+    /// This is `MAX_FREE`, unless the disk header configures metaclusters spanning multiple
+    /// sectors.
+    fn max_free(&self) -> usize {
+        max_free(self.metacluster_sectors())
+    }
+
+    /// Walk and validate the metacluster chain of the freelist.
+    ///
+    /// This follows the freelist from the head metacluster, checking every metacluster against the
+    /// checksum stored in its predecessor. As it goes, `progress` is called with the fraction of
+    /// the device covered so far. Since the device size bounds the length of the freelist, the
+    /// progress increases monotonically, and it ends with `1.0`.
+    ///
+    /// The number of free clusters (not counting the metaclusters) is returned. If the chain is
+    /// longer than the device could hold, it must cycle, and `Error::FreelistCycle` is returned.
+    fn walk_freelist<F>(&self, progress: &mut F) -> Result<usize, Error>
+        where F: FnMut(f64) {
+        Ok(self.count_freelist(progress)?.0)
+    }
+
+    /// Walk the freelist, counting the free clusters with and without the metaclusters.
+    ///
+    /// This is `walk_freelist`, but it also returns the number of clusters `freelist_pop` can
+    /// hand out, i.e. the free clusters and the metaclusters on the data device.
+    fn count_freelist<F>(&self, progress: &mut F) -> Result<(usize, usize), Error>
+        where F: FnMut(f64) {
+        debug!(self, "walking the freelist"; "subsystem" => subsystem::FREELIST);
+
+        // The number of clusters of the device, bounding the length of the freelist.
+        let total = self.driver.number_of_sectors();
+
+        let freelist_head = match self.state.lock().freelist_head {
+            Some(freelist_head) => freelist_head,
+            // The freelist is empty.
+            None => {
+                self.tail_metaclusters.store(0, ORDERING);
+                progress(1.0);
+                return Ok((0, 0));
+            },
+        };
+
+        // Load the head metacluster, whose checksum is stored in the state block.
+        let mut metacluster = self.load_head_metacluster(freelist_head)?;
+        let mut cluster = freelist_head.cluster;
+        let mut free = 0;
+        let mut metaclusters = 0;
+        let mut allocatable = 0;
+        loop {
+            // Count the metacluster and the free clusters it points to. Metaclusters on the
+            // metadata device cannot hold data, so they aren't allocatable.
+            free += metacluster.free.len();
+            metaclusters += 1;
+            allocatable += metacluster.free.len();
+            if !self.cache.is_metadata(cluster.into()) {
+                allocatable += 1;
+            }
+            if free + metaclusters > total {
+                return Err(Error::FreelistCycle {
+                    cluster: cluster,
+                });
+            }
+
+            progress((free + metaclusters) as f64 / total as f64);
+
+            // Go to the next metacluster, if any.
+            let next = match metacluster.next {
+                Some(next) => next,
+                None => break,
+            };
+            let expected = metacluster.next_checksum;
+            // Metaclusters are only linked to when they're full. Check the metacluster against the
+            // checksum stored in its predecessor.
+            metacluster = self.read_metacluster(next, self.max_free() as u8, |metacluster| {
+                if self.metacluster_checksum_matches(metacluster, expected) {
+                    Ok(())
+                } else {
+                    Err(Error::MetacluterChecksumMismatch {
+                        cluster: next,
+                        expected: expected,
+                        found: self.metacluster_checksum(metacluster),
+                    })
+                }
+            })?;
+            cluster = next;
+        }
+
+        // Resynchronize the number of metaclusters.
+        self.tail_metaclusters.store(metaclusters - 1, ORDERING);
+        progress(1.0);
+
+        Ok((free, allocatable))
+    }
+
+    /// Iterate over the free clusters.
+    ///
+    /// This lazily walks the metacluster chain, yielding every free cluster (including the
+    /// metaclusters on the data device) in the order they would be popped. Only a single
+    /// metacluster is held in memory at a time, so this is cheap even on large freelists.
+    ///
+    /// This is meant for debugging and tooling: The metaclusters are not verified, and the walk
+    /// stops early (with a warning) on read errors or cycles. Use `walk_freelist` to validate
+    /// the freelist.
+    pub fn iter_free_clusters(&self) -> impl Iterator<Item = cluster::Pointer> + '_ {
+        let (state, head_metacluster) = self.lock_freelist();
+
+        FreeClusters {
+            manager: self,
+            free: head_metacluster.free.clone(),
+            metacluster: state.freelist_head.map(|freelist_head| freelist_head.cluster),
+            next: head_metacluster.next,
+            metaclusters: 0,
+        }
+    }
+
+    /// Dump the layout of the device.
+    ///
+    /// This writes a human-readable description of the disk header, the state block, every
+    /// metacluster of the freelist (with the stored and the computed checksum) and the free
+    /// clusters to `w`. Nothing is modified.
+    ///
+    /// This is a forensic aid: Damaged metaclusters are reported in the dump rather than failing
+    /// it, and only errors writing to `w` are returned.
+    pub fn dump_layout<W: std::io::Write>(&self, w: &mut W) -> Result<(), Error> {
+        debug!(self, "dumping the layout"; "subsystem" => subsystem::ALLOC);
+
+        // Dump the disk header.
+        let header = &self.driver.header;
+        writeln!(w, "header:")?;
+        writeln!(w, "  version: {}", header.version_number)?;
+        writeln!(w, "  checksum algorithm: {:?}", header.checksum_algorithm)?;
+        writeln!(w, "  large pages: {}", header.large_pages)?;
+        writeln!(w, "  journal: {}", header.journal)?;
+        writeln!(w, "  metacluster sectors: {}", header.metacluster_sectors())?;
+        writeln!(w, "  dedup sectors: {}", header.dedup_sectors)?;
+        writeln!(w, "  index sectors: {}", header.index_sectors)?;
+        writeln!(w, "  sectors: {}", self.driver.number_of_sectors())?;
+
+        // Dump the state block. The state is copied out, so the lock isn't held while reading.
+        let (superpage, freelist_head, metaclusters, backup_generation, live_index, reserve) = {
+            let state = self.state.lock();
+            (state.superpage, state.freelist_head, state.metaclusters, state.backup_generation, state.live_index,
+             state.reserve.clone())
+        };
+        writeln!(w, "state block:")?;
+        writeln!(w, "  address: {}", self.state_block_address())?;
+        writeln!(w, "  compression algorithm: {:?}", self.config.compression_algorithm)?;
+        writeln!(w, "  checksum width: {:?}", self.config.checksum_width)?;
+        writeln!(w, "  dedup policy: {:?}", self.config.dedup_policy)?;
+        match superpage {
+            Some(superpage) => writeln!(w, "  superpage: {}", superpage.cluster)?,
+            None => writeln!(w, "  superpage: none")?,
+        }
+        writeln!(w, "  metadata device metaclusters: {}", metaclusters)?;
+        write!(w, "  reserve ({} of {}):", reserve.len(), self.config.reserve_clusters)?;
+        for cluster in reserve {
+            write!(w, " {}", cluster)?;
+        }
+        writeln!(w)?;
+        writeln!(w, "  backup generation: {}", backup_generation)?;
+        writeln!(w, "  live index generation: {}", live_index)?;
+        writeln!(w, "  first data cluster: {}", self.first_data_cluster())?;
+
+        // Dump the metacluster chain, starting with the head metacluster, whose checksum is stored
+        // in the state block. Every other metacluster is full, and its checksum is stored in its
+        // predecessor.
+        writeln!(w, "metaclusters:")?;
+        let mut next = freelist_head.map(|head| (head.cluster, head.counter, head.checksum));
+        let mut count = 0;
+        let mut free = 0;
+        while let Some((cluster, counter, stored)) = next.take() {
+            // Guard against cycles.
+            if count > self.driver.number_of_sectors() {
+                writeln!(w, "  {}: cycle", cluster)?;
+                break;
+            }
+            count += 1;
+
+            let metacluster = match self.read_metacluster(cluster, counter, |_| Ok(())) {
+                Ok(metacluster) => metacluster,
+                Err(Error::InvalidMetacluster { .. }) => {
+                    writeln!(w, "  {}: undecodable", cluster)?;
+                    break;
+                },
+                Err(err) => {
+                    writeln!(w, "  {}: unreadable ({})", cluster, err)?;
+                    break;
+                },
+            };
+
+            free += metacluster.free.len();
+            let computed = self.metacluster_checksum(&metacluster);
+            writeln!(w, "  {}: {} free, stored checksum {:016x}, computed checksum {:016x}{}", cluster,
+                     metacluster.free.len(), stored, computed,
+                     if self.metacluster_checksum_matches(&metacluster, stored) { "" } else { " (MISMATCH)" })?;
+
+            next = metacluster.next.map(|cluster| (cluster, self.max_free() as u8, metacluster.next_checksum));
+        }
+
+        // Dump the free clusters, in the order they would be popped (metaclusters included).
+        writeln!(w, "freelist:")?;
+        writeln!(w, "  metaclusters: {}", count)?;
+        writeln!(w, "  free clusters: {}", free)?;
+        write!(w, "  pop order:")?;
+        for cluster in self.iter_free_clusters() {
+            write!(w, " {}", cluster)?;
+        }
+        writeln!(w)?;
+
+        Ok(())
+    }
+
+    /// Pick the cluster for a new head metacluster.
+    ///
+    /// This is used when `cluster` is pushed, and a new head metacluster is needed to hold it. If
+    /// a separate metadata device with room left is attached, the metacluster is placed on it.
+    /// Otherwise, it is placed in a cluster taken from the reserve, if any is left. In both cases,
+    /// `cluster` is stored in the metacluster. Only once the reserve is used up, `cluster` itself
+    /// becomes the metacluster.
+    ///
+    /// The metacluster and its initial free clusters are returned.
+    fn new_metacluster(&self, state: &mut state_block::State, cluster: cluster::Pointer)
+        -> (cluster::Pointer, Vec<cluster::Pointer>) {
+        if let Some(metacluster) = self.metadata_metacluster(state) {
+            trace!(self, "placing metacluster on the metadata device"; "subsystem" => subsystem::FREELIST,
+                   "metacluster" => metacluster);
+
+            // Push the metacluster onto the stack.
+            state.metaclusters += 1;
+
+            (metacluster, vec![cluster])
+        } else if let Some(metacluster) = state.reserve.pop() {
+            trace!(self, "placing metacluster in a reserved cluster"; "subsystem" => subsystem::FREELIST,
+                   "metacluster" => metacluster, "reserve" => state.reserve.len());
+
+            (metacluster, vec![cluster])
+        } else {
+            (cluster, Vec::new())
+        }
+    }
+
+    /// Get the next free slot of the metacluster stack on the metadata device.
+    ///
+    /// If there is no separate metadata device, or it is full, `None` is returned.
+    fn metadata_metacluster(&self, state: &state_block::State) -> Option<cluster::Pointer> {
+        // Since the metacluster chain is only ever extended or shortened at its head, the
+        // metaclusters are stacked on the metadata device, following the state block.
+        self.cache.metadata_start().and_then(|start| {
+            cluster::Pointer::new((start + 2) as u64 + state.metaclusters)
+        }).filter(|&metacluster| self.cache.is_metadata(metacluster.into()))
+    }
+
+    /// Push to the freelist.
+    ///
+    /// This pushes `cluster` to the freelist and returns the cache transaction, or an error.
+    ///
+    /// The algorithm works as follows: If the metacluster is full, the pushed cluster is used as
+    /// the new, empty head metacluster, which is linked to the old head metacluster. If not, the
+    /// free cluster is simply pushed.
+    ///
+    /// A new metacluster is placed on the metadata device or in a reserved cluster, and once both
+    /// are used up, in the pushed cluster (see `new_metacluster`). Hence, pushing never needs a
+    /// free cluster, and freeing succeeds even when the device is full.
+    fn freelist_push(&self, cluster: cluster::Pointer) -> cache::Transaction {
+        trace!(self, "pushing to freelist"; "subsystem" => subsystem::FREELIST, "cluster" => cluster);
+
+        // Lock the freelist.
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+
+        // Insert the cluster.
+        let transaction = self.freelist_insert(&mut state, &mut head_metacluster, cluster);
+        let discard = self.should_discard(&state, cluster);
+
+        // Flush the state block, and journal the push.
+        let transaction = transaction.then(self.flush_state_block(&state));
+        let transaction = self.journaled(&[journal::Record::Push(cluster)], transaction);
+
+        // Let the device know that the cluster is unused, once the push is committed.
+        if discard {
+            self.discard(transaction, &[cluster])
+        } else {
+            transaction
+        }
+    }
+
+    /// Push several clusters to the freelist at once.
+    ///
+    /// This is equivalent to pushing each of `clusters` in order, but the state block is only
+    /// flushed once, and everything is chained into one transaction.
+    ///
+    /// This is the path the freed clusters take (see `free_range`), so they top up the reserve
+    /// first: As long as it holds fewer clusters than configured, the clusters go there instead of
+    /// the freelist, replacing the ones taken by new metaclusters. `freelist_push`, which builds
+    /// and replays the freelist, leaves the reserve as it is.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `clusters` is empty.
+    fn freelist_push_batch(&self, clusters: &[cluster::Pointer]) -> cache::Transaction {
+        assert!(!clusters.is_empty(), "Pushing an empty batch to the freelist.");
+        trace!(self, "pushing batch to freelist"; "subsystem" => subsystem::FREELIST,
+               "clusters" => clusters.len());
+
+        // Lock the freelist.
+        let (mut state, mut head_metacluster) = self.lock_freelist();
+
+        // Insert the clusters one by one, chaining the transactions, and record the clusters which
+        // become metaclusters on the way.
+        let mut transaction = cache::Transacting::no_transaction(());
+        let mut metaclusters = BTreeSet::new();
+        for &cluster in clusters {
+            if state.reserve.len() < self.config.reserve_clusters as usize {
+                // Top up the reserve. The cluster is erased like any freed cluster.
+                trace!(self, "topping up the reserve"; "subsystem" => subsystem::FREELIST,
+                       "cluster" => cluster, "reserve" => state.reserve.len() + 1);
+                state.reserve.push(cluster);
+                transaction = transaction.and(self.erase(cluster));
+            } else {
+                transaction = transaction.and(self.freelist_insert(&mut state, &mut head_metacluster, cluster));
+            }
+            if let Some(freelist_head) = state.freelist_head {
+                metaclusters.insert(freelist_head.cluster);
+            }
+        }
+
+        // Discard the clusters, except for those which became metaclusters. This includes the
+        // clusters which topped up the reserve, and were then taken by a new metacluster later in
+        // the batch. Since the discards are queued after the metaclusters are written, the writes
+        // wouldn't cancel them (see `Cache::write`).
+        let discarded: Vec<_> = if self.config.trim_on_free {
+            clusters.iter().cloned().filter(|cluster| !metaclusters.contains(cluster)).collect()
+        } else {
+            Vec::new()
+        };
+
+        // Flush the state block once all clusters are inserted, and journal the pushes.
+        let transaction = transaction.then(self.flush_state_block(&state));
+        let records: Vec<_> = clusters.iter().map(|&cluster| journal::Record::Push(cluster)).collect();
+        let transaction = self.journaled(&records, transaction);
+
+        // Let the device know that the clusters are unused, once the pushes are committed.
+        self.discard(transaction, &discarded)
+    }
+
+    /// Insert a cluster into the freelist.
+    ///
+    /// This updates the head metacluster and `state`, but unlike `freelist_push`, it leaves
+    /// flushing the state block to the caller.
+    fn freelist_insert(&self, state: &mut state_block::State, head_metacluster: &mut Metacluster,
+                       cluster: cluster::Pointer) -> cache::Transacting<()> {
+        // Under the lowest-first strategy, put the cluster in its place along the chain.
+        if self.strategy == AllocationStrategy::LowestFirst && state.freelist_head.is_some() {
+            match self.freelist_insert_sorted(state, head_metacluster, cluster) {
+                Ok(transaction) => return transaction,
+                // Fall back to pushing the cluster to the head metacluster. The order of the chain
+                // is restored the next time the strategy is set.
+                Err(err) => warn!(self, "failed to insert free cluster in order"; "subsystem" => subsystem::FREELIST,
+                                  "cluster" => cluster, "error" => err),
+            }
+        }
+
+        if let Some(freelist_head) = state.freelist_head {
+            if head_metacluster.free.len() == self.max_free() {
+                // The head metacluster is full, so we will use the cluster to create a new
+                // head metacluster. If there is a separate metadata device with room left, the
+                // new metacluster is placed there instead, and `cluster` becomes its first free
+                // cluster.
+                let (metacluster, free) = self.new_metacluster(state, cluster);
+                debug!(self, "creating new metacluster"; "subsystem" => subsystem::FREELIST,
+                       "cluster" => metacluster);
+
+                // Replace the free clusters to make ensure that there isn't duplicates.
+                head_metacluster.free = free;
+                // Update the head metacluster's next pointer to point to the old head metacluster.
+                head_metacluster.next = Some(freelist_head.cluster);
+                self.tail_metaclusters.fetch_add(1, ORDERING);
+                // Update the head metacluster's next metacluster checksum to be the checksum of
+                // the old metacluster as stored in the state block, since the old metacluster will
+                // become the new metacluster's next. This simple trick is allows us to bypass
+                // recalculation of the checksum. Small optimization, but hey, it works.
+                head_metacluster.next_checksum = freelist_head.checksum;
+                // Update the state block freelist head metadata to point to the new head
+                // metacluster.
+                state.freelist_head = Some(state_block::FreelistHead {
+                    cluster: metacluster,
+                    // Calculate the checksum of the new head metacluster.
+                    checksum: self.metacluster_checksum(head_metacluster),
+                    // At most one free cluster is stored in the new head metacluster.
+                    counter: head_metacluster.free.len() as u8,
+                });
+                // Erase the cluster if it doesn't become the metacluster (in which case it is
+                // overwritten anyway).
+                let erase = if metacluster == cluster {
+                    cache::Transacting::no_transaction(())
+                } else {
+                    self.erase(cluster)
+                };
+                // Write the metacluster to `metacluster`. This won't leave the system in an
+                // inconsistent state, as only `metacluster`, which is free, will be changed.
+                // Flushing the state block afterwards won't either, as a new, valid metacluster is
+                // then stored at `metacluster`.
+                cache::Transacting::new((), Some(erase.then(self.write_head_metacluster(head_metacluster, metacluster))))
+            } else {
+                // There is more space in the head metacluster.
+
+                // Push the new free cluster.
+                head_metacluster.free.push(cluster);
+                // If the lowest-first insertion fell back to this, at least keep the free clusters
+                // of the head metacluster in descending order, so the lowest one is popped first.
+                // The whole head metacluster is rewritten below, so reordering it is fine.
+                if self.strategy == AllocationStrategy::LowestFirst {
+                    head_metacluster.free.sort_by(|a, b| b.cmp(a));
+                }
+                // Update the counter and checksum of the freelist head, so they cover the new
+                // free cluster.
+                state.freelist_head = Some(state_block::FreelistHead {
+                    cluster: freelist_head.cluster,
+                    checksum: self.metacluster_checksum(head_metacluster),
+                    counter: head_metacluster.free.len() as u8,
+                });
+                // Erase the cluster, and then write the head metacluster, such that it is on the
+                // disk before the state block covering it is. Woosh!
+                let erase = self.erase(cluster);
+                cache::Transacting::new((), Some(erase.then(self.write_head_metacluster(head_metacluster, freelist_head.cluster))))
+            }
+        } else {
+            // The freelist is empty, so we set the cluster up as an empty metacluster as the
+            // head metacluster (or, with a separate metadata device, set up a metacluster there
+            // containing the cluster).
+            let (metacluster, free) = self.new_metacluster(state, cluster);
+            *head_metacluster = Metacluster {
+                next_checksum: 0,
+                next: None,
+                free: free,
+            };
+            self.tail_metaclusters.store(0, ORDERING);
+            state.freelist_head = Some(state_block::FreelistHead {
+                cluster: metacluster,
+                checksum: self.metacluster_checksum(head_metacluster),
+                counter: head_metacluster.free.len() as u8,
+            });
+            // Erase the cluster, unless it became the head metacluster, which the state block now
+            // points to. Then write the head metacluster, such that it is on the disk before the
+            // state block is.
+            let erase = if metacluster == cluster {
+                cache::Transacting::no_transaction(())
+            } else {
+                self.erase(cluster)
+            };
+            cache::Transacting::new((), Some(erase.then(self.write_head_metacluster(head_metacluster, metacluster))))
+        }
+    }
+
+    /// Insert a cluster into the freelist, in order.
+    ///
+    /// This is `freelist_insert` under `AllocationStrategy::LowestFirst`, which keeps the free
+    /// clusters in ascending order along the whole chain (within a metacluster, they are stored in
+    /// descending order, as the last one is popped first), so a pop returns the lowest free
+    /// cluster. The metaclusters are loaded from the head up to the one `cluster` belongs in, and
+    /// their free clusters shift towards the head metacluster, which gains one. Hence, inserting a
+    /// cluster which belongs deep in the chain rewrites every metacluster on the way.
+    ///
+    /// The clusters holding the metaclusters are outside the order: A new metacluster placed in a
+    /// free cluster takes the lowest one, and is handed out as usual once exhausted.
+    ///
+    /// If the chain can't be read, an error is returned, and nothing is changed.
+    fn freelist_insert_sorted(&self, state: &mut state_block::State, head_metacluster: &mut Metacluster,
+                              cluster: cluster::Pointer) -> Result<cache::Transacting<()>, Error> {
+        let freelist_head = state.freelist_head.expect("Inserting in order into an empty freelist.");
+
+        // Load the metaclusters up to the first one holding a higher free cluster, which is where
+        // `cluster` belongs.
+        let below = |metacluster: &Metacluster| metacluster.free.first().map_or(true, |&highest| highest < cluster);
+        let mut chain = Vec::new();
+        let mut next = if below(head_metacluster) { head_metacluster.next } else { None };
+        while let Some(metacluster_cluster) = next {
+            // Guard against cycles.
+            if chain.len() > self.driver.number_of_sectors() {
+                return Err(Error::FreelistCycle {
+                    cluster: metacluster_cluster,
+                });
+            }
+
+            let metacluster = self.read_metacluster(metacluster_cluster, self.max_free() as u8, |_| Ok(()))?;
+            next = if below(&metacluster) { metacluster.next } else { None };
+            chain.push((metacluster_cluster, metacluster, true));
+        }
+
+        // Pool their free clusters with `cluster`, in ascending order.
+        let mut free: Vec<cluster::Pointer> = head_metacluster.free.iter()
+            .chain(chain.iter().flat_map(|&(_, ref metacluster, _)| metacluster.free.iter()))
+            .cloned()
+            .chain(Some(cluster))
+            .collect();
+        free.sort();
+
+        let mut erase = true;
+        let full = head_metacluster.free.len() == self.max_free();
+        if full {
+            // The head metacluster is full, so the lowest free cluster goes to a new head
+            // metacluster (see `freelist_insert`), and the old one joins the chain, keeping its
+            // free clusters.
+            let lowest = free.remove(0);
+            let (metacluster, new_free) = self.new_metacluster(state, lowest);
+            debug!(self, "creating new metacluster"; "subsystem" => subsystem::FREELIST,
+                   "cluster" => metacluster);
+
+            let old_head_metacluster = std::mem::replace(head_metacluster, Metacluster {
+                next_checksum: 0,
+                next: Some(freelist_head.cluster),
+                free: new_free,
+            });
+            chain.insert(0, (freelist_head.cluster, old_head_metacluster, true));
+            self.tail_metaclusters.fetch_add(1, ORDERING);
+            // The checksum and counter are recalculated when the chain is rewritten.
+            state.freelist_head = Some(state_block::FreelistHead {
+                cluster: metacluster,
+                checksum: self.metacluster_checksum(head_metacluster),
+                counter: head_metacluster.free.len() as u8,
+            });
+            // Unless `cluster` became the metacluster (in which case it is overwritten anyway),
+            // it is one of the free clusters.
+            erase = metacluster != cluster;
+        }
+
+        // Deal the clusters out again, lowest first. The clusters of a metacluster are stored in
+        // descending order.
+        let mut clusters = free.into_iter();
+        let mut deal = |len| {
+            let mut free: Vec<_> = clusters.by_ref().take(len).collect();
+            free.sort_by(|a, b| b.cmp(a));
+            free
+        };
+        if !full {
+            head_metacluster.free = deal(head_metacluster.free.len() + 1);
+        }
+        for &mut (_, ref mut metacluster, _) in &mut chain {
+            metacluster.free = deal(metacluster.free.len());
+        }
+
+        // Erase the cluster, and then rewrite the metaclusters, towards the head metacluster.
+        let erase = if erase {
+            self.erase(cluster)
+        } else {
+            cache::Transacting::no_transaction(())
+        };
+        let (transaction, _) = self.rewrite_metacluster_chain(state, head_metacluster, &mut chain, true);
+
+        Ok(erase.and(transaction))
+    }
+
+    /// Erase a cluster.
+    ///
+    /// This overwrites `cluster` with the configured fill pattern, so the old data isn't
+    /// recoverable. Nothing is done if the pattern is `FillPattern::Keep`.
+    ///
+    /// The returned value holds the transaction of the write, if any.
+    fn erase(&self, cluster: cluster::Pointer) -> cache::Transacting<()> {
+        cache::Transacting::new((), self.config.free_fill.sector().map(|buf| {
+            trace!(self, "erasing cluster"; "subsystem" => subsystem::ALLOC, "cluster" => cluster);
+
+            self.cache.write(cluster, buf)
+        }))
+    }
+
+    /// Should a freed cluster be discarded?
+    ///
+    /// This is the case if `trim_on_free` is enabled, and `cluster`, which was just inserted into
+    /// the freelist, didn't become the head metacluster (as given by `state`), since it is about
+    /// to be written.
+    fn should_discard(&self, state: &state_block::State, cluster: cluster::Pointer) -> bool {
+        self.config.trim_on_free && state.freelist_head.map(|head| head.cluster) != Some(cluster)
+    }
+
+    /// Discard freed clusters.
+    ///
+    /// This tells the device that `clusters` are unused, once `transaction`, which pushes them to
+    /// the freelist, is flushed. Hence, a crash before the push is committed never loses the
+    /// content of a cluster, which the metadata on the disk still refers to.
+    ///
+    /// Discarding is merely a hint to the device, so failures are logged rather than returned.
+    fn discard<'a>(&self, transaction: cache::Transaction<'a>, clusters: &[cluster::Pointer])
+        -> cache::Transaction<'a> {
+        let mut transaction = transaction;
+        for &cluster in clusters {
+            trace!(self, "discarding cluster"; "subsystem" => subsystem::ALLOC, "cluster" => cluster);
+
+            transaction = transaction.then_discard(cluster.into());
+        }
+
+        transaction
+    }
+}
+
+impl Drop for Manager {
+    fn drop(&mut self) {
+        // Skip it, if the manager was properly shut down.
+        if self.shut_down {
+            return;
+        }
+
+        info!(self, "closing the page manager"; "subsystem" => subsystem::ALLOC);
+
+        // Flush the allocation metadata. This is only a best-effort fallback, since errors cannot be
+        // returned from here. Use `shutdown` to handle them.
+        if let Err(err) = self.sync_metadata() {
+            warn!(self, "failed to flush the allocation metadata"; "subsystem" => subsystem::ALLOC,
+                  "error" => err);
+        }
+    }
+}
+
+delegate_log!(Manager.cache);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::mem_sim::MemSim;
+
+    /// Assert that an expression matches a pattern.
+    ///
+    /// The pattern can be followed by an `if` guard, like in `match` arms.
+    macro_rules! assert_matches {
+        ($expr:expr, $pat:pat) => {
+            assert_matches!($expr, $pat if true)
+        };
+        ($expr:expr, $pat:pat if $guard:expr) => {
+            match $expr {
+                $pat if $guard => (),
+                _ => panic!("assertion failed: `{}` doesn't match `{}`", stringify!($expr),
+                            stringify!($pat if $guard)),
+            }
+        };
+    }
+
+    /// The number of sectors of the simulated disk.
+    const TEST_SECTORS: disk::Sector = 256;
+
+    /// Set up a manager on a simulated disk.
+    ///
+    /// This writes a fresh disk header to `disk`, and pushes every cluster following the state
+    /// block to the freelist.
+    fn manager(disk: &MemSim, config: state_block::Config) -> Manager {
+        setup(disk, None, header::DiskHeader::default(), config)
+    }
+
+    /// Set up a manager on a simulated data disk and a simulated metadata disk.
+    fn split_manager(data: &MemSim, metadata: &MemSim, config: state_block::Config) -> Manager {
+        setup(data, Some(metadata), header::DiskHeader::default(), config)
+    }
+
+    /// Set up a manager with the journal enabled on a simulated disk.
+    pub fn journaled_manager(disk: &MemSim, config: state_block::Config) -> Manager {
+        let mut header = header::DiskHeader::default();
+        header.journal = true;
+
+        setup(disk, None, header, config)
+    }
+
+    /// Make a fake clock, advancing `step` nanoseconds on every reading.
+    fn fake_clock(step: u32) -> impl Fn() -> Instant + Send + Sync + 'static {
+        let start = Instant::now();
+        let readings = AtomicUsize::new(0);
+
+        move || start + Duration::new(0, step) * readings.fetch_add(1, ORDERING) as u32
+    }
+
+    /// Make a page of noise.
+    ///
+    /// The page is filled with xorshift output seeded by `seed`, so it doesn't compress, and
+    /// distinct seeds give distinct pages.
+    fn noise_page(seed: u64) -> disk::SectorBuf {
+        let mut buf = [0; disk::SECTOR_SIZE];
+        let mut x = seed ^ 0x2545F4914F6CDD1D;
+        for byte in buf.iter_mut() {
+            // Xorshift.
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            *byte = x as u8;
+        }
+
+        buf
+    }
+
+    /// Fill the device completely.
+    ///
+    /// This allocates pages of noise seeded by `seed`, `seed + 1`, and so on, until the clusters
+    /// run out, and returns the pages. Since the pages don't compress, each takes a cluster of its
+    /// own.
+    fn fill(manager: &mut Manager, seed: u64) -> Vec<page::Pointer> {
+        let mut pages = Vec::new();
+        loop {
+            match manager.alloc(&noise_page(seed + pages.len() as u64)) {
+                Ok(page) => pages.push(page.execute()),
+                Err(Error::OutOfClusters) => return pages,
+                Err(_) => panic!("unexpected error"),
+            }
+        }
+    }
+
+    /// Combine the transactions of a batch allocated by `alloc_many`, and execute them at once.
+    fn execute_batch(batch: Vec<cache::Transacting<page::Pointer>>) -> Vec<page::Pointer> {
+        let mut pages = Vec::with_capacity(batch.len());
+        batch.into_iter()
+            .map(|page| page.map(|page| pages.push(page)))
+            .fold(cache::Transacting::no_transaction(()), |transaction, page| transaction.and(page))
+            .execute();
+
+        pages
+    }
+
+    /// Open the driver of a simulated disk, writing a fresh disk header first.
+    fn driver(disk: &MemSim) -> vdev::Driver {
+        driver_with_header(disk, header::DiskHeader::default())
+    }
+
+    /// Open the driver of a simulated disk, writing disk header `header` first.
+    fn driver_with_header(disk: &MemSim, header: header::DiskHeader) -> vdev::Driver {
+        let mut raw = disk.clone();
+        raw.write(0, &header.encode()).unwrap();
+
+        vdev::Driver::open(slog::Discard, raw, b"").unwrap()
+    }
+
+    /// Set up a manager on a simulated disk and an optional metadata disk.
+    fn setup(disk: &MemSim, metadata: Option<&MemSim>, header: header::DiskHeader,
+             mut config: state_block::Config) -> Manager {
+        let driver = driver_with_header(disk, header);
+        // Resolve the compression profile, like formatting would.
+        let compression_level = config.resolve_compression();
+        // Skip the journal cluster, the deduplication table slots, and the live page index slots, if
+        // any.
+        let first = driver.header.state_block_address + 1 + driver.header.journal as disk::Sector
+            + 2 * driver.header.dedup_sectors as disk::Sector
+            + 2 * driver.header.index_sectors as disk::Sector;
+        let mut manager = Manager::new(Cache::with_metadata(driver, metadata.map(driver)), config,
+                                       compression_level, state_block::State::default());
+
+        // Fill the freelist.
+        for cluster in first..disk.number_of_sectors() {
+            manager.freelist_push(cluster::Pointer::new(cluster as u64).unwrap()).execute();
+        }
+
+        manager
+    }
+
+    #[test]
+    fn sync_metadata() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.sync_metadata().unwrap();
+
+        // The page data is still only in the cache.
+        assert_eq!(disk.sector(page.cluster), [0; disk::SECTOR_SIZE]);
+
+        // Crash, and reopen the disk.
+        let freelist_head = manager.state.lock().freelist_head;
+        let free: Vec<_> = manager.iter_free_clusters().collect();
+        mem::forget(manager);
+        let manager = Manager::open(driver(&disk), None, false, None).unwrap();
+
+        // The freelist is durable, and the page's cluster isn't handed out again.
+        assert_eq!(manager.state.lock().freelist_head, freelist_head);
+        assert_eq!(manager.iter_free_clusters().collect::<Vec<_>>(), free);
+        assert!(!free.contains(&page.cluster));
+    }
+
+    #[test]
+    fn metadata_device() {
+        let data = MemSim::new(TEST_SECTORS);
+        let metadata = MemSim::new(TEST_SECTORS);
+        let mut manager = split_manager(&data, &metadata, state_block::Config::default());
+
+        // Allocate enough pages to go through several metaclusters.
+        let buf = [0xAB; disk::SECTOR_SIZE];
+        for n in 0..TEST_SECTORS / 2 {
+            manager.store(&buf, 0).unwrap().execute();
+        }
+        manager.sync_metadata().unwrap();
+
+        // Every written sector of the data disk (besides the header) holds page data...
+        for sector in 1..TEST_SECTORS {
+            let sector = data.sector(sector);
+            assert!(sector == buf || sector == [0; disk::SECTOR_SIZE]);
+        }
+        // ...while the state block landed on the metadata disk.
+        let state_block = state_block::StateBlock::decode(
+            &metadata.sector(1),
+            manager.driver.header.checksum_algorithm,
+        ).unwrap();
+        assert_eq!(state_block.state.freelist_head, manager.state.lock().freelist_head);
+        assert!(manager.cache.is_metadata(state_block.state.freelist_head.unwrap().cluster.into()));
+    }
+
+    #[test]
+    fn clusters_for_page_count() {
+        // Without compression, every page occupies a cluster.
+        assert_eq!(Manager::clusters_for_page_count(0, 1.0), 0);
+        assert_eq!(Manager::clusters_for_page_count(100, 1.0), 100);
+        assert_eq!(Manager::clusters_for_page_count(100, 0.5), 100);
+
+        // With 4:1 compression, four pages share a cluster.
+        assert_eq!(Manager::clusters_for_page_count(100, 4.0), 25);
+        assert_eq!(Manager::clusters_for_page_count(101, 4.0), 26);
+        assert_eq!(Manager::clusters_for_page_count(100, 4.5), 25);
+
+        // The cluster capacity bounds the packing.
+        assert_eq!(Manager::clusters_for_page_count(4096, 1e9), 2);
+    }
+
+    #[test]
+    fn atomic_swap() {
+        // Crash after every number of writes in turn, until the swap goes through. With
+        // compression, the old page lives in the last allocated cluster, which the new page must
+        // not be packed into.
+        for &algorithm in &[state_block::CompressionAlgorithm::Identity, state_block::CompressionAlgorithm::Lz4] {
+            for writes in 0.. {
+                let disk = MemSim::new(TEST_SECTORS);
+                let mut manager = manager(&disk, state_block::Config {
+                    compression_algorithm: algorithm,
+                    .. Default::default()
+                });
+
+                let old = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+                if algorithm != state_block::CompressionAlgorithm::Identity {
+                    assert_eq!(manager.last_cluster.lock().as_ref().unwrap().cluster, old.cluster);
+                }
+                manager.sync_metadata().unwrap();
+                manager.cache.trim(0).unwrap();
+
+                disk.crash_after(writes);
+                let res = manager.atomic_swap(old, &[2; disk::SECTOR_SIZE]);
+                let image = disk.snapshot();
+                mem::forget(manager);
+
+                // Reopen the disk as it was when the writes stopped reaching it.
+                let manager = Manager::open(vdev::Driver::open(slog::Discard, image, b"").unwrap(), None, false, None)
+                    .unwrap();
+                let free: Vec<_> = manager.iter_free_clusters().collect();
+                let intact = |page: page::Pointer, content| {
+                    !free.contains(&page.cluster) && manager.read(page).unwrap() == [content; disk::SECTOR_SIZE]
+                };
+
+                if let Ok(new) = res {
+                    // The swap went through, so the new page is valid, and in a cluster of its own.
+                    assert!(intact(new, 2));
+                    assert!(new.cluster != old.cluster);
+                    break;
+                }
+                // The swap failed, so the old page must still be valid.
+                assert!(intact(old, 1));
+            }
+        }
+
+        // Swap in content equal to another live page.
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        let old = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        let other = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        let new = manager.atomic_swap(old, &[2; disk::SECTOR_SIZE]).unwrap();
+        assert_eq!(new, other);
+        assert!(!manager.live.lock().contains_key(&old.cluster));
+
+        // Freeing the other reference leaves the new page alive.
+        manager.free(other).unwrap().map(|transaction| transaction.execute());
+        assert!(!manager.iter_free_clusters().any(|cluster| cluster == new.cluster));
+        assert_eq!(manager.read(new).unwrap(), [2; disk::SECTOR_SIZE]);
+
+        // Swapping in the same content keeps the page, too.
+        assert_eq!(manager.atomic_swap(new, &[2; disk::SECTOR_SIZE]).unwrap(), new);
+        assert_eq!(manager.read(new).unwrap(), [2; disk::SECTOR_SIZE]);
+        assert_eq!(manager.live_page_count(), 1);
+    }
+
+    #[test]
+    fn free() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let page = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.free(page).unwrap().unwrap().execute();
+        assert!(!manager.live.lock().contains_key(&page.cluster));
+
+        // The freed page is no longer handed out as a duplicate, but its cluster is reused.
+        let again = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(again.cluster, page.cluster);
+        assert!(manager.live.lock().contains_key(&again.cluster));
+    }
+
+    #[test]
+    fn free_range() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        // Allocate an object spanning several clusters.
+        let pages: Vec<_> = (0..4u8).map(|n| {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()
+        }).collect();
+
+        // Free it, in one transaction.
+        manager.free_range(&pages).unwrap().unwrap().execute();
+
+        // Every cluster is back in the freelist.
+        for page in &pages {
+            assert!(!manager.live.lock().contains_key(&page.cluster));
+            assert!(manager.head_metacluster.lock().free.contains(&page.cluster));
+        }
+
+        // An empty range frees nothing.
+        assert!(manager.free_range(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn tampered_freelist_counter() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        manager.sync_metadata().unwrap();
+
+        // The untampered freelist head is valid.
+        let freelist_head = manager.state.lock().freelist_head.unwrap();
+        assert_eq!(manager.load_head_metacluster(freelist_head).unwrap().free,
+                   manager.head_metacluster.lock().free);
+
+        // A counter exceeding the capacity of a metacluster.
+        let mut tampered = freelist_head;
+        tampered.counter = MAX_FREE as u8 + 1;
+        assert_matches!(manager.load_head_metacluster(tampered),
+                        Err(Error::InvalidFreelistCounter { counter })
+                        if counter == tampered.counter);
+
+        // A counter inconsistent with the metacluster. The head metacluster isn't empty, given
+        // the number of clusters of the disk.
+        tampered.counter = freelist_head.counter - 1;
+        assert_matches!(manager.load_head_metacluster(tampered),
+                        Err(Error::MetacluterChecksumMismatch { .. }));
+    }
+
+    #[test]
+    fn alloc_run_same_cluster() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        let bufs: Vec<_> = (0..4u8).map(|n| [n; disk::SECTOR_SIZE]).collect();
+        let pages = manager.alloc_run(&bufs, Contiguity::SameCluster).unwrap().execute();
+
+        // Every page is packed into the same cluster.
+        for (page, buf) in pages.iter().zip(&bufs) {
+            assert_eq!(page.cluster, pages[0].cluster);
+            assert_eq!(manager.read(*page).unwrap(), *buf);
+        }
+    }
+
+    #[test]
+    fn alloc_many() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Start a cluster for the batch to extend.
+        let first = manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+
+        // The batch holds a duplicate of a page of the batch, and one of the stored page.
+        let mut bufs: Vec<_> = (1..9u8).map(|n| [n; disk::SECTOR_SIZE]).collect();
+        bufs.push([1; disk::SECTOR_SIZE]);
+        bufs.push([0; disk::SECTOR_SIZE]);
+
+        let pages = execute_batch(manager.alloc_many(&bufs).unwrap());
+
+        // The new pages are packed into the last allocated cluster, following the stored page.
+        for (n, page) in pages[..8].iter().enumerate() {
+            assert_eq!(page.cluster, first.cluster);
+            assert_eq!(page.offset, Some(n as u32 + 1));
+        }
+        // The duplicates are resolved.
+        assert_eq!(pages[8], pages[0]);
+        assert_eq!(pages[9], first);
+
+        manager.cache.trim(0).unwrap();
+        for (page, buf) in pages.iter().zip(&bufs) {
+            assert_eq!(manager.read(*page).unwrap(), *buf);
+        }
+
+        // The cluster is still extended afterwards.
+        let next = manager.alloc(&[9; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(next.cluster, first.cluster);
+        assert_eq!(next.offset, Some(9));
+    }
+
+    #[test]
+    fn alloc_many_mixed() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Interleave compressible and incompressible pages.
+        let bufs = vec![[1; disk::SECTOR_SIZE], noise_page(1), [2; disk::SECTOR_SIZE], noise_page(2)];
+
+        let pages = execute_batch(manager.alloc_many(&bufs).unwrap());
+
+        // The incompressible pages are stored uncompressed, in clusters of their own.
+        assert_eq!(pages[1].offset, None);
+        assert_eq!(pages[3].offset, None);
+        assert!(pages[1].cluster != pages[3].cluster);
+        assert!(pages[0].offset.is_some());
+        assert!(pages[2].offset.is_some());
+
+        manager.cache.trim(0).unwrap();
+        for (page, buf) in pages.iter().zip(&bufs) {
+            assert_eq!(manager.read(*page).unwrap(), *buf);
+        }
+    }
+
+    #[test]
+    fn alloc_many_out_of_clusters() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        // Every page needs a cluster of its own, which there isn't enough of. The batch ends with
+        // a duplicate of a stored page.
+        let stored = manager.alloc(&[0xAA; disk::SECTOR_SIZE]).unwrap().execute();
+        let free = manager.free_clusters().unwrap();
+        let clusters: Vec<_> = manager.iter_free_clusters().collect();
+        let mut bufs: Vec<_> = (0..free + 1).map(|n| {
+            let mut buf = [0; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, n);
+            buf
+        }).collect();
+        bufs.push([0xAA; disk::SECTOR_SIZE]);
+        match manager.alloc_many(&bufs) {
+            Err(Error::OutOfClusters) => (),
+            _ => panic!("expected running out of clusters"),
+        }
+
+        // Nothing was allocated, the freelist is left as it was, and the reference taken to the
+        // duplicate was given back.
+        assert_eq!(manager.free_clusters().unwrap(), free);
+        assert_eq!(manager.iter_free_clusters().collect::<Vec<_>>(), clusters);
+        assert_eq!(manager.live.lock().len(), 1);
+        assert!(manager.live.lock()[&stored.cluster].duplicates.is_empty());
+
+        // A batch which fits is stored uncompressed, one page per cluster.
+        let pages = execute_batch(manager.alloc_many(&bufs[..2]).unwrap());
+        assert!(pages[0].cluster != pages[1].cluster);
+        assert_eq!(pages[0].offset, None);
+        assert_eq!(manager.read(pages[1]).unwrap(), bufs[1]);
+
+        // The rest of the clusters can be taken in one batch, switching metaclusters on the way.
+        let rest = execute_batch(manager.alloc_many(&bufs[2..free as usize]).unwrap());
+        assert_eq!(manager.free_clusters().unwrap(), 0);
+        manager.cache.trim(0).unwrap();
+        for (page, buf) in rest.iter().zip(&bufs[2..]) {
+            assert_eq!(manager.read(*page).unwrap(), *buf);
+        }
+
+        // The stored page has a single reference, so freeing it once frees its cluster.
+        manager.free(stored).unwrap().unwrap().execute();
+        assert_eq!(manager.free_clusters().unwrap(), 1);
+    }
+
+    #[test]
+    fn alloc_many_bookkeeping() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        let stored = manager.alloc(&[0xAA; disk::SECTOR_SIZE]).unwrap().execute();
+
+        // Enable automatic compaction, without ever running a pass, so the allocations are
+        // merely counted.
+        manager.set_auto_compaction(CompactionPolicy {
+            threshold: 1.0,
+            budget: 0,
+            interval: usize::max_value(),
+        }, |_, _| ());
+
+        // Every page of the batch is counted once, but the copies within the batch aren't.
+        let bufs = vec![noise_page(1), [1; disk::SECTOR_SIZE], noise_page(1), noise_page(2), [0xAA; disk::SECTOR_SIZE]];
+        let batch = manager.alloc_many(&bufs).unwrap();
+        assert_eq!(manager.operations_since_compaction.load(ORDERING), 4);
+
+        // The transactions can be executed on their own, in any order.
+        let mut pages: Vec<_> = batch.into_iter().rev().map(|page| page.execute()).collect();
+        pages.reverse();
+        assert_eq!(pages[2], pages[0]);
+        assert_eq!(pages[4], stored);
+        manager.cache.trim(0).unwrap();
+        for (page, buf) in pages.iter().zip(&bufs) {
+            assert_eq!(manager.read(*page).unwrap(), *buf);
+        }
+
+        // Isolated pages are counted as well, unless they're deduplicated.
+        manager.alloc_isolated(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.alloc_isolated(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(manager.operations_since_compaction.load(ORDERING), 5);
+    }
+
+    #[test]
+    fn alloc_run_same_cluster_too_large() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Noise doesn't compress, so two pages cannot share a cluster.
+        let bufs: Vec<_> = (0..2).map(noise_page).collect();
+
+        assert_matches!(manager.alloc_run(&bufs, Contiguity::SameCluster),
+                        Err(Error::RunTooLarge { pages: 2 }));
+        // Nothing was allocated.
+        assert!(manager.live.lock().is_empty());
+    }
+
+    #[test]
+    fn alloc_run_contiguous_clusters() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let bufs: Vec<_> = (0..4u8).map(|n| [n; disk::SECTOR_SIZE]).collect();
+        let pages = manager.alloc_run(&bufs, Contiguity::ContiguousClusters).unwrap().execute();
+
+        // The pages are stored in consecutive clusters.
+        let start: u64 = pages[0].cluster.into();
+        for (n, (page, buf)) in pages.iter().zip(&bufs).enumerate() {
+            assert_eq!(page.cluster.into(), start + n as u64);
+            assert_eq!(manager.read(*page).unwrap(), *buf);
+        }
+
+        // The clusters are no longer free.
+        for page in &pages {
+            assert!(!manager.head_metacluster.lock().free.contains(&page.cluster));
+        }
+    }
+
+    #[test]
+    fn alloc_run_contiguous_clusters_unavailable() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        // The head metacluster cannot hold a run this long.
+        let bufs = vec![[0; disk::SECTOR_SIZE]; MAX_FREE + 1];
+        assert_matches!(manager.alloc_run(&bufs, Contiguity::ContiguousClusters),
+                        Err(Error::NoContiguousClusters { clusters })
+                        if clusters == MAX_FREE + 1);
+        // Nothing was allocated.
+        assert!(manager.live.lock().is_empty());
+    }
+
+    #[test]
+    fn coalesce() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        // Empty the freelist.
+        while manager.freelist_pop().is_ok() {}
+
+        // Free a run of clusters, and bury it in the metacluster chain under scattered clusters,
+        // leaving 30 of them in the head metacluster.
+        let first = manager.first_data_cluster() as u64;
+        let run = 200..210;
+        let scattered = (first..run.start).filter(|x| x % 2 == 0).take(MAX_FREE - 9 + 1 + 30);
+        for cluster in run.clone().chain(scattered) {
+            manager.freelist_push(cluster::Pointer::new(cluster).unwrap()).execute();
+        }
+        let mut free: Vec<_> = manager.iter_free_clusters().collect();
+
+        // The run is out of reach.
+        let bufs: Vec<_> = (0..8u8).map(|n| [n; disk::SECTOR_SIZE]).collect();
+        assert_matches!(manager.alloc_run(&bufs, Contiguity::ContiguousClusters),
+                        Err(Error::NoContiguousClusters { clusters: 8 }));
+
+        // Coalescing brings it to the head metacluster. The first cluster of the run became a
+        // metacluster.
+        assert_eq!(manager.coalesce().unwrap(), 9);
+        manager.walk_freelist(&mut |_| ()).unwrap();
+        let mut coalesced: Vec<_> = manager.iter_free_clusters().collect();
+        free.sort();
+        coalesced.sort();
+        assert_eq!(coalesced, free);
+
+        let pages = manager.alloc_run(&bufs, Contiguity::ContiguousClusters).unwrap().execute();
+        for (page, buf) in pages.iter().zip(&bufs) {
+            assert!(run.contains(&page.cluster.into()));
+            assert_eq!(manager.read(*page).unwrap(), *buf);
+        }
+    }
+
+    #[test]
+    fn set_compression_algorithm() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            cluster_tags: true,
+            .. Default::default()
+        });
+
+        // Write under LZ4, then switch to Zstandard.
+        let old = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.set_compression_algorithm(state_block::CompressionAlgorithm::Zstd).unwrap().execute();
+        let new = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(old.offset.is_some() && new.offset.is_some());
+        assert!(old.cluster != new.cluster);
+
+        // Both clusters read back, through their own algorithm.
+        assert_eq!(manager.read(old).unwrap(), [1; disk::SECTOR_SIZE]);
+        assert_eq!(manager.read(new).unwrap(), [2; disk::SECTOR_SIZE]);
+
+        // The change is persisted.
+        manager.sync_metadata().unwrap();
+        let state_block = state_block::StateBlock::decode(
+            &disk.sector(manager.state_block_address()),
+            manager.driver.header.checksum_algorithm,
+        ).unwrap();
+        assert_eq!(state_block.config.compression_algorithm, state_block::CompressionAlgorithm::Zstd);
+
+        // Without cluster tags, the algorithm of the existing clusters is unknown.
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        assert_matches!(manager.set_compression_algorithm(state_block::CompressionAlgorithm::Zstd),
+                        Err(Error::UntaggedClusters));
+
+        // Unless compression was disabled, in which case tagging is enabled along with it.
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+        manager.set_compression_algorithm(state_block::CompressionAlgorithm::Lz4).unwrap().execute();
+        assert!(manager.config.tags_clusters());
+    }
+
+    #[test]
+    fn read_compressed_page_with_compression_disabled() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        // A pointer claiming the page to be compressed.
+        let page = manager.alloc(&[7; disk::SECTOR_SIZE]).unwrap().execute();
+        let compressed = page::Pointer {
+            offset: Some(0),
+            .. page
+        };
+        assert_matches!(manager.read(compressed),
+                        Err(Error::CompressionDisabledButPageCompressed { page })
+                        if page == compressed);
+
+        // The uncompressed page still reads fine.
+        assert_eq!(manager.read(page).unwrap(), [7; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn read_into() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+
+        // Reading into a buffer gives the same bytes as `read`, without taking any scratch buffer
+        // from the pool.
+        let allocations = manager.pool.allocations();
+        let mut buf = [0; disk::SECTOR_SIZE];
+        manager.read_into(page, &mut buf).unwrap();
+        assert_eq!(buf, manager.read(page).unwrap());
+        assert_eq!(manager.pool.allocations(), allocations);
+    }
+
+    #[test]
+    fn read_into_compressed() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        let pages: Vec<_> = (0..4u8).map(|n| {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()
+        }).collect();
+
+        let mut buf = [0; disk::SECTOR_SIZE];
+        for page in pages {
+            manager.read_into(page, &mut buf).unwrap();
+            assert_eq!(buf, manager.read(page).unwrap());
+        }
+    }
+
+    #[test]
+    fn read_compressed_at_offset() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Pack a few distinct pages into a cluster.
+        let bufs: Vec<_> = (0..3).map(|n| {
+            let mut buf = [0; disk::SECTOR_SIZE];
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = (i % 7 + n * 40) as u8;
+            }
+            buf
+        }).collect();
+        let pages: Vec<_> = bufs.iter().map(|buf| manager.alloc(buf).unwrap().execute()).collect();
+        assert!(pages[2].cluster == pages[0].cluster);
+        assert_eq!(pages[2].offset, Some(2));
+
+        // Read the page at the nonzero offset from the disk.
+        manager.cache.trim(0).unwrap();
+        assert_eq!(manager.read(pages[2]).unwrap()[..], bufs[2][..]);
+    }
+
+    #[test]
+    fn corrupt_page() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        // Flush and evict the cache, so the corruption isn't hidden by it.
+        manager.cache.trim(0).unwrap();
+
+        // Flip a bit in the page.
+        disk.corrupt(page.cluster.into() as disk::Sector, 100, 0x01);
+        assert_matches!(manager.read(page), Err(Error::PageChecksumMismatch { page: found, .. })
+                        if found == page);
+    }
+
+    #[test]
+    fn corrupt_compression_tag() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Auto,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+
+        // The tag is the last byte of the data, whose length prefixes the cluster.
+        let sector = page.cluster.into() as disk::Sector;
+        let len = LittleEndian::read_u16(&disk.sector(sector)) as usize;
+        // Turn the tag into an invalid algorithm.
+        disk.corrupt(sector, LENGTH_PREFIX_SIZE + len - 1, 0x80);
+        assert_matches!(manager.read(page), Err(Error::InvalidCompression { kind, .. })
+                        if kind == CompressionCorruption::LengthPrefixCorrupt);
+    }
+
+    #[test]
+    fn corrupt_compressed_cluster() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+
+        // Wipe the cluster, including the length prefix.
+        let sector = page.cluster.into() as disk::Sector;
+        let data = disk.sector(sector);
+        for (offset, &byte) in data.iter().enumerate() {
+            disk.corrupt(sector, offset, byte);
+        }
+
+        // The error names the corrupted cluster, which holds no data at all.
+        assert_matches!(manager.read(page), Err(Error::InvalidCompression { cluster, kind })
+                        if cluster == page.cluster && kind == CompressionCorruption::DataCorrupt);
+    }
+
+    #[test]
+    fn corrupt_compressed_data() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+
+        // Point the first match of the LZ4 stream far beyond the start of the output.
+        let sector = page.cluster.into() as disk::Sector;
+        disk.corrupt(sector, LENGTH_PREFIX_SIZE + 3, 0x80);
+        assert_matches!(manager.read(page), Err(Error::InvalidCompression { cluster, kind })
+                        if cluster == page.cluster && kind == CompressionCorruption::DataCorrupt);
+    }
+
+    #[test]
+    fn corrupt_length_prefix() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+
+        // Make the length prefix exceed the cluster, by a lot, and by a single byte.
+        let sector = page.cluster.into() as disk::Sector;
+        let original = disk.sector(sector);
+        for &len in &[0x8000 | LittleEndian::read_u16(&original), (disk::SECTOR_SIZE - LENGTH_PREFIX_SIZE + 1) as u16] {
+            let mut corrupted = original;
+            LittleEndian::write_u16(&mut corrupted, len);
+            disk.clone().write(sector, &corrupted).unwrap();
+            assert_matches!(manager.read(page), Err(Error::InvalidCompression { cluster, kind })
+                            if cluster == page.cluster && kind == CompressionCorruption::LengthPrefixCorrupt);
+            manager.cache.trim(0).unwrap();
+        }
+
+        // The padding past the data is never looked at.
+        let mut corrupted = original;
+        corrupted[disk::SECTOR_SIZE - 1] ^= 0x01;
+        disk.clone().write(sector, &corrupted).unwrap();
+        assert_eq!(manager.read(page).unwrap(), [0; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn corrupt_metacluster() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+
+        // Flip a bit in the first free cluster pointer of the head metacluster.
+        let freelist_head = manager.state.lock().freelist_head.unwrap();
+        disk.corrupt(freelist_head.cluster.into() as disk::Sector, 16, 0x01);
+        assert_matches!(manager.load_head_metacluster(freelist_head),
+                        Err(Error::MetacluterChecksumMismatch { cluster, .. })
+                        if cluster == freelist_head.cluster);
+    }
+
+    #[test]
+    fn corrupt_torn_write() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+
+        // Simulate a torn write, where only the first half of the sector made it to the disk.
+        let sector = page.cluster.into() as disk::Sector;
+        for offset in disk::SECTOR_SIZE / 2..disk::SECTOR_SIZE {
+            disk.corrupt(sector, offset, 0xAB);
+        }
+        assert_matches!(manager.read(page), Err(Error::PageChecksumMismatch { .. }));
+    }
+
+    #[test]
+    fn out_of_bounds_page() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let manager = manager(&disk, state_block::Config::default());
+
+        // A page pointing past the end of the disk.
+        let page = page::Pointer {
+            cluster: cluster::Pointer::new(TEST_SECTORS as u64).unwrap(),
+            offset: None,
+            checksum: 0,
+        };
+        assert_matches!(manager.read(page), Err(Error::Disk(disk::Error::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn undelete() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(manager.free_to_trash(page).unwrap().is_none());
+
+        // The cluster is kept in the trash rather than the freelist.
+        assert!(!manager.live.lock().contains_key(&page.cluster));
+        assert!(!manager.head_metacluster.lock().free.contains(&page.cluster));
+
+        // Bring it back.
+        manager.undelete(page).unwrap();
+        assert!(manager.live.lock().contains_key(&page.cluster));
+        assert!(manager.trash.lock().is_empty());
+        assert_eq!(manager.read(page).unwrap(), [0xAB; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn evict_trash() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(manager.free_to_trash(page).unwrap().is_none());
+
+        // Allocate until the freelist runs empty, and the trashed cluster is evicted and reused.
+        let mut n = 0u64;
+        loop {
+            let mut buf = [0; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, n);
+            n += 1;
+
+            if manager.alloc(&buf).unwrap().execute().cluster == page.cluster {
+                break;
+            }
+        }
+
+        // The page is lost.
+        assert!(manager.trash.lock().is_empty());
+        assert!(manager.undelete(page).is_err());
+    }
+
+    #[test]
+    fn rebuild_refcounts() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Two pages packed into the same cluster.
+        let a = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        let b = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(a.cluster, b.cluster);
+
+        // Corrupt the reference count of the cluster by counting a page twice, which would leak
+        // the cluster when both pages are freed.
+        manager.live.lock().get_mut(&a.cluster).unwrap().pages.push(a);
+
+        // Rebuild from the actual live pages.
+        assert!(manager.rebuild_refcounts(vec![a, b].into_iter()).unwrap().is_none());
+        assert_eq!(manager.live.lock()[&a.cluster].pages, [a, b]);
+
+        // Freeing both pages now reclaims the cluster.
+        assert!(manager.free(a).unwrap().is_none());
+        manager.free(b).unwrap().unwrap().execute();
+        assert!(manager.head_metacluster.lock().free.contains(&a.cluster));
+    }
+
+    #[test]
+    fn rebuild_refcounts_leaked() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        let a = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        let b = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+
+        // `a` is no longer referenced by the caller's index, so its cluster is reclaimed.
+        manager.rebuild_refcounts(vec![b].into_iter()).unwrap().unwrap().execute();
+        assert!(!manager.live.lock().contains_key(&a.cluster));
+        assert!(manager.head_metacluster.lock().free.contains(&a.cluster));
+        assert_eq!(manager.read(b).unwrap(), [2; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn validate_page_pointer() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(page.offset, Some(0));
+        manager.validate_page_pointer(page).unwrap();
+
+        // A checksum not matching the stored page.
+        let mut invalid = page;
+        invalid.checksum ^= 1;
+        assert_matches!(manager.validate_page_pointer(invalid),
+                        Err(Error::PageChecksumMismatch { .. }));
+
+        // An offset past the pages stored in the cluster.
+        let mut invalid = page;
+        invalid.offset = Some(1);
+        assert_matches!(manager.validate_page_pointer(invalid),
+                        Err(Error::ImplausibleOffset { .. }));
+        // Neither is recorded as corruption.
+        assert!(manager.warnings().is_empty());
+
+        // A cluster past the end of the device.
+        let mut invalid = page;
+        invalid.cluster = cluster::Pointer::new(TEST_SECTORS as u64).unwrap();
+        assert_matches!(manager.validate_page_pointer(invalid),
+                        Err(Error::ClusterOutOfBounds { .. }));
+
+        // The state block isn't a data cluster.
+        invalid.cluster = cluster::Pointer::new(manager.state_block_address() as u64).unwrap();
+        assert_matches!(manager.validate_page_pointer(invalid),
+                        Err(Error::ClusterOutOfBounds { .. }));
+
+        // An offset past the capacity of a cluster.
+        let mut invalid = page;
+        invalid.offset = Some(page::MAX_PAGES_PER_CLUSTER as u32);
+        assert_matches!(manager.validate_page_pointer(invalid),
+                        Err(Error::ImplausibleOffset { .. }));
+
+        // A freed cluster.
+        manager.free(page).unwrap().unwrap().execute();
+        assert_matches!(manager.validate_page_pointer(page), Err(Error::UnallocatedCluster { .. }));
+
+        // Reopen without a persisted live page index, so the index is incomplete.
+        let page = manager.alloc(&[0xCD; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+        let free = manager.iter_free_clusters().next().unwrap();
+        mem::forget(manager);
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert!(!manager.index_complete);
+
+        // The page is validated against the freelist and the stored cluster instead.
+        manager.validate_page_pointer(page).unwrap();
+        let mut invalid = page;
+        invalid.cluster = free;
+        assert_matches!(manager.validate_page_pointer(invalid), Err(Error::UnallocatedCluster { .. }));
+    }
+
+    #[test]
+    fn persisted_live_index() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut header = header::DiskHeader::default();
+        header.index_sectors = 2;
+        let mut manager = setup(&disk, None, header, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        let reopen = || Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+
+        // Pack four pages into a cluster, and free two of them.
+        let pages: Vec<_> = (0..4u8).map(|n| manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()).collect();
+        manager.free(pages[0]).unwrap().map(|transaction| transaction.execute());
+        manager.free(pages[2]).unwrap().map(|transaction| transaction.execute());
+
+        // Crash, with everything on the disk.
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+        assert_ne!(manager.state.lock().live_index, 0);
+        mem::forget(manager);
+
+        // The index is loaded on open, so the pages allocated before are covered.
+        let mut manager = reopen();
+        assert!(manager.index_complete);
+        assert_eq!(manager.live_page_count(), 2);
+        assert_eq!(manager.fragmentation(), 0.5);
+        assert_eq!(manager.read_cluster_decompressed(pages[1].cluster).unwrap().len(), 4 * disk::SECTOR_SIZE);
+
+        // Compaction moves them.
+        let mut remapped = Vec::new();
+        manager.compact(None, &mut |old, new| remapped.push((old, new))).unwrap();
+        assert_eq!(remapped.len(), 2);
+        for &(old, new) in &remapped {
+            assert_eq!(manager.read(new).unwrap(), [old.offset.unwrap() as u8; disk::SECTOR_SIZE]);
+        }
+        assert_matches!(manager.read_cluster_decompressed(pages[1].cluster), Err(Error::ClusterNotInUse { .. }));
+        let pages: Vec<_> = remapped.into_iter().map(|(_, new)| new).collect();
+
+        // Allocate after syncing, and crash before the next sync. The index went stale, so it isn't
+        // loaded.
+        manager.sync_metadata().unwrap();
+        manager.alloc(&[4; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+        mem::forget(manager);
+        let mut manager = reopen();
+        assert!(!manager.index_complete);
+        assert_eq!(manager.live_page_count(), 0);
+
+        // Rebuilding the index persists it.
+        assert!(manager.rebuild_refcounts(pages.iter().cloned()).unwrap().is_none());
+        manager.cache.trim(0).unwrap();
+        mem::forget(manager);
+        let manager = reopen();
+        assert!(manager.index_complete);
+        assert_eq!(manager.live_page_count(), 2);
+    }
+
+    #[test]
+    fn allocator_stats() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let manager = manager(&disk, state_block::Config::default());
+
+        let pages: Vec<_> = (0..4).map(|n| manager.alloc(&noise_page(n)).unwrap().execute()).collect();
+        manager.alloc(&noise_page(0)).unwrap().execute();
+        manager.read(pages[0]).unwrap();
+
+        let stats = manager.allocator_stats().unwrap();
+        assert_eq!(stats.free_clusters, manager.free_clusters().unwrap());
+        assert_eq!(stats.live_pages, 4);
+        assert_eq!(stats.live_clusters, manager.live.lock().len());
+        assert_eq!(stats.compressions, manager.compressions.load(ORDERING));
+        assert_eq!(stats.dedup.hits, 1);
+
+        let metrics = manager.cache_metrics();
+        assert!(metrics.hits > 0);
+        assert_eq!(metrics.sectors, manager.cache.len());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_stats() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let manager = manager(&disk, state_block::Config::default());
+        manager.alloc(&noise_page(0)).unwrap().execute();
+
+        // The stats round-trip through JSON under stable field names.
+        let stats = manager.allocator_stats().unwrap();
+        let json = serde_json::to_string(&stats).unwrap();
+        assert!(json.contains("\"free_clusters\""));
+        assert_eq!(serde_json::from_str::<AllocatorStats>(&json).unwrap(), stats);
+
+        let metrics = manager.cache_metrics();
+        let json = serde_json::to_string(&metrics).unwrap();
+        assert!(json.contains("\"max_sectors\""));
+        assert_eq!(serde_json::from_str::<cache::CacheMetrics>(&json).unwrap(), metrics);
+    }
+
+    #[test]
+    fn packing_stats() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Nothing is allocated yet.
+        assert_eq!(manager.packing_stats(), PackingStats {
+            pages_per_compressed_cluster: 0.0,
+            logical_to_physical: 0.0,
+        });
+
+        // Four compressible pages packed into one cluster.
+        for n in 0..4u8 {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute();
+        }
+
+        // One incompressible page, stored in a cluster of its own.
+        assert_eq!(manager.alloc(&noise_page(0)).unwrap().execute().offset, None);
+
+        assert_eq!(manager.packing_stats(), PackingStats {
+            pages_per_compressed_cluster: 4.0,
+            logical_to_physical: 2.5,
+        });
+    }
+
+    #[test]
+    fn live_page_count() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        assert_eq!(manager.live_page_count(), 0);
+
+        // Four distinct pages, packed into one cluster.
+        let pages: Vec<_> = (0..4u8).map(|n| manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()).collect();
+        assert_eq!(manager.live_page_count(), 4);
+
+        // Duplicates aren't stored anew, so they don't count.
+        assert_eq!(manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute(), pages[2]);
+        assert_eq!(manager.live_page_count(), 4);
+
+        // Freed pages no longer count.
+        manager.free(pages[0]).unwrap().map(|transaction| transaction.execute());
+        assert_eq!(manager.live_page_count(), 3);
+
+        // The duplicated page holds two references, so it stays live until both are dropped.
+        manager.free(pages[2]).unwrap().map(|transaction| transaction.execute());
+        assert_eq!(manager.live_page_count(), 3);
+        assert_eq!(manager.read(pages[2]).unwrap(), [2; disk::SECTOR_SIZE]);
+        manager.free(pages[2]).unwrap().map(|transaction| transaction.execute());
+        assert_eq!(manager.live_page_count(), 2);
+    }
+
+    #[test]
+    fn secondary_compression_algorithm() {
+        // Random nibbles have too few repetitions for LZ4, but Zstandard entropy codes them.
+        let mut buf = noise_page(0);
+        for byte in buf.iter_mut() {
+            *byte &= 0xF;
+        }
+
+        // LZ4 alone stores the page uncompressed.
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        assert_eq!(manager.alloc(&buf).unwrap().execute().offset, None);
+
+        // With Zstandard as the secondary algorithm, it is compressed.
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            secondary_compression_algorithm: state_block::CompressionAlgorithm::Zstd,
+            .. Default::default()
+        });
+        let page = manager.alloc(&buf).unwrap().execute();
+        assert_eq!(page.offset, Some(0));
+        assert_eq!(manager.last_cluster.lock().as_ref().unwrap().algorithm, state_block::CompressionAlgorithm::Zstd);
+
+        // The cluster is tagged with the secondary algorithm, and reads back through it.
+        assert_eq!(manager.read(page).unwrap(), buf);
+        manager.cache.trim(0).unwrap();
+        let sector = disk.sector(page.cluster.into() as disk::Sector);
+        let len = LittleEndian::read_u16(&sector) as usize;
+        assert_eq!(sector[LENGTH_PREFIX_SIZE + len - 1], state_block::CompressionAlgorithm::Zstd as u8);
+        assert_eq!(manager.read(page).unwrap(), buf);
+    }
+
+    #[test]
+    fn alloc_placed() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // The first page starts a new cluster.
+        let (first, placement) = manager.alloc_placed(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(placement == Placement::Fresh);
+
+        // Related pages allocated right after are co-located with it.
+        for n in 1..4u8 {
+            let (page, placement) = manager.alloc_placed(&[n; disk::SECTOR_SIZE]).unwrap().execute();
+            assert!(placement == Placement::Extended);
+            assert_eq!(page.cluster, first.cluster);
+        }
+
+        // Duplicates are reported as such.
+        let (page, placement) = manager.alloc_placed(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(placement == Placement::Duplicate);
+        assert_eq!(page, first);
+    }
+
+    #[test]
+    fn read_state_block() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            free_fill: state_block::FillPattern::Zero,
+            .. Default::default()
+        });
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+
+        // Corrupt the freelist, but leave the state block intact.
+        let freelist_head = manager.state.lock().freelist_head.unwrap();
+        disk.corrupt(freelist_head.cluster.into() as disk::Sector, 16, 0x01);
+        assert!(manager.load_head_metacluster(freelist_head).is_err());
+
+        // The state block can still be read.
+        let block = state_block::read(&driver(&disk)).unwrap();
+        assert!(block.config.free_fill == state_block::FillPattern::Zero);
+        assert_eq!(block.state.freelist_head, Some(freelist_head));
+    }
+
+    #[test]
+    fn free_metacluster() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        // A page pointing to the head metacluster.
+        let freelist_head = manager.state.lock().freelist_head.unwrap();
+        let page = page::Pointer {
+            cluster: freelist_head.cluster,
+            offset: None,
+            checksum: 0,
+        };
+
+        assert_matches!(manager.free(page), Err(Error::ClusterInUseAsMetadata { cluster })
+                        if cluster == freelist_head.cluster);
+        // The freelist is untouched.
+        assert_eq!(manager.state.lock().freelist_head, Some(freelist_head));
+    }
+
+    #[test]
+    fn free_tail_metacluster() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        let chain = manager.load_metacluster_chain(&manager.head_metacluster.lock()).unwrap();
+        assert!(chain.len() >= 2);
+        let freelist_head = manager.state.lock().freelist_head.unwrap();
+
+        // Pages pointing to a metacluster deep in the chain, or to the state block, are rejected.
+        let state_block = cluster::Pointer::new(manager.state_block_address() as u64).unwrap();
+        for &target in &[chain[1].0, chain[chain.len() - 1].0, state_block] {
+            let page = page::Pointer {
+                cluster: target,
+                offset: None,
+                checksum: 0,
+            };
+            assert_matches!(manager.free(page), Err(Error::ClusterInUseAsMetadata { cluster })
+                            if cluster == target);
+        }
+
+        // The freelist is untouched.
+        assert_eq!(manager.state.lock().freelist_head, Some(freelist_head));
+        assert_eq!(manager.load_metacluster_chain(&manager.head_metacluster.lock()).unwrap().len(), chain.len());
+    }
+
+    #[test]
+    fn lazy_metacluster_verification() {
+        for &verify in &[true, false] {
+            let disk = MemSim::new(TEST_SECTORS);
+            let mut manager = manager(&disk, state_block::Config {
+                compression_algorithm: state_block::CompressionAlgorithm::Identity,
+                .. Default::default()
+            });
+            manager.set_verify_metaclusters(verify);
+
+            // Allocate more pages than the head metacluster holds, forcing a switch.
+            let mut pages = Vec::new();
+            for n in 0..MAX_FREE as u64 + 8 {
+                let mut buf = [0; disk::SECTOR_SIZE];
+                LittleEndian::write(&mut buf, n);
+                pages.push(manager.alloc(&buf).unwrap().execute());
+            }
+
+            // Only the verifying manager computed checksums while traversing.
+            if verify {
+                assert!(manager.metacluster_hashes.load(ORDERING) > 0);
+            } else {
+                assert_eq!(manager.metacluster_hashes.load(ORDERING), 0);
+            }
+
+            // Either way, the pages are intact on good data.
+            for (n, &page) in pages.iter().enumerate() {
+                assert_eq!(LittleEndian::read(&manager.read(page).unwrap()), n as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn max_pages_per_cluster() {
+        for &algorithm in &[state_block::CompressionAlgorithm::Identity,
+                            state_block::CompressionAlgorithm::Zstd] {
+            let disk = MemSim::new(TEST_SECTORS);
+            let mut manager = manager(&disk, state_block::Config {
+                compression_algorithm: algorithm,
+                .. Default::default()
+            });
+
+            // Fill the first cluster with highly compressible, distinct pages, until a page spills
+            // into a new cluster.
+            let first = manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+            let mut packed = 1;
+            loop {
+                let mut buf = [0; disk::SECTOR_SIZE];
+                LittleEndian::write(&mut buf, packed as u64);
+
+                if manager.alloc(&buf).unwrap().execute().cluster != first.cluster {
+                    break;
+                }
+                packed += 1;
+            }
+
+            assert_eq!(packed, manager.max_pages_per_cluster());
+        }
+
+        assert_eq!(page::MAX_PAGES_PER_CLUSTER, CLUSTER_CAPACITY / disk::SECTOR_SIZE);
+    }
+
+    #[test]
+    fn changed_clusters_since() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut header = header::DiskHeader::default();
+        header.index_sectors = 2;
+        let mut manager = setup(&disk, None, header, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        // The first round of writes.
+        let mut first: Vec<_> = (0..4u8).map(|n| {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute().cluster
+        }).collect();
+        first.sort();
+        assert_eq!(manager.changed_clusters_since(0).unwrap(), first);
+
+        // Checkpoint, and do a second round of writes.
+        let generation = manager.bump_backup_generation().execute();
+        assert_eq!(generation, 1);
+        let mut second: Vec<_> = (4..8u8).map(|n| {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute().cluster
+        }).collect();
+        second.sort();
+
+        // Only the second round changed since the checkpoint.
+        assert_eq!(manager.changed_clusters_since(generation).unwrap(), second);
+
+        // The generation is persisted in the state block.
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+        let block = state_block::read(&driver(&disk)).unwrap();
+        assert_eq!(block.state.backup_generation, generation);
+        mem::forget(manager);
+
+        // The changed clusters are persisted along with the live page index.
+        let reopened = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(reopened.changed_clusters_since(generation).unwrap(), second);
+        assert_eq!(reopened.changed_clusters_since(0).unwrap().len(), 8);
+        drop(reopened);
+
+        // Without the index, the clusters written before opening are unknown, so a full backup is
+        // needed.
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = setup(&disk, None, header::DiskHeader::default(), state_block::Config::default());
+        manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.shutdown().unwrap();
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert!(manager.changed_clusters_since(0).is_none());
+    }
+
+    #[test]
+    fn rebalance() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        manager.set_wear_tracking(true);
+
+        // A cold, incompressible page, stored in a cluster of its own.
+        let buf = noise_page(0);
+        let cold = manager.alloc(&buf).unwrap().execute();
+
+        // Skew the writes onto a single cluster, by packing compressible pages into it.
+        let hot: Vec<_> = (0..2 * WEAR_THRESHOLD as u8).map(|n| {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()
+        }).collect();
+        assert!(hot.iter().all(|page| page.cluster == hot[0].cluster));
+
+        let mut moved = Vec::new();
+        manager.rebalance(&mut |old, new| moved.push((old, new))).unwrap();
+        // Follow the remappings of a page to its final pointer.
+        let resolve = |mut page: page::Pointer| {
+            while let Some(&(_, new)) = moved.iter().find(|&&(old, _)| old == page) {
+                page = new;
+            }
+            page
+        };
+
+        // The hot data moved to the formerly cold cluster.
+        for (n, &page) in hot.iter().enumerate() {
+            let new = resolve(page);
+            assert_eq!(new.cluster, cold.cluster);
+            assert_eq!(manager.read(new).unwrap(), [n as u8; disk::SECTOR_SIZE]);
+        }
+
+        // And the cold data rests in the worn cluster.
+        let new = resolve(cold);
+        assert_eq!(new.cluster, hot[0].cluster);
+        assert_eq!(manager.read(new).unwrap(), buf);
+
+        // The spare cluster is given back.
+        let spare = moved.iter().find(|&&(old, _)| old == cold).unwrap().1.cluster;
+        assert!(manager.iter_free_clusters().any(|cluster| cluster == spare));
+    }
+
+    #[test]
+    fn persisted_write_counts() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut header = header::DiskHeader::default();
+        header.index_sectors = 2;
+        let mut manager = setup(&disk, None, header, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        manager.set_wear_tracking(true);
+
+        // Write three pages to a cluster, and free it.
+        let pages: Vec<_> = (0..3u8).map(|n| manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()).collect();
+        let cluster = pages[0].cluster;
+        for &page in &pages {
+            manager.free(page).unwrap().map(|transaction| transaction.execute());
+        }
+
+        // Crash, with everything on the disk.
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+        mem::forget(manager);
+
+        // The write counts are loaded with the live page index, even for the free cluster.
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.writes.lock().get(&cluster), Some(&3));
+    }
+
+    #[test]
+    fn walk_freelist() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        manager.sync_metadata().unwrap();
+
+        let mut reports = Vec::new();
+        let free = manager.walk_freelist(&mut |progress| reports.push(progress)).unwrap();
+
+        // The freelist spans multiple metaclusters, each reported.
+        assert!(reports.len() > 2);
+        assert!(free > MAX_FREE);
+        // The progress increases, and ends at 1.
+        assert!(reports.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(reports[..reports.len() - 1].iter().all(|&progress| progress > 0.0 && progress < 1.0));
+        assert_eq!(*reports.last().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn checksum_page() {
+        for &width in &[state_block::ChecksumWidth::Narrow, state_block::ChecksumWidth::Wide] {
+            let disk = MemSim::new(TEST_SECTORS);
+            let mut manager = manager(&disk, state_block::Config {
+                checksum_width: width,
+                .. Default::default()
+            });
+
+            let buf = [42; disk::SECTOR_SIZE];
+            let page = manager.alloc(&buf).unwrap().execute();
+            assert_eq!(manager.checksum_page(&buf), page.checksum);
+        }
+    }
+
+    #[test]
+    fn extend_offsets() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Pack several pages into one cluster.
+        let pages: Vec<_> = (0..8u8).map(|n| {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()
+        }).collect();
+        assert!(pages.iter().all(|page| page.cluster == pages[0].cluster));
+
+        // Every page is stored at its own offset, from which it reads back.
+        for (n, &page) in pages.iter().enumerate() {
+            assert_eq!(page.offset, Some(n as u32));
+            assert_eq!(manager.read(page).unwrap(), [n as u8; disk::SECTOR_SIZE]);
+        }
+    }
+
+    #[test]
+    fn auto_compaction() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Fill a few compressed clusters.
+        let pages: Vec<_> = (0..1024u64).map(|n| {
+            let mut buf = [0; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, n);
+            manager.alloc(&buf).unwrap().execute()
+        }).collect();
+        let clusters = manager.live.lock().len();
+        assert!(clusters > 1);
+
+        // Fragment the clusters, keeping only every 16th page.
+        for (n, &page) in pages.iter().enumerate() {
+            if n % 16 != 15 && n != pages.len() - 1 {
+                manager.free(page).unwrap().map(|transaction| transaction.execute());
+            }
+        }
+        assert!(manager.fragmentation() > 0.9);
+        // Without a policy, nothing happened.
+        assert_eq!(manager.live.lock().len(), clusters);
+
+        let moved = Arc::new(Mutex::new(Vec::new()));
+        let moved_clone = moved.clone();
+        manager.set_auto_compaction(CompactionPolicy {
+            threshold: 0.5,
+            budget: clusters,
+            interval: 1,
+        }, move |old, new| moved_clone.lock().push((old, new)));
+
+        // The next free triggers the compaction.
+        let last = pages[pages.len() - 1];
+        assert!(manager.free(last).unwrap().is_none());
+        assert!(manager.live.lock().len() < clusters);
+        assert!(manager.fragmentation() < 0.5);
+
+        // The remaining pages moved, and are intact.
+        for (n, &page) in pages.iter().enumerate() {
+            if n % 16 == 15 && n != pages.len() - 1 {
+                let &(_, new) = moved.lock().iter().find(|&&(old, _)| old == page).unwrap();
+                let mut buf = [0; disk::SECTOR_SIZE];
+                LittleEndian::write(&mut buf, n as u64);
+                assert_eq!(manager.read(new).unwrap(), buf);
+            }
+        }
+    }
+
+    #[test]
+    fn auto_compaction_on_alloc() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Fill a few compressed clusters, and fragment them, keeping only every 16th page.
+        let pages: Vec<_> = (0..1024u64).map(|n| {
+            let mut buf = [0; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, n);
+            manager.alloc(&buf).unwrap().execute()
+        }).collect();
+        let clusters = manager.live.lock().len();
+        let kept: Vec<_> = pages.iter().cloned().enumerate().filter(|&(n, _)| n % 16 == 15).collect();
+        manager.free_range(&pages.iter().cloned().enumerate().filter(|&(n, _)| n % 16 != 15)
+            .map(|(_, page)| page).collect::<Vec<_>>()).unwrap().map(|transaction| transaction.execute());
+
+        // Most compressed clusters are sparsely occupied.
+        let histogram = manager.fragmentation_histogram();
+        assert_eq!(histogram.iter().sum::<usize>(), manager.live.lock().len());
+        assert!(histogram[0] + histogram[1] > histogram[HISTOGRAM_BUCKETS - 1]);
+
+        let moved = Arc::new(Mutex::new(Vec::new()));
+        let moved_clone = moved.clone();
+        manager.set_auto_compaction(CompactionPolicy {
+            threshold: 0.5,
+            budget: clusters,
+            interval: 1,
+        }, move |old, new| moved_clone.lock().push((old, new)));
+
+        // The next allocation triggers the compaction.
+        manager.alloc(&noise_page(0)).unwrap().execute();
+        assert!(manager.live.lock().len() < clusters);
+        assert!(manager.fragmentation() < 0.5);
+        assert!(manager.fragmentation_histogram()[HISTOGRAM_BUCKETS - 1] > 0);
+
+        // The kept pages moved, and are intact.
+        for (n, page) in kept {
+            let &(_, new) = moved.lock().iter().find(|&&(old, _)| old == page).unwrap();
+            let mut buf = [0; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, n as u64);
+            assert_eq!(manager.read(new).unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn fragmentation_histogram() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        assert_eq!(manager.fragmentation_histogram(), [0; HISTOGRAM_BUCKETS]);
+
+        // Pack four pages into a cluster, and free three of them.
+        let pages: Vec<_> = (0..4u8).map(|n| manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()).collect();
+        assert!(pages.iter().all(|page| page.cluster == pages[0].cluster));
+        let mut expected = [0; HISTOGRAM_BUCKETS];
+        expected[HISTOGRAM_BUCKETS - 1] = 1;
+        assert_eq!(manager.fragmentation_histogram(), expected);
+
+        manager.free_range(&pages[..3]).unwrap();
+        let mut expected = [0; HISTOGRAM_BUCKETS];
+        expected[HISTOGRAM_BUCKETS / 4] = 1;
+        assert_eq!(manager.fragmentation_histogram(), expected);
+    }
+
+    #[test]
+    fn verify_and_repair() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+        let free = manager.walk_freelist(&mut |_| ()).unwrap();
+
+        // A live page, and a page which the caller no longer references.
+        let page = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        // A cluster which is neither free nor referenced.
+        manager.freelist_pop().unwrap().execute();
+        // A duplicate freelist entry, which the freelist head counter and checksum don't cover.
+        let duplicate = manager.head_metacluster.lock().free[0];
+        manager.head_metacluster.lock().free.push(duplicate);
+
+        let report = manager.verify_and_repair(vec![page].into_iter()).unwrap();
+        assert_eq!(report.duplicates_removed, 1);
+        assert_eq!(report.clusters_reclaimed, 2);
+        assert!(report.metaclusters_rewritten >= 1);
+
+        // Only the live page's cluster is missing from the freelist, which is consistent again.
+        assert_eq!(manager.walk_freelist(&mut |_| ()).unwrap(), free - 1);
+        assert_eq!(manager.read(page).unwrap(), [1; disk::SECTOR_SIZE]);
+
+        // A second pass finds nothing to repair.
+        assert!(manager.verify_and_repair(vec![page].into_iter()).unwrap() == RepairReport::default());
+    }
+
+    #[test]
+    fn dedup_entropy_threshold() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            dedup_entropy_threshold: 64,
+            .. Default::default()
+        });
+
+        // A high-entropy page skips the lookup, so its duplicate is stored anew.
+        let random = noise_page(0);
+        manager.alloc(&random).unwrap().execute();
+        assert!(manager.alloc_placed(&random).unwrap().execute().1 == Placement::Fresh);
+
+        // A repetitive page still goes through the lookup.
+        let mut repetitive = [0; disk::SECTOR_SIZE];
+        for (n, byte) in repetitive.iter_mut().enumerate() {
+            *byte = (n % 16) as u8;
+        }
+        manager.alloc(&repetitive).unwrap().execute();
+        assert!(manager.alloc_placed(&repetitive).unwrap().execute().1 == Placement::Duplicate);
+    }
+
+    #[test]
+    fn large_page() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let mut buf = vec![0; 4 * disk::SECTOR_SIZE];
+        for (n, byte) in buf.iter_mut().enumerate() {
+            *byte = (n / 7) as u8;
+        }
+
+        // Large pages must be enabled first.
+        assert_matches!(manager.alloc_large(&buf), Err(Error::LargePagesDisabled));
+        manager.driver.header.large_pages = true;
+
+        let page = manager.alloc_large(&buf).unwrap().execute();
+        assert_eq!(page.sectors, 4);
+        assert_eq!(manager.read_large(page).unwrap(), buf);
+
+        // Corruption in any sector is detected.
+        manager.cache.trim(0).unwrap();
+        disk.corrupt(page.first.cluster.into() as disk::Sector + 3, 100, 0x01);
+        assert!(manager.read_large(page).is_err());
+
+        manager.free_large(page).unwrap().unwrap().execute();
+    }
+
+    #[test]
+    fn estimate_free_bytes() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        let free = manager.walk_freelist(&mut |_| ()).unwrap() as u64;
+        assert_eq!(manager.estimate_free_bytes(1.0).unwrap(), free * disk::SECTOR_SIZE as u64);
+        assert_eq!(manager.estimate_free_bytes(2.5).unwrap(), free * disk::SECTOR_SIZE as u64 * 5 / 2);
+
+        // One page in the last allocated cluster leaves room for three more at a ratio of 4.
+        manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(manager.estimate_free_bytes(4.0).unwrap(),
+                   (free - 1) * disk::SECTOR_SIZE as u64 * 4 + 3 * disk::SECTOR_SIZE as u64);
+    }
+
+    #[test]
+    fn replay_journal() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = journaled_manager(&disk, state_block::Config::default());
+        manager.sync_metadata().unwrap();
+
+        // Allocate a few pages, and free one of them.
+        let pages: Vec<_> = (0..4).map(|n| manager.alloc(&noise_page(n)).unwrap().execute()).collect();
+        manager.free(pages[1]).unwrap().map(|transaction| transaction.execute());
+        let freelist_head = manager.state.lock().freelist_head;
+        let free = manager.head_metacluster.lock().free.clone();
+
+        // Crash, with only the journal making it to the disk.
+        manager.cache.flush(manager.journal_address()).unwrap();
+        mem::forget(manager);
+        assert!(state_block::read(&vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap())
+                .unwrap().state.freelist_head != freelist_head);
+
+        // Opening replays the journal.
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.state.lock().freelist_head, freelist_head);
+        assert_eq!(manager.head_metacluster.lock().free, free);
+        // Only the freed cluster is back in the freelist.
+        for page in &pages {
+            let is_free = free.contains(&page.cluster)
+                || freelist_head.map(|freelist_head| freelist_head.cluster) == Some(page.cluster);
+            assert_eq!(is_free, page == &pages[1]);
+        }
+
+        // The journal was checkpointed, so opening again changes nothing.
+        drop(manager);
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.state.lock().freelist_head, freelist_head);
+    }
+
+    #[test]
+    fn full_journal() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = journaled_manager(&disk, state_block::Config::default());
+        manager.sync_metadata().unwrap();
+
+        // Overflow the journal.
+        let pages: Vec<_> = (0..journal::MAX_RECORDS as u64 + 8).map(|n| {
+            manager.alloc(&noise_page(n)).unwrap().execute()
+        }).collect();
+        let freelist_head = manager.state.lock().freelist_head;
+        let free = manager.head_metacluster.lock().free.clone();
+
+        // Crash, with only the journal making it to the disk.
+        manager.cache.flush(manager.journal_address()).unwrap();
+        mem::forget(manager);
+
+        // The records dropped by the checkpoint were covered by the metadata on the disk, so no
+        // allocated cluster is free after replaying.
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.state.lock().freelist_head, freelist_head);
+        assert_eq!(manager.head_metacluster.lock().free, free);
+        let free: BTreeSet<_> = manager.iter_free_clusters().collect();
+        for page in &pages {
+            assert!(!free.contains(&page.cluster));
+        }
+    }
+
+    #[test]
+    fn journal_never_written() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = journaled_manager(&disk, state_block::Config::default());
+        manager.sync_metadata().unwrap();
+        let freelist_head = manager.state.lock().freelist_head;
+        let address = manager.journal_address();
+        drop(manager);
+
+        // Wipe the journal, as if it was never written.
+        let data = disk.sector(address);
+        for (offset, &byte) in data.iter().enumerate() {
+            disk.corrupt(address, offset, byte);
+        }
+
+        // It is taken to be empty.
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.state.lock().freelist_head, freelist_head);
+    }
+
+    #[test]
+    fn iter_free_clusters() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        // Exactly the pushed clusters are yielded.
+        let free: Vec<_> = manager.iter_free_clusters().collect();
+        let mut sorted = free.clone();
+        sorted.sort();
+        let pushed: Vec<_> = (manager.first_data_cluster()..TEST_SECTORS)
+            .map(|cluster| cluster::Pointer::new(cluster as u64).unwrap())
+            .collect();
+        assert_eq!(sorted, pushed);
+
+        // They're yielded in the order they're popped.
+        for cluster in free {
+            assert_eq!(manager.freelist_pop().unwrap().execute(), cluster);
+        }
+        assert_matches!(manager.freelist_pop(), Err(Error::OutOfClusters));
+        assert_eq!(manager.iter_free_clusters().count(), 0);
+    }
+
+    #[test]
+    fn trim_freelist_to_device_bounds() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let driver = driver(&disk);
+        let first = driver.header.state_block_address + 1;
+        let mut config = state_block::Config::default();
+        let compression_level = config.resolve_compression();
+        let mut manager = Manager::new(Cache::from(driver), config, compression_level, state_block::State::default());
+
+        // Fill the freelist backwards, so the head metacluster is at the start of the device, and
+        // the metacluster chain extends to its end.
+        for cluster in (first..TEST_SECTORS).rev() {
+            manager.freelist_push(cluster::Pointer::new(cluster as u64).unwrap()).execute();
+        }
+        manager.sync_metadata().unwrap();
+        let free: Vec<_> = manager.iter_free_clusters().collect();
+        drop(manager);
+
+        // Truncate the device, leaving the freelist pointing past its end.
+        let end = TEST_SECTORS / 2;
+        disk.truncate(end);
+
+        // Trim the freelist on open.
+        let mut manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, true, None)
+            .unwrap();
+        let trimmed: Vec<_> = manager.iter_free_clusters().collect();
+        assert!(!trimmed.is_empty());
+        for cluster in &trimmed {
+            assert!(u64::from(*cluster) < end as u64);
+            assert!(free.contains(cluster));
+        }
+
+        // Nothing is left to trim.
+        assert_eq!(manager.trim_freelist_to_device_bounds().unwrap(), 0);
+
+        // Every allocation stays within the device.
+        for _ in 0..trimmed.len() {
+            assert!(u64::from(manager.freelist_pop().unwrap().execute()) < end as u64);
+        }
+        assert_matches!(manager.freelist_pop(), Err(Error::OutOfClusters));
+    }
+
+    #[test]
+    fn flush_dedup_table() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut header = header::DiskHeader::default();
+        header.dedup_sectors = 2;
+        // Persist the live page index too, as only tracked pages are handed out as duplicates.
+        header.index_sectors = 2;
+        let mut manager = setup(&disk, None, header, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            dedup_policy: state_block::DedupPolicy::Always,
+            .. Default::default()
+        });
+        let reopen = || Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+
+        // Persist a table holding `a`, then one holding both `a` and `b`.
+        let a = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.flush_dedup_table().unwrap().execute();
+        let b = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.flush_dedup_table().unwrap().execute();
+
+        // Crash, with everything on the disk.
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+        let first_slot = manager.dedup_table_address();
+        mem::forget(manager);
+
+        // The table reloads on open, so the pages are found as duplicates.
+        let mut manager = reopen();
+        assert_eq!(manager.dedup_stats().entries, 2);
+        assert_eq!(manager.alloc_placed(&[1; disk::SECTOR_SIZE]).unwrap().execute(), (a, Placement::Duplicate));
+        assert_eq!(manager.alloc_placed(&[2; disk::SECTOR_SIZE]).unwrap().execute(), (b, Placement::Duplicate));
+        mem::forget(manager);
+
+        // Tear the newest table (generation 2, in the first slot). The previous one is loaded.
+        disk.corrupt(first_slot + 1, 0, 0xFF);
+        let manager = reopen();
+        assert_eq!(manager.dedup_stats().entries, 1);
+        assert_eq!(manager.dedup_table.dedup(&[1; disk::SECTOR_SIZE], a.checksum), Some(a));
+        mem::forget(manager);
+
+        // Without a reserved region, the table cannot be persisted.
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        assert_matches!(manager.flush_dedup_table(), Err(Error::DedupPersistenceDisabled));
+    }
+
+    #[test]
+    fn stale_dedup_candidates() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut header = header::DiskHeader::default();
+        header.dedup_sectors = 2;
+        header.index_sectors = 2;
+        let mut manager = setup(&disk, None, header, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            dedup_policy: state_block::DedupPolicy::Always,
+            .. Default::default()
+        });
+
+        // Persist a table holding a page, then free the page, and reuse its cluster.
+        let page = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.flush_dedup_table().unwrap().execute();
+        manager.free(page).unwrap().unwrap().execute();
+        let reused = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(reused.cluster, page.cluster);
+
+        // Crash, with everything on the disk.
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+        mem::forget(manager);
+
+        // The stale candidate is dropped on open, so the content is stored anew.
+        let mut manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.dedup_stats().entries, 0);
+        let (new, placement) = manager.alloc_placed(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(placement == Placement::Fresh);
+        assert_eq!(manager.read(new).unwrap(), [1; disk::SECTOR_SIZE]);
+        assert_eq!(manager.read(reused).unwrap(), [2; disk::SECTOR_SIZE]);
+    }
+
+    /// A drain capturing the message and subsystem of every log record.
+    #[derive(Clone, Default)]
+    struct Capture {
+        records: Arc<Mutex<Vec<(String, Option<String>)>>>,
+    }
+
+    impl slog::Drain for Capture {
+        type Error = ();
+
+        fn log(&self, info: &slog::Record, _: &slog::OwnedKeyValueList) -> Result<(), ()> {
+            let mut subsystem = SubsystemSerializer(None);
+            for &(key, value) in info.values() {
+                value.serialize(info, key, &mut subsystem).unwrap();
+            }
+
+            self.records.lock().push((format!("{}", info.msg()), subsystem.0));
+            Ok(())
+        }
+    }
+
+    /// A serializer picking out the `"subsystem"` key of a record.
+    struct SubsystemSerializer(Option<String>);
+
+    impl slog::ser::Serializer for SubsystemSerializer {
+        fn emit_str(&mut self, key: &str, val: &str) -> slog::ser::Result {
+            if key == "subsystem" {
+                self.0 = Some(val.to_owned());
+            }
+
+            Ok(())
+        }
+
+        fn emit_arguments(&mut self, _: &str, _: &fmt::Arguments) -> slog::ser::Result {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn log_subsystems() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        let capture = Capture::default();
+        manager.driver.log = Box::new(capture.clone());
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+        assert_eq!(manager.read(page).unwrap(), [0xAB; disk::SECTOR_SIZE]);
+
+        let records = capture.records.lock();
+        // Every record is tagged.
+        assert!(records.iter().all(|&(_, ref subsystem)| subsystem.is_some()));
+
+        let subsystem_of = |msg: &str| records.iter()
+            .find(|&&(ref record, _)| record == msg)
+            .and_then(|&(_, ref subsystem)| subsystem.clone());
+        assert_eq!(subsystem_of("allocating page").as_ref().map(|x| &**x), Some(subsystem::ALLOC));
+        assert_eq!(subsystem_of("popping from freelist").as_ref().map(|x| &**x), Some(subsystem::FREELIST));
+        assert_eq!(subsystem_of("compressing data").as_ref().map(|x| &**x), Some(subsystem::COMPRESSION));
+        assert_eq!(subsystem_of("trimming cache").as_ref().map(|x| &**x), Some(subsystem::CACHE));
+        assert_eq!(subsystem_of("writing data").as_ref().map(|x| &**x), Some(subsystem::VDEV));
+    }
+
+    /// A disk returning corrupted data for the next few reads of some sector.
+    #[derive(Clone)]
+    struct Flaky {
+        inner: MemSim,
+        /// The flaky sector.
+        sector: disk::Sector,
+        /// The number of reads of `sector` left to corrupt.
+        corrupt: Arc<Mutex<usize>>,
+        /// Do writes fail?
+        failing_writes: Arc<Mutex<bool>>,
+    }
+
+    impl disk::Disk for Flaky {
+        fn number_of_sectors(&self) -> disk::Sector {
+            self.inner.number_of_sectors()
+        }
+
+        fn write(&mut self, sector: disk::Sector, buf: &disk::SectorBuf) -> Result<(), disk::Error> {
+            if *self.failing_writes.lock() {
+                return Err(disk::Error::CorruptSector {
+                    sector: sector,
+                });
+            }
+
+            self.inner.write(sector, buf)
+        }
+
+        fn read_to(&self, sector: disk::Sector, buf: &mut disk::SectorBuf) -> Result<(), disk::Error> {
+            self.inner.read_to(sector, buf)?;
+
+            // Flip a bit, if the read is to be corrupted.
+            let mut corrupt = self.corrupt.lock();
+            if sector == self.sector && *corrupt > 0 {
+                *corrupt -= 1;
+                buf[0] ^= 1;
+            }
+
+            Ok(())
+        }
+
+        fn heal(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+            Ok(())
+        }
+
+        fn barrier(&mut self) -> Result<(), disk::Error> {
+            self.inner.barrier()
+        }
+    }
+
+    #[test]
+    fn checksum_retries() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+
+        // Put a flaky disk beneath the manager. The cache heals and rereads a sector once on
+        // verification failure, so two corrupted reads make it through to the manager.
+        let flaky = Flaky {
+            inner: disk.clone(),
+            sector: page.cluster.into() as disk::Sector,
+            corrupt: Arc::new(Mutex::new(0)),
+            failing_writes: Arc::new(Mutex::new(false)),
+        };
+        manager.driver.disk = Box::new(flaky.clone());
+
+        // Without retries, the mismatch is surfaced.
+        *flaky.corrupt.lock() = 2;
+        match manager.read(page) {
+            Err(Error::PageChecksumMismatch { .. }) => (),
+            _ => panic!("expected a checksum mismatch"),
+        }
+        // Even once the disk recovers, the corrupted cluster stays in the cache.
+        assert!(manager.read(page).is_err());
+
+        // With retries, the cluster is read from the disk again.
+        manager.set_checksum_retries(1);
+        assert_eq!(manager.read(page).unwrap(), [0xAB; disk::SECTOR_SIZE]);
+
+        manager.cache.trim(0).unwrap();
+        *flaky.corrupt.lock() = 2;
+        assert_eq!(manager.read(page).unwrap(), [0xAB; disk::SECTOR_SIZE]);
+        assert_eq!(*flaky.corrupt.lock(), 0);
+
+        // Persistent corruption exhausts the retries.
+        manager.cache.trim(0).unwrap();
+        *flaky.corrupt.lock() = 4;
+        assert!(manager.read(page).is_err());
+    }
+
+    #[test]
+    fn health_check_failed_write() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        manager.sync_metadata().unwrap();
+
+        let flaky = Flaky {
+            inner: disk.clone(),
+            sector: 0,
+            corrupt: Arc::new(Mutex::new(0)),
+            failing_writes: Arc::new(Mutex::new(false)),
+        };
+        manager.driver.disk = Box::new(flaky.clone());
+
+        // Allocate, and fail to make the allocation durable.
+        manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        *flaky.failing_writes.lock() = true;
+        assert!(manager.sync_metadata().is_err());
+
+        // The failed write degrades the health.
+        let warnings = manager.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_matches!(warnings[0], Warning::WriteFailed(_));
+        assert_eq!(manager.health_check(), HealthStatus::Degraded {
+            reasons: vec![DegradedReason::RecentCorruption {
+                warnings: 1,
+            }],
+        });
+
+        // Once the disk recovers, the metadata makes it to the disk.
+        *flaky.failing_writes.lock() = false;
+        manager.sync_metadata().unwrap();
+    }
+
+    /// A disk recording the sectors it is told to discard.
+    ///
+    /// Discarded sectors are zeroed, like an SSD might do.
+    #[derive(Clone)]
+    struct Discarding {
+        inner: MemSim,
+        /// The discarded sectors, in order.
+        discarded: Arc<Mutex<Vec<disk::Sector>>>,
+    }
+
+    impl disk::Disk for Discarding {
+        fn number_of_sectors(&self) -> disk::Sector {
+            self.inner.number_of_sectors()
+        }
+
+        fn write(&mut self, sector: disk::Sector, buf: &disk::SectorBuf) -> Result<(), disk::Error> {
+            self.inner.write(sector, buf)
+        }
+
+        fn read_to(&self, sector: disk::Sector, buf: &mut disk::SectorBuf) -> Result<(), disk::Error> {
+            self.inner.read_to(sector, buf)
+        }
+
+        fn heal(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+            self.inner.heal(sector)
+        }
+
+        fn discard(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+            self.discarded.lock().push(sector);
+
+            self.inner.write(sector, &[0; disk::SECTOR_SIZE])
+        }
+    }
+
+    #[test]
+    fn trim_on_free() {
+        for &trim_on_free in &[false, true] {
+            let disk = MemSim::new(TEST_SECTORS);
+            let mut manager = manager(&disk, state_block::Config {
+                trim_on_free: trim_on_free,
+                .. Default::default()
+            });
+            let discarding = Discarding {
+                inner: disk.clone(),
+                discarded: Arc::new(Mutex::new(Vec::new())),
+            };
+            manager.driver.disk = Box::new(discarding.clone());
+
+            let page = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+            manager.free(page).unwrap().unwrap().execute();
+
+            // Nothing is discarded before the push is committed.
+            assert!(discarding.discarded.lock().is_empty());
+            manager.cache.trim(0).unwrap();
+
+            // The freed cluster is discarded only if enabled.
+            let expected = if trim_on_free { vec![page.cluster.into()] } else { Vec::new() };
+            assert_eq!(*discarding.discarded.lock(), expected);
+
+            // The cluster is still reused afterwards.
+            let again = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+            assert_eq!(again.cluster, page.cluster);
+            manager.cache.trim(0).unwrap();
+            assert_eq!(manager.read(again).unwrap(), [2; disk::SECTOR_SIZE]);
+        }
+    }
+
+    #[test]
+    fn trim_on_free_reserve() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            trim_on_free: true,
+            reserve_clusters: 1,
+            .. Default::default()
+        });
+        let discarding = Discarding {
+            inner: disk.clone(),
+            discarded: Arc::new(Mutex::new(Vec::new())),
+        };
+        manager.driver.disk = Box::new(discarding.clone());
+
+        // Free every cluster in one batch. The first one tops up the reserve, and is taken by the
+        // head metacluster right away. Every time the head metacluster fills up, the reserve is
+        // depleted and topped up again.
+        let pages = fill(&mut manager, 0);
+        manager.free_range(&pages).unwrap().unwrap().execute();
+        assert!(manager.tail_metaclusters.load(ORDERING) > 0);
+        manager.cache.trim(0).unwrap();
+
+        // None of the metaclusters were discarded, so the freelist is intact.
+        let head = manager.state.lock().freelist_head.unwrap().cluster;
+        assert!(!discarding.discarded.lock().contains(&head.into()));
+        let reserve = manager.state.lock().reserve.len();
+        assert_eq!(reserve, 1);
+        assert_eq!(manager.free_clusters().unwrap() + reserve as u64, pages.len() as u64);
+    }
+
+    #[test]
+    fn trim_on_free_reused() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            trim_on_free: true,
+            .. Default::default()
+        });
+        let discarding = Discarding {
+            inner: disk.clone(),
+            discarded: Arc::new(Mutex::new(Vec::new())),
+        };
+        manager.driver.disk = Box::new(discarding.clone());
+
+        let page = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+
+        // Free the cluster, and reuse it before the push is flushed.
+        manager.free(page).unwrap().unwrap().execute();
+        let again = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(again.cluster, page.cluster);
+        manager.cache.trim(0).unwrap();
+
+        // The write cancelled the discard, so the new content survives.
+        assert!(discarding.discarded.lock().is_empty());
+        assert_eq!(manager.read(again).unwrap(), [2; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn set_cache_size() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        manager.set_max_readahead(0);
+
+        let mut pages = Vec::new();
+        for n in 0..32 {
+            pages.push((n, manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()));
+        }
+        manager.cache.trim(0).unwrap();
+
+        // Grow the cache, and read everything into it.
+        manager.set_cache_size(64).unwrap();
+        assert_eq!(manager.cache.max_sectors(), 64);
+        for &(n, page) in &pages {
+            assert_eq!(manager.read(page).unwrap(), [n; disk::SECTOR_SIZE]);
+        }
+        let grown = manager.cache.len();
+        assert!(grown > 8 && grown <= 64);
+
+        // Dirty some blocks, then shrink the cache below its size.
+        for n in 32..40 {
+            pages.push((n, manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()));
+        }
+        assert!(manager.cache.dirty_blocks() > 0);
+        manager.set_cache_size(8).unwrap();
+        assert!(manager.cache.len() <= 8);
+
+        // Reading stays within the bounds, and nothing was lost.
+        for &(n, page) in &pages {
+            assert_eq!(manager.read(page).unwrap(), [n; disk::SECTOR_SIZE]);
+            assert!(manager.cache.len() <= 8);
+        }
+
+        // The same holds when reading straight from the disk.
+        manager.cache.trim(0).unwrap();
+        for &(n, page) in &pages {
+            assert_eq!(manager.read(page).unwrap(), [n; disk::SECTOR_SIZE]);
+        }
+    }
+
+    #[test]
+    fn dump_layout() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        for n in 0..4 {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute();
+        }
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+
+        let dump = |manager: &Manager| {
+            let mut out = Vec::new();
+            manager.dump_layout(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let free = manager.walk_freelist(&mut |_| ()).unwrap();
+        let metaclusters = manager.load_metacluster_chain(&manager.head_metacluster.lock()).unwrap().len() + 1;
+        let layout = dump(&manager);
+        assert!(layout.contains(&format!("  free clusters: {}\n", free)));
+        assert!(layout.contains(&format!("  metaclusters: {}\n", metaclusters)));
+        assert!(layout.contains(&format!("  sectors: {}\n", TEST_SECTORS)));
+        assert!(layout.contains("  reserve (0 of 0):\n"));
+        assert!(!layout.contains("MISMATCH"));
+
+        // Flip a bit in the first free cluster pointer of the head metacluster.
+        let freelist_head = manager.state.lock().freelist_head.unwrap();
+        disk.corrupt(freelist_head.cluster.into() as disk::Sector, 16, 0x01);
+
+        let layout = dump(&manager);
+        let line = layout.lines().find(|line| line.starts_with(&format!("  {}: ", freelist_head.cluster))).unwrap();
+        assert!(line.contains(&format!("stored checksum {:016x}", freelist_head.checksum)));
+        assert!(line.ends_with("(MISMATCH)"));
+        assert_eq!(layout.matches("MISMATCH").count(), 1);
+    }
+
+    #[test]
+    fn free_on_full_device() {
+        let disk = MemSim::new(TEST_SECTORS);
+        // Store every page in its own cluster.
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        // Fill the device completely.
+        let mut pages = fill(&mut manager, 0);
+        assert_eq!(manager.iter_free_clusters().count(), 0);
+
+        // Freeing needs no free cluster, as the freed cluster becomes the head metacluster.
+        let page = pages.pop().unwrap();
+        manager.free(page).unwrap().unwrap().execute();
+        assert_eq!(manager.state.lock().freelist_head.unwrap().cluster, page.cluster);
+        manager.sync_metadata().unwrap();
+        assert_eq!(manager.walk_freelist(&mut |_| ()).unwrap(), 0);
+
+        // The freed cluster can be allocated again, and everything can be freed.
+        pages.push(manager.alloc(&[0xFF; disk::SECTOR_SIZE]).unwrap().execute());
+        manager.free_range(&pages).unwrap().unwrap().execute();
+        manager.sync_metadata().unwrap();
+        assert_eq!(manager.iter_free_clusters().count(), TEST_SECTORS - manager.first_data_cluster());
+    }
+
+    #[test]
+    fn free_on_full_device_with_reserve() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = Manager::format(driver(&disk), None, state_block::Config {
+            reserve_clusters: 4,
+            .. Default::default()
+        }, FreelistConstruction::Bulk).unwrap();
+
+        // The reserve is set aside at the end of the device, and is never handed out.
+        let reserve = manager.state.lock().reserve.clone();
+        let expected: Vec<_> = (TEST_SECTORS - 4..TEST_SECTORS)
+            .map(|cluster| cluster::Pointer::new(cluster as u64).unwrap())
+            .collect();
+        assert_eq!(reserve, expected);
+        let mut pages = fill(&mut manager, 0);
+        assert_eq!(pages.len(), (TEST_SECTORS - manager.first_data_cluster()) as usize - 4);
+        assert!(pages.iter().all(|page| !reserve.contains(&page.cluster)));
+
+        // Freeing on the full device places the new head metacluster in a reserved cluster, so the
+        // freed cluster stays free.
+        let page = pages.pop().unwrap();
+        manager.free(page).unwrap().unwrap().execute();
+        assert_eq!(manager.state.lock().freelist_head.unwrap().cluster, reserve[3]);
+        assert_eq!(manager.head_metacluster.lock().free, [page.cluster]);
+        assert_eq!(manager.state.lock().reserve, &reserve[..3]);
+
+        // The reserve is persisted in the state block.
+        manager.sync_metadata().unwrap();
+        drop(manager);
+        let mut manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None,
+                                        false, None).unwrap();
+        assert_eq!(manager.state.lock().reserve, &reserve[..3]);
+
+        // The freed cluster is handed out first, and then the exhausted metacluster.
+        assert_eq!(manager.alloc(&noise_page(1000)).unwrap().execute().cluster, page.cluster);
+        assert_eq!(manager.alloc(&noise_page(1001)).unwrap().execute().cluster, reserve[3]);
+        assert_matches!(manager.alloc(&noise_page(1002)), Err(Error::OutOfClusters));
+    }
+
+    #[test]
+    fn free_refills_reserve() {
+        let disk = MemSim::new(TEST_SECTORS);
+        // Building the freelist incrementally pushes the clusters one by one, and the metaclusters
+        // created along the way use up the reserve.
+        let mut manager = Manager::format(driver(&disk), None, state_block::Config {
+            reserve_clusters: 3,
+            .. Default::default()
+        }, FreelistConstruction::Incremental).unwrap();
+        assert!(manager.state.lock().reserve.is_empty());
+
+        // The metaclusters in the formerly reserved clusters are handed out like any other.
+        let mut pages = fill(&mut manager, 0);
+        assert_eq!(pages.len(), (TEST_SECTORS - manager.first_data_cluster()) as usize);
+
+        // The freed clusters top up the reserve, before the freelist grows.
+        for n in 1..4 {
+            let page = pages.pop().unwrap();
+            manager.free(page).unwrap().unwrap().execute();
+            assert_eq!(manager.state.lock().reserve.len(), n);
+            assert_eq!(manager.state.lock().reserve.last(), Some(&page.cluster));
+            assert_eq!(manager.free_clusters().unwrap(), 0);
+        }
+
+        // Once the reserve is full, the freed clusters go to the freelist. The new head metacluster
+        // takes a reserved cluster, which the next freed cluster replaces.
+        let reserve = manager.state.lock().reserve.clone();
+        let page = pages.pop().unwrap();
+        manager.free(page).unwrap().unwrap().execute();
+        assert_eq!(manager.state.lock().freelist_head.unwrap().cluster, reserve[2]);
+        assert_eq!(manager.head_metacluster.lock().free, [page.cluster]);
+        assert_eq!(manager.state.lock().reserve, &reserve[..2]);
+
+        let page = pages.pop().unwrap();
+        manager.free(page).unwrap().unwrap().execute();
+        assert_eq!(manager.state.lock().reserve.len(), 3);
+        assert_eq!(manager.free_clusters().unwrap(), 2);
+    }
+
+    #[test]
+    fn alloc_raw() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Highly compressible pages are stored uncompressed, each in a cluster of its own,
+        // without ever invoking the compressor.
+        let a = manager.alloc_raw(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        let b = manager.alloc_raw(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(manager.compressions.load(ORDERING), 0);
+        assert!(a.offset.is_none() && b.offset.is_none());
+        assert!(a.cluster != b.cluster);
+
+        manager.cache.trim(0).unwrap();
+        assert_eq!(disk.sector(a.cluster), [1; disk::SECTOR_SIZE]);
+        assert_eq!(manager.read(a).unwrap(), [1; disk::SECTOR_SIZE]);
+        assert_eq!(manager.read(b).unwrap(), [2; disk::SECTOR_SIZE]);
+
+        // Raw pages are deduplicated, both ways.
+        assert_eq!(manager.alloc_raw(&[1; disk::SECTOR_SIZE]).unwrap().execute(), a);
+        assert_eq!(manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute(), b);
+        assert_eq!(manager.compressions.load(ORDERING), 0);
+
+        // Ordinary allocations still compress.
+        let c = manager.alloc(&[3; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(manager.compressions.load(ORDERING) > 0);
+        assert_eq!(manager.alloc_raw(&[3; disk::SECTOR_SIZE]).unwrap().execute(), c);
+    }
+
+    #[test]
+    fn read_located() {
+        let data = MemSim::new(TEST_SECTORS);
+        let metadata = MemSim::new(TEST_SECTORS);
+        let mut manager = split_manager(&data, &metadata, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+
+        // The page lives on the data device, at the reported sector.
+        let (buf, location) = manager.read_located(page).unwrap();
+        assert_eq!(buf, [0xAB; disk::SECTOR_SIZE]);
+        assert_eq!(location, PhysicalLocation {
+            device: 0,
+            sector: page.cluster.into() as disk::Sector,
+        });
+        assert_eq!(data.sector(location.sector), [0xAB; disk::SECTOR_SIZE]);
+
+        // The head metacluster lives on the metadata device.
+        let head = manager.state.lock().freelist_head.unwrap().cluster;
+        let (device, sector) = manager.cache.locate(head.into());
+        assert_eq!(device, 1);
+        assert_eq!(sector, head.into() as disk::Sector - manager.cache.metadata_start().unwrap());
+        assert!(metadata.sector(sector) != [0; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn compact_dedup_table() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        let pages: Vec<_> = (0..4)
+            .map(|n| manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute())
+            .collect();
+        assert_eq!(manager.dedup_stats().entries, 4);
+        // Nothing is stale yet.
+        assert_eq!(manager.compact_dedup_table(), 0);
+
+        // Free the clusters of two pages behind the deduplication table's back.
+        for page in &pages[..2] {
+            manager.live.lock().remove(&page.cluster);
+            manager.freelist_push(page.cluster).execute();
+        }
+
+        // Exactly their entries are removed.
+        assert_eq!(manager.compact_dedup_table(), 2);
+        assert_eq!(manager.dedup_stats().entries, 2);
+
+        // The removed pages are stored anew, while the others are still deduplicated.
+        let hits = manager.dedup_stats().hits;
+        manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(manager.dedup_stats().hits, hits);
+        assert_eq!(manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute(), pages[2]);
+        assert_eq!(manager.alloc(&[3; disk::SECTOR_SIZE]).unwrap().execute(), pages[3]);
+        assert_eq!(manager.dedup_stats().hits, hits + 2);
+        assert_eq!(manager.compact_dedup_table(), 0);
+    }
+
+    #[test]
+    fn alloc_isolated() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        let last_cluster = |manager: &Manager| {
+            manager.last_cluster.lock().as_ref().map(|state| (state.cluster, state.uncompressed.len()))
+        };
+
+        // Isolated pages don't become the last allocated cluster...
+        let a = manager.alloc_isolated(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(a.offset, Some(0));
+        assert_eq!(last_cluster(&manager), None);
+
+        // ...nor extend it.
+        let b = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        let before = last_cluster(&manager);
+        assert_eq!(before, Some((b.cluster, disk::SECTOR_SIZE)));
+        let c = manager.alloc_isolated(&[3; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(c.offset, Some(0));
+        assert!(c.cluster != a.cluster && c.cluster != b.cluster);
+        assert_eq!(last_cluster(&manager), before);
+
+        // The last allocated cluster is still extended by ordinary allocations.
+        let d = manager.alloc(&[4; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(d.cluster, b.cluster);
+
+        manager.cache.trim(0).unwrap();
+        for (n, &page) in [a, b, c, d].iter().enumerate() {
+            assert_eq!(manager.read(page).unwrap(), [n as u8 + 1; disk::SECTOR_SIZE]);
+        }
+    }
+
+    #[test]
+    fn head_free_count() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        assert_eq!(manager.head_free_count(), manager.head_metacluster.lock().free.len());
+
+        // The mirror tracks pops...
+        let mut popped = Vec::new();
+        for _ in 0..8 {
+            popped.push(manager.freelist_pop().unwrap().execute());
+            assert_eq!(manager.head_free_count(), manager.head_metacluster.lock().free.len());
+        }
+        // ...and pushes...
+        for cluster in popped {
+            manager.freelist_push(cluster).execute();
+            assert_eq!(manager.head_free_count(), manager.head_metacluster.lock().free.len());
+        }
+        // ...down to an empty freelist...
+        while manager.freelist_pop().is_ok() {
+            assert_eq!(manager.head_free_count(), manager.head_metacluster.lock().free.len());
+        }
+        assert_eq!(manager.head_free_count(), 0);
+
+        // ...and is restored on open.
+        let cluster = cluster::Pointer::new(manager.first_data_cluster() as u64).unwrap();
+        manager.freelist_push(cluster).execute();
+        manager.freelist_push(cluster::Pointer::new(u64::from(cluster) + 1).unwrap()).execute();
+        assert_eq!(manager.head_free_count(), 1);
+        drop(manager);
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.head_free_count(), 1);
+    }
+
+    #[test]
+    fn format() {
+        // A device spanning several full metaclusters.
+        let sectors = (8 * (MAX_FREE + 1) + 100) as disk::Sector;
+        for &construction in &[FreelistConstruction::Bulk, FreelistConstruction::Incremental] {
+            let disk = MemSim::new(sectors);
+            let mut manager = Manager::format(driver(&disk), None, state_block::Config::default(), construction)
+                .unwrap();
+
+            // The freelist holds exactly the data clusters.
+            let first = manager.first_data_cluster();
+            let mut free: Vec<_> = manager.iter_free_clusters().collect();
+            free.sort();
+            let expected: Vec<_> = (first..sectors)
+                .map(|cluster| cluster::Pointer::new(cluster as u64).unwrap())
+                .collect();
+            assert_eq!(free, expected);
+            if construction == FreelistConstruction::Bulk {
+                assert_eq!(manager.load_metacluster_chain(&manager.head_metacluster.lock()).unwrap().len(), 8);
+            }
+
+            // The metacluster checksums are valid on the disk.
+            drop(manager);
+            let mut manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None,
+                                            false, None).unwrap();
+            let metaclusters = manager.load_metacluster_chain(&manager.head_metacluster.lock()).unwrap().len() + 1;
+            assert_eq!(manager.walk_freelist(&mut |_| ()).unwrap(), expected.len() - metaclusters);
+
+            // Every cluster can be allocated.
+            for _ in 0..expected.len() {
+                manager.freelist_pop().unwrap().execute();
+            }
+            assert_matches!(manager.freelist_pop(), Err(Error::OutOfClusters));
+        }
+    }
+
+    #[test]
+    fn format_with_metadata() {
+        // Thousands of clusters, more metaclusters than the metadata device has room for.
+        let sectors = 4000;
+        let data = MemSim::new(sectors);
+        let metadata = MemSim::new(8);
+        let mut manager = Manager::format(driver(&data), Some(driver(&metadata)), state_block::Config::default(),
+                                          FreelistConstruction::Bulk).unwrap();
+
+        // The freelist holds exactly the data clusters.
+        let first = manager.first_data_cluster();
+        let mut free: Vec<_> = manager.iter_free_clusters().collect();
+        free.sort();
+        let expected: Vec<_> = (first..sectors)
+            .map(|cluster| cluster::Pointer::new(cluster as u64).unwrap())
+            .collect();
+        assert_eq!(free, expected);
+
+        // The metaclusters fill the metadata device, and the rest is placed on the data device.
+        let chain = manager.load_metacluster_chain(&manager.head_metacluster.lock()).unwrap();
+        let stacked = manager.state.lock().metaclusters;
+        assert!(stacked > 0);
+        assert!(manager.metadata_metacluster(&manager.state.lock()).is_none());
+        assert_eq!(chain.len(), manager.tail_metaclusters.load(ORDERING));
+        let on_metadata = chain.iter().filter(|&&(cluster, _, _)| manager.cache.is_metadata(cluster.into())).count();
+        assert_eq!(on_metadata as u64, stacked);
+        assert!(on_metadata < chain.len());
+        drop(manager);
+
+        // The chain is intact on the disk.
+        let mut manager = Manager::open(vdev::Driver::open(slog::Discard, data.clone(), b"").unwrap(),
+                                        Some(vdev::Driver::open(slog::Discard, metadata.clone(), b"").unwrap()),
+                                        false, None).unwrap();
+        assert_eq!(manager.state.lock().metaclusters, stacked);
+
+        // Every data cluster can be allocated, and the stacked metaclusters are popped along the way.
+        for _ in 0..expected.len() {
+            manager.freelist_pop().unwrap().execute();
+        }
+        assert_matches!(manager.freelist_pop(), Err(Error::OutOfClusters));
+        assert_eq!(manager.state.lock().metaclusters, 0);
+    }
+
+    #[test]
+    fn heal_from_mirror() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut header = header::DiskHeader::default();
+        header.vdev_stack.push(header::Vdev::Mirror);
+        let mut manager = Manager::format(driver_with_header(&disk, header), None, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        }, FreelistConstruction::Bulk).unwrap();
+        // The higher half of the disk mirrors the lower half.
+        let half = manager.driver.number_of_sectors();
+        assert_eq!(half, TEST_SECTORS / 2);
+
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.trim(0).unwrap();
+        let sector = page.cluster.into() as disk::Sector;
+        assert_eq!(disk.sector(sector + half), [0xAB; disk::SECTOR_SIZE]);
+
+        // Corrupt the primary copy. The read is served from the mirror, and the primary repaired.
+        disk.corrupt(sector, 0, 0x01);
+        assert_eq!(manager.read(page).unwrap(), [0xAB; disk::SECTOR_SIZE]);
+        assert_eq!(disk.sector(sector), [0xAB; disk::SECTOR_SIZE]);
+        // The repair is recorded.
+        assert_eq!(manager.warnings(), vec![Warning::Healed(page.cluster)]);
+
+        // With both copies corrupt, the mismatch is surfaced.
+        manager.cache.trim(0).unwrap();
+        disk.corrupt(sector, 0, 0x01);
+        disk.corrupt(sector + half, 0, 0x01);
+        assert_matches!(manager.read(page), Err(Error::PageChecksumMismatch { page: x, .. })
+                        if x == page);
+        assert_eq!(manager.warnings(), vec![Warning::Healed(page.cluster), Warning::ChecksumMismatch(page)]);
+    }
+
+    #[test]
+    fn free_on_refilled_device() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+        let clusters = (TEST_SECTORS - manager.first_data_cluster()) as usize;
+
+        // Fill the device, and free half of it, over and over. Every freed cluster becomes a
+        // metacluster or goes into one, so no cluster has to be set aside for the freelist.
+        let mut pages = VecDeque::new();
+        for round in 0..4 {
+            // Seed every round apart, so nothing is deduplicated.
+            pages.extend(fill(&mut manager, round * TEST_SECTORS as u64));
+            assert_eq!(pages.len(), clusters);
+
+            for _ in 0..clusters / 2 {
+                manager.free(pages.pop_front().unwrap()).unwrap().unwrap().execute();
+            }
+            manager.sync_metadata().unwrap();
+            assert_eq!(manager.iter_free_clusters().count(), clusters / 2);
+        }
+    }
+
+    #[test]
+    fn read_batch() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Allocate pages which are half pseudorandom, so only a few fit in every cluster.
+        let mut bufs = Vec::new();
+        let mut pages = Vec::new();
+        for n in 0..64 {
+            let mut buf = noise_page(n);
+            for byte in &mut buf[disk::SECTOR_SIZE / 2..] {
+                *byte = 0;
+            }
+
+            pages.push(manager.alloc(&buf).unwrap().execute());
+            bufs.push(buf);
+        }
+
+        // The batch spans many compressed clusters.
+        let clusters: BTreeSet<_> = pages.iter().filter(|page| page.offset.is_some()).map(|page| page.cluster)
+            .collect();
+        assert!(clusters.len() >= 8);
+
+        // Read the pages backwards, with some of them twice.
+        let batch: Vec<_> = (0..pages.len()).rev().chain(0..8).collect();
+        let batch_pages: Vec<_> = batch.iter().map(|&n| pages[n]).collect();
+
+        // Every worker count gives the pages in order.
+        for &workers in &[1, 3, 8, 100] {
+            let read = manager.read_batch(&batch_pages, workers).unwrap();
+            assert_eq!(read.len(), batch.len());
+            for (buf, &n) in read.iter().zip(&batch) {
+                assert_eq!(buf[..], bufs[n][..]);
+            }
+        }
+
+        // An empty batch is fine too.
+        assert!(manager.read_batch(&[], 4).unwrap().is_empty());
+
+        // A page of a corrupt cluster fails the batch, rather than being handed out.
+        manager.cache.trim(0).unwrap();
+        let corrupt = pages.iter().position(|page| page.offset == Some(0)).unwrap();
+        let sector = pages[corrupt].cluster.into() as disk::Sector;
+        disk.corrupt(sector, 8, 0x01);
+        assert!(manager.read_batch(&batch_pages, 4).is_err());
+        // The pages of the other clusters are intact.
+        let intact: Vec<_> = (0..pages.len()).filter(|&n| pages[n].cluster != pages[corrupt].cluster).collect();
+        let intact_pages: Vec<_> = intact.iter().map(|&n| pages[n]).collect();
+        for (buf, &n) in manager.read_batch(&intact_pages, 4).unwrap().iter().zip(&intact) {
+            assert_eq!(buf[..], bufs[n][..]);
+        }
+    }
+
+    #[test]
+    fn verify_page() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        let good = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        let bad = manager.alloc(&[0xCD; disk::SECTOR_SIZE]).unwrap().execute();
+        // Flush and evict the cache, so the corruption isn't hidden by it.
+        manager.cache.trim(0).unwrap();
+        disk.corrupt(bad.cluster.into() as disk::Sector, 100, 0x01);
+
+        // Neither verification takes a buffer from the pool.
+        let allocations = manager.pool.allocations();
+        assert!(manager.verify_page(good).is_ok());
+        assert_matches!(manager.verify_page(bad), Err(Error::PageChecksumMismatch { page, .. })
+                        if page == bad);
+        assert_eq!(manager.pool.allocations(), allocations);
+    }
+
+    #[test]
+    fn drain_transactions() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+        manager.sync_metadata().unwrap();
+        let state_block = disk.sector(manager.state_block_address());
+
+        // Queue some allocations.
+        manager.set_deferred_transactions(true).unwrap();
+        let pages: Vec<_> = (1..5).map(|n| {
+            let buf = [n; disk::SECTOR_SIZE];
+            (manager.alloc(&buf).unwrap().execute(), buf)
+        }).collect();
+
+        // Neither flushing nor trimming the cache touches the device.
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+        for &(page, _) in &pages {
+            assert_eq!(disk.sector(page.cluster.into() as disk::Sector), [0; disk::SECTOR_SIZE]);
+        }
+        assert_eq!(disk.sector(manager.state_block_address()), state_block);
+
+        // Draining lands every page, along with the allocation metadata.
+        manager.drain_transactions().unwrap();
+        for &(page, buf) in &pages {
+            assert_eq!(disk.sector(page.cluster.into() as disk::Sector), buf);
+        }
+        assert!(disk.sector(manager.state_block_address()) != state_block);
+
+        // Leaving deferred mode drains as well.
+        let buf = [5; disk::SECTOR_SIZE];
+        let page = manager.alloc(&buf).unwrap().execute();
+        manager.set_deferred_transactions(false).unwrap();
+        assert_eq!(disk.sector(page.cluster.into() as disk::Sector), buf);
+
+        // The pages read back after reopening.
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        for (page, buf) in pages {
+            assert_eq!(manager.read(page).unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn deferred_transactions_fill_cache() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+        manager.sync_metadata().unwrap();
+        manager.set_cache_size(16).unwrap();
+
+        // Queue more allocations than the cache holds.
+        manager.set_deferred_transactions(true).unwrap();
+        let pages: Vec<_> = (1..33).map(|n| {
+            let buf = [n; disk::SECTOR_SIZE];
+            (manager.alloc(&buf).unwrap().execute(), buf)
+        }).collect();
+
+        // Deferred mode was left once the queue filled the cache, so the cache stays within its
+        // bounds.
+        assert!(!manager.cache.deferring.load(ORDERING));
+        assert_eq!(manager.cache.deferred_sectors(), 0);
+        assert!(manager.cache.len() <= 16);
+
+        // Every page made it to the disk.
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+        for (page, buf) in pages {
+            assert_eq!(manager.read(page).unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn health_check() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+        assert_eq!(manager.health_check(), HealthStatus::Healthy);
+
+        // Fill the device, until few clusters are left.
+        let mut pages = Vec::new();
+        while manager.free_clusters().unwrap() as usize >= LOW_SPACE_CLUSTERS / 2 {
+            let mut buf = [0; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, pages.len() as u64);
+            pages.push(manager.alloc(&buf).unwrap().execute());
+        }
+        let free = manager.free_clusters().unwrap() as usize;
+        assert_eq!(manager.health_check(), HealthStatus::Degraded {
+            reasons: vec![DegradedReason::LowSpace {
+                free: free,
+            }],
+        });
+
+        // Corrupt a page, and read it.
+        manager.cache.trim(0).unwrap();
+        disk.corrupt(pages[0].cluster.into() as disk::Sector, 100, 0x01);
+        assert!(manager.read(pages[0]).is_err());
+        assert_eq!(manager.warnings(), vec![Warning::ChecksumMismatch(pages[0])]);
+        assert_eq!(manager.health_check(), HealthStatus::Degraded {
+            reasons: vec![DegradedReason::LowSpace {
+                free: free,
+            }, DegradedReason::RecentCorruption {
+                warnings: 1,
+            }],
+        });
+
+        // Exhaust the device.
+        loop {
+            let mut buf = [0; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, pages.len() as u64);
+            match manager.alloc(&buf) {
+                Ok(page) => pages.push(page.execute()),
+                Err(_) => break,
+            }
+        }
+        assert_eq!(manager.health_check(), HealthStatus::Failing);
+    }
+
+    #[test]
+    fn reopen_with_other_algorithm() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(page.offset.is_some());
+        let address = manager.state_block_address();
+        let checksum_algorithm = manager.driver.header.checksum_algorithm;
+        manager.shutdown().unwrap();
+
+        // Switch the algorithm behind the back of the manager. The clusters aren't tagged, so
+        // they're read as Zstandard.
+        let mut state_block = state_block::StateBlock::decode(&disk.sector(address), checksum_algorithm).unwrap();
+        state_block.config.compression_algorithm = state_block::CompressionAlgorithm::Zstd;
+        disk.clone().write(address, &state_block.encode(checksum_algorithm)).unwrap();
+
+        // The LZ4 cluster fails to decompress, rather than giving back garbage.
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_matches!(manager.read(page), Err(Error::InvalidCompression { cluster, kind })
+                        if cluster == page.cluster && kind == CompressionCorruption::DataCorrupt);
+    }
+
+    #[test]
+    fn corrupt_zstd_frame() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Zstd,
+            .. Default::default()
+        });
+        let cluster = cluster::Pointer::new(100).unwrap();
+        let mut input = [0; disk::SECTOR_SIZE];
+        for (n, byte) in input.iter_mut().enumerate() {
+            *byte = (n % 7) as u8 * 3;
+        }
+        let buf = manager.compress(state_block::CompressionAlgorithm::Zstd, &input).unwrap();
+        let frame = unpad(&buf).unwrap().len();
+
+        // Flipping any byte of the frame is caught by Zstandard, rather than giving back garbage.
+        for n in LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + frame {
+            let mut corrupted = buf;
+            corrupted[n] ^= 0xFF;
+            let mut out = Vec::new();
+            assert_matches!(manager.decompress(cluster, &corrupted, &mut out),
+                            Err(Error::InvalidCompression { kind: CompressionCorruption::DataCorrupt, .. }));
+        }
+
+        // The intact frame still decompresses.
+        let mut out = Vec::new();
+        manager.decompress(cluster, &buf, &mut out).unwrap();
+        assert_eq!(out[..], input[..]);
+    }
+
+    #[test]
+    fn compression_level() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Zstd,
+            compression_level: 250,
+            .. Default::default()
+        });
+        assert_eq!(manager.compression_level(), 250);
+
+        // The level is out of range, so it is clamped rather than rejected by the compressor.
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(page.offset.is_some());
+        assert_eq!(manager.read(page).unwrap(), [0xAB; disk::SECTOR_SIZE]);
+
+        // The level survives reopening.
+        manager.shutdown().unwrap();
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.config.compression_level, 250);
+        assert_eq!(manager.compression_level(), 250);
+        assert_eq!(manager.read(page).unwrap(), [0xAB; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn padding() {
+        // Data ending in 0xFF, in zeros, or in both, is recovered exactly.
+        for data in &[&[1, 2, 0xFF][..], &[1, 2, 0, 0], &[0xFF, 0], &[0, 0xFF, 0xFF], &[0], &[]] {
+            assert_eq!(unpad(&pad(data).unwrap()), Some(*data));
+        }
+        let run = [0xFF; 100];
+        assert_eq!(unpad(&pad(&run).unwrap()), Some(&run[..]));
+
+        // The length is stored in front of the data.
+        let buf = pad(&[7; 300]).unwrap();
+        assert_eq!(LittleEndian::read_u16(&buf), 300);
+        assert_eq!(buf[LENGTH_PREFIX_SIZE + 299], 7);
+        assert!(buf[LENGTH_PREFIX_SIZE + 300..].iter().all(|&x| x == 0));
+
+        // Data filling the cluster exactly fits, however it ends.
+        for &last in &[0, 0xFF] {
+            let mut data = [0xFF; disk::SECTOR_SIZE - LENGTH_PREFIX_SIZE];
+            data[disk::SECTOR_SIZE - LENGTH_PREFIX_SIZE - 1] = last;
+            let buf = pad(&data).unwrap();
+            assert_eq!(buf[disk::SECTOR_SIZE - 1], last);
+            assert_eq!(unpad(&buf), Some(&data[..]));
+        }
+        // Any more doesn't.
+        assert_eq!(pad(&[0; disk::SECTOR_SIZE - LENGTH_PREFIX_SIZE + 1]), None);
+
+        // Length prefixes exceeding the cluster are rejected.
+        let mut buf = [0; disk::SECTOR_SIZE];
+        LittleEndian::write_u16(&mut buf, (disk::SECTOR_SIZE - LENGTH_PREFIX_SIZE + 1) as u16);
+        assert_eq!(unpad(&buf), None);
+        LittleEndian::write_u16(&mut buf, 0xFFFF);
+        assert_eq!(unpad(&buf), None);
+    }
+
+    #[test]
+    fn bench_workloads() {
+        // Run every workload of the benchmarks (see `benches/alloc.rs`) once.
+        let lz4 = state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        };
+        let configs = [state_block::Config::default(), state_block::Config {
+            checksum_width: state_block::ChecksumWidth::Wide,
+            dedup_policy: state_block::DedupPolicy::Adaptive,
+            .. Default::default()
+        }, state_block::Config {
+            dedup_policy: state_block::DedupPolicy::Disabled,
+            .. Default::default()
+        }, lz4];
+        let compressible = |n: usize| {
+            let mut buf = [0; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, n as u64);
+            buf
+        };
+
+        // Unique, duplicate and compressible allocations.
+        for &config in &configs {
+            for &unique in &[true, false] {
+                let disk = MemSim::new(TEST_SECTORS);
+                let manager = manager(&disk, config);
+                for n in 0..128 {
+                    manager.alloc(&noise_page(if unique { n } else { 0 })).unwrap().execute();
+                }
+            }
+        }
+        let disk = MemSim::new(TEST_SECTORS);
+        let packed = manager(&disk, lz4);
+        let pages: Vec<_> = (0..128).map(|n| packed.alloc(&compressible(n)).unwrap().execute()).collect();
+        assert!(pages.iter().all(|page| page.offset.is_some()));
+        let disk = MemSim::new(TEST_SECTORS);
+        let bufs: Vec<_> = (0..128).map(compressible).collect();
+        execute_batch(manager(&disk, lz4).alloc_many(&bufs).unwrap());
+
+        // Allocation churn.
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut churned = manager(&disk, state_block::Config::default());
+        for n in 0..128 {
+            let page = churned.alloc(&noise_page(n)).unwrap().execute();
+            if let Some(transaction) = churned.free(page).unwrap() {
+                transaction.execute();
+            }
+        }
+
+        // Random and batched reads of mixed pages.
+        let disk = MemSim::new(TEST_SECTORS);
+        let mixed = manager(&disk, lz4);
+        let bufs: Vec<_> = (0..128).map(|n| if n % 4 == 3 { noise_page(n as u64) } else { compressible(n) }).collect();
+        let pages: Vec<_> = bufs.iter().map(|buf| mixed.alloc(buf).unwrap().execute()).collect();
+        for n in 0..128 {
+            let n = n * 0x9E3779B9 % 128;
+            assert_eq!(mixed.read(pages[n]).unwrap(), bufs[n]);
+        }
+        assert_eq!(mixed.read_batch(&pages, 4).unwrap(), bufs);
+
+        // Checksums, which tell the pages apart.
+        for &algorithm in &[header::ChecksumAlgorithm::SeaHash, header::ChecksumAlgorithm::Crc32c] {
+            let mut checksums: Vec<_> = bufs.iter().map(|buf| algorithm.hash(buf)).collect();
+            checksums.sort();
+            checksums.dedup();
+            assert_eq!(checksums.len(), bufs.len());
+        }
+    }
+
+    #[test]
+    fn compress_roundtrip() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        let cluster = cluster::Pointer::new(100).unwrap();
+
+        // Pages ending in 0xFF and in zeros survive compression.
+        let mut ends_in_ff = [0; disk::SECTOR_SIZE];
+        ends_in_ff[disk::SECTOR_SIZE - 1] = 0xFF;
+        let mut ends_in_zeros = [0xFF; disk::SECTOR_SIZE];
+        ends_in_zeros[disk::SECTOR_SIZE - 100..].copy_from_slice(&[0; 100]);
+        let mut ends_in_run = [0; disk::SECTOR_SIZE];
+        ends_in_run[disk::SECTOR_SIZE - 100..].copy_from_slice(&[0xFF; 100]);
+        for input in &[ends_in_ff, ends_in_zeros, ends_in_run] {
+            let buf = manager.compress(state_block::CompressionAlgorithm::Lz4, input).unwrap();
+            let mut out = Vec::new();
+            manager.decompress(cluster, &buf, &mut out).unwrap();
+            assert_eq!(out[..], input[..]);
+        }
+
+        // So does a page ending in a run of 0xFF bytes, when packed into a cluster.
+        let page = manager.alloc(&ends_in_run).unwrap().execute();
+        assert_eq!(manager.read(page).unwrap(), ends_in_run);
+    }
+
+    #[test]
+    fn crc32c() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut header = header::DiskHeader::default();
+        header.checksum_algorithm = header::ChecksumAlgorithm::Crc32c;
+        let mut manager = setup(&disk, None, header, state_block::Config::default());
+
+        // The page checksums are CRCs.
+        let buf = [0xAB; disk::SECTOR_SIZE];
+        let page = manager.alloc(&buf).unwrap().execute();
+        assert_eq!(page.checksum, header::ChecksumAlgorithm::Crc32c.hash(&buf));
+
+        // So are the metacluster checksums, which are verified when reopening.
+        manager.shutdown().unwrap();
+        let mut manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false,
+                                        None).unwrap();
+        assert_eq!(manager.driver.header.checksum_algorithm, header::ChecksumAlgorithm::Crc32c);
+        assert_eq!(manager.read(page).unwrap(), buf);
+        manager.alloc(&[0xCD; disk::SECTOR_SIZE]).unwrap().execute();
+    }
+
+    #[test]
+    fn free_clusters() {
+        let disk = MemSim::new(2 * (MAX_FREE as disk::Sector + 1) + 20);
+        let driver = driver(&disk);
+        let first = driver.header.state_block_address + 1;
+        let mut manager = Manager::new(Cache::from(driver), state_block::Config::default(), 0,
+                                       state_block::State::default());
+        assert_eq!(manager.free_clusters().unwrap(), 0);
+        assert_eq!(manager.approx_free_clusters(), 0);
+
+        // Push every cluster. The first of every `MAX_FREE + 1` pushes becomes a metacluster.
+        let pushed = (disk.number_of_sectors() - first) as usize;
+        for cluster in first..disk.number_of_sectors() {
+            manager.freelist_push(cluster::Pointer::new(cluster as u64).unwrap()).execute();
+        }
+        let metaclusters = (pushed + MAX_FREE) / (MAX_FREE + 1);
+        assert_eq!(metaclusters, 3);
+        // The metaclusters are handed out too, so they're counted.
+        assert_eq!(manager.free_clusters().unwrap(), pushed as u64);
+
+        // The following metaclusters are full, so the approximation is exact, save for the
+        // metaclusters.
+        assert_eq!(manager.approx_free_clusters(), (pushed - metaclusters) as u64);
+
+        // Popping is reflected by both.
+        manager.freelist_pop().unwrap().execute();
+        assert_eq!(manager.free_clusters().unwrap(), pushed as u64 - 1);
+        assert_eq!(manager.approx_free_clusters(), (pushed - metaclusters - 1) as u64);
+
+        // Popping every free cluster of the head metacluster, and then the metacluster itself,
+        // switches to the next metacluster.
+        while manager.head_free_count() > 0 {
+            manager.freelist_pop().unwrap().execute();
+        }
+        manager.freelist_pop().unwrap().execute();
+        assert_eq!(manager.approx_free_clusters(), 2 * MAX_FREE as u64);
+        assert_eq!(manager.free_clusters().unwrap(), 2 * (MAX_FREE as u64 + 1));
+    }
+
+    #[test]
+    fn lowest_first_within_head_metacluster() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        // Empty the freelist, and set up a new head metacluster.
+        while manager.freelist_pop().is_ok() {}
+        let first = manager.first_data_cluster() as u64;
+        manager.freelist_push(cluster::Pointer::new(first).unwrap()).execute();
+
+        // Free high clusters, then low ones.
+        manager.set_allocation_strategy(AllocationStrategy::LowestFirst).unwrap();
+        for &cluster in &[first + 30, first + 20, first + 5, first + 10] {
+            manager.freelist_push(cluster::Pointer::new(cluster).unwrap()).execute();
+        }
+
+        // The lowest cluster of the head metacluster is popped first, rather than the most
+        // recently freed.
+        for &cluster in &[first + 5, first + 10, first + 20, first + 30] {
+            assert_eq!(manager.freelist_pop().unwrap().execute(), cluster::Pointer::new(cluster).unwrap());
+        }
+    }
+
+    #[test]
+    fn lowest_first_across_metaclusters() {
+        let data = MemSim::new(TEST_SECTORS);
+        let metadata = MemSim::new(TEST_SECTORS);
+        // The metaclusters live on the metadata device, so every cluster popped is a free one.
+        let mut manager = split_manager(&data, &metadata, state_block::Config::default());
+
+        // Empty the freelist, and sort the clusters.
+        let mut clusters = Vec::new();
+        while let Ok(cluster) = manager.freelist_pop() {
+            clusters.push(cluster.execute());
+        }
+        clusters.sort();
+        assert!(clusters.len() > 2 * MAX_FREE + 30);
+        let (low, rest) = clusters.split_at(MAX_FREE + 20);
+        let (middle, high) = rest.split_at(10);
+
+        // Free more low clusters than a metacluster holds, and then the high ones, which end up
+        // in front of the low ones.
+        for &cluster in low.iter().chain(high) {
+            manager.freelist_push(cluster).execute();
+        }
+        assert!(manager.load_metacluster_chain(&manager.head_metacluster.lock()).unwrap().len() > 1);
+
+        // Switching to lowest-first sorts the whole freelist, and the clusters in between are then
+        // freed into their place in the chain.
+        manager.set_allocation_strategy(AllocationStrategy::LowestFirst).unwrap();
+        for &cluster in middle.iter().rev() {
+            manager.freelist_push(cluster).execute();
+        }
+
+        // The free clusters are popped in ascending order, across the metaclusters.
+        for &cluster in low.iter().chain(middle).chain(high) {
+            assert_eq!(manager.freelist_pop().unwrap().execute(), cluster);
+        }
+        assert!(manager.freelist_pop().is_err());
+    }
+
+    #[test]
+    fn open_conflicting_config() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            secondary_compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+        manager.sync_metadata().unwrap();
+        drop(manager);
+
+        let driver = vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap();
+        assert_matches!(Manager::open(driver, None, false, None),
+                        Err(Error::Config(state_block::ConfigError::SecondaryCompressionWithoutCompression)));
+    }
+
+    #[test]
+    fn read_cluster_decompressed() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Pack three pages into a cluster.
+        let pages: Vec<_> = (0..3u8).map(|n| manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()).collect();
+        assert!(pages.iter().all(|page| page.cluster == pages[0].cluster));
+
+        // The decompressed cluster holds exactly the three pages, in order.
+        let decompressed = manager.read_cluster_decompressed(pages[0].cluster).unwrap();
+        assert_eq!(decompressed.len(), 3 * disk::SECTOR_SIZE);
+        for (n, page) in decompressed.chunks(disk::SECTOR_SIZE).enumerate() {
+            assert!(page.iter().all(|&x| x == n as u8));
+        }
+
+        // Free clusters aren't in use.
+        let free = manager.iter_free_clusters().next().unwrap();
+        assert_matches!(manager.read_cluster_decompressed(free), Err(Error::ClusterNotInUse { .. }));
+    }
+
+    #[test]
+    fn migrate_checksum() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Store compressed and uncompressed pages.
+        let mut bufs: Vec<disk::SectorBuf> = (0..4u8).map(|n| [n; disk::SECTOR_SIZE]).collect();
+        let mut noise = [0; disk::SECTOR_SIZE];
+        for (n, x) in noise.iter_mut().enumerate() {
+            *x = (n * 7919 % 251) as u8 ^ (n >> 3) as u8;
+        }
+        bufs.push(noise);
+        let pages: Vec<_> = bufs.iter().map(|buf| manager.alloc(buf).unwrap().execute()).collect();
+
+        // Keep a page only in a snapshot.
+        let snapshot = manager.snapshot();
+        let swapped = manager.atomic_swap(pages[0], &[9; disk::SECTOR_SIZE]).unwrap();
+
+        let from = manager.driver.header.checksum_algorithm;
+        let mut remapped = Vec::new();
+        manager.migrate_checksum(header::ChecksumAlgorithm::Sha256, &mut |old, new| {
+            remapped.push((old, new));
+        }).unwrap();
+        let remapped = |page: &page::Pointer| remapped.iter().find(|&&(old, _)| old == *page).unwrap().1;
+        assert_eq!(manager.driver.header.checksum_algorithm, header::ChecksumAlgorithm::Sha256);
+        assert_eq!(manager.driver.header.previous_checksum_algorithm, Some(from));
+
+        // Every page validates under the new algorithm, at its old location.
+        for (page, buf) in pages.iter().zip(&bufs) {
+            let new = remapped(page);
+            assert_eq!(new.cluster, page.cluster);
+            assert_eq!(new.offset, page.offset);
+            assert_ne!(new.checksum, page.checksum);
+            assert_eq!(manager.read(new).unwrap(), *buf);
+            // The old pointer still validates under the previous algorithm.
+            assert_eq!(manager.read(*page).unwrap(), *buf);
+        }
+        // The snapshot was remapped as well.
+        assert_eq!(manager.read_at(snapshot, remapped(&pages[0])).unwrap(), bufs[0]);
+        assert_eq!(manager.read(remapped(&swapped)).unwrap(), [9; disk::SECTOR_SIZE]);
+
+        // The metadata was rewritten under the new algorithm, so the manager opens again.
+        drop(manager);
+        let mut manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.driver.header.checksum_algorithm, header::ChecksumAlgorithm::Sha256);
+        for (page, buf) in pages.iter().zip(&bufs) {
+            assert_eq!(manager.read(remapped(page)).unwrap(), *buf);
+        }
+
+        // Without the live page index, the pages allocated before the reopen are unknown.
+        assert_matches!(manager.migrate_checksum(header::ChecksumAlgorithm::Crc32c, &mut |_, _| ()),
+                        Err(Error::IndexIncomplete));
+    }
+
+    #[test]
+    fn migrate_checksum_crash() {
+        // Crash after every number of writes in turn, until the migration goes through.
+        for writes in 0.. {
+            let disk = MemSim::new(TEST_SECTORS);
+            let mut header = header::DiskHeader::default();
+            header.index_sectors = 2;
+            header.dedup_sectors = 2;
+            let mut manager = setup(&disk, None, header, state_block::Config {
+                compression_algorithm: state_block::CompressionAlgorithm::Identity,
+                .. Default::default()
+            });
+
+            let bufs: Vec<disk::SectorBuf> = (0..4u8).map(|n| [n; disk::SECTOR_SIZE]).collect();
+            let pages: Vec<_> = bufs.iter().map(|buf| manager.alloc(buf).unwrap().execute()).collect();
+            // Trash another page.
+            let trashed = manager.alloc(&[9; disk::SECTOR_SIZE]).unwrap().execute();
+            assert!(manager.free_to_trash(trashed).unwrap().is_none());
+            assert!(!manager.trash.lock().is_empty());
+            manager.sync_metadata().unwrap();
+            manager.cache.trim(0).unwrap();
+
+            disk.crash_after(writes);
+            let mut remapped = Vec::new();
+            let res = manager.migrate_checksum(header::ChecksumAlgorithm::Sha256, &mut |old, new| {
+                remapped.push((old, new));
+            });
+            let image = disk.snapshot();
+            mem::forget(manager);
+
+            // Reopen the disk as it was when the writes stopped reaching it. The old pointers are
+            // valid either way.
+            let manager = Manager::open(vdev::Driver::open(slog::Discard, image, b"").unwrap(), None, false, None).unwrap();
+            for (page, buf) in pages.iter().zip(&bufs) {
+                assert_eq!(manager.read(*page).unwrap(), *buf);
+            }
+
+            if res.is_ok() {
+                // The migration went through, so the new pointers are valid, and the trash was
+                // evicted.
+                assert_eq!(manager.driver.header.checksum_algorithm, header::ChecksumAlgorithm::Sha256);
+                assert_eq!(remapped.len(), pages.len());
+                for &(old, new) in &remapped {
+                    assert_eq!(manager.read(new).unwrap(), manager.read(old).unwrap());
+                }
+                assert!(manager.trash.lock().is_empty());
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn alloc_with_checksum() {
+        let other_disk = MemSim::new(TEST_SECTORS);
+        let mut other = manager(&other_disk, state_block::Config::default());
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let buf = [0xAB; disk::SECTOR_SIZE];
+        let checksum = manager.driver.header.hash(&buf);
+        let page = manager.alloc_with_checksum(&buf, checksum).unwrap().execute();
+
+        // The fast path gives the same pointer as the regular path.
+        assert_eq!(page, other.alloc(&buf).unwrap().execute());
+        assert_eq!(manager.read(page).unwrap(), buf);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic]
+    fn alloc_with_wrong_checksum() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let buf = [0xAB; disk::SECTOR_SIZE];
+        let checksum = manager.driver.header.hash(&buf);
+        manager.alloc_with_checksum(&buf, checksum ^ 1).unwrap().execute();
+    }
+
+    #[test]
+    fn import_dedup_entries() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        let buf = [0xAB; disk::SECTOR_SIZE];
+        let page = manager.alloc(&buf).unwrap().execute();
+        let checksum = manager.driver.header.hash(&buf);
+        manager.shutdown().unwrap();
+
+        // A freshly opened manager knows nothing of the page...
+        let mut manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert_eq!(manager.dedup_stats().entries, 0);
+
+        // ...until it is imported. The inconsistent entry, the entry not matching the page, and
+        // the entry of a free cluster are skipped.
+        let bogus = page::Pointer {
+            checksum: page.checksum ^ 1,
+            .. page
+        };
+        let free = page::Pointer {
+            cluster: manager.iter_free_clusters().next().unwrap(),
+            .. page
+        };
+        assert_eq!(manager.import_dedup_entries(vec![
+            (checksum, page),
+            (checksum, bogus),
+            (checksum ^ 1, bogus),
+            (checksum, free),
+        ]), 1);
+        assert_eq!(manager.dedup_stats().entries, 1);
+
+        // The page is deduplicated right away.
+        assert_eq!(manager.alloc(&buf).unwrap().execute(), page);
+        assert_eq!(manager.dedup_stats().hits, 1);
+    }
+
+    #[test]
+    fn open_after_format() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let config = state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        };
+        let mut manager = Manager::format(driver(&disk), None, config, FreelistConstruction::default()).unwrap();
+        let pages: Vec<_> = (0..4u8).map(|n| {
+            (manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute(), [n; disk::SECTOR_SIZE])
+        }).collect();
+        let address = manager.state_block_address();
+        let checksum_algorithm = manager.driver.header.checksum_algorithm;
+        manager.shutdown().unwrap();
+        let freelist_head = state_block::StateBlock::decode(&disk.sector(address), checksum_algorithm).unwrap()
+            .state.freelist_head.unwrap();
+
+        // The state is loaded, while the in-memory state starts out fresh.
+        let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert!(manager.config == config);
+        assert_eq!(manager.state.lock().freelist_head, Some(freelist_head));
+        assert_eq!(manager.head_metacluster.lock().free.len(), freelist_head.counter as usize);
+        assert!(manager.last_cluster.lock().is_none());
+        assert_eq!(manager.dedup_stats().entries, 0);
+        for &(page, buf) in &pages {
+            assert_eq!(manager.read(page).unwrap(), buf);
+        }
+        drop(manager);
+
+        // A corrupt head metacluster is caught.
+        disk.corrupt(freelist_head.cluster.into() as disk::Sector, 16, 0x01);
+        let driver = vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap();
+        assert_matches!(Manager::open(driver, None, false, None),
+                        Err(Error::MetacluterChecksumMismatch { cluster, .. })
+                        if cluster == freelist_head.cluster);
+        disk.corrupt(freelist_head.cluster.into() as disk::Sector, 16, 0x01);
+
+        // So is a corrupt state block.
+        disk.corrupt(address, 40, 0x01);
+        let driver = vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap();
+        assert_matches!(Manager::open(driver, None, false, None),
+                        Err(Error::StateBlock(state_block::Error::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn open_blank_device() {
+        let disk = MemSim::new(TEST_SECTORS);
+        assert_matches!(Manager::open(driver(&disk), None, false, None), Err(Error::NotFormatted));
+    }
+
+    #[test]
+    fn read_and_cache() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let pages: Vec<_> = (0..4).map(|n| {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute()
+        }).collect();
+        // Flush and evict everything.
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+
+        manager.read_and_cache(&pages).unwrap();
+
+        // Reading the pages is now served by the cache.
+        let (hits, misses) = (manager.cache.hits(), manager.cache.misses());
+        for (n, &page) in pages.iter().enumerate() {
+            assert_eq!(manager.read(page).unwrap()[..], [n as u8; disk::SECTOR_SIZE][..]);
+        }
+        assert_eq!(manager.cache.misses(), misses);
+        assert_eq!(manager.cache.hits(), hits + pages.len());
+    }
+
+    #[test]
+    fn multi_sector_metacluster() {
+        let sectors = header::MAX_METACLUSTER_SECTORS as usize;
+        let metacluster = Metacluster {
+            next_checksum: 0xDEADBEEF,
+            next: cluster::Pointer::new(3),
+            free: (0..max_free(sectors) as u64).map(|n| cluster::Pointer::new(n + 10).unwrap()).collect(),
+        };
+        assert!(metacluster.free.len() > 200);
+
+        let buf = metacluster.encode_sectors(sectors);
+        assert_eq!(buf.len(), sectors * disk::SECTOR_SIZE);
+
+        // The metacluster round-trips, and the checksum covers every free cluster.
+        let algorithm = header::ChecksumAlgorithm::SeaHash;
+        let decoded = Metacluster::decode(&buf, metacluster.free.len() as u8).unwrap();
+        assert_eq!(decoded.next_checksum, metacluster.next_checksum);
+        assert_eq!(decoded.next, metacluster.next);
+        assert_eq!(decoded.free, metacluster.free);
+        assert_eq!(decoded.checksum(algorithm), metacluster.checksum(algorithm));
+        let mut corrupted = buf.clone();
+        corrupted[buf.len() - 1] ^= 1;
+        assert!(Metacluster::decode(&corrupted, metacluster.free.len() as u8).unwrap().checksum(algorithm)
+                != metacluster.checksum(algorithm));
+
+        // A metacluster fitting in a single sector is encoded the same way, regardless of the
+        // size.
+        let small = Metacluster {
+            next_checksum: 1,
+            next: None,
+            free: metacluster.free[..MAX_FREE].to_vec(),
+        };
+        assert_eq!(active_sectors(small.free.len()), 1);
+        assert_eq!(active_sectors(small.free.len() + 1), 2);
+        assert_eq!(&small.encode_sectors(sectors)[..disk::SECTOR_SIZE], &small.encode_sectors(1)[..]);
+    }
+
+    #[test]
+    fn multi_sector_metaclusters() {
+        for &construction in &[FreelistConstruction::Bulk, FreelistConstruction::Incremental] {
+            let disk = MemSim::new(4 * TEST_SECTORS);
+            let mut header = header::DiskHeader::default();
+            header.metacluster_size = header::MAX_METACLUSTER_SECTORS;
+            let mut manager = Manager::format(driver_with_header(&disk, header), None, state_block::Config::default(),
+                                              construction).unwrap();
+            let clusters = 4 * TEST_SECTORS - manager.first_data_cluster();
+
+            // Every metacluster holds hundreds of free clusters, so a handful of them cover the
+            // device.
+            assert_eq!(manager.max_free(), max_free(header::MAX_METACLUSTER_SECTORS as usize));
+            assert!(manager.max_free() > 200);
+            assert!(manager.tail_metaclusters.load(ORDERING) < 4);
+            assert_eq!(manager.free_clusters().unwrap(), clusters as u64);
+
+            // Allocate every cluster, including the ones holding the sectors of the metaclusters,
+            // and free them again.
+            let pages = fill(&mut manager, 0);
+            assert_eq!(pages.len(), clusters);
+            manager.free_range(&pages).unwrap().unwrap().execute();
+            manager.sync_metadata().unwrap();
+            drop(manager);
+
+            // The freelist is intact after reopening, and every cluster is handed out once.
+            let manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false,
+                                        None).unwrap();
+            assert_eq!(manager.free_clusters().unwrap(), clusters as u64);
+            let mut popped: Vec<_> = (0..clusters).map(|_| manager.freelist_pop().unwrap().execute()).collect();
+            popped.sort();
+            popped.dedup();
+            assert_eq!(popped.len(), clusters);
+            assert!(manager.freelist_pop().is_err());
+        }
+    }
+
+    #[test]
+    fn multi_sector_metacluster_conflicts() {
+        let mut header = header::DiskHeader::default();
+        header.metacluster_size = header::MAX_METACLUSTER_SECTORS;
+
+        // The sectors of the metaclusters would be discarded along with the free clusters.
+        let disk = MemSim::new(TEST_SECTORS);
+        assert_matches!(Manager::format(driver_with_header(&disk, header), None, state_block::Config {
+            trim_on_free: true,
+            .. Default::default()
+        }, FreelistConstruction::Bulk), Err(Error::UnsupportedMetaclusterSize { sectors: 4 }));
+
+        // The sectors of the metaclusters would be split across the devices.
+        let data = MemSim::new(TEST_SECTORS);
+        let metadata = MemSim::new(TEST_SECTORS);
+        assert_matches!(Manager::format(driver_with_header(&data, header), Some(driver_with_header(&metadata, header)),
+                                        state_block::Config::default(), FreelistConstruction::Bulk),
+                        Err(Error::UnsupportedMetaclusterSize { sectors: 4 }));
+
+        // Neither can be enabled behind the back of the manager.
+        let disk = MemSim::new(TEST_SECTORS);
+        let manager = Manager::format(driver_with_header(&disk, header), None, state_block::Config::default(),
+                                      FreelistConstruction::Bulk).unwrap();
+        let address = manager.state_block_address();
+        let checksum_algorithm = manager.driver.header.checksum_algorithm;
+        manager.shutdown().unwrap();
+        let mut state_block = state_block::StateBlock::decode(&disk.sector(address), checksum_algorithm).unwrap();
+        state_block.config.trim_on_free = true;
+        disk.clone().write(address, &state_block.encode(checksum_algorithm)).unwrap();
+        assert_matches!(Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false,
+                                      None),
+                        Err(Error::UnsupportedMetaclusterSize { sectors: 4 }));
+    }
+
+    #[test]
+    fn decode_overlong_metacluster() {
+        let buf = Metacluster {
+            next_checksum: 0,
+            next: None,
+            free: Vec::new(),
+        }.encode_sectors(1);
+
+        // The active region must fit in the buffer.
+        assert!(Metacluster::decode(&buf, MAX_FREE as u8).is_some());
+        assert!(Metacluster::decode(&buf, MAX_FREE as u8 + 1).is_none());
+        assert!(Metacluster::decode(&buf, 255).is_none());
+        assert!(Metacluster::decode(&buf[..8], 0).is_none());
+    }
+
+    #[test]
+    fn read_overflowing_offset() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let page = manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(page.offset.is_some());
+
+        // The offset overflows on 32-bit targets, and is beyond the cluster on the others. Either
+        // way, an error is returned, rather than panicking.
+        let page = page::Pointer {
+            offset: Some(u32::MAX),
+            .. page
+        };
+        if cfg!(target_pointer_width = "32") {
+            assert_matches!(manager.read(page),
+                            Err(Error::InvalidPageOffset { page: x })
+                            if x == page);
+        } else {
+            assert_matches!(manager.read(page), Err(Error::InvalidCompression { cluster, kind })
+                            if cluster == page.cluster && kind == CompressionCorruption::DataCorrupt);
+        }
+    }
+
+    #[test]
+    fn relocate_page() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        let buf = [42; disk::SECTOR_SIZE];
+        let page = manager.alloc(&buf).unwrap().execute();
+        let other = manager.alloc(&[43; disk::SECTOR_SIZE]).unwrap().execute();
+
+        // Relocate the page to a free cluster of our choice.
+        let target = manager.head_metacluster.lock().free[0];
+        let new = manager.relocate_page(page, target).unwrap();
+        assert_eq!(new.cluster, target);
+        assert!(!manager.head_metacluster.lock().free.contains(&target));
+        assert_eq!(manager.read(new).unwrap(), buf);
+
+        // The old cluster still holds the other page, so it is kept.
+        assert_eq!(manager.read(other).unwrap(), [43; disk::SECTOR_SIZE]);
+        assert_eq!(manager.live.lock()[&page.cluster].pages, vec![other]);
+
+        // A cluster in use cannot be targeted.
+        assert_matches!(manager.relocate_page(other, target),
+                        Err(Error::ClusterUnavailable { cluster })
+                        if cluster == target);
+
+        // Neither can the state block, nor a cluster past the end of the device.
+        let state_block = cluster::Pointer::new(manager.state_block_address() as u64).unwrap();
+        let past_end = cluster::Pointer::new(manager.driver.number_of_sectors() as u64).unwrap();
+        for &target in &[state_block, past_end] {
+            assert_matches!(manager.relocate_page(other, target),
+                            Err(Error::ClusterOutOfBounds { page })
+                            if page.cluster == target);
+        }
+        // The page stays where it was.
+        assert_eq!(manager.read(other).unwrap(), [43; disk::SECTOR_SIZE]);
+        assert_eq!(manager.live.lock()[&page.cluster].pages, vec![other]);
+    }
+
+    #[test]
+    fn background_flush() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let manager = Arc::new(Mutex::new(manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        })));
+
+        // Write a burst of pages. Compression is disabled, so every page dirties its own cluster.
+        for n in 0..64u8 {
+            manager.lock().alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute();
+        }
+        assert!(manager.lock().dirty_sectors() >= 64);
+
+        // Let the flusher run, until it has trickled everything to the disk.
+        let flusher = Manager::spawn_flusher(manager.clone(), FlushPolicy {
+            sectors: 16,
+            interval: Duration::from_millis(1),
+        });
+        for _ in 0..1000 {
+            if manager.lock().dirty_sectors() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(flusher);
+
+        // The sync has nothing left to do.
+        let mut manager = manager.lock();
+        assert_eq!(manager.dirty_sectors(), 0);
+        manager.sync_metadata().unwrap();
+        assert_eq!(manager.dirty_sectors(), 0);
+    }
+
+    #[test]
+    fn dedup_stats() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        // Allocate three unique pages, each duplicated once.
+        for n in 0..3 {
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute();
+            manager.alloc(&[n; disk::SECTOR_SIZE]).unwrap().execute();
+        }
+        // And another unique page.
+        manager.alloc(&[3; disk::SECTOR_SIZE]).unwrap().execute();
+
+        let stats = manager.dedup_stats();
+        assert_eq!(stats.entries, 4);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 4);
+        assert_eq!(stats.bytes_saved, 3 * disk::SECTOR_SIZE as u64);
+    }
+
+    #[test]
+    fn read_at_snapshot() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let page = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        let snapshot = manager.snapshot();
+
+        // Overwrite the page, and fill the disk, so the old cluster would be reused, if it wasn't
+        // retained.
+        let new = manager.atomic_swap(page, &[2; disk::SECTOR_SIZE]).unwrap();
+        let mut n = 0u64;
+        loop {
+            let mut buf = [0xFF; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, n);
+            n += 1;
+            match manager.alloc(&buf) {
+                Ok(allocated) => assert!(allocated.execute().cluster != page.cluster),
+                Err(Error::OutOfClusters) => break,
+                Err(err) => panic!("{}", err),
+            }
+        }
+
+        assert_eq!(manager.read_at(snapshot, page).unwrap(), [1; disk::SECTOR_SIZE]);
+        assert_eq!(manager.read(new).unwrap(), [2; disk::SECTOR_SIZE]);
+        // The new page didn't exist at the time of the snapshot.
+        assert_matches!(manager.read_at(snapshot, new), Err(Error::PageNotInSnapshot { page })
+                        if page == new);
+
+        // Dropping the snapshot releases the old cluster.
+        manager.drop_snapshot(snapshot).unwrap().unwrap().execute();
+        assert_matches!(manager.read_at(snapshot, page), Err(Error::UnknownSnapshot));
+        assert_eq!(manager.freelist_pop().unwrap().execute(), page.cluster);
+    }
+
+    #[test]
+    fn persisted_trash_and_snapshots() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut header = header::DiskHeader::default();
+        header.index_sectors = 2;
+        let mut manager = setup(&disk, None, header, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Identity,
+            .. Default::default()
+        });
+
+        // Trash a page, and retain another one for a snapshot.
+        let trashed = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        let retained = manager.alloc(&[2; disk::SECTOR_SIZE]).unwrap().execute();
+        assert!(manager.free_to_trash(trashed).unwrap().is_none());
+        let snapshot = manager.snapshot();
+        assert!(manager.free(retained).unwrap().is_none());
+
+        // Crash, with everything on the disk.
+        manager.sync_metadata().unwrap();
+        manager.cache.trim(0).unwrap();
+        mem::forget(manager);
+
+        // Both clusters are loaded with the live page index, rather than leaked.
+        let mut manager = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert!(manager.snapshots() == [snapshot]);
+        assert_eq!(manager.read_at(snapshot, retained).unwrap(), [2; disk::SECTOR_SIZE]);
+        manager.undelete(trashed).unwrap();
+        assert_eq!(manager.read(trashed).unwrap(), [1; disk::SECTOR_SIZE]);
+
+        // New snapshots continue after the loaded ones.
+        assert!(manager.snapshot() != snapshot);
+
+        // Dropping the snapshot releases the retained cluster.
+        manager.drop_snapshot(snapshot).unwrap().unwrap().execute();
+        assert!(manager.iter_free_clusters().any(|cluster| cluster == retained.cluster));
+    }
+
+    #[test]
+    fn fill_freed_clusters() {
+        for &fill in &[state_block::FillPattern::Zero, state_block::FillPattern::DeadBeef] {
+            let disk = MemSim::new(TEST_SECTORS);
+            let mut manager = manager(&disk, state_block::Config {
+                free_fill: fill,
+                .. Default::default()
+            });
+
+            let page = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+            manager.free(page).unwrap().unwrap().execute();
+            manager.cache.flush(page.cluster).unwrap();
+
+            // The freed cluster was overwritten with the pattern.
+            assert_eq!(disk.sector(page.cluster), fill.sector().unwrap());
+        }
+    }
+
+    #[test]
+    fn fill_spares_head_metacluster() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            free_fill: state_block::FillPattern::DeadBeef,
+            .. Default::default()
+        });
+
+        // Empty the freelist, so the next freed cluster becomes the head metacluster.
+        let mut popped = Vec::new();
+        while let Ok(cluster) = manager.freelist_pop() {
+            popped.push(cluster.execute());
+        }
+        let cluster = popped.pop().unwrap();
+        manager.freelist_push(cluster).execute();
+
+        // Crash, with the cache flushed, but without syncing the metadata.
+        manager.cache.trim(0).unwrap();
+        mem::forget(manager);
+
+        // The head metacluster wasn't overwritten with the pattern.
+        assert!(disk.sector(cluster) != state_block::FillPattern::DeadBeef.sector().unwrap());
+        let manager = Manager::open(driver(&disk), None, false, None).unwrap();
+        assert_eq!(manager.iter_free_clusters().collect::<Vec<_>>(), [cluster]);
+    }
+
+    #[test]
+    fn keep_freed_clusters() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let page = manager.alloc(&[1; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.cache.flush(page.cluster).unwrap();
+        manager.free(page).unwrap().unwrap().execute();
+        manager.cache.flush(page.cluster).unwrap();
+
+        // The freed cluster was left untouched.
+        assert_eq!(disk.sector(page.cluster), [1; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn read_verified() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        let buf = [7; disk::SECTOR_SIZE];
+        let page = manager.alloc(&buf).unwrap().execute();
+        let checksum = manager.checksum(&buf);
+
+        assert_eq!(manager.read_verified(page, checksum).unwrap(), buf);
+        assert_eq!(manager.read_verified(page, checksum ^ 1), Err(Error::ExternalChecksumMismatch {
+            page: page,
+            expected: checksum ^ 1,
+            found: checksum,
+        }));
+    }
+
+    #[test]
+    fn concurrent_pooled_reads() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        let pages: Vec<_> = (0..32u8).map(|n| {
+            let buf = [n; disk::SECTOR_SIZE];
+            (manager.alloc(&buf).unwrap().execute(), buf)
+        }).collect();
+
+        // Give every thread a buffer up front. A read holds at most one buffer at a time.
+        manager.pool = pool::Pool::with_buffers(4, CLUSTER_CAPACITY);
+        let manager = Arc::new(manager);
+        let pages = Arc::new(pages);
+        let threads: Vec<_> = (0..4).map(|_| {
+            let manager = manager.clone();
+            let pages = pages.clone();
+
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    for &(page, buf) in pages.iter() {
+                        assert_eq!(manager.read(page).unwrap(), buf);
+                    }
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // The buffers were reused rather than allocated for every read, so the pool never ran
+        // empty, and every buffer was returned.
+        assert_eq!(manager.pool.allocations(), 0);
+        assert_eq!(manager.pool.len(), 4);
+    }
+
+    #[test]
+    fn iter_pages_in_write_order() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        // Allocate some pages, and free a cluster in the middle, so that the next allocation
+        // reuses a lower cluster than its predecessor.
+        let mut pages: Vec<_> = (0..8u8).map(|n| {
+            let buf = [n; disk::SECTOR_SIZE];
+            (manager.alloc(&buf).unwrap().execute(), buf)
+        }).collect();
+        let (freed, _) = pages.remove(3);
+        manager.free(freed).unwrap().unwrap().execute();
+        let buf = [0xFF; disk::SECTOR_SIZE];
+        pages.push((manager.alloc(&buf).unwrap().execute(), buf));
+
+        let iterated: Vec<_> = manager.iter_pages_in_write_order().map(Result::unwrap).collect();
+        assert_eq!(iterated, pages);
+    }
+
+    #[test]
+    fn concurrent_alloc() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let manager = Arc::new(manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        }));
+
+        // Hammer the allocator from several threads sharing the manager. The pages are
+        // compressible, so they're packed into shared clusters.
+        let threads: Vec<_> = (0..4u8).map(|n| {
+            let manager = manager.clone();
+
+            thread::spawn(move || {
+                (0..32u8).map(|i| {
+                    let mut buf = [i; disk::SECTOR_SIZE];
+                    buf[0] = n;
+
+                    (manager.alloc(&buf).unwrap().execute(), buf)
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+        let pages: Vec<_> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+
+        // No page was lost, neither in the cache nor on the disk.
+        for &(page, buf) in &pages {
+            assert_eq!(manager.read(page).unwrap(), buf);
+        }
+        manager.cache.trim(0).unwrap();
+        for &(page, buf) in &pages {
+            assert_eq!(manager.read(page).unwrap(), buf);
+        }
+
+        // Every page is tracked once, and the clusters are shared.
+        let live: usize = manager.live.lock().values().map(|live_pages| live_pages.pages.len()).sum();
+        assert_eq!(live, pages.len());
+        assert!(manager.live.lock().len() < pages.len());
+    }
+
+    #[test]
+    fn concurrent_freelist_pop() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let manager = Arc::new(manager(&disk, state_block::Config::default()));
+
+        // Pop from several threads sharing the manager, until the freelist runs dry.
+        let threads: Vec<_> = (0..4).map(|_| {
+            let manager = manager.clone();
+
+            thread::spawn(move || {
+                let mut popped = Vec::new();
+                while let Ok(cluster) = manager.freelist_pop() {
+                    popped.push(cluster.execute());
+                }
+
+                popped
+            })
+        }).collect();
+
+        // Every cluster was handed out exactly once.
+        let mut popped: Vec<_> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+        let len = popped.len();
+        popped.sort();
+        popped.dedup();
+        assert_eq!(popped.len(), len);
+        assert_eq!(len as disk::Sector, TEST_SECTORS - manager.first_data_cluster());
+    }
+
+    #[test]
+    fn concurrent_freelist_push_pop() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let manager = Arc::new(manager(&disk, state_block::Config::default()));
+        let free = manager.free_clusters().unwrap();
+
+        // Pop and push back from several threads sharing the manager, switching metaclusters
+        // back and forth.
+        let threads: Vec<_> = (0..4).map(|_| {
+            let manager = manager.clone();
+
+            thread::spawn(move || {
+                for _ in 0..64 {
+                    let clusters: Vec<_> = (0..MAX_FREE / 2)
+                        .map(|_| manager.freelist_pop().unwrap().execute())
+                        .collect();
+                    for cluster in clusters {
+                        manager.freelist_push(cluster).execute();
+                    }
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // The freelist is intact, and holds every cluster exactly once.
+        assert_eq!(manager.free_clusters().unwrap(), free);
+        let mut clusters: Vec<_> = manager.iter_free_clusters().collect();
+        clusters.sort();
+        clusters.dedup();
+        assert_eq!(clusters.len() as u64, free);
+    }
+
+    #[test]
+    fn shutdown() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.shutdown().unwrap();
+    }
+
+    #[test]
+    fn open_with_capacity() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+        let page = manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+        manager.shutdown().unwrap();
+
+        let hints = CapacityHints {
+            working_set: 4096,
+            buffers: 8,
+            live_pages: 1 << 18,
+        };
+        let mut sized = Manager::open_with_capacity(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(),
+                                                    None, false, None, hints).unwrap();
+        // The structures start out presized...
+        assert!(sized.cache.capacity() >= 4096);
+        assert_eq!(sized.pool.len(), 8);
+        assert!(sized.live.lock().capacity() >= 1 << 18);
+        assert_eq!(sized.dedup_table.capacity(), 1 << 18);
+        assert_eq!(sized.read(page).unwrap(), [0xAB; disk::SECTOR_SIZE]);
+        let sized_page = sized.alloc(&[0xCD; disk::SECTOR_SIZE]).unwrap().execute();
+        drop(sized);
+
+        // ...but otherwise behave like the default ones.
+        let plain = Manager::open(vdev::Driver::open(slog::Discard, disk.clone(), b"").unwrap(), None, false, None)
+            .unwrap();
+        assert!(plain.cache.capacity() < 4096);
+        assert_eq!(plain.pool.len(), 0);
+        assert!(plain.dedup_table.capacity() < 1 << 18);
+        assert_eq!(plain.read(page).unwrap(), [0xAB; disk::SECTOR_SIZE]);
+        assert_eq!(plain.read(sized_page).unwrap(), [0xCD; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn shutdown_failing_flush() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config::default());
+
+        manager.alloc(&[0xAB; disk::SECTOR_SIZE]).unwrap().execute();
+
+        // Make the final flush fail, and check that the error is surfaced.
+        disk.fail_writes();
+        assert!(manager.shutdown().is_err());
+    }
+
+    #[test]
+    fn auto_compression() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Auto,
+            .. Default::default()
+        });
+        // Every algorithm compresses a page within a microsecond, beating the speed floor.
+        manager.set_clock(fake_clock(1_000));
+
+        // A cluster-load of highly redundant data.
+        let redundant: Vec<_> = (0..8u8).map(|n| {
+            let buf = [n; disk::SECTOR_SIZE];
+            (manager.alloc(&buf).unwrap().execute(), buf)
+        }).collect();
+
+        // The pages are packed into the same cluster, reusing its algorithm.
+        let algorithm = manager.last_cluster.lock().as_ref().unwrap().algorithm;
+        for &(page, _) in &redundant {
+            assert_eq!(page.cluster, redundant[0].0.cluster);
+        }
+
+        // The algorithm compressing the first page the best is chosen, ties won by the faster.
+        let lengths: Vec<_> = AUTO_ALGORITHMS.iter().map(|&algorithm| {
+            let mut compressed = Vec::new();
+            manager.compress_into(algorithm, &[0; disk::SECTOR_SIZE], &mut compressed);
+            compressed.len()
+        }).collect();
+        let best = (0..lengths.len()).min_by_key(|&n| (lengths[n], n)).unwrap();
+        assert_eq!(algorithm, AUTO_ALGORITHMS[best]);
+
+        // A cluster-load of noise, which no algorithm can compress.
+        let noisy: Vec<_> = (0..8).map(|n| {
+            let buf = noise_page(n);
+            (manager.alloc(&buf).unwrap().execute(), buf)
+        }).collect();
+
+        // The noise is stored uncompressed.
+        for &(page, _) in &noisy {
+            assert_eq!(page.offset, None);
+        }
+
+        // Every page reads back, decompressed through the tagged algorithm.
+        for (page, buf) in redundant.into_iter().chain(noisy) {
+            assert_eq!(manager.read(page).unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn auto_compression_speed_floor() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Auto,
+            .. Default::default()
+        });
+        // Compressing a page takes a millisecond, far below the speed floor.
+        manager.set_clock(fake_clock(1_000_000));
+
+        // Only the fastest algorithm is eligible, however well the others compress.
+        let page = manager.alloc(&[0; disk::SECTOR_SIZE]).unwrap().execute();
+        assert_eq!(manager.last_cluster.lock().as_ref().unwrap().algorithm, AUTO_ALGORITHMS[0]);
+        assert_eq!(manager.read(page).unwrap(), [0; disk::SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn compact_until() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Fill a few compressed clusters, and fragment them, keeping only every 16th page.
+        let mut pages: Vec<_> = (0..1024u64).map(|n| {
+            let mut buf = [0; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, n);
+            (manager.alloc(&buf).unwrap().execute(), buf)
+        }).collect();
+        for (n, &(page, _)) in pages.iter().enumerate() {
+            if n % 16 != 15 {
+                manager.free(page).unwrap().map(|transaction| transaction.execute());
+            }
+        }
+        pages.retain(|&(page, _)| manager.live.lock().get(&page.cluster).map_or(false, |live_pages| {
+            live_pages.pages.contains(&page)
+        }));
+        let clusters = manager.live.lock().len();
+        assert!(clusters > 2);
+
+        let mut remap = |old, new| {
+            for &mut (ref mut page, _) in &mut pages {
+                if *page == old {
+                    *page = new;
+                }
+            }
+        };
+
+        // Free a single cluster.
+        let freed = manager.compact_until(1, &mut remap).unwrap();
+        assert!(freed >= 1);
+        assert_eq!(manager.live.lock().len(), clusters - freed);
+
+        // Asking for more than possible frees what can be freed.
+        let more = manager.compact_until(clusters, &mut remap).unwrap();
+        assert!(more < clusters);
+        assert_eq!(manager.live.lock().len(), clusters - freed - more);
+        assert_eq!(manager.compact_until(1, &mut remap).unwrap(), 0);
+
+        // Every page is intact.
+        for &(page, buf) in &pages {
+            assert_eq!(manager.read(page).unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn compact_ordered() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Spread the compressible pages over their own clusters, like in `compact`.
+        let mut noise = [0; disk::SECTOR_SIZE];
+        let mut pages = Vec::new();
+        for n in 0..8 {
+            for (i, byte) in noise.iter_mut().enumerate() {
+                *byte = (i * 0x9E3779B9 >> 8 ^ n * 31) as u8;
+            }
+            manager.alloc(&noise).unwrap().execute();
+
+            let buf = [n as u8; disk::SECTOR_SIZE];
+            pages.push((manager.alloc(&buf).unwrap().execute(), buf));
+        }
+
+        // Ask for some of the pages to be packed in a scattered order.
+        let hint = [6, 1, 4, 3];
+        let order: Vec<_> = hint.iter().map(|&n| pages[n].0).collect();
+        manager.compact(Some(&order), &mut |old, new| {
+            for &mut (ref mut page, _) in &mut pages {
+                if *page == old {
+                    *page = new;
+                }
+            }
+        }).unwrap();
+
+        // The hinted pages lead a fresh cluster together, in the order given.
+        let cluster = pages[hint[0]].0.cluster;
+        for (offset, &n) in hint.iter().enumerate() {
+            assert!(pages[n].0.cluster == cluster);
+            assert_eq!(pages[n].0.offset, Some(offset as _));
+        }
+        // Every page is intact.
+        for (page, buf) in pages {
+            assert_eq!(manager.read(page).unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Alternate between compressible and incompressible pages. Every incompressible page
+        // interrupts the packing, so each compressible page ends up in its own cluster.
+        let mut noise = [0; disk::SECTOR_SIZE];
+        let mut pages = Vec::new();
+        for n in 0..8 {
+            for (i, byte) in noise.iter_mut().enumerate() {
+                *byte = (i * 0x9E3779B9 >> 8 ^ n * 31) as u8;
+            }
+            manager.alloc(&noise).unwrap().execute();
+
+            let buf = [n as u8; disk::SECTOR_SIZE];
+            pages.push((manager.alloc(&buf).unwrap().execute(), buf));
+        }
+
+        let clusters_before = manager.live.lock().len();
+        manager.compact(None, &mut |old, new| {
+            for &mut (ref mut page, _) in &mut pages {
+                if *page == old {
+                    *page = new;
+                }
+            }
+        }).unwrap();
+
+        // The compressible pages now share clusters.
+        assert!(manager.live.lock().len() < clusters_before);
+        // Every page is intact.
+        for (page, buf) in pages {
+            assert_eq!(manager.read(page).unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn compact_snapshot() {
+        let disk = MemSim::new(TEST_SECTORS);
+        let mut manager = manager(&disk, state_block::Config {
+            compression_algorithm: state_block::CompressionAlgorithm::Lz4,
+            .. Default::default()
+        });
+
+        // Spread compressible pages over several clusters, like in `compact`.
+        let mut noise = [0; disk::SECTOR_SIZE];
+        let mut pages = Vec::new();
+        for n in 0..8 {
+            for (i, byte) in noise.iter_mut().enumerate() {
+                *byte = (i * 0x9E3779B9 >> 8 ^ n * 31) as u8;
+            }
+            manager.alloc(&noise).unwrap().execute();
+
+            let buf = [n as u8; disk::SECTOR_SIZE];
+            pages.push((manager.alloc(&buf).unwrap().execute(), buf));
+        }
+
+        // Compact with a snapshot in place.
+        let snapshot = manager.snapshot();
+        manager.compact(None, &mut |_, _| ()).unwrap();
+        assert!(!manager.retained.lock().is_empty());
+
+        // Fill the disk, so the old clusters would be reused, if they weren't retained.
+        let mut n = 0u64;
+        loop {
+            let mut buf = [0xFF; disk::SECTOR_SIZE];
+            LittleEndian::write(&mut buf, n);
+            n += 1;
+            match manager.alloc(&buf) {
+                Ok(allocated) => { allocated.execute(); },
+                Err(Error::OutOfClusters) => break,
+                Err(err) => panic!("{}", err),
+            }
+        }
+
+        // The snapshot still reads the pages under their old pointers.
+        for (page, buf) in pages {
+            assert_eq!(manager.read_at(snapshot, page).unwrap(), buf);
+        }
+    }
+}
@@ -1,10 +1,14 @@
 use crossbeam::sync::SegQueue;
+use std::cmp;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
 
 /// A writable guard to a cache block.
 type WriteGuard<'a> = chashmap::WriteGuard<'a, disk::Sector, Block>;
 
 /// The default initial capacity of the sector map.
 const INITIAL_CAPACITY: usize = 256;
+/// The default maximal number of sectors read ahead on a cache miss.
+const DEFAULT_MAX_READAHEAD: usize = 32;
 
 // TODO: Merge `Transaction` and `Transacting`.
 
@@ -14,15 +18,20 @@ const INITIAL_CAPACITY: usize = 256;
 /// mutable reference to the block, allowing it to create child transactions of the write.
 ///
 /// The transaction will be flushable when this handler is dropped.
-#[derive(Copy, Clone)]
 #[must_use]
 struct Transaction<'a> {
+    /// The cache the transaction writes to.
+    cache: &'a Cache,
     /// The sector of the transaction.
     sector: disk::Sector,
     /// The block in question.
     ///
     /// This is a lock guard, and thus as long it is held, the block cannot be flushed.
     block: chashmap::WriteGuard<'a>,
+    /// The clusters written by the transaction and the transactions chained before it.
+    ///
+    /// These are in order of writing, without duplicates.
+    affected: Vec<cluster::Pointer>,
 }
 
 impl<'a> Transaction<'a> {
@@ -41,26 +50,92 @@ impl<'a> Transaction<'a> {
     /// This makes a new transaction which will execute `self` transaction then `other`.
     ///
     /// Beware that it will make `self` flushable, by executing the lock.
-    pub fn then(self, other: Transaction) -> Transaction {
+    pub fn then(mut self, other: Transaction) -> Transaction {
         // Make `other` depend on `self`.
         other.block.flush_dependencies.push(self.sector);
 
+        // The chained transaction writes the clusters of both.
+        let mut affected = mem::replace(&mut self.affected, Vec::new());
+        for cluster in other.affected {
+            if !affected.contains(&cluster) {
+                affected.push(cluster);
+            }
+        }
+
         // Since `other` depends on `self`, we can safely use `other`.
         Transaction {
+            cache: other.cache,
             sector: other.sector,
             block: other.block,
+            affected: affected,
         }
 
         // Now `self` will drop, releasing the lock, and making it flushable.
     }
 
+    /// Make another transaction depend on the current, without executing it.
+    ///
+    /// This is like `then`, but `self` keeps its lock, so further transactions can be made to
+    /// depend on it as well.
+    pub fn precede(&self, other: Transaction) -> Transaction {
+        // Make `other` depend on `self`.
+        other.block.flush_dependencies.push(self.sector);
+
+        // The dependent transaction writes the clusters of both.
+        let mut affected = self.affected.clone();
+        for cluster in other.affected {
+            if !affected.contains(&cluster) {
+                affected.push(cluster);
+            }
+        }
+
+        Transaction {
+            cache: other.cache,
+            sector: other.sector,
+            block: other.block,
+            affected: affected,
+        }
+    }
+
+    /// Get the clusters written by the transaction.
+    ///
+    /// This covers the transactions chained before it (through `then`), in order of writing.
+    /// Sectors which aren't clusters (i.e. the disk header) are left out.
+    pub fn affected_clusters(&self) -> &[cluster::Pointer] {
+        &self.affected
+    }
+
+    /// Discard a sector once the transaction is flushed.
+    ///
+    /// This makes the cache tell the device holding `sector` that its content is no longer
+    /// needed, but only once the block of the transaction (and hence every transaction chained
+    /// before it) has hit the disk. Until then, the content is left alone, so a crash never loses
+    /// data, which is still referred to.
+    ///
+    /// If `sector` is written before that, the discard is cancelled, as the new content must
+    /// survive.
+    pub fn then_discard(self, sector: disk::Sector) -> Transaction {
+        self.block.discards.push(sector);
+        self.cache.pending_discards.lock().insert(sector, self.sector);
+
+        self
+    }
+
     /// Execute the transaction.
+    ///
+    /// Once the block is flushable, the cache is trimmed, if the write made it hold more sectors
+    /// than allowed.
     pub fn execute(self) {
+        let cache = self.cache;
         // Release the lock.
         drop(self.block);
         // To avoid the safety destructor from running, we leak `self`, knowing that everything is
         // deallocated.
         mem::forget(self);
+
+        // Keep the cache within its bounds. This cannot happen while the lock is held, as the
+        // block might be picked for trimming.
+        cache.keep_within_bounds();
     }
 }
 
@@ -107,6 +182,15 @@ impl<'a, T> Transacting<'a, T> {
         }
     }
 
+    /// Execute the transaction, if any, and return the inner value.
+    fn execute(self) -> T {
+        if let Some(transaction) = self.transaction {
+            transaction.execute();
+        }
+
+        self.inner
+    }
+
     /// Chain the transaction together with another transaction, so they're executed sequentially.
     ///
     /// This makes a new transaction which will execute `self`'s transaction then `other`.
@@ -119,6 +203,38 @@ impl<'a, T> Transacting<'a, T> {
             other
         }
     }
+
+    /// Make a transaction depend on the transaction, if any, without executing it.
+    ///
+    /// This is like `then`, but `self` is kept, so further transactions can be made to depend on
+    /// it as well.
+    fn precede(&self, other: Transaction) -> Transaction {
+        if let Some(ref transaction) = self.transaction {
+            transaction.precede(other)
+        } else {
+            // `self` contained no transaction, so `other` depends on nothing.
+            other
+        }
+    }
+
+    /// Map the inner value, keeping the transaction.
+    fn map<U, F>(self, f: F) -> Transacting<U>
+        where F: FnOnce(T) -> U {
+        Transacting::new(f(self.inner), self.transaction)
+    }
+
+    /// Chain the transaction together with another, possibly empty, transaction.
+    ///
+    /// This is like `then`, but `other` might not hold any transaction. The inner value of
+    /// `other` is kept.
+    fn and<U>(self, other: Transacting<U>) -> Transacting<U> {
+        Transacting::new(other.inner, match (self.transaction, other.transaction) {
+            // Append the other transaction to the current transaction.
+            (Some(transaction), Some(other)) => Some(transaction.then(other)),
+            // At most one of them contains a transaction, so we'll simply use that.
+            (transaction, other) => transaction.or(other),
+        })
+    }
 }
 
 /// A cache block.
@@ -150,6 +266,22 @@ struct Block {
     /// In other words, the sectors in this vector are _guaranteed_ to be written before the block
     /// itself.
     flush_dependencies: Vec<disk::Sector>,
+    /// Is this block held back by a deferred transaction?
+    ///
+    /// Deferred blocks (and the blocks depending on them) are not flushed until the deferred
+    /// transactions are drained.
+    deferred: bool,
+    /// Sectors to discard, once the block is flushed.
+    ///
+    /// These are discarded after the block has hit the disk, and before any block depending on
+    /// it is written, unless the discard was cancelled in the meantime (see
+    /// `Cache::take_discard`).
+    discards: Vec<disk::Sector>,
+    /// Was this block read ahead, without being verified yet?
+    ///
+    /// Prefetching doesn't know how to verify the sectors it reads, so the first read of a
+    /// prefetched block goes through verification (and healing), like a cache miss.
+    prefetched: bool,
 }
 
 impl Block {
@@ -159,6 +291,9 @@ impl Block {
             data: data,
             dirty: false,
             flush_dependencies: Vec::new(),
+            deferred: false,
+            discards: Vec::new(),
+            prefetched: false,
         }
     }
 }
@@ -171,6 +306,73 @@ enum CacheOperation {
     Touch(disk::Sector),
 }
 
+/// The state of the adaptive read-ahead.
+///
+/// Reads of increasing, nearby sectors are considered sequential, and grow the window of sectors
+/// prefetched on a cache miss. Any other read shrinks the window, so random access patterns
+/// don't waste I/O on prefetching.
+struct ReadAhead {
+    /// The last sector read, if any.
+    last: Option<disk::Sector>,
+    /// The number of sectors to prefetch following a missing sector.
+    window: usize,
+    /// The maximal size of the window.
+    max: usize,
+}
+
+impl ReadAhead {
+    /// Register a read of some sector.
+    ///
+    /// This updates the window based on the access pattern, and returns the new window.
+    fn access(&mut self, sector: disk::Sector) -> usize {
+        // Sectors already prefetched are skipped over by the window, so they count as sequential.
+        let sequential = self.last.map_or(false, |last| {
+            sector > last && sector - last <= self.window as disk::Sector + 1
+        });
+
+        self.window = if sequential {
+            // Double the window, up to the maximum.
+            cmp::min(cmp::max(self.window * 2, 1), self.max)
+        } else {
+            // Halve the window.
+            self.window / 2
+        };
+        self.last = Some(sector);
+
+        self.window
+    }
+}
+
+/// The metrics of a cache.
+///
+/// This is returned by `Cache::metrics`, for exporting to monitoring systems. The counters are
+/// cumulative since the cache was created.
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct CacheMetrics {
+    /// The number of reads served from the cache.
+    pub hits: usize,
+    /// The number of reads, which had to fetch the sector from the disk.
+    pub misses: usize,
+    /// The number of sectors prefetched by the read-ahead.
+    pub prefetches: usize,
+    /// The number of cached sectors.
+    pub sectors: usize,
+    /// The maximal number of cached sectors.
+    pub max_sectors: usize,
+}
+
+/// A separate metadata device.
+///
+/// The sectors of the device are mapped after the sectors of the data device, such that the
+/// addresses of the two devices never overlap.
+struct MetadataDevice {
+    /// The address of the device's first sector.
+    start: disk::Sector,
+    /// The driver of the device.
+    driver: vdev::Driver,
+}
+
 /// A cached disk.
 ///
 /// This wrapper manages caching and the consistency issues originating from it.
@@ -183,6 +385,8 @@ enum CacheOperation {
 struct Cache {
     /// The inner driver.
     driver: vdev::Driver,
+    /// The separate metadata device, if any.
+    metadata: Option<MetadataDevice>,
 
     /// The cache tracker operation queue.
     ///
@@ -198,93 +402,611 @@ struct Cache {
 
     /// The sector-to-cache block map.
     sector_map: CHashMap<disk::Sector, Block>,
+
+    /// The number of reads served from the cache.
+    hits: AtomicUsize,
+    /// The number of reads, which had to fetch the sector from the disk.
+    misses: AtomicUsize,
+    /// The adaptive read-ahead.
+    readahead: Mutex<ReadAhead>,
+    /// The number of sectors prefetched by the read-ahead.
+    prefetches: AtomicUsize,
+    /// The maximal number of cached sectors.
+    ///
+    /// This is enforced whenever sectors are fetched from the disk or written, by trimming the
+    /// cache.
+    max_sectors: AtomicUsize,
+    /// Are write transactions deferred?
+    deferring: AtomicBool,
+    /// The sectors written by deferred transactions, in order of writing.
+    deferred: Mutex<Vec<disk::Sector>>,
+    /// The sectors waiting to be discarded.
+    ///
+    /// This maps every such sector to the block whose flush discards it. Writing the sector
+    /// removes it, cancelling the discard.
+    pending_discards: Mutex<HashMap<disk::Sector, disk::Sector>>,
 }
 
 impl From<vdev::Driver> for Cache {
     fn from(driver: vdev::Driver) -> Cache {
+        Cache::with_metadata(driver, None)
+    }
+}
+
+impl Cache {
+    /// Create a cache with an optional separate metadata device.
+    ///
+    /// The sectors of `metadata` are mapped after the sectors of `driver`.
+    fn with_metadata(driver: vdev::Driver, metadata: Option<vdev::Driver>) -> Cache {
+        Cache::with_capacity(driver, metadata, INITIAL_CAPACITY)
+    }
+
+    /// Create a cache with an optional separate metadata device, sized for some working set.
+    ///
+    /// The sector map is presized to hold at least `sectors` sectors without reallocating.
+    fn with_capacity(driver: vdev::Driver, metadata: Option<vdev::Driver>, sectors: usize) -> Cache {
         Cache {
+            // Map the metadata device after the data device.
+            metadata: metadata.map(|metadata| MetadataDevice {
+                start: driver.number_of_sectors(),
+                driver: metadata,
+            }),
             // Store the driver.
             driver: driver,
             // Set empty/default values.
             queue: SegQueue::new(),
             tracker: Mutex::new(mlcr::Cache::new()),
-            sector_map: CHashMap::with_capacity(INITIAL_CAPACITY),
+            sector_map: CHashMap::with_capacity(cmp::max(sectors, INITIAL_CAPACITY)),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            readahead: Mutex::new(ReadAhead {
+                last: None,
+                window: 0,
+                max: DEFAULT_MAX_READAHEAD,
+            }),
+            prefetches: AtomicUsize::new(0),
+            max_sectors: AtomicUsize::new(usize::MAX),
+            deferring: AtomicBool::new(false),
+            deferred: Mutex::new(Vec::new()),
+            pending_discards: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the address of the metadata device's first sector, if any.
+    fn metadata_start(&self) -> Option<disk::Sector> {
+        self.metadata.as_ref().map(|metadata| metadata.start)
+    }
+
+    /// Get the number of reads served from the cache so far.
+    fn hits(&self) -> usize {
+        self.hits.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Get the number of reads, which had to fetch the sector from the disk, so far.
+    fn misses(&self) -> usize {
+        self.misses.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Get the number of sectors the sector map can hold without reallocating.
+    fn capacity(&self) -> usize {
+        self.sector_map.capacity()
+    }
+
+    /// Get the number of sectors prefetched by the read-ahead so far.
+    fn prefetches(&self) -> usize {
+        self.prefetches.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Get the number of cached sectors.
+    fn len(&self) -> usize {
+        self.sector_map.len()
+    }
+
+    /// Get the maximal number of cached sectors.
+    fn max_sectors(&self) -> usize {
+        self.max_sectors.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Get the metrics of the cache.
+    fn metrics(&self) -> CacheMetrics {
+        CacheMetrics {
+            hits: self.hits(),
+            misses: self.misses(),
+            prefetches: self.prefetches(),
+            sectors: self.len(),
+            max_sectors: self.max_sectors(),
+        }
+    }
+
+    /// Set the maximal number of cached sectors.
+    ///
+    /// If the cache holds more than `max` sectors, it is trimmed down to `max` sectors before
+    /// returning, flushing the dirty blocks evicted.
+    fn set_max_sectors(&self, max: usize) -> Result<(), disk::Error> {
+        info!(self, "resizing cache"; "subsystem" => subsystem::CACHE, "max sectors" => max);
+
+        self.max_sectors.store(max, atomic::Ordering::Relaxed);
+        self.enforce_max_sectors()
+    }
+
+    /// Trim the cache, if it holds more sectors than allowed.
+    fn enforce_max_sectors(&self) -> Result<(), disk::Error> {
+        let max = self.max_sectors();
+        if self.len() > max {
+            self.trim(max)?;
+        }
+
+        Ok(())
+    }
+
+    /// Trim the cache, if it holds more sectors than allowed, after an insertion.
+    ///
+    /// The insertion itself already succeeded, so failing to trim is logged rather than
+    /// returned. The blocks which couldn't be flushed stay in the cache, and are retried on the
+    /// next trim.
+    ///
+    /// Blocks held back by deferred transactions cannot be trimmed, so once they alone fill the
+    /// cache, deferring is stopped, and the queued transactions are applied (see
+    /// `set_deferring`).
+    fn keep_within_bounds(&self) {
+        let max = self.max_sectors();
+        if self.deferring.load(atomic::Ordering::Relaxed) && self.deferred_sectors() >= max {
+            warn!(self, "deferred transactions fill the cache, applying them"; "subsystem" => subsystem::CACHE,
+                  "max sectors" => max);
+
+            self.set_deferring(false);
+            if let Err(err) = self.drain_deferred() {
+                warn!(self, "failed to apply deferred transactions"; "subsystem" => subsystem::CACHE,
+                      "error" => err);
+            }
+        }
+
+        if let Err(err) = self.enforce_max_sectors() {
+            warn!(self, "failed to trim cache"; "subsystem" => subsystem::CACHE, "error" => err);
+        }
+    }
+
+    /// Set the maximal number of sectors read ahead on a cache miss.
+    ///
+    /// Setting it to zero disables the read-ahead.
+    fn set_max_readahead(&self, sectors: usize) {
+        let mut readahead = self.readahead.lock();
+        readahead.max = sectors;
+        readahead.window = cmp::min(readahead.window, sectors);
+    }
+
+    /// Set whether write transactions are deferred.
+    ///
+    /// While deferring, the sectors written are queued rather than made flushable, so nothing
+    /// written hits the disk until `drain_deferred` is called, regardless of flushes and trims.
+    /// Disabling it leaves the queued sectors alone until they're drained.
+    ///
+    /// The queue is bounded by the maximal number of cached sectors: Once it is reached,
+    /// deferring is disabled, and the queue is drained (see `keep_within_bounds`).
+    fn set_deferring(&self, deferring: bool) {
+        info!(self, "setting deferred transactions"; "subsystem" => subsystem::CACHE, "deferring" => deferring);
+
+        self.deferring.store(deferring, atomic::Ordering::Relaxed);
+    }
+
+    /// Get the number of sectors queued by deferred transactions.
+    fn deferred_sectors(&self) -> usize {
+        self.deferred.lock().len()
+    }
+
+    /// Check if some sector is held back by a deferred transaction.
+    fn is_deferred(&self, sector: disk::Sector) -> bool {
+        self.sector_map.find(sector).map_or(false, |block| block.deferred)
+    }
+
+    /// Check if some sector is cached and dirty.
+    fn is_dirty(&self, sector: disk::Sector) -> bool {
+        self.sector_map.find(sector).map_or(false, |block| block.dirty)
+    }
+
+    /// Apply the deferred transactions.
+    ///
+    /// This releases the queued sectors and flushes them to the disk in order of writing. This is
+    /// not atomic: The flush dependencies only make sure that a crash in the middle leaves a
+    /// prefix of the transactions applied.
+    fn drain_deferred(&self) -> Result<(), disk::Error> {
+        let sectors = mem::replace(&mut *self.deferred.lock(), Vec::new());
+
+        debug!(self, "draining deferred transactions"; "subsystem" => subsystem::CACHE,
+               "sectors" => sectors.len());
+
+        // Release every sector before flushing any, as they might depend on each other.
+        for &sector in &sectors {
+            if let Some(mut block) = self.sector_map.get_mut(sector) {
+                block.deferred = false;
+            }
+        }
+
+        for &sector in &sectors {
+            self.flush(sector)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check if some sector lives on the metadata device.
+    fn is_metadata(&self, sector: disk::Sector) -> bool {
+        self.metadata.as_ref().map_or(false, |metadata| {
+            sector >= metadata.start
+                && sector - metadata.start < metadata.driver.number_of_sectors()
+        })
+    }
+
+    /// Find the driver and the driver-local sector number of some sector.
+    fn route(&self, sector: disk::Sector) -> (&vdev::Driver, disk::Sector) {
+        match self.metadata {
+            // The sector lives on the metadata device.
+            Some(ref metadata) if sector >= metadata.start => (&metadata.driver, sector - metadata.start),
+            // The sector lives on the data device.
+            _ => (&self.driver, sector),
+        }
+    }
+
+    /// Find the device index and the device-local sector number of some sector.
+    ///
+    /// The data device has index `0`, and the metadata device index `1`.
+    fn locate(&self, sector: disk::Sector) -> (usize, disk::Sector) {
+        match self.metadata {
+            // The sector lives on the metadata device.
+            Some(ref metadata) if sector >= metadata.start => (1, sector - metadata.start),
+            // The sector lives on the data device.
+            _ => (0, sector),
         }
     }
-}
 
-impl Cache {
     /// Execute a write transaction.
     ///
-    /// This creates a transaction writing `buf` into sector `sector`, when dropped.
+    /// This creates a transaction writing `buf` into sector `sector`, when dropped. If the write
+    /// inserts a new block, the cache is trimmed back to its bounds when the transaction is
+    /// executed.
     fn write<F>(&self, sector: disk::Sector, buf: disk::SectorBuf) -> Transaction {
-        debug!(self, "writing sector"; "sector" => sector);
+        debug!(self, "writing sector"; "subsystem" => subsystem::CACHE, "sector" => sector);
+
+        // Insert the sector into the cache tracker, if it's new, so it can be trimmed.
+        if !self.sector_map.contains_key(&sector) {
+            self.queue.push(CacheOperation::Create(sector));
+        }
 
         // Acquire the lock to the block, and initialize if it doesn't already exist.
         let lock = self.sector_map.get_mut_or(sector, Block::default());
         // Set the dirty flag.
         lock.dirty = true;
-        // Update the data.
+        // Update the data. It is authoritative, so it needs no verification.
         lock.data = buf;
+        lock.prefetched = false;
+
+        // Cancel any pending discard of the sector. The block queuing it might be flushed after
+        // the new content hits the disk, and the discard would then wipe it.
+        self.pending_discards.lock().remove(&sector);
+
+        // Hold the block back, if transactions are deferred.
+        if self.deferring.load(atomic::Ordering::Relaxed) && !lock.deferred {
+            lock.deferred = true;
+            self.deferred.lock().push(sector);
+        }
 
         Transaction {
-            block: lock,
             cache: self,
+            sector: sector,
+            block: lock,
+            affected: cluster::Pointer::new(sector as u64).into_iter().collect(),
         }
     }
 
     /// Read a sector.
     ///
     /// This reads sector `sector`, and applies the closure `map`. If `sector` needs to be fetched
-    /// from the disk, and `map` fails, data recovery is attempted.
+    /// from the disk (or was read ahead, and is read for the first time), and `map` fails, data
+    /// recovery is attempted.
     ///
     /// If an I/O operation fails, the error is returned. Otherwise, the return value of `map` is
     /// returned.
     fn read_then<F, T, E>(&self, sector: disk::Sector, map: F) -> Result<T, E>
         where F: Fn(&disk::SectorBuf) -> Result<T, E>,
               E: From<disk::Error> {
-        debug!(self, "reading sector"; "sector" => sector);
+        debug!(self, "reading sector"; "subsystem" => subsystem::CACHE, "sector" => sector);
+
+        // Update the read-ahead window with the access.
+        let window = self.readahead.lock().access(sector);
 
         // Check if the sector is already available in the cache.
         if let Some(accessor) = self.sector_map.find(sector) {
-            // Yup, we found the sector in the cache.
-            trace!(self, "cache hit; reading from cache"; "sector" => sector);
+            // Blocks read ahead are yet to be verified, which happens below.
+            if !accessor.prefetched {
+                // Yup, we found the sector in the cache.
+                trace!(self, "cache hit; reading from cache"; "subsystem" => subsystem::CACHE,
+                       "sector" => sector);
+                self.hits.fetch_add(1, atomic::Ordering::Relaxed);
+
+                // Touch the sector.
+                self.queue.push(CacheOperation::Touch(sector));
+
+                return handler(accessor);
+            }
+        }
+
+        let (driver, local) = self.route(sector);
+
+        // Check if the sector was read ahead, and verify it on its first read.
+        if let Some(mut block) = self.sector_map.get_mut(sector) {
+            trace!(self, "cache hit on prefetched sector; verifying"; "subsystem" => subsystem::CACHE,
+                   "sector" => sector);
+            self.hits.fetch_add(1, atomic::Ordering::Relaxed);
 
             // Touch the sector.
             self.queue.push(CacheOperation::Touch(sector));
 
-            handler(accessor)
-        } else {
-            trace!(self, "cache miss; reading from disk"; "sector" => sector);
+            // Another read might have verified the block in the meantime.
+            if !block.prefetched {
+                return map(&block.data);
+            }
 
-            // Occupy the block in the map, so that we can later on insert it.
-            let block = self.sector_map.get_mut_or(sector, Block::default());
-            // Insert the sector into the cache tracker.
-            self.queue.push(CacheOperation::Create(sector));
+            let res = self.verify(driver, sector, local, &mut block, &map);
+            // Only trust the block once it passed verification.
+            block.prefetched = res.is_err();
+
+            return res;
+        }
+
+        trace!(self, "cache miss; reading from disk"; "subsystem" => subsystem::CACHE,
+               "sector" => sector);
+        self.misses.fetch_add(1, atomic::Ordering::Relaxed);
+
+        // Occupy the block in the map, so that we can later on insert it.
+        let mut block = self.sector_map.get_mut_or(sector, Block::default());
+        // Insert the sector into the cache tracker.
+        self.queue.push(CacheOperation::Create(sector));
+
+        // Fetch the data from the disk, and verify it.
+        driver.read_to(local, &mut block.data)?;
+        let res = self.verify(driver, sector, local, &mut block, &map);
+
+        // Release the block. Prefetching inserts other blocks into the map, so it mustn't happen
+        // while the block is held.
+        drop(block);
+
+        // Prefetch the following sectors, if the access pattern is sequential.
+        self.prefetch(driver, sector, local, window);
+        // Keep the cache within its bounds.
+        self.keep_within_bounds();
+
+        res
+    }
+
+    /// Verify a block fetched from the disk.
+    ///
+    /// This applies `map` to the data of `block`, which holds sector `sector` (being `local` on
+    /// `driver`). If `map` fails, the data is likely corrupt, so we try to recover it through the
+    /// vdev's redundancy, read it again and see if it passes verification this time. If not,
+    /// healing failed, and we cannot do anything about it.
+    fn verify<F, T, E>(&self, driver: &vdev::Driver, sector: disk::Sector, local: disk::Sector,
+                       block: &mut Block, map: &F) -> Result<T, E>
+        where F: Fn(&disk::SectorBuf) -> Result<T, E>,
+              E: From<disk::Error> {
+        match map(&block.data) {
+            Err(err) => {
+                warn!(self, "data verification failed"; "subsystem" => subsystem::CACHE,
+                      "sector" => sector, "error" => err);
+
+                // Attempt to heal the sector.
+                driver.heal(local)?;
+                // Read it again.
+                driver.read_to(local, &mut block.data)?;
+
+                // Try to verify it once again.
+                map(&block.data)
+            },
+            x => x,
+        }
+    }
+
+    /// Prefetch the sectors following a sector.
+    ///
+    /// This reads up to `window` sectors following `sector` (which is `local` on `driver`) into
+    /// the cache, stopping at the end of the device. Sectors which are already cached are
+    /// skipped. Prefetching is only an optimization, so I/O errors merely stop it.
+    ///
+    /// The prefetched blocks are marked, so they're verified on their first read.
+    fn prefetch(&self, driver: &vdev::Driver, sector: disk::Sector, local: disk::Sector, window: usize) {
+        for n in 1..window as disk::Sector + 1 {
+            // Don't read past the end of the device.
+            if local + n >= driver.number_of_sectors() {
+                break;
+            }
+
+            // Skip sectors which are already cached.
+            if self.sector_map.contains_key(&(sector + n)) {
+                continue;
+            }
+
+            trace!(self, "prefetching sector"; "subsystem" => subsystem::CACHE, "sector" => sector + n);
 
-            // Fetch the data from the disk.
-            self.disk.read_to(sector, &mut block.data)?;
-
-            // Handle the block through the closure, and resolve respective verification issues.
-            match map(&block.data) {
-                Err(err) => {
-                    // The verifier failed, so the data is likely corrupt. We'll try to recover the
-                    // data through the vdev's redundancy, then we read it again and see if it passes
-                    // verification this time. If not, healing failed, and we cannot do anything about
-                    // it.
-                    warn!(self, "data verification failed"; "sector" => sector, "error" => err);
-
-                    // Attempt to heal the sector.
-                    self.disk.heal(sector)?;
-                    // Read it again.
-                    self.disk.read_to(sector, &mut block.data)?;
-
-                    // Try to verify it once again.
-                    map(&block.data)
-                },
-                x => x,
+            let mut block = Block::new([0; disk::SECTOR_SIZE]);
+            block.prefetched = true;
+            if driver.read_to(local + n, &mut block.data).is_err() {
+                break;
             }
+
+            // Insert the block into the cache and the cache tracker.
+            self.sector_map.insert(sector + n, block);
+            self.queue.push(CacheOperation::Create(sector + n));
+            self.prefetches.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Issue a write barrier.
+    ///
+    /// This makes sure that every write issued so far hits the disk(s) before any following write
+    /// does.
+    fn barrier(&self) -> Result<(), disk::Error> {
+        trace!(self, "issuing write barrier"; "subsystem" => subsystem::CACHE);
+
+        self.driver.barrier()?;
+        if let Some(ref metadata) = self.metadata {
+            metadata.driver.barrier()?;
         }
+
+        Ok(())
+    }
+
+    /// Flush a sector.
+    ///
+    /// This writes sector `sector` and its flush dependencies to the disk, but unlike `trim`, it
+    /// keeps the blocks in the cache and leaves unrelated dirty blocks alone.
+    fn flush(&self, sector: disk::Sector) -> Result<(), disk::Error> {
+        debug!(self, "flushing sector"; "subsystem" => subsystem::CACHE, "sector" => sector);
+
+        // Are there writes issued since the last barrier?
+        let mut unordered = false;
+
+        // Like in `trim`, we traverse the dependency graph depth-first, and write a block once all
+        // its dependencies are flushed. Every element holds whether the sector is revisited after
+        // flushing a dependency.
+        let mut stack = vec![(sector, false)];
+        while let Some((sector, revisited)) = stack.pop() {
+            // Skip the sector if it isn't cached (and hence not dirty).
+            if let Some(mut block) = self.sector_map.get_mut(sector) {
+                // Skip the sector if it is already in sync with the disk, or held back.
+                if !block.dirty || block.deferred {
+                    continue;
+                }
+
+                // Drop the dependencies, which have been flushed. A dependency is only dropped once
+                // it is, so bailing out below leaves the dependency graph intact.
+                while block.flush_dependencies.last().map_or(false, |&dep| !self.is_dirty(dep)) {
+                    block.flush_dependencies.pop();
+                }
+
+                // A block cannot be written before its dependencies, so if one of them is held
+                // back, so is the block (and the blocks on the stack depending on it).
+                if block.flush_dependencies.last().map_or(false, |&dep| self.is_deferred(dep)) {
+                    trace!(self, "dependency held back by deferred transaction";
+                           "subsystem" => subsystem::CACHE, "sector" => sector);
+                    break;
+                }
+
+                if let Some(&dep) = block.flush_dependencies.last() {
+                    trace!(self, "traversing dependency"; "subsystem" => subsystem::CACHE,
+                           "sector" => sector,
+                           "depending sector" => dep);
+
+                    // Revisit the sector after the dependency has been flushed.
+                    stack.push((sector, true));
+                    stack.push((dep, false));
+                } else {
+                    // All dependencies are flushed, but they might not have hit the disk yet. Put
+                    // up a barrier, so they do before the sector.
+                    if revisited && unordered {
+                        self.barrier()?;
+                    }
+
+                    // Now, we can safely write the sector.
+                    let (driver, local) = self.route(sector);
+                    driver.write(local, block.data)?;
+                    unordered = true;
+                    // Unset the dirty flag.
+                    block.dirty = false;
+
+                    // Discard the sectors waiting for the block.
+                    let discards: Vec<_> = mem::replace(&mut block.discards, Vec::new()).into_iter()
+                        .filter(|&discarded| self.take_discard(discarded, sector))
+                        .collect();
+                    if !discards.is_empty() {
+                        // Release the block, since discarding evicts from the cache.
+                        drop(block);
+                        self.barrier()?;
+                        unordered = false;
+                        self.discard_all(discards);
+                    }
+                }
+            }
+        }
+
+        // Make sure that the flushed sectors hit the disk.
+        if unordered {
+            self.barrier()?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the number of dirty blocks.
+    fn dirty_blocks(&self) -> usize {
+        self.sector_map.iter().filter(|&(_, block)| block.dirty).count()
+    }
+
+    /// Flush some dirty blocks.
+    ///
+    /// This writes up to `max` dirty blocks (and their flush dependencies) to the disk, keeping
+    /// them in the cache. The number of blocks chosen is returned.
+    ///
+    /// This allows for trickling dirty blocks to the disk, so later flushes have little to do.
+    fn flush_some(&self, max: usize) -> Result<usize, disk::Error> {
+        trace!(self, "flushing some dirty blocks"; "subsystem" => subsystem::CACHE, "max" => max);
+
+        // Pick the dirty blocks. Blocks dirtied in the meantime are simply left for later.
+        let dirty: Vec<_> = self.sector_map.iter()
+            .filter(|&(_, block)| block.dirty && !block.deferred)
+            .map(|(&sector, _)| sector)
+            .take(max)
+            .collect();
+        for &sector in &dirty {
+            self.flush(sector)?;
+        }
+
+        Ok(dirty.len())
+    }
+
+    /// Evict a block from the cache.
+    ///
+    /// This drops the cached block of `sector`, such that the next read fetches it from the disk
+    /// again. Returns whether the block was evicted, i.e. whether it was cached and clean.
+    fn evict(&self, sector: disk::Sector) -> bool {
+        debug!(self, "evicting block"; "subsystem" => subsystem::CACHE, "sector" => sector);
+
+        // Lock the cache tracker.
+        let tracker = self.tracker.lock();
+        // Commit the buffered operations to the tracker, so the block's creation isn't replayed
+        // after it is removed.
+        while let Some(op) = self.queue.try_pop() {
+            match op {
+                // Create new cache block.
+                CacheOperation::Create(sector) => tracker.create(sector),
+                // Touch a cache block.
+                CacheOperation::Touch(sector) => tracker.touch(sector),
+            }
+        }
+
+        // Only clean blocks can be evicted, as the cache holds the only copy of dirty ones.
+        if self.sector_map.find(sector).map_or(false, |block| !block.dirty) {
+            // Remove the sector from the cache tracker and the sector map.
+            tracker.remove(sector);
+            self.sector_map.remove(sector);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Discard a sector on the disk.
+    ///
+    /// This tells the device holding `sector` that its content is no longer needed. The cached
+    /// block, if clean, is evicted, since it no longer reflects the content of the disk.
+    fn discard(&self, sector: disk::Sector) -> Result<(), disk::Error> {
+        trace!(self, "discarding sector"; "subsystem" => subsystem::CACHE, "sector" => sector);
+
+        // Drop the stale block.
+        self.evict(sector);
+
+        // Forward the call to the device of the sector.
+        let (driver, local) = self.route(sector);
+        driver.discard(local)
     }
 
     /// Trim the cache.
@@ -292,7 +1014,7 @@ impl Cache {
     /// This reduces the cache to exactly `to` blocks. Note that this is quite expensive, and
     /// should thus only be called once in a whle.
     fn trim(&self, to: usize) -> Result<(), disk::Error> {
-        info!(self, "trimming cache"; "to" => to);
+        info!(self, "trimming cache"; "subsystem" => subsystem::CACHE, "to" => to);
 
         // Lock the cache tracker.
         let tracker = self.tracker.lock();
@@ -306,62 +1028,106 @@ impl Cache {
             }
         }
 
+        // Are there writes issued since the last barrier?
+        let mut unordered = false;
+        // The discarded sectors, whose blocks must be evicted.
+        let mut evict = Vec::new();
+
         // The set of blocks to trim.
         let mut flush: HashSet<_> = tracker.trim(to).collect();
         // Exhaust the set until it is empty.
         while let Some(tl_sector) = flush.drain().first() {
-            debug!(self, "flushing and removing block"; "sector" => tl_sector);
+            debug!(self, "flushing and removing block"; "subsystem" => subsystem::CACHE,
+                   "sector" => tl_sector);
 
             // Acquire the lock. To avoid changes while we traverse, we use mutable locks,
             // excluding other writes.
             // TODO: Perhaps use immutable locks and then upgrade them to mutable when changing the
             //       dirty flag. This could improve performance significantly.
             let tl_block = self.sector_map.get_mut(tl_sector);
-            // Skip flushing, if the block is not dirty.
-            if !tl_block.dirty {
+            // Skip flushing, if the block is not dirty, or held back by a deferred transaction.
+            if !tl_block.dirty || tl_block.deferred {
                 continue;
             }
             // Start with an empty stack to do our search. This stack will hold the state of the
             // traversal, following a variant of DFS, where we dive as deep as possible first, and
-            // then backtrack when we can go deeper. Every element must be a dirty block.
+            // then backtrack when we can go deeper. Every element must be a dirty block, and holds
+            // whether it is revisited after flushing a dependency.
             let mut stack = Vec::new();
             // Push the sector we will flush to the stack.
-            stack.push((tl_sector, tl_block));
+            stack.push((tl_sector, tl_block, false));
 
             // Traverse!
             loop {
                 // Pop the top of the stack to deepen it.
-                if let Some((sector, block)) = stack.pop() {
+                if let Some((sector, block, revisited)) = stack.pop() {
+                    // Drop the dependencies, which have been flushed. A dependency is only dropped
+                    // once it is, so bailing out below leaves the dependency graph intact.
+                    while block.flush_dependencies.last().map_or(false, |&dep| !self.is_dirty(dep)) {
+                        block.flush_dependencies.pop();
+                    }
+
+                    // If a dependency is held back by a deferred transaction, the block cannot be
+                    // flushed (nor removed), and neither can the blocks depending on it.
+                    if block.flush_dependencies.last().map_or(false, |&dep| self.is_deferred(dep)) {
+                        trace!(self, "dependency held back by deferred transaction";
+                               "subsystem" => subsystem::CACHE, "sector" => sector);
+                        break;
+                    }
+
                     // See if the block has flush dependencies, which must be flushed before.
-                    if let Some(dep) = block.flush_dependencies.pop() {
+                    if let Some(&dep) = block.flush_dependencies.last() {
                         // It got at least one flush dependencies.
-                        trace!(self, "traversing dependency";
+                        trace!(self, "traversing dependency"; "subsystem" => subsystem::CACHE,
                                "sector" => sector,
                                "depending sector" => dep);
 
                         // Since it could potentially have more than one dependencies, we push it
                         // back so that we can reinvestigate later.
-                        stack.push((sector, block));
+                        stack.push((sector, block, true));
 
                         // Check if the block is dirty, or we can skip it. This holds up the
                         // invariant that every block in `stack` are dirty.
                         if block.dirty {
                             // Push the dependency to the stack.
-                            stack.push((dep, self.sector_map.get_mut(sector)));
+                            stack.push((dep, self.sector_map.get_mut(sector), false));
                         }
                     } else {
                         // The block is dirty and needs to be flushed. Note that we need not to
                         // check if it is dirty, as our invariant states that al blocks in `stack`
                         // are dirty.
-                        debug!(self, "flushing block"; "sector" => sector);
+                        debug!(self, "flushing block"; "subsystem" => subsystem::CACHE, "sector" => sector);
+
+                        // Put up a barrier, so the flushed dependencies hit the disk before the
+                        // sector.
+                        if revisited && unordered {
+                            self.barrier()?;
+                        }
 
                         // No more flush dependencies on the former top of the stack (`block`), so
                         // we can safely write the sector, knowing that all dependencies have been
                         // flushed.
-                        self.driver.write(sector, block.data)?;
+                        let (driver, local) = self.route(sector);
+                        driver.write(local, block.data)?;
+                        unordered = true;
                         // Unset the dirty flag.
                         block.dirty = false;
 
+                        // Discard the sectors waiting for the block, once it has hit the disk.
+                        // Evicting takes the cache tracker, which we hold, so the stale blocks
+                        // are evicted after the traversal.
+                        let discards: Vec<_> = block.discards.drain(..)
+                            .filter(|&discarded| self.take_discard(discarded, sector))
+                            .collect();
+                        if !discards.is_empty() {
+                            self.barrier()?;
+                            unordered = false;
+                            for &discarded in &discards {
+                                self.discard_device(discarded);
+                            }
+                            evict.extend(discards);
+                        }
+
                         // Clean up the block if it is a top-level block (a block which will be
                         // removed).
                         if flush.remove(sector) || sector == tl_sector {
@@ -384,17 +1150,374 @@ impl Cache {
                 }
             }
         }
+
+        // Make sure that the flushed blocks hit the disk.
+        if unordered {
+            self.barrier()?;
+        }
+
+        // Release the cache tracker, and evict the discarded sectors.
+        drop(tracker);
+        for sector in evict {
+            self.evict(sector);
+        }
+
+        Ok(())
+    }
+
+    /// Take a pending discard.
+    ///
+    /// This returns whether `sector` is still to be discarded by the block of sector `by`, which
+    /// was just flushed, and clears the pending discard if so. The discard is skipped, if the
+    /// sector was written since it was queued, or it was queued again by another block.
+    fn take_discard(&self, sector: disk::Sector, by: disk::Sector) -> bool {
+        let mut pending = self.pending_discards.lock();
+        if pending.get(&sector) == Some(&by) {
+            pending.remove(&sector);
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Discard several sectors.
+    ///
+    /// This evicts the blocks of `sectors`, and discards them on the device.
+    fn discard_all(&self, sectors: Vec<disk::Sector>) {
+        for sector in sectors {
+            self.evict(sector);
+            self.discard_device(sector);
+        }
+    }
+
+    /// Discard a sector on the device, leaving the cache alone.
+    ///
+    /// Discarding is merely a hint to the device, so failures are logged rather than returned.
+    fn discard_device(&self, sector: disk::Sector) {
+        let (driver, local) = self.route(sector);
+        if let Err(err) = driver.discard(local) {
+            warn!(self, "failed to discard sector"; "subsystem" => subsystem::CACHE,
+                  "sector" => sector, "error" => err);
+        }
     }
 }
 
 impl Drop for Cache {
     fn drop(&mut self) {
-        info!(self, "closing cache");
+        info!(self, "closing cache"; "subsystem" => subsystem::CACHE);
 
+        // Apply the deferred transactions, as they would be lost otherwise.
+        self.drain_deferred();
         self.trim(0);
     }
 }
 
 delegate_log!(Cache.driver);
 
-// TODO: Add tests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::mem_sim::MemSim;
+    use std::sync::{Arc, Mutex};
+
+    /// Open a cache on a simulated disk with a fresh disk header.
+    fn cache(disk: &MemSim) -> Cache {
+        cache_on(disk, disk.clone())
+    }
+
+    /// Open a cache on a disk wrapping simulated disk `inner`.
+    ///
+    /// The fresh disk header is written to `inner` directly, bypassing the wrapper.
+    fn cache_on<D: Disk>(inner: &MemSim, disk: D) -> Cache {
+        inner.clone().write(0, &header::DiskHeader::default().encode()).unwrap();
+
+        Cache::from(vdev::Driver::open(slog::Discard, disk, b"").unwrap())
+    }
+
+    /// A disk reordering writes.
+    ///
+    /// Writes are buffered, and only land on the inner disk on barriers, in reverse order, like a
+    /// reordering controller might do.
+    #[derive(Clone)]
+    struct Reordering {
+        /// The inner disk.
+        inner: MemSim,
+        /// The writes which haven't landed yet.
+        pending: Arc<Mutex<Vec<(disk::Sector, disk::SectorBuf)>>>,
+        /// The sectors in the order they landed.
+        landed: Arc<Mutex<Vec<disk::Sector>>>,
+    }
+
+    impl Reordering {
+        /// Create a reordering disk on a fresh simulated disk of some number of sectors.
+        fn new(sectors: disk::Sector) -> Reordering {
+            Reordering {
+                inner: MemSim::new(sectors),
+                pending: Arc::new(Mutex::new(Vec::new())),
+                landed: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    impl Disk for Reordering {
+        fn number_of_sectors(&self) -> disk::Sector {
+            self.inner.number_of_sectors()
+        }
+
+        fn write(&mut self, sector: disk::Sector, buf: &disk::SectorBuf) -> Result<(), disk::Error> {
+            self.pending.lock().unwrap().push((sector, *buf));
+
+            Ok(())
+        }
+
+        fn read_to(&self, sector: disk::Sector, buf: &mut disk::SectorBuf) -> Result<(), disk::Error> {
+            self.inner.read_to(sector, buf)
+        }
+
+        fn heal(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+            Ok(())
+        }
+
+        fn barrier(&mut self) -> Result<(), disk::Error> {
+            // Land the pending writes, newest first.
+            let mut pending = self.pending.lock().unwrap();
+            while let Some((sector, buf)) = pending.pop() {
+                self.inner.write(sector, &buf)?;
+                self.landed.lock().unwrap().push(sector);
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn barrier() {
+        let disk = Reordering::new(16);
+        let cache = cache_on(&disk.inner, disk.clone());
+
+        // Write a cluster, and then a state block depending on it.
+        cache.write(2, [1; disk::SECTOR_SIZE]).then(cache.write(1, [2; disk::SECTOR_SIZE])).execute();
+        cache.flush(1).unwrap();
+
+        // Despite the reordering, the cluster landed before the state block.
+        let landed: Vec<_> = disk.landed.lock().unwrap().iter().cloned().filter(|&sector| sector != 0).collect();
+        assert_eq!(landed, [2, 1]);
+    }
+
+    #[test]
+    fn precede() {
+        let disk = Reordering::new(16);
+        let cache = cache_on(&disk.inner, disk.clone());
+
+        // Make two writes depend on a state block, which is executed last.
+        let state = cache.write(1, [1; disk::SECTOR_SIZE]);
+        let first = state.precede(cache.write(2, [2; disk::SECTOR_SIZE]));
+        let second = state.precede(cache.write(3, [3; disk::SECTOR_SIZE]));
+        state.execute();
+        first.execute();
+        second.execute();
+        cache.flush(3).unwrap();
+        cache.flush(2).unwrap();
+
+        // Both writes landed after the state block.
+        let landed: Vec<_> = disk.landed.lock().unwrap().iter().cloned().filter(|&sector| sector != 0).collect();
+        assert_eq!(landed[0], 1);
+        assert_eq!(landed.len(), 3);
+    }
+
+    #[test]
+    fn deferred_chain() {
+        let disk = Reordering::new(16);
+        let cache = cache_on(&disk.inner, disk.clone());
+        let landed = || disk.landed.lock().unwrap().iter().cloned().filter(|&sector| sector != 0).collect::<Vec<_>>();
+
+        // Chain three writes, the first of which is deferred.
+        cache.set_deferring(true);
+        let deferred = cache.write(3, [1; disk::SECTOR_SIZE]);
+        cache.set_deferring(false);
+        deferred.then(cache.write(2, [2; disk::SECTOR_SIZE])).then(cache.write(1, [3; disk::SECTOR_SIZE])).execute();
+
+        // Neither flushing nor trimming writes anything, no matter how often they're tried.
+        for _ in 0..2 {
+            cache.flush(1).unwrap();
+            cache.trim(0).unwrap();
+            assert!(landed().is_empty());
+        }
+
+        // Once the deferred write is released, the chain lands in order.
+        cache.drain_deferred().unwrap();
+        cache.trim(0).unwrap();
+        assert_eq!(landed(), [3, 2, 1]);
+    }
+
+    #[test]
+    fn readahead_sequential() {
+        let disk = MemSim::new(64);
+        let cache = cache(&disk);
+
+        for sector in 1..33 {
+            cache.read_then(sector, |_| Ok::<_, disk::Error>(())).unwrap();
+        }
+
+        // The read-ahead kicked in, serving some of the reads from the cache.
+        assert!(cache.prefetches() > 0);
+        assert!(cache.hits() > 0);
+        assert_eq!(cache.hits() + cache.misses(), 32);
+        // Sectors ahead of the last read were prefetched.
+        assert!(cache.sector_map.contains_key(&33));
+
+        // The metrics agree.
+        let metrics = cache.metrics();
+        assert_eq!(metrics.hits, cache.hits());
+        assert_eq!(metrics.misses, cache.misses());
+        assert_eq!(metrics.prefetches, cache.prefetches());
+        assert_eq!(metrics.sectors, cache.len());
+        assert_eq!(metrics.max_sectors, cache.max_sectors());
+    }
+
+    /// A disk recording the sectors healed.
+    #[derive(Clone)]
+    struct Healing {
+        /// The inner disk.
+        inner: MemSim,
+        /// The sectors healed.
+        healed: Arc<Mutex<Vec<disk::Sector>>>,
+    }
+
+    impl Healing {
+        /// Create a healing disk on a fresh simulated disk of some number of sectors.
+        fn new(sectors: disk::Sector) -> Healing {
+            Healing {
+                inner: MemSim::new(sectors),
+                healed: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    impl Disk for Healing {
+        fn number_of_sectors(&self) -> disk::Sector {
+            self.inner.number_of_sectors()
+        }
+
+        fn write(&mut self, sector: disk::Sector, buf: &disk::SectorBuf) -> Result<(), disk::Error> {
+            self.inner.write(sector, buf)
+        }
+
+        fn read_to(&self, sector: disk::Sector, buf: &mut disk::SectorBuf) -> Result<(), disk::Error> {
+            self.inner.read_to(sector, buf)
+        }
+
+        fn heal(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+            self.healed.lock().unwrap().push(sector);
+
+            Ok(())
+        }
+
+        fn barrier(&mut self) -> Result<(), disk::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn readahead_verified() {
+        let disk = Healing::new(64);
+        let cache = cache_on(&disk.inner, disk.clone());
+
+        for sector in 1..33 {
+            cache.read_then(sector, |_| Ok::<_, disk::Error>(())).unwrap();
+        }
+        assert!(cache.sector_map.find(33).unwrap().prefetched);
+
+        // The first read of a prefetched sector is verified, and healed on failure.
+        let attempts = AtomicUsize::new(0);
+        cache.read_then(33, |_| if attempts.fetch_add(1, atomic::Ordering::Relaxed) == 0 {
+            Err(disk::Error::CorruptSector { sector: 33 })
+        } else {
+            Ok(())
+        }).unwrap();
+        assert_eq!(attempts.load(atomic::Ordering::Relaxed), 2);
+        assert_eq!(*disk.healed.lock().unwrap(), [33]);
+        assert!(!cache.sector_map.find(33).unwrap().prefetched);
+
+        // Once verified, it's an ordinary cache hit.
+        cache.read_then(33, |_| Ok::<_, disk::Error>(())).unwrap();
+        assert_eq!(*disk.healed.lock().unwrap(), [33]);
+    }
+
+    #[test]
+    fn readahead_random() {
+        let disk = MemSim::new(64);
+        let cache = cache(&disk);
+
+        for &sector in &[40, 3, 27, 9, 55, 14, 33, 2, 61, 20] {
+            cache.read_then(sector, |_| Ok::<_, disk::Error>(())).unwrap();
+        }
+
+        // No sequential access, so nothing was prefetched.
+        assert_eq!(cache.prefetches(), 0);
+        assert_eq!(cache.misses(), 10);
+    }
+
+    #[test]
+    fn affected_clusters() {
+        let disk = MemSim::new(16);
+        let cache = cache(&disk);
+        let clusters: Vec<_> = [3, 5, 7].iter().map(|&n| cluster::Pointer::new(n).unwrap()).collect();
+
+        // A single write affects its cluster.
+        let transaction = cache.write(3, [3; disk::SECTOR_SIZE]);
+        assert_eq!(transaction.affected_clusters(), &clusters[..1]);
+
+        // Chained writes affect every cluster, in order.
+        let transaction = transaction
+            .then(cache.write(5, [5; disk::SECTOR_SIZE]))
+            .then(cache.write(7, [7; disk::SECTOR_SIZE]));
+        assert_eq!(transaction.affected_clusters(), &clusters[..]);
+        transaction.execute();
+
+        // Rewriting a cluster doesn't list it twice, and the disk header isn't a cluster.
+        let transaction = cache.write(0, header::DiskHeader::default().encode())
+            .then(cache.write(5, [5; disk::SECTOR_SIZE]))
+            .then(cache.write(3, [3; disk::SECTOR_SIZE]))
+            .then(cache.write(5, [6; disk::SECTOR_SIZE]));
+        assert_eq!(transaction.affected_clusters(), &[clusters[1], clusters[0]]);
+        transaction.execute();
+    }
+
+    #[test]
+    fn max_sectors_on_write() {
+        let disk = MemSim::new(64);
+        let cache = cache(&disk);
+        cache.set_max_sectors(4).unwrap();
+
+        // Writing new sectors trims the cache, flushing the evicted blocks.
+        for sector in 1..17 {
+            cache.write(sector, [sector as u8; disk::SECTOR_SIZE]).execute();
+            assert!(cache.len() <= 4);
+        }
+        for sector in 1..13 {
+            assert_eq!(disk.sector(sector), [sector as u8; disk::SECTOR_SIZE]);
+        }
+
+        // Nothing was lost.
+        cache.trim(0).unwrap();
+        for sector in 1..17 {
+            assert_eq!(disk.sector(sector), [sector as u8; disk::SECTOR_SIZE]);
+        }
+    }
+
+    #[test]
+    fn readahead_disabled() {
+        let disk = MemSim::new(64);
+        let cache = cache(&disk);
+        cache.set_max_readahead(0);
+
+        for sector in 1..33 {
+            cache.read_then(sector, |_| Ok::<_, disk::Error>(())).unwrap();
+        }
+
+        assert_eq!(cache.prefetches(), 0);
+        assert_eq!(cache.misses(), 32);
+    }
+}
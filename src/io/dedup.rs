@@ -7,10 +7,15 @@ extern crate ring;
 
 use crossbeam::sync::AtomicOption;
 use ring::digest;
-use std::sync::atomic;
+use std::sync::atomic::{self, AtomicUsize};
 
 /// The atomic ordering used in the table.
 const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
+/// The interval at which candidates are verified regardless of the measured costs.
+///
+/// Without this, an adaptive policy which stopped verifying could never notice that verification
+/// got cheap again.
+const PROBE_INTERVAL: usize = 64;
 
 /// A SHA-256 fingerprint of a page.
 ///
@@ -31,8 +36,19 @@ impl Fingerprint {
     }
 }
 
-/// The maximal number of pagess the table can contain.
-const MAX_PAGES_IN_TABLE: usize = 1 << 16;
+/// The default number of pages the table can contain.
+///
+/// See `Table::with_capacity`.
+const DEFAULT_PAGES_IN_TABLE: usize = 1 << 16;
+/// The size (in bytes) of the preamble of a persisted table.
+///
+/// The preamble consists of the checksum, the generation, and the number of entries.
+const PERSISTED_PREAMBLE_SIZE: usize = 24;
+/// The size (in bytes) of an entry of a persisted table.
+///
+/// An entry consists of the page pointer, the high 32 bits of its checksum (which don't fit in the
+/// pointer's integer form), and the fingerprint.
+const PERSISTED_ENTRY_SIZE: usize = 52;
 
 /// A deduplication candidate.
 ///
@@ -50,7 +66,9 @@ struct Candidate {
     /// No fingerprint function mapping a domain to a smaller codomain is injective (gives unique
     /// fingerprints), but with wide enough fingerprints, finding collisions gets practically
     /// impossible. Even if an user had malicious intends, they cannot compute a collision.
-    fingerprint: Fingerprint,
+    ///
+    /// Imported candidates have no fingerprint until they're verified against the stored page.
+    fingerprint: Option<Fingerprint>,
 }
 
 impl Candidate {
@@ -60,10 +78,207 @@ impl Candidate {
     fn is_match(&self, buf: &disk::SectorBuf) -> bool {
         // Check the fingerprint against the hash of the buffer. Again, this is strictly speak
         // heuristic, but for all practical purposes, no collisions will ever be found.
-        candidate.fingerprint == fingerprint(buf)
+        candidate.fingerprint == Some(fingerprint(buf))
+    }
+}
+
+/// A persisted deduplication table.
+///
+/// This is the form in which the table is written to the disk, so the table is warm right after
+/// the manager is opened.
+#[derive(Clone)]
+struct Persisted {
+    /// The generation of the table.
+    ///
+    /// This increases with every flush, identifying the newest of the persisted tables.
+    generation: u64,
+    /// The candidates of the table.
+    candidates: Vec<Candidate>,
+}
+
+impl Persisted {
+    /// Parse the binary representation of a persisted table.
+    ///
+    /// If the checksum doesn't match (e.g. because the write was torn), or the entries don't fit
+    /// in `buf`, `None` is returned.
+    fn decode(buf: &[u8], checksum_algorithm: header::ChecksumAlgorithm) -> Option<Persisted> {
+        if buf.len() < PERSISTED_PREAMBLE_SIZE {
+            return None;
+        }
+
+        // Make sure that the checksum matches the 8 byte field in the start.
+        if LittleEndian::read(buf) != checksum_algorithm.hash(&buf[8..]) {
+            return None;
+        }
+
+        // Load the number of entries, and make sure that they fit.
+        let len = LittleEndian::read::<u32>(&buf[16..]) as usize;
+        if len > (buf.len() - PERSISTED_PREAMBLE_SIZE) / PERSISTED_ENTRY_SIZE {
+            return None;
+        }
+
+        // Load the entries.
+        let candidates = buf[PERSISTED_PREAMBLE_SIZE..].chunks(PERSISTED_ENTRY_SIZE).take(len).map(|entry| {
+            // Restore the high bits of the checksum.
+            let mut page = page::Pointer::from(LittleEndian::read::<u128>(entry));
+            page.checksum |= (LittleEndian::read::<u32>(&entry[16..]) as u64) << 32;
+
+            Candidate {
+                page: page,
+                fingerprint: Some(Fingerprint(LittleEndian::read(&entry[20..]), LittleEndian::read(&entry[36..]))),
+            }
+        }).collect();
+
+        Some(Persisted {
+            generation: LittleEndian::read(&buf[8..]),
+            candidates: candidates,
+        })
+    }
+
+    /// Encode the persisted table into `len` bytes.
+    ///
+    /// The candidates which don't fit are dropped, which merely makes the table colder. So are
+    /// the unverified candidates, which have no fingerprint to persist.
+    fn encode(&self, len: usize, checksum_algorithm: header::ChecksumAlgorithm) -> Vec<u8> {
+        let mut buf = vec![0; len];
+
+        // Write the entries.
+        let mut entries = 0u32;
+        let verified = self.candidates.iter().filter_map(|candidate| candidate.fingerprint.map(|fingerprint| {
+            (candidate.page, fingerprint)
+        }));
+        for ((page, fingerprint), entry) in verified.zip(buf[PERSISTED_PREAMBLE_SIZE..].chunks_mut(PERSISTED_ENTRY_SIZE)) {
+            // Skip the truncated entry at the end.
+            if entry.len() < PERSISTED_ENTRY_SIZE {
+                break;
+            }
+
+            LittleEndian::write(entry, u128::from(page));
+            LittleEndian::write(&mut entry[16..], (page.checksum >> 32) as u32);
+            LittleEndian::write(&mut entry[20..], fingerprint.0);
+            LittleEndian::write(&mut entry[36..], fingerprint.1);
+            entries += 1;
+        }
+
+        // Write the generation and the number of entries.
+        LittleEndian::write(&mut buf[8..], self.generation);
+        LittleEndian::write(&mut buf[16..], entries);
+
+        // Calculate and store the checksum.
+        let cksum = checksum_algorithm.hash(&buf[8..]);
+        LittleEndian::write(&mut buf, cksum);
+
+        buf
+    }
+}
+
+/// Estimate the entropy of a page.
+///
+/// This cheaply estimates the entropy of `buf` by counting its distinct byte values. Random data
+/// uses nearly all 256 values, while text and repetitive data use far fewer.
+fn distinct_bytes(buf: &disk::SectorBuf) -> usize {
+    let mut seen = [false; 256];
+    let mut distinct = 0;
+    for &byte in buf.iter() {
+        if !seen[byte as usize] {
+            seen[byte as usize] = true;
+            distinct += 1;
+        }
+    }
+
+    distinct
+}
+
+/// The mode of a deduplication lookup.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Mode {
+    /// Verify the candidate against the fingerprint of the page.
+    Verify,
+    /// Trust a candidate with matching checksum, without verification.
+    Trust,
+    /// Skip deduplication.
+    Skip,
+}
+
+/// A tracker of the costs of deduplication.
+///
+/// This keeps moving averages of the time (in nanoseconds) it takes to verify a deduplication
+/// candidate and to store a fresh page, which is used to decide the mode of lookups under the
+/// adaptive policy.
+#[derive(Default)]
+struct Cost {
+    /// The average cost of verification.
+    verify: AtomicUsize,
+    /// The average cost of storing a page.
+    store: AtomicUsize,
+    /// The number of lookups done.
+    lookups: AtomicUsize,
+}
+
+impl Cost {
+    /// Record the cost of a verification.
+    fn record_verify(&self, nanos: usize) {
+        Cost::record(&self.verify, nanos);
+    }
+
+    /// Record the cost of storing a page.
+    fn record_store(&self, nanos: usize) {
+        Cost::record(&self.store, nanos);
+    }
+
+    /// Update a moving average with a new sample.
+    fn record(average: &AtomicUsize, sample: usize) {
+        // Weight the new sample by 1/8. This is racy, but an approximate average is sufficient.
+        let old = average.load(ORDERING);
+        average.store((old * 7 + sample) / 8, ORDERING);
+    }
+
+    /// Choose the mode of the next lookup.
+    ///
+    /// This chooses the mode of the lookup based on the policy `policy`, the checksum width
+    /// `width`, and the measured costs.
+    fn mode(&self, policy: state_block::DedupPolicy, width: state_block::ChecksumWidth) -> Mode {
+        match policy {
+            state_block::DedupPolicy::Always => Mode::Verify,
+            state_block::DedupPolicy::Disabled => Mode::Skip,
+            state_block::DedupPolicy::Adaptive => {
+                // Verify every once in a while to keep the cost measure up to date. Verify as
+                // well, if verification is cheaper than storing the page.
+                if self.lookups.fetch_add(1, ORDERING) % PROBE_INTERVAL == 0
+                    || self.verify.load(ORDERING) <= self.store.load(ORDERING) {
+                    Mode::Verify
+                } else if width == state_block::ChecksumWidth::Wide {
+                    // Wide checksums practically never collide, so trusting them is low-risk.
+                    Mode::Trust
+                } else {
+                    // Narrow checksums are too weak to be trusted on their own.
+                    Mode::Skip
+                }
+            },
+        }
     }
 }
 
+/// Statistics of a deduplication table.
+///
+/// These are cumulative since the table was created, except for `entries`.
+#[derive(PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Stats {
+    /// The number of pages in the table.
+    pub entries: usize,
+    /// The number of lookups, which found a duplicate.
+    pub hits: usize,
+    /// The number of lookups, which found no duplicate.
+    pub misses: usize,
+    /// The number of pages replaced by other pages sharing their table entry.
+    pub evictions: usize,
+    /// The number of bytes saved by deduplication.
+    ///
+    /// This is the number of hits times the page size.
+    pub bytes_saved: u64,
+}
+
 /// A deduplication table.
 ///
 /// Deduplication tables stores information needed to determine if some page already exist or the
@@ -74,52 +289,270 @@ struct Table {
     /// The table of candidates.
     ///
     /// When looking up a particular candidate, the checksum modulo the table size is used. If this
-    /// entry is `None`, there is no candidate.
-    table: [AtomicOption<Candidate>; MAX_PAGES_IN_TABLE],
+    /// entry is `None`, there is no candidate. See `entry`.
+    table: Vec<AtomicOption<Candidate>>,
+    /// The number of pages in the table.
+    entries: AtomicUsize,
+    /// The number of lookups, which found a duplicate.
+    hits: AtomicUsize,
+    /// The number of lookups, which found no duplicate.
+    misses: AtomicUsize,
+    /// The number of pages replaced by other pages sharing their entry.
+    evictions: AtomicUsize,
+}
+
+impl Default for Table {
+    fn default() -> Table {
+        Table::with_capacity(DEFAULT_PAGES_IN_TABLE)
+    }
 }
 
 impl Table {
+    /// Create a table with room for some number of pages.
+    ///
+    /// This allocates `pages` entries (rounded up to a power of two) up front. The table never
+    /// grows, so more pages only evict each other.
+    fn with_capacity(pages: usize) -> Table {
+        let len = pages.max(1).next_power_of_two();
+        let mut table = Vec::with_capacity(len);
+        for _ in 0..len {
+            table.push(AtomicOption::new());
+        }
+
+        Table {
+            table: table,
+            entries: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the number of pages the table can contain.
+    fn capacity(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Get the entry of some checksum.
+    fn entry(&self, cksum: u64) -> &AtomicOption<Candidate> {
+        &self.table[cksum as usize % self.table.len()]
+    }
+
     /// Find a duplicate of some page.
     ///
     /// This searches for a duplicate of `buf` which has checksum `cksum`. If no duplicate is
     /// found, `None` is returned.
-    fn dedup(&self, buf: &disk::SectorBuf, cksum: u32) -> Option<page::Pointer> {
+    fn dedup(&self, buf: &disk::SectorBuf, cksum: u64) -> Option<page::Pointer> {
+        self.dedup_with(buf, cksum, |_| None)
+    }
+
+    /// Find a duplicate of some page, reading unverified candidates through a closure.
+    ///
+    /// This is like `dedup`, but an imported candidate without a fingerprint is verified by
+    /// reading its data through `read` and comparing it to `buf`. If it matches, the candidate is
+    /// fingerprinted, so later lookups are cheap. If not (or if it cannot be read), the candidate
+    /// is dropped.
+    fn dedup_with<F>(&self, buf: &disk::SectorBuf, cksum: u64, read: F) -> Option<page::Pointer>
+        where F: FnOnce(page::Pointer) -> Option<disk::SectorBuf> {
         // We look up in the table with the checksum under some modulus, since that is faster to
         // calculate than a cryptographic hash, meaning that we can refine candidates based on a
         // rougher first-hand measure.
-        let entry = self.table[cksum % MAX_PAGES_IN_TABLE];
+        let entry = self.entry(cksum);
 
         // Temporarily remove the entry from the table.
         if let Some(candidate) = entry.take(ORDERING) {
             // A candidate exists.
 
+            // Verify imported candidates lazily, on their first potential match.
+            if candidate.fingerprint.is_none() && cksum == candidate.page.checksum {
+                return if read(candidate.page).map_or(false, |data| data == *buf) {
+                    // The candidate matches, so fingerprint it and put it back.
+                    entry.swap(Candidate {
+                        page: candidate.page,
+                        fingerprint: Some(fingerprint(buf)),
+                    }, ORDERING);
+
+                    self.count(Some(candidate.page))
+                } else {
+                    // The import was bogus, so drop the candidate.
+                    self.entries.fetch_sub(1, ORDERING);
+
+                    self.count(None)
+                };
+            }
+
             // Put it back into the entry.
             entry.swap(candidate);
 
             // Check if the checksum and fingerprint matches.
             if cksum == candidate.page.checksum && candidate.isMatch(buf) {
                 // Yup.
-                Some(candidate.page)
+                self.count(Some(candidate.page))
             } else {
                 // Nup.
-                None
+                self.count(None)
             }
         } else {
             // No candidate was stored in the table.
-            None
+            self.count(None)
+        }
+    }
+
+    /// Find a duplicate of some page by the checksum alone.
+    ///
+    /// This is like `dedup`, but it skips verification of the candidate, and hence trusts the
+    /// checksum `cksum` to be collision-free.
+    fn dedup_trusted(&self, cksum: u64) -> Option<page::Pointer> {
+        let entry = self.entry(cksum);
+
+        // Temporarily remove the entry from the table.
+        if let Some(candidate) = entry.take(ORDERING) {
+            // Put it back into the entry.
+            entry.swap(candidate);
+
+            // Check if the checksum matches.
+            if cksum == candidate.page.checksum {
+                self.count(Some(candidate.page))
+            } else {
+                self.count(None)
+            }
+        } else {
+            // No candidate was stored in the table.
+            self.count(None)
+        }
+    }
+
+    /// Count a lookup as a hit or a miss.
+    ///
+    /// The result of the lookup, `duplicate`, is returned.
+    fn count(&self, duplicate: Option<page::Pointer>) -> Option<page::Pointer> {
+        if duplicate.is_some() {
+            self.hits.fetch_add(1, ORDERING);
+        } else {
+            self.misses.fetch_add(1, ORDERING);
+        }
+
+        duplicate
+    }
+
+    /// Get the statistics of the table.
+    fn stats(&self) -> Stats {
+        let hits = self.hits.load(ORDERING);
+
+        Stats {
+            entries: self.entries.load(ORDERING),
+            hits: hits,
+            misses: self.misses.load(ORDERING),
+            evictions: self.evictions.load(ORDERING),
+            bytes_saved: hits as u64 * disk::SECTOR_SIZE as u64,
+        }
+    }
+
+    /// Remove a page from the table.
+    ///
+    /// This removes the candidate of page `page`, if any, such that freed pages aren't handed out
+    /// as duplicates.
+    fn remove(&self, page: page::Pointer) {
+        let entry = self.entry(page.checksum);
+
+        // Take out the candidate, and put it back if it belongs to another page.
+        if let Some(candidate) = entry.take(ORDERING) {
+            if candidate.page != page {
+                entry.swap(candidate, ORDERING);
+            } else {
+                self.entries.fetch_sub(1, ORDERING);
+            }
+        }
+    }
+
+    /// Retain only the candidates satisfying a predicate.
+    ///
+    /// Every candidate, whose page `keep` returns `false` for, is removed from the table. The
+    /// number of candidates removed is returned.
+    fn retain<F>(&self, mut keep: F) -> usize
+        where F: FnMut(page::Pointer) -> bool {
+        let mut removed = 0;
+        for entry in self.table.iter() {
+            // Take out the candidate, and put it back if it is kept.
+            if let Some(candidate) = entry.take(ORDERING) {
+                if keep(candidate.page) {
+                    entry.swap(candidate, ORDERING);
+                } else {
+                    removed += 1;
+                }
+            }
+        }
+        self.entries.fetch_sub(removed, ORDERING);
+
+        removed
+    }
+
+    /// Get the persistable form of the table.
+    ///
+    /// The candidates are collected into a table of generation `generation`.
+    fn persisted(&self, generation: u64) -> Persisted {
+        let mut candidates = Vec::new();
+        for entry in self.table.iter() {
+            // Temporarily remove the entry from the table to read it.
+            if let Some(candidate) = entry.take(ORDERING) {
+                entry.swap(candidate, ORDERING);
+                candidates.push(candidate);
+            }
+        }
+
+        Persisted {
+            generation: generation,
+            candidates: candidates,
+        }
+    }
+
+    /// Restore the candidates of a persisted table.
+    fn restore(&self, persisted: Persisted) {
+        for candidate in persisted.candidates {
+            if self.entry(candidate.page.checksum).swap(candidate, ORDERING).is_none() {
+                self.entries.fetch_add(1, ORDERING);
+            }
         }
     }
 
     /// Insert a page into the table.
     ///
     /// This inserts page `page` with data `buf` into the deduplication table.
-    fn insert(&mut self, buf: &disk::SectorBuf, page: page::Pointer) {
-        // Overwrite the old entry with the new updated entry.
-        self.table[page.checksum % MAX_PAGES_IN_TABLE].swap(Candidate {
+    fn insert(&self, buf: &disk::SectorBuf, page: page::Pointer) {
+        self.put(Candidate {
             page: page,
             // TODO: This fingerprint might be double-calculated due to the use in `dedup`.
-            fingerprint: fingerprint(buf),
-        }, ORDERING);
+            fingerprint: Some(fingerprint(buf)),
+        });
+    }
+
+    /// Import a page into the table without its data.
+    ///
+    /// The candidate is unverified, and only verified when it is first matched. See
+    /// `dedup_with`.
+    fn import(&self, page: page::Pointer) {
+        self.put(Candidate {
+            page: page,
+            fingerprint: None,
+        });
+    }
+
+    /// Put a candidate into the table, evicting the old candidate of its entry.
+    fn put(&self, candidate: Candidate) {
+        let page = candidate.page;
+        // Overwrite the old entry with the new updated entry.
+        let old = self.entry(page.checksum).swap(candidate, ORDERING);
+
+        // Count the evicted page, if any.
+        match old {
+            Some(ref candidate) if candidate.page != page => {
+                self.evictions.fetch_add(1, ORDERING);
+            },
+            Some(_) => (),
+            None => {
+                self.entries.fetch_add(1, ORDERING);
+            },
+        }
     }
 }
 
@@ -146,6 +579,53 @@ mod tests {
         assert_eq!(table.dedup(&Default::default(), 13), p2);
     }
 
+    #[test]
+    fn with_capacity() {
+        assert_eq!(Table::with_capacity(1000).capacity(), 1024);
+        assert_eq!(Table::with_capacity(0).capacity(), 1);
+        assert_eq!(Table::default().capacity(), DEFAULT_PAGES_IN_TABLE);
+
+        // Pages beyond the capacity share entries.
+        let table = Table::with_capacity(4);
+        let p1 = page::Pointer {
+            checksum: 1,
+            .. Default::default()
+        };
+        let p2 = page::Pointer {
+            checksum: 5,
+            cluster: cluster::Pointer::new(100).unwrap(),
+            .. Default::default()
+        };
+        table.insert(&[1; disk::SECTOR_SIZE], p1);
+        table.insert(&[5; disk::SECTOR_SIZE], p2);
+        assert_eq!(table.stats().entries, 1);
+        assert_eq!(table.stats().evictions, 1);
+        assert_eq!(table.dedup(&[5; disk::SECTOR_SIZE], 5), Some(p2));
+    }
+
+    #[test]
+    fn retain() {
+        let mut table = Table::default();
+        let pages: Vec<_> = (1..5).map(|n| page::Pointer {
+            cluster: cluster::Pointer::new(n).unwrap(),
+            checksum: n,
+            .. Default::default()
+        }).collect();
+        for (n, &page) in pages.iter().enumerate() {
+            table.insert(&[n as u8; disk::SECTOR_SIZE], page);
+        }
+
+        assert_eq!(table.retain(|page| page.checksum % 2 == 0), 2);
+        assert_eq!(table.stats().entries, 2);
+        assert_eq!(table.dedup(&[0; disk::SECTOR_SIZE], 1), None);
+        assert_eq!(table.dedup(&[1; disk::SECTOR_SIZE], 2), Some(pages[1]));
+        assert_eq!(table.dedup(&[2; disk::SECTOR_SIZE], 3), None);
+        assert_eq!(table.dedup(&[3; disk::SECTOR_SIZE], 4), Some(pages[3]));
+
+        // Nothing is left to remove.
+        assert_eq!(table.retain(|page| page.checksum % 2 == 0), 0);
+    }
+
     #[test]
     fn checksum_collision() {
         let mut table = Table::default();
@@ -164,4 +644,159 @@ mod tests {
 
         assert_eq!(table.dedup(&Default::default(), 7), p2);
     }
+
+    #[test]
+    fn stats() {
+        let mut table = Table::default();
+        let p1 = page::Pointer {
+            cksum: 7,
+            .. Default::default()
+        };
+        let p2 = page::Pointer {
+            cksum: 7,
+            cluster: cluster::Pointer::new(100).unwrap(),
+            .. Default::default()
+        };
+
+        table.insert(&[0; disk::SECTOR_SIZE], p1);
+        assert_eq!(table.dedup(&[0; disk::SECTOR_SIZE], 7), Some(p1));
+        assert_eq!(table.dedup(&[0; disk::SECTOR_SIZE], 8), None);
+        // Evict `p1`.
+        table.insert(&[1; disk::SECTOR_SIZE], p2);
+        assert_eq!(table.stats(), Stats {
+            entries: 1,
+            hits: 1,
+            misses: 1,
+            evictions: 1,
+            bytes_saved: disk::SECTOR_SIZE as u64,
+        });
+
+        table.remove(p2);
+        assert_eq!(table.stats().entries, 0);
+    }
+
+    #[test]
+    fn persisted_inverse_identity() {
+        let table = Table::default();
+        let p1 = page::Pointer {
+            cksum: 7,
+            .. Default::default()
+        };
+        let p2 = page::Pointer {
+            cksum: 0xDEADBEEF00000013,
+            cluster: cluster::Pointer::new(100).unwrap(),
+            .. Default::default()
+        };
+        table.insert(&[0; disk::SECTOR_SIZE], p1);
+        table.insert(&[1; disk::SECTOR_SIZE], p2);
+
+        let buf = table.persisted(3).encode(disk::SECTOR_SIZE, header::ChecksumAlgorithm::SeaHash);
+        let persisted = Persisted::decode(&buf, header::ChecksumAlgorithm::SeaHash).unwrap();
+        assert_eq!(persisted.generation, 3);
+
+        // The restored table finds the same duplicates.
+        let restored = Table::default();
+        restored.restore(persisted);
+        assert_eq!(restored.stats().entries, 2);
+        assert_eq!(restored.dedup(&[0; disk::SECTOR_SIZE], 7), Some(p1));
+        assert_eq!(restored.dedup(&[1; disk::SECTOR_SIZE], 0xDEADBEEF00000013), Some(p2));
+
+        // Only the entries fitting in the buffer are persisted.
+        let buf = table.persisted(3).encode(PERSISTED_PREAMBLE_SIZE + PERSISTED_ENTRY_SIZE, header::ChecksumAlgorithm::SeaHash);
+        assert_eq!(Persisted::decode(&buf, header::ChecksumAlgorithm::SeaHash).unwrap().candidates.len(), 1);
+    }
+
+    #[test]
+    fn imported_candidate() {
+        let table = Table::default();
+        let p1 = page::Pointer {
+            cksum: 7,
+            .. Default::default()
+        };
+        let p2 = page::Pointer {
+            cksum: 13,
+            .. Default::default()
+        };
+        table.import(p1);
+        table.import(p2);
+        assert_eq!(table.stats().entries, 2);
+
+        // Unverified candidates aren't persisted.
+        assert_eq!(table.persisted(1).encode(disk::SECTOR_SIZE, header::ChecksumAlgorithm::SeaHash)[16], 0);
+
+        // The first match verifies the candidate against the stored data...
+        assert_eq!(table.dedup_with(&[1; disk::SECTOR_SIZE], 7, |page| {
+            assert!(page == p1);
+            Some([1; disk::SECTOR_SIZE])
+        }), Some(p1));
+        // ...after which it is fingerprinted.
+        assert_eq!(table.dedup(&[1; disk::SECTOR_SIZE], 7), Some(p1));
+        assert_eq!(table.dedup(&[2; disk::SECTOR_SIZE], 7), None);
+
+        // A mismatching candidate is dropped.
+        assert_eq!(table.dedup_with(&[1; disk::SECTOR_SIZE], 13, |_| Some([2; disk::SECTOR_SIZE])), None);
+        assert_eq!(table.stats().entries, 1);
+    }
+
+    #[test]
+    fn persisted_torn_write() {
+        let table = Table::default();
+        table.insert(&[0; disk::SECTOR_SIZE], page::Pointer {
+            cksum: 7,
+            .. Default::default()
+        });
+
+        let mut buf = table.persisted(1).encode(disk::SECTOR_SIZE, header::ChecksumAlgorithm::SeaHash);
+        buf[PERSISTED_PREAMBLE_SIZE] ^= 1;
+        assert!(Persisted::decode(&buf, header::ChecksumAlgorithm::SeaHash).is_none());
+    }
+
+    #[test]
+    fn adaptive_mode() {
+        let cost = Cost::default();
+        // Skip the probing lookup.
+        cost.lookups.store(1, ORDERING);
+
+        // Verification is cheaper than storing.
+        cost.verify.store(10, ORDERING);
+        cost.store.store(100, ORDERING);
+        assert_eq!(cost.mode(state_block::DedupPolicy::Adaptive, state_block::ChecksumWidth::Wide),
+                   Mode::Verify);
+
+        // Verification is more expensive than storing.
+        cost.verify.store(1000, ORDERING);
+        assert_eq!(cost.mode(state_block::DedupPolicy::Adaptive, state_block::ChecksumWidth::Wide),
+                   Mode::Trust);
+        assert_eq!(cost.mode(state_block::DedupPolicy::Adaptive, state_block::ChecksumWidth::Narrow),
+                   Mode::Skip);
+
+        // The static policies ignore the costs.
+        assert_eq!(cost.mode(state_block::DedupPolicy::Always, state_block::ChecksumWidth::Narrow),
+                   Mode::Verify);
+        assert_eq!(cost.mode(state_block::DedupPolicy::Disabled, state_block::ChecksumWidth::Wide),
+                   Mode::Skip);
+    }
+
+    #[test]
+    fn adaptive_probe() {
+        let cost = Cost::default();
+        cost.verify.store(1000, ORDERING);
+
+        // Every `PROBE_INTERVAL`'th lookup verifies regardless of the costs.
+        for n in 0..PROBE_INTERVAL * 2 {
+            let mode = cost.mode(state_block::DedupPolicy::Adaptive, state_block::ChecksumWidth::Wide);
+            assert_eq!(mode == Mode::Verify, n % PROBE_INTERVAL == 0);
+        }
+    }
+
+    #[test]
+    fn distinct_bytes() {
+        assert_eq!(super::distinct_bytes(&[0; disk::SECTOR_SIZE]), 1);
+
+        let mut buf = [0; disk::SECTOR_SIZE];
+        for (n, byte) in buf.iter_mut().enumerate() {
+            *byte = n as u8;
+        }
+        assert_eq!(super::distinct_bytes(&buf), 256);
+    }
 }
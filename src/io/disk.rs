@@ -76,4 +76,23 @@ trait Disk {
     /// Note that after it is called, it is still necessary to check if the healed sector is valid,
     /// as there is a certain probability that the recovery will fail.
     fn heal(&mut self, sector: disk::Sector) -> Result<(), disk::Error>;
+
+    /// Issue a write barrier.
+    ///
+    /// This makes sure that every preceding write hits the disk before any following write does,
+    /// even if the device (e.g. its controller) reorders writes. The default implementation does
+    /// nothing, which suffices for devices writing in order.
+    fn barrier(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Discard a sector.
+    ///
+    /// This tells the device that the content of `sector` is no longer needed, allowing e.g. SSDs
+    /// to reclaim it. Afterwards, the sector's content is unspecified until it is written again.
+    /// The default implementation does nothing, which suffices for devices without support for
+    /// discarding.
+    fn discard(&mut self, sector: Sector) -> Result<(), Error> {
+        Ok(())
+    }
 }
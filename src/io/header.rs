@@ -20,13 +20,24 @@ const VERSION_NUMBER: u32 = 0;
 const PARTIAL_COMPATIBILITY_MAGIC_NUMBER: &[u8] = b"~TFS fmt";
 /// The magic number of images with total TFS compatibility.
 const TOTAL_COMPATIBILITY_MAGIC_NUMBER: &[u8] = b"TFS fmt ";
+/// The feature flag of large pages.
+const FEATURE_LARGE_PAGES: u16 = 1;
+/// The feature flag of the allocation metadata journal.
+const FEATURE_JOURNAL: u16 = 2;
+/// The maximal size (in sectors) of a metacluster.
+///
+/// This is bounded by the freelist head counter of the state block, which is a single byte, and
+/// thus cannot cover the free clusters of larger metaclusters.
+pub const MAX_METACLUSTER_SECTORS: u8 = 4;
 
 quick_error! {
     /// A disk header reading error.
     enum Error {
-        /// Unknown format (not TFS).
-        UnknownFormat {
-            description("Unknown format (not TFS).")
+        /// The magic number is unknown.
+        ///
+        /// The device doesn't hold a TFS image, or its disk header is damaged.
+        BadMagic {
+            description("Unknown magic number (not TFS).")
         }
         /// The version is incompatible with this implementation.
         ///
@@ -47,6 +58,18 @@ quick_error! {
         UnknownStateFlag {
             description("Unknown state flag.")
         }
+        /// Unknown feature flags.
+        UnknownFeatures {
+            description("Unknown feature flags.")
+        }
+        /// Invalid metacluster size.
+        InvalidMetaclusterSize {
+            /// The invalid size (in sectors).
+            sectors: u8,
+        } {
+            display("Invalid metacluster size of {} sectors.", sectors)
+            description("Invalid metacluster size.")
+        }
         /// The checksums doesn't match.
         ChecksumMismatch {
             /// The checksum of the data.
@@ -95,7 +118,7 @@ impl TryFrom<&[u8]> for MagicNumber {
             // Total compatibility.
             TOTAL_COMPATIBILITY_MAGIC_NUMBER => Ok(MagicNumber::TotalCompatibility),
             // Unknown format; abort.
-            _ => Err(Error::UnknownFormat),
+            _ => Err(Error::BadMagic),
         }
     }
 }
@@ -110,12 +133,26 @@ impl Into<&'static [u8]> for MagicNumber {
 }
 
 /// A checksum algorithm configuration option.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 enum ChecksumAlgorithm {
     /// SeaHash checksum.
     ///
     /// SeaHash was designed for TFS, and is described [in this
     /// post](http://ticki.github.io/blog/seahash-explained/).
     SeaHash = 1,
+    /// SHA-256 checksum.
+    ///
+    /// The digest is truncated to its first 64 bits. This is notably slower than SeaHash, but
+    /// cryptographically strong.
+    Sha256 = 2,
+    /// CRC-32C (Castagnoli) checksum.
+    ///
+    /// The 32-bit checksum is zero-extended to 64 bits. This is faster than SeaHash, but weaker,
+    /// and thus suited for throughput-bound workloads. Page pointers of narrow width hold it in
+    /// full.
+    Crc32c = 3,
 }
 
 impl ChecksumAlgorithm {
@@ -125,6 +162,10 @@ impl ChecksumAlgorithm {
         match self {
             // Hash the thing via SeaHash, then take the 16 lowest bits (truncating cast).
             ChecksumAlgorithm::SeaHash => seahash::hash(buf),
+            // Hash via SHA-256, then take the 64 first bits.
+            ChecksumAlgorithm::Sha256 => LittleEndian::read(ring::digest::digest(&ring::digest::SHA256, buf).as_ref()),
+            // Calculate the CRC-32C, and zero-extend it.
+            ChecksumAlgorithm::Crc32c => crc::crc32::checksum_castagnoli(buf) as u64,
         }
     }
 }
@@ -135,6 +176,8 @@ impl TryFrom<u16> for ChecksumAlgorithm {
     fn try_from(from: u16) -> Result<ChecksumAlgorithm, Error> {
         match from {
             1 => Ok(ChecksumAlgorithm::SeaHash),
+            2 => Ok(ChecksumAlgorithm::Sha256),
+            3 => Ok(ChecksumAlgorithm::Crc32c),
             0x8000...0xFFFF => Err(Error::UnknownChecksumAlgorithm),
             _ => Err(Error::InvalidChecksumAlgorithm),
         }
@@ -185,6 +228,42 @@ struct DiskHeader {
     version_number: u32,
     /// The chosen checksum algorithm.
     checksum_algorithm: ChecksumAlgorithm,
+    /// Are large pages enabled?
+    ///
+    /// Large pages span multiple sectors, stored uncompressed in consecutive clusters.
+    large_pages: bool,
+    /// Is the allocation metadata journal enabled?
+    ///
+    /// If so, freelist operations are logged to the journal, which occupies the cluster
+    /// following the state block.
+    journal: bool,
+    /// The size of metaclusters (in sectors).
+    ///
+    /// Larger metaclusters hold more free clusters each, shortening the metacluster chain. The
+    /// sectors following the first one are stored in free clusters of the metacluster, so larger
+    /// metaclusters support neither `trim_on_free` nor a separate metadata device. Zero
+    /// denotes the default of a single sector, such that images predating this option are read
+    /// correctly. See `metacluster_sectors`.
+    metacluster_size: u8,
+    /// The size (in sectors) of each slot of the persisted deduplication table.
+    ///
+    /// The deduplication table is persisted in two alternating slots following the state block
+    /// (and the journal), so a torn write leaves the other slot intact. Zero disables the
+    /// persistence.
+    dedup_sectors: u16,
+    /// The size (in sectors) of each slot of the persisted live page index.
+    ///
+    /// Like the deduplication table, the live page index is persisted in two alternating slots,
+    /// which follow those of the deduplication table. Zero disables the persistence, confining the
+    /// index to the pages allocated since the manager was opened.
+    index_sectors: u16,
+    /// The checksum algorithm migrated from, if any.
+    ///
+    /// Migrating the checksum algorithm rewrites the metadata and gives every page a new pointer,
+    /// which can't happen atomically. Hence, the header switches first, and the metadata and the
+    /// page pointers checksummed under the previous algorithm are still accepted until the next
+    /// migration.
+    previous_checksum_algorithm: Option<ChecksumAlgorithm>,
     /// The state flag.
     state_flag: StateFlag,
     /// The vdev setup.
@@ -205,6 +284,13 @@ struct DiskHeader {
 }
 
 impl DiskHeader {
+    /// Get the checksum algorithms accepted when verifying.
+    ///
+    /// This yields the current checksum algorithm, followed by the previous one, if any.
+    fn checksum_algorithms(&self) -> impl Iterator<Item = ChecksumAlgorithm> {
+        Some(self.checksum_algorithm).into_iter().chain(self.previous_checksum_algorithm)
+    }
+
     /// Parse the disk header from some sequence of bytes.
     ///
     /// This will construct it into memory while performing error checks on the header to ensure
@@ -235,6 +321,30 @@ impl DiskHeader {
         // Load the checksum algorithm config field.
         let checksum_algorithm = ChecksumAlgorithm::try_from(LittleEndian::read(buf[16..]))?;
 
+        // Load the feature flags, and make sure that they're all known.
+        let features: u16 = LittleEndian::read(buf[18..]);
+        if features & !(FEATURE_LARGE_PAGES | FEATURE_JOURNAL) != 0 {
+            return Err(Error::UnknownFeatures);
+        }
+
+        // Load the metacluster size, and make sure that it is within bounds.
+        let metacluster_size = buf[20];
+        if metacluster_size > MAX_METACLUSTER_SECTORS {
+            return Err(Error::InvalidMetaclusterSize {
+                sectors: metacluster_size,
+            });
+        }
+
+        // Load the size of the deduplication table slots.
+        let dedup_sectors = LittleEndian::read(buf[22..]);
+        // Load the size of the live page index slots.
+        let index_sectors = LittleEndian::read(buf[24..]);
+        // Load the previous checksum algorithm, where zero means none.
+        let previous_checksum_algorithm = match LittleEndian::read::<u16>(buf[26..]) {
+            0 => None,
+            algorithm => Some(ChecksumAlgorithm::try_from(algorithm)?),
+        };
+
         // # State section
         //
         // This section holds the state of disk and pointers to information on the state of the
@@ -310,11 +420,22 @@ impl DiskHeader {
             magic_number: magic_number,
             version_number: version_number,
             checksum_algorithm: checksum_algorithm,
+            large_pages: features & FEATURE_LARGE_PAGES != 0,
+            journal: features & FEATURE_JOURNAL != 0,
+            metacluster_size: metacluster_size,
+            dedup_sectors: dedup_sectors,
+            index_sectors: index_sectors,
+            previous_checksum_algorithm: previous_checksum_algorithm,
             state_flag: state_flag,
             vdev_stack: vdev_stack,
         }
     }
 
+    /// Get the size of metaclusters (in sectors).
+    pub fn metacluster_sectors(&self) -> usize {
+        cmp::max(self.metacluster_size, 1) as usize
+    }
+
     /// Encode the header into a sector-sized buffer.
     fn encode(&self) -> disk::SectorBuf {
         // Create a buffer to hold the data.
@@ -328,6 +449,23 @@ impl DiskHeader {
 
         // Write the checksum algorithm.
         LittleEndian::write(&mut buf[16..], self.checksum_algorithm as u16);
+        // Write the feature flags.
+        let mut features = 0;
+        if self.large_pages {
+            features |= FEATURE_LARGE_PAGES;
+        }
+        if self.journal {
+            features |= FEATURE_JOURNAL;
+        }
+        LittleEndian::write(&mut buf[18..], features);
+        // Write the metacluster size.
+        buf[20] = self.metacluster_size;
+        // Write the size of the deduplication table slots.
+        LittleEndian::write(&mut buf[22..], self.dedup_sectors);
+        // Write the size of the live page index slots.
+        LittleEndian::write(&mut buf[24..], self.index_sectors);
+        // Write the previous checksum algorithm, if any.
+        LittleEndian::write(&mut buf[26..], self.previous_checksum_algorithm.map_or(0, |algorithm| algorithm as u16));
 
         // Write the state flag.
         buf[32] = self.state_flag as u8;
@@ -388,6 +526,37 @@ mod tests {
 
         header.state_flag = StateFlag::Inconsistent;
         assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.large_pages = true;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.journal = true;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.metacluster_size = MAX_METACLUSTER_SECTORS;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.dedup_sectors = 300;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.index_sectors = 40;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.previous_checksum_algorithm = Some(ChecksumAlgorithm::SeaHash);
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.checksum_algorithm = ChecksumAlgorithm::Sha256;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+
+        header.checksum_algorithm = ChecksumAlgorithm::Crc32c;
+        assert_eq!(DiskHeader::decode(header.encode()).unwrap(), header);
+    }
+
+    #[test]
+    fn crc32c() {
+        // The check value of CRC-32C.
+        assert_eq!(ChecksumAlgorithm::Crc32c.hash(b"123456789"), 0xE3069283);
+        assert_eq!(ChecksumAlgorithm::Crc32c.hash(b""), 0);
     }
 
     #[test]
@@ -439,12 +608,12 @@ mod tests {
     }
 
     #[test]
-    fn unknown_format() {
+    fn bad_magic() {
         let mut sector = DiskHeader::default().encode();
         sector[0] = b'A';
 
         LittleEndian::write(&mut sector[504..], seahash::hash(sector[..504]));
-        assert_eq!(DiskHeader::decode(sector), Err(Error::UnknownFormat));
+        assert_eq!(DiskHeader::decode(sector), Err(Error::BadMagic));
     }
 
     #[test]
@@ -456,6 +625,24 @@ mod tests {
         assert_eq!(DiskHeader::decode(sector), Err(Error::IncompatibleVersion));
     }
 
+    #[test]
+    fn unknown_features() {
+        let mut sector = DiskHeader::default().encode();
+        sector[19] = 0x80;
+        LittleEndian::write(&mut sector[504..], seahash::hash(sector[..504]));
+        assert_eq!(DiskHeader::decode(sector), Err(Error::UnknownFeatures));
+    }
+
+    #[test]
+    fn invalid_metacluster_size() {
+        let mut sector = DiskHeader::default().encode();
+        sector[20] = MAX_METACLUSTER_SECTORS + 1;
+        LittleEndian::write(&mut sector[504..], seahash::hash(sector[..504]));
+        assert_eq!(DiskHeader::decode(sector), Err(Error::InvalidMetaclusterSize {
+            sectors: MAX_METACLUSTER_SECTORS + 1,
+        }));
+    }
+
     #[test]
     fn unknown_state_flag() {
         let mut sector = DiskHeader::default().encode();
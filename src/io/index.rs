@@ -0,0 +1,352 @@
+//! The persisted live page index.
+//!
+//! The live page index keeps track of the live pages of every cluster in use, which are the
+//! reference counts of the clusters. This module provides the form in which it is written to the
+//! disk, so the index survives reopening the manager. Along with it go the clusters which are
+//! neither free nor in use (the trash and the clusters retained for snapshots), and the snapshots
+//! themselves.
+
+/// The size (in bytes) of the preamble of a persisted index.
+///
+/// The preamble consists of the checksum, the generation, the next sequence number, the number of
+/// clusters, the number of write counts, the next snapshot identifier, the number of clusters in
+/// the trash, the number of retained clusters, and the number of snapshots.
+const PREAMBLE_SIZE: usize = 52;
+/// The size (in bytes) of the header of a cluster entry.
+///
+/// The header consists of the cluster pointer, the sequence number, the number of pages stored in
+/// the cluster, the number of live pages (whose entries follow the header), and the backup
+/// generation in which the cluster was last written.
+const CLUSTER_SIZE: usize = 32;
+/// The size (in bytes) of a page pointer entry.
+///
+/// A page pointer entry consists of the page pointer and the high 32 bits of its checksum (which
+/// don't fit in the pointer's integer form).
+const POINTER_SIZE: usize = 20;
+/// The size (in bytes) of a page entry of a cluster.
+///
+/// A page entry consists of a page pointer entry and the number of duplicates handed out of the
+/// page.
+const PAGE_SIZE: usize = POINTER_SIZE + 4;
+/// The size (in bytes) of a write count entry.
+///
+/// A write count entry consists of the cluster pointer and its write count. The entries follow the
+/// clusters.
+const WRITES_SIZE: usize = 16;
+/// The size (in bytes) of the header of a snapshot entry.
+///
+/// The header consists of the snapshot identifier and the number of pages, whose page pointer
+/// entries follow the header.
+const SNAPSHOT_SIZE: usize = 12;
+
+/// Read a page pointer entry.
+fn read_pointer(entry: &[u8]) -> page::Pointer {
+    // Restore the high bits of the checksum.
+    let mut page = page::Pointer::from(LittleEndian::read::<u128>(entry));
+    page.checksum |= (LittleEndian::read::<u32>(&entry[16..]) as u64) << 32;
+
+    page
+}
+
+/// Write a page pointer entry.
+fn write_pointer(entry: &mut [u8], page: page::Pointer) {
+    LittleEndian::write(entry, u128::from(page));
+    LittleEndian::write(&mut entry[16..], (page.checksum >> 32) as u32);
+}
+
+/// Read `len` cluster pointers off the start of `rest`.
+///
+/// If they don't fit in `rest`, or one of them is null, `None` is returned.
+fn read_clusters(rest: &mut &[u8], len: usize) -> Option<Vec<cluster::Pointer>> {
+    if len > rest.len() / 8 {
+        return None;
+    }
+
+    let clusters = rest.chunks(8).take(len).map(|entry| cluster::Pointer::new(LittleEndian::read(entry))).collect();
+    *rest = &rest[len * 8..];
+
+    clusters
+}
+
+/// A cluster of a persisted index.
+#[derive(Clone, Debug, PartialEq)]
+struct Cluster {
+    /// The cluster.
+    cluster: cluster::Pointer,
+    /// The allocation sequence number of the cluster.
+    sequence: u64,
+    /// The number of pages stored in the cluster, including the freed ones.
+    stored: usize,
+    /// The backup generation in which the cluster was last written.
+    written: u64,
+    /// The live pages of the cluster, in order of allocation.
+    ///
+    /// Every page comes with the number of duplicates handed out of it, so its reference count is
+    /// one more than that.
+    pages: Vec<(page::Pointer, usize)>,
+}
+
+/// A persisted live page index.
+#[derive(Clone, Debug, PartialEq)]
+struct Persisted {
+    /// The generation of the index.
+    ///
+    /// This increases with every flush. The state block stores the generation of the current
+    /// index, so an index which went stale can be told apart from the current one.
+    generation: u64,
+    /// The sequence number of the next cluster taken into use.
+    next_sequence: u64,
+    /// The clusters in use.
+    clusters: Vec<Cluster>,
+    /// The write counts of the clusters, if tracked.
+    writes: Vec<(cluster::Pointer, u64)>,
+    /// The identifier of the next snapshot.
+    next_snapshot: u64,
+    /// The clusters in the trash, oldest first.
+    trash: Vec<cluster::Pointer>,
+    /// The clusters retained for some snapshot.
+    retained: Vec<cluster::Pointer>,
+    /// The live pages at the time of every snapshot, by snapshot identifier.
+    snapshots: Vec<(u64, Vec<page::Pointer>)>,
+}
+
+impl Persisted {
+    /// Parse the binary representation of a persisted index.
+    ///
+    /// If the checksum doesn't match (e.g. because the write was torn), or the entries don't fit
+    /// in `buf`, `None` is returned.
+    fn decode(buf: &[u8], checksum_algorithm: header::ChecksumAlgorithm) -> Option<Persisted> {
+        if buf.len() < PREAMBLE_SIZE {
+            return None;
+        }
+
+        // Make sure that the checksum matches the 8 byte field in the start.
+        if LittleEndian::read(buf) != checksum_algorithm.hash(&buf[8..]) {
+            return None;
+        }
+
+        // Load the clusters one by one, making sure that every entry fits.
+        let len = LittleEndian::read::<u32>(&buf[24..]) as usize;
+        let mut clusters = Vec::new();
+        let mut rest = &buf[PREAMBLE_SIZE..];
+        for _ in 0..len {
+            if rest.len() < CLUSTER_SIZE {
+                return None;
+            }
+            let pages = LittleEndian::read::<u32>(&rest[20..]) as usize;
+            if pages > (rest.len() - CLUSTER_SIZE) / PAGE_SIZE {
+                return None;
+            }
+
+            clusters.push(Cluster {
+                cluster: cluster::Pointer::new(LittleEndian::read(rest))?,
+                sequence: LittleEndian::read(&rest[8..]),
+                stored: LittleEndian::read::<u32>(&rest[16..]) as usize,
+                written: LittleEndian::read(&rest[24..]),
+                pages: rest[CLUSTER_SIZE..].chunks(PAGE_SIZE).take(pages).map(|entry| {
+                    (read_pointer(entry), LittleEndian::read::<u32>(&entry[POINTER_SIZE..]) as usize)
+                }).collect(),
+            });
+            rest = &rest[CLUSTER_SIZE + pages * PAGE_SIZE..];
+        }
+
+        // Load the write counts.
+        let len = LittleEndian::read::<u32>(&buf[28..]) as usize;
+        if len > rest.len() / WRITES_SIZE {
+            return None;
+        }
+        let mut writes = Vec::with_capacity(len);
+        for entry in rest.chunks(WRITES_SIZE).take(len) {
+            writes.push((cluster::Pointer::new(LittleEndian::read(entry))?, LittleEndian::read(&entry[8..])));
+        }
+        rest = &rest[len * WRITES_SIZE..];
+
+        // Load the trash, followed by the retained clusters.
+        let trash = read_clusters(&mut rest, LittleEndian::read::<u32>(&buf[40..]) as usize)?;
+        let retained = read_clusters(&mut rest, LittleEndian::read::<u32>(&buf[44..]) as usize)?;
+
+        // Load the snapshots one by one, making sure that every entry fits.
+        let len = LittleEndian::read::<u32>(&buf[48..]) as usize;
+        let mut snapshots = Vec::new();
+        for _ in 0..len {
+            if rest.len() < SNAPSHOT_SIZE {
+                return None;
+            }
+            let pages = LittleEndian::read::<u32>(&rest[8..]) as usize;
+            if pages > (rest.len() - SNAPSHOT_SIZE) / POINTER_SIZE {
+                return None;
+            }
+
+            snapshots.push((
+                LittleEndian::read(rest),
+                rest[SNAPSHOT_SIZE..].chunks(POINTER_SIZE).take(pages).map(read_pointer).collect(),
+            ));
+            rest = &rest[SNAPSHOT_SIZE + pages * POINTER_SIZE..];
+        }
+
+        Some(Persisted {
+            generation: LittleEndian::read(&buf[8..]),
+            next_sequence: LittleEndian::read(&buf[16..]),
+            clusters: clusters,
+            writes: writes,
+            next_snapshot: LittleEndian::read(&buf[32..]),
+            trash: trash,
+            retained: retained,
+            snapshots: snapshots,
+        })
+    }
+
+    /// Encode the persisted index into `len` bytes.
+    ///
+    /// Unlike the deduplication table, the index cannot drop entries without losing track of
+    /// clusters in use, so if it doesn't fit, `None` is returned.
+    fn encode(&self, len: usize, checksum_algorithm: header::ChecksumAlgorithm) -> Option<Vec<u8>> {
+        let size = PREAMBLE_SIZE
+            + self.clusters.iter().map(|cluster| CLUSTER_SIZE + cluster.pages.len() * PAGE_SIZE).sum::<usize>()
+            + self.writes.len() * WRITES_SIZE
+            + (self.trash.len() + self.retained.len()) * 8
+            + self.snapshots.iter().map(|&(_, ref pages)| SNAPSHOT_SIZE + pages.len() * POINTER_SIZE).sum::<usize>();
+        if size > len {
+            return None;
+        }
+
+        let mut buf = vec![0; len];
+
+        // Write the clusters.
+        let mut start = PREAMBLE_SIZE;
+        for cluster in &self.clusters {
+            let entry = &mut buf[start..];
+            LittleEndian::write(entry, cluster.cluster);
+            LittleEndian::write(&mut entry[8..], cluster.sequence);
+            LittleEndian::write(&mut entry[16..], cluster.stored as u32);
+            LittleEndian::write(&mut entry[20..], cluster.pages.len() as u32);
+            LittleEndian::write(&mut entry[24..], cluster.written);
+            for (&(page, duplicates), entry) in cluster.pages.iter().zip(entry[CLUSTER_SIZE..].chunks_mut(PAGE_SIZE)) {
+                write_pointer(entry, page);
+                LittleEndian::write(&mut entry[POINTER_SIZE..], duplicates as u32);
+            }
+
+            start += CLUSTER_SIZE + cluster.pages.len() * PAGE_SIZE;
+        }
+
+        // Write the write counts.
+        for (&(cluster, writes), entry) in self.writes.iter().zip(buf[start..].chunks_mut(WRITES_SIZE)) {
+            LittleEndian::write(entry, cluster);
+            LittleEndian::write(&mut entry[8..], writes);
+        }
+        start += self.writes.len() * WRITES_SIZE;
+
+        // Write the trash, followed by the retained clusters.
+        for &cluster in self.trash.iter().chain(&self.retained) {
+            LittleEndian::write(&mut buf[start..], cluster);
+            start += 8;
+        }
+
+        // Write the snapshots.
+        for &(id, ref pages) in &self.snapshots {
+            let entry = &mut buf[start..];
+            LittleEndian::write(entry, id);
+            LittleEndian::write(&mut entry[8..], pages.len() as u32);
+            for (&page, entry) in pages.iter().zip(entry[SNAPSHOT_SIZE..].chunks_mut(POINTER_SIZE)) {
+                write_pointer(entry, page);
+            }
+
+            start += SNAPSHOT_SIZE + pages.len() * POINTER_SIZE;
+        }
+
+        // Write the preamble.
+        LittleEndian::write(&mut buf[8..], self.generation);
+        LittleEndian::write(&mut buf[16..], self.next_sequence);
+        LittleEndian::write(&mut buf[24..], self.clusters.len() as u32);
+        LittleEndian::write(&mut buf[28..], self.writes.len() as u32);
+        LittleEndian::write(&mut buf[32..], self.next_snapshot);
+        LittleEndian::write(&mut buf[40..], self.trash.len() as u32);
+        LittleEndian::write(&mut buf[44..], self.retained.len() as u32);
+        LittleEndian::write(&mut buf[48..], self.snapshots.len() as u32);
+
+        // Calculate and store the checksum.
+        let cksum = checksum_algorithm.hash(&buf[8..]);
+        LittleEndian::write(&mut buf, cksum);
+
+        Some(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make a persisted index of two clusters with their write counts, a cluster in the trash,
+    /// and a snapshot retaining another cluster.
+    fn persisted() -> Persisted {
+        let a = cluster::Pointer::new(100).unwrap();
+        let b = cluster::Pointer::new(200).unwrap();
+        let c = cluster::Pointer::new(300).unwrap();
+        let d = cluster::Pointer::new(400).unwrap();
+
+        Persisted {
+            generation: 3,
+            next_sequence: 2,
+            clusters: vec![Cluster {
+                cluster: a,
+                sequence: 0,
+                stored: 3,
+                written: 4,
+                pages: vec![(page::Pointer {
+                    cluster: a,
+                    offset: Some(0),
+                    checksum: 0xDEADBEEF00000013,
+                }, 0), (page::Pointer {
+                    cluster: a,
+                    offset: Some(2),
+                    checksum: 7,
+                }, 2)],
+            }, Cluster {
+                cluster: b,
+                sequence: 1,
+                stored: 1,
+                written: 0,
+                pages: vec![(page::Pointer {
+                    cluster: b,
+                    offset: None,
+                    checksum: 13,
+                }, 0)],
+            }],
+            writes: vec![(a, 17), (b, 1)],
+            next_snapshot: 6,
+            trash: vec![c],
+            retained: vec![d],
+            snapshots: vec![(5, vec![page::Pointer {
+                cluster: d,
+                offset: Some(1),
+                checksum: 0xCAFEBABE00000005,
+            }])],
+        }
+    }
+
+    #[test]
+    fn inverse_identity() {
+        let persisted = persisted();
+        let buf = persisted.encode(disk::SECTOR_SIZE, header::ChecksumAlgorithm::SeaHash).unwrap();
+        assert_eq!(Persisted::decode(&buf, header::ChecksumAlgorithm::SeaHash).unwrap(), persisted);
+    }
+
+    #[test]
+    fn overflow() {
+        // The exact size fits, but a byte less doesn't.
+        let size = PREAMBLE_SIZE + 2 * CLUSTER_SIZE + 3 * PAGE_SIZE + 2 * WRITES_SIZE + 2 * 8 + SNAPSHOT_SIZE
+            + POINTER_SIZE;
+        assert!(persisted().encode(size, header::ChecksumAlgorithm::SeaHash).is_some());
+        assert!(persisted().encode(size - 1, header::ChecksumAlgorithm::SeaHash).is_none());
+    }
+
+    #[test]
+    fn torn_write() {
+        let mut buf = persisted().encode(disk::SECTOR_SIZE, header::ChecksumAlgorithm::SeaHash).unwrap();
+        buf[PREAMBLE_SIZE] ^= 1;
+        assert!(Persisted::decode(&buf, header::ChecksumAlgorithm::SeaHash).is_none());
+
+        // A blank slot was never written.
+        assert!(Persisted::decode(&[0; disk::SECTOR_SIZE], header::ChecksumAlgorithm::SeaHash).is_none());
+    }
+}
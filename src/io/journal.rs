@@ -0,0 +1,218 @@
+//! Allocation metadata journaling.
+//!
+//! The journal is a write-ahead log of the freelist operations done since the last checkpoint. It
+//! lives in a single sector, which is written before the metaclusters and the state block it
+//! covers. Should the system crash before the latter reach the disk, the operations are replayed
+//! on top of the last checkpointed state, when the manager is opened.
+//!
+//! The journal is only used if enabled in the disk header.
+
+/// The size (in bytes) of a journal record.
+///
+/// A record consists of a one byte kind followed by the cluster pointer.
+const RECORD_SIZE: usize = 9;
+/// The size (in bytes) of the journal preamble.
+///
+/// The preamble consists of the checksum and the number of records.
+const PREAMBLE_SIZE: usize = 16;
+/// The maximal number of records in the journal.
+///
+/// When the journal is full, a checkpoint is needed before another record can be appended.
+pub const MAX_RECORDS: usize = (disk::SECTOR_SIZE - PREAMBLE_SIZE) / RECORD_SIZE;
+
+quick_error! {
+    /// A journal parsing error.
+    enum Error {
+        /// The checksums doesn't match.
+        ChecksumMismatch {
+            /// The checksum of the data.
+            expected: u64,
+            /// The expected/stored value of the checksum.
+            found: u64,
+        } {
+            display("Mismatching checksums in the journal - expected {:x}, found {:x}.", expected, found)
+            description("Mismatching checksum.")
+        }
+        /// The journal holds more records than it can fit.
+        InvalidLength {
+            /// The stored number of records.
+            records: u16,
+        } {
+            display("The journal claims to hold {} records, exceeding its capacity.", records)
+            description("Invalid journal length.")
+        }
+        /// Invalid record.
+        InvalidRecord {
+            /// The invalid record kind.
+            kind: u8,
+        } {
+            display("Invalid journal record kind {}.", kind)
+            description("Invalid journal record.")
+        }
+    }
+}
+
+/// A journal record.
+///
+/// Each record describes a single freelist operation.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Record {
+    /// A cluster was popped from the freelist.
+    Pop(cluster::Pointer),
+    /// A cluster was pushed to the freelist.
+    Push(cluster::Pointer),
+}
+
+/// The journal.
+#[derive(Default, PartialEq, Eq, Clone)]
+struct Journal {
+    /// The records since the last checkpoint, oldest first.
+    records: Vec<Record>,
+}
+
+impl Journal {
+    /// Append a record to the journal.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the journal is full.
+    pub fn push(&mut self, record: Record) {
+        assert!(self.records.len() < MAX_RECORDS, "Appending to a full journal.");
+
+        self.records.push(record);
+    }
+
+    /// Check if `records` more records would fit in the journal.
+    pub fn has_room(&self, records: usize) -> bool {
+        self.records.len() + records <= MAX_RECORDS
+    }
+
+    /// Clear the journal.
+    ///
+    /// This is done on checkpoints, when the records are reflected in the main structures.
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Parse the binary representation of a journal.
+    ///
+    /// A zeroed sector (i.e. a journal, which was never written) holds no records.
+    fn decode(buf: &disk::SectorBuf, checksum_algorithm: header::ChecksumAlgorithm) -> Result<Journal, Error> {
+        // The journal is only written on the first checkpoint or operation, so a fresh disk has
+        // no valid checksum in its place.
+        if buf.iter().all(|&x| x == 0) {
+            return Ok(Journal::default());
+        }
+
+        // Make sure that the checksum of the journal matches the 8 byte field in the start.
+        let expected = LittleEndian::read(&buf);
+        let found = checksum_algorithm.hash(&buf[8..]);
+        if expected != found {
+            return Err(Error::ChecksumMismatch {
+                expected: expected,
+                found: found,
+            });
+        }
+
+        // Load the number of records, and make sure that they fit.
+        let len: u16 = LittleEndian::read(&buf[8..]);
+        if len as usize > MAX_RECORDS {
+            return Err(Error::InvalidLength {
+                records: len,
+            });
+        }
+
+        // Load the records.
+        let mut records = Vec::with_capacity(len as usize);
+        for record in buf[PREAMBLE_SIZE..].chunks(RECORD_SIZE).take(len as usize) {
+            let cluster = cluster::Pointer::new(LittleEndian::read(&record[1..]));
+            records.push(match (record[0], cluster) {
+                (1, Some(cluster)) => Record::Pop(cluster),
+                (2, Some(cluster)) => Record::Push(cluster),
+                // Either the kind is unknown, or the cluster pointer is null.
+                (kind, _) => return Err(Error::InvalidRecord {
+                    kind: kind,
+                }),
+            });
+        }
+
+        Ok(Journal {
+            records: records,
+        })
+    }
+
+    /// Encode the journal.
+    fn encode(&self, checksum_algorithm: header::ChecksumAlgorithm) -> disk::SectorBuf {
+        // Create a buffer to hold the data.
+        let mut buf = disk::SectorBuf::default();
+
+        // Write the number of records.
+        LittleEndian::write(&mut buf[8..], self.records.len() as u16);
+        // Write the records.
+        for (record, slot) in self.records.iter().zip(buf[PREAMBLE_SIZE..].chunks_mut(RECORD_SIZE)) {
+            let (kind, cluster) = match *record {
+                Record::Pop(cluster) => (1, cluster),
+                Record::Push(cluster) => (2, cluster),
+            };
+
+            slot[0] = kind;
+            LittleEndian::write(&mut slot[1..], u64::from(cluster));
+        }
+
+        // Calculate and store the checksum.
+        let cksum = checksum_algorithm.hash(&buf[8..]);
+        LittleEndian::write(&mut buf, cksum);
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inverse_identity() {
+        let mut journal = Journal::default();
+        assert_eq!(Journal::decode(journal.encode()).unwrap(), journal);
+
+        journal.push(Record::Pop(cluster::Pointer::new(2).unwrap()));
+        journal.push(Record::Push(cluster::Pointer::new(300).unwrap()));
+        assert_eq!(Journal::decode(journal.encode()).unwrap(), journal);
+
+        // Fill the journal.
+        while journal.has_room(1) {
+            journal.push(Record::Push(cluster::Pointer::new(7).unwrap()));
+        }
+        assert_eq!(journal.records.len(), MAX_RECORDS);
+        assert_eq!(Journal::decode(journal.encode()).unwrap(), journal);
+
+        journal.clear();
+        assert_eq!(Journal::decode(journal.encode()).unwrap(), Journal::default());
+    }
+
+    #[test]
+    fn never_written() {
+        assert_eq!(Journal::decode(&[0; disk::SECTOR_SIZE], header::ChecksumAlgorithm::SeaHash).unwrap(),
+                   Journal::default());
+    }
+
+    #[test]
+    fn mismatching_checksum() {
+        let mut sector = Journal::default().encode();
+        sector[PREAMBLE_SIZE] = 1;
+        assert_eq!(Journal::decode(sector), Err(Error::ChecksumMismatch));
+    }
+
+    #[test]
+    fn invalid_record() {
+        let mut journal = Journal::default();
+        journal.push(Record::Pop(cluster::Pointer::new(2).unwrap()));
+        let mut sector = journal.encode();
+        sector[PREAMBLE_SIZE] = 3;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(Journal::decode(sector), Err(Error::InvalidRecord {
+            kind: 3,
+        }));
+    }
+}
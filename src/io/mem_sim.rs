@@ -0,0 +1,146 @@
+//! In-memory disk simulation.
+//!
+//! This module provides a disk living entirely in memory. It is used for testing the I/O stack
+//! without touching any real devices.
+//!
+//! Besides the crate's own tests, it is exposed through the `testing` feature, such that
+//! downstream crates can test their handling of corruption and failing devices.
+
+use std::sync::{atomic, Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+/// An in-memory disk.
+///
+/// The sectors are shared between clones, so a test can keep a handle to the disk while the I/O
+/// stack owns another, and then inspect what actually hit the "device".
+#[derive(Clone)]
+pub struct MemSim {
+    /// The sectors of the disk.
+    sectors: Arc<RwLock<Vec<disk::SectorBuf>>>,
+    /// Should writes fail?
+    ///
+    /// This is used to simulate a failing device.
+    fail_writes: Arc<AtomicBool>,
+    /// The number of writes left, before every write fails.
+    ///
+    /// This is used to simulate a crash at some point. `usize::MAX` means no limit.
+    writes_left: Arc<AtomicUsize>,
+}
+
+impl MemSim {
+    /// Create a new, zeroed in-memory disk with `sectors` sectors.
+    pub fn new(sectors: disk::Sector) -> MemSim {
+        MemSim {
+            sectors: Arc::new(RwLock::new(vec![[0; disk::SECTOR_SIZE]; sectors])),
+            fail_writes: Arc::new(AtomicBool::new(false)),
+            writes_left: Arc::new(AtomicUsize::new(usize::MAX)),
+        }
+    }
+
+    /// Corrupt a sector by flipping some bits.
+    ///
+    /// This XORs byte `offset` of sector `sector` with `mask`, bypassing the I/O stack. As
+    /// clusters coincide with sectors, this can be used to corrupt pages and metaclusters, and
+    /// by corrupting a range of bytes, torn writes can be simulated.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the sector or offset is out of bounds.
+    pub fn corrupt(&self, sector: disk::Sector, offset: usize, mask: u8) {
+        self.sectors.write().unwrap()[sector][offset] ^= mask;
+    }
+
+    /// Make every following write fail.
+    pub fn fail_writes(&self) {
+        self.fail_writes.store(true, atomic::Ordering::SeqCst);
+    }
+
+    /// Make every write after the next `writes` writes fail.
+    ///
+    /// Together with `snapshot`, this simulates a crash after `writes` writes: Whatever the I/O
+    /// stack does afterwards never reaches the device.
+    pub fn crash_after(&self, writes: usize) {
+        self.writes_left.store(writes, atomic::Ordering::SeqCst);
+    }
+
+    /// Copy the disk as it is.
+    ///
+    /// Unlike clones, the copy doesn't share the sectors, and its writes don't fail. This captures
+    /// what reached the device at some point, e.g. to reopen it as if the system crashed there.
+    pub fn snapshot(&self) -> MemSim {
+        MemSim {
+            sectors: Arc::new(RwLock::new(self.sectors.read().unwrap().clone())),
+            fail_writes: Arc::new(AtomicBool::new(false)),
+            writes_left: Arc::new(AtomicUsize::new(usize::MAX)),
+        }
+    }
+
+    /// Truncate the disk to `sectors` sectors.
+    ///
+    /// This simulates the device being shrunk out-of-band, e.g. an image file being truncated.
+    /// The sectors beyond the new end are discarded.
+    pub fn truncate(&self, sectors: disk::Sector) {
+        self.sectors.write().unwrap().truncate(sectors);
+    }
+
+    /// Get a copy of some sector, bypassing the I/O stack.
+    pub fn sector(&self, sector: disk::Sector) -> disk::SectorBuf {
+        self.sectors.read().unwrap()[sector]
+    }
+}
+
+impl Disk for MemSim {
+    fn number_of_sectors(&self) -> disk::Sector {
+        self.sectors.read().unwrap().len()
+    }
+
+    fn write(&mut self, sector: disk::Sector, buf: &disk::SectorBuf) -> Result<(), disk::Error> {
+        // Simulate a failing device, if requested.
+        if self.fail_writes.load(atomic::Ordering::SeqCst) {
+            return Err(disk::Error::CorruptSector {
+                sector: sector,
+            });
+        }
+
+        // Look up the sector, and throw an error if it is out of bounds.
+        let mut sectors = self.sectors.write().unwrap();
+
+        // Simulate a crash, once the writes run out. The sectors are locked, so the count is
+        // consistent between clones.
+        let writes_left = self.writes_left.load(atomic::Ordering::SeqCst);
+        if writes_left == 0 {
+            return Err(disk::Error::CorruptSector {
+                sector: sector,
+            });
+        } else if writes_left != usize::MAX {
+            self.writes_left.store(writes_left - 1, atomic::Ordering::SeqCst);
+        }
+
+        let target = sectors.get_mut(sector).ok_or(disk::Error::OutOfBounds {
+            sector: sector,
+        })?;
+
+        // Overwrite the sector.
+        *target = *buf;
+
+        Ok(())
+    }
+
+    fn read_to(&self, sector: disk::Sector, buf: &mut disk::SectorBuf) -> Result<(), disk::Error> {
+        // Look up the sector, and throw an error if it is out of bounds.
+        let sectors = self.sectors.read().unwrap();
+        let source = sectors.get(sector).ok_or(disk::Error::OutOfBounds {
+            sector: sector,
+        })?;
+
+        // Copy the sector into the buffer.
+        *buf = *source;
+
+        Ok(())
+    }
+
+    fn heal(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+        // There is no redundancy to heal from, so this is a no-op.
+        Ok(())
+    }
+}
@@ -5,6 +5,24 @@ mod crypto;
 mod dedup;
 mod disk;
 mod header;
+mod index;
+mod journal;
+#[cfg(any(test, feature = "testing"))]
+pub mod mem_sim;
 mod page;
+mod pool;
 mod state_block;
+mod subsystem;
 mod vdev;
+
+// The allocator and its configuration, for the benchmarks.
+#[cfg(feature = "testing")]
+pub use self::alloc::{FreelistConstruction, Manager};
+#[cfg(feature = "testing")]
+pub use self::disk::{Disk, SECTOR_SIZE, SectorBuf};
+#[cfg(feature = "testing")]
+pub use self::header::{ChecksumAlgorithm, DiskHeader};
+#[cfg(feature = "testing")]
+pub use self::state_block::{ChecksumWidth, CompressionAlgorithm, Config, DedupPolicy};
+#[cfg(feature = "testing")]
+pub use self::vdev::Driver;
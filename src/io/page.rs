@@ -16,6 +16,22 @@
 //! cluster by compressing the pages together. To avoid storing metadata in the clusters, the
 //! pointers contains this information instead.
 
+/// The maximal number of pages a cluster can hold.
+///
+/// This is the number of pages which fit in the decompressed cluster capacity. Uncompressed
+/// clusters hold exactly one page.
+pub const MAX_PAGES_PER_CLUSTER: usize = alloc::CLUSTER_CAPACITY / disk::SECTOR_SIZE;
+/// The size (in bytes) of a page pointer with narrow checksums.
+const NARROW_POINTER_SIZE: usize = 16;
+/// The size (in bytes) of a page pointer with wide checksums.
+///
+/// Wide pointers are narrow pointers followed by the 32 high bits of the checksum.
+const WIDE_POINTER_SIZE: usize = 20;
+/// The size (in bytes) of the number of sectors of a large page pointer.
+///
+/// Large page pointers are page pointers followed by the number of sectors.
+const LARGE_SECTORS_SIZE: usize = 4;
+
 /// A page pointer.
 ///
 /// Page pointer contains information necessary for read and write pages on the disk. They're
@@ -58,7 +74,99 @@ struct Pointer {
     ///
     /// Most other approaches have the issue of not detecting phantom writes or not preserving
     /// consistency on crashes.
-    checksum: u32,
+    ///
+    /// Depending on the configured `state_block::ChecksumWidth`, only the 32 lowest bits might be used, in
+    /// which case the higher bits are zero.
+    checksum: u64,
+}
+
+impl Pointer {
+    /// Encode the page pointer with some checksum width.
+    ///
+    /// This writes the pointer into the start of `buf`, which must be at least
+    /// `width.pointer_size()` bytes long.
+    pub fn encode(self, width: state_block::ChecksumWidth, buf: &mut [u8]) {
+        // Write the narrow part of the pointer.
+        LittleEndian::write(buf, self.into());
+
+        if width == state_block::ChecksumWidth::Wide {
+            // Write the 32 high bits of the checksum after the narrow part.
+            LittleEndian::write(&mut buf[NARROW_POINTER_SIZE..], (self.checksum >> 32) as u32);
+        }
+    }
+
+    /// Decode a page pointer with some checksum width.
+    ///
+    /// This reads the pointer from the start of `buf`, which must be at least
+    /// `width.pointer_size()` bytes long.
+    pub fn decode(width: state_block::ChecksumWidth, buf: &[u8]) -> Pointer {
+        // Read the narrow part of the pointer.
+        let mut ptr = Pointer::from(LittleEndian::read(buf));
+
+        if width == state_block::ChecksumWidth::Wide {
+            // Read the 32 high bits of the checksum following the narrow part.
+            ptr.checksum |= (LittleEndian::read(&buf[NARROW_POINTER_SIZE..]) as u32 as u64) << 32;
+        }
+
+        ptr
+    }
+}
+
+/// A pointer to a large page.
+///
+/// Large pages span multiple sectors, and are stored uncompressed in consecutive clusters. They
+/// are only available if enabled in the disk header.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+struct LargePointer {
+    /// A pointer to the first sector of the page.
+    ///
+    /// The checksum covers the whole page.
+    first: Pointer,
+    /// The number of sectors of the page.
+    sectors: u32,
+}
+
+impl LargePointer {
+    /// The size (in bytes) of a large page pointer with some checksum width.
+    pub fn size(width: state_block::ChecksumWidth) -> usize {
+        width.pointer_size() + LARGE_SECTORS_SIZE
+    }
+
+    /// Encode the large page pointer with some checksum width.
+    ///
+    /// This writes the pointer into the start of `buf`, which must be at least
+    /// `LargePointer::size(width)` bytes long.
+    pub fn encode(self, width: state_block::ChecksumWidth, buf: &mut [u8]) {
+        // Write the pointer to the first sector.
+        self.first.encode(width, buf);
+        // Write the number of sectors after it.
+        LittleEndian::write(&mut buf[width.pointer_size()..], self.sectors);
+    }
+
+    /// Decode a large page pointer with some checksum width.
+    ///
+    /// This reads the pointer from the start of `buf`, which must be at least
+    /// `LargePointer::size(width)` bytes long.
+    pub fn decode(width: state_block::ChecksumWidth, buf: &[u8]) -> LargePointer {
+        LargePointer {
+            // Read the pointer to the first sector.
+            first: Pointer::decode(width, buf),
+            // Read the number of sectors following it.
+            sectors: LittleEndian::read(&buf[width.pointer_size()..]),
+        }
+    }
+
+    /// Get the pointers to the sectors of the page.
+    ///
+    /// The pointers carry the checksum of the whole page.
+    pub fn sectors(self) -> impl Iterator<Item = Pointer> {
+        let start: u64 = self.first.cluster.into();
+        (0..self.sectors as u64).map(move |n| Pointer {
+            // The address is non-zero, since `start` is.
+            cluster: cluster::Pointer::new(start + n).unwrap(),
+            .. self.first
+        })
+    }
 }
 
 impl Into<u128> for Pointer {
@@ -72,7 +180,8 @@ impl Into<u128> for Pointer {
 
                 offset
             }) as u128) << 64
-            | (self.checksum as u128) << 64 << 32
+            // Only the 32 lowest bits of the checksum fit into the narrow representation.
+            | (self.checksum as u32 as u128) << 64 << 32
     }
 }
 
@@ -89,7 +198,7 @@ impl From<u128> for Pointer {
                 n => Some(n),
             },
             // The highest 32 bit then store the checksum.
-            checksum: (from >> 64 >> 32) as u32,
+            checksum: (from >> 64 >> 32) as u32 as u64,
         }
     }
 }
@@ -134,4 +243,57 @@ mod tests {
         assert_eq!(ptr.checksum, 0xCCCCCCCC);
 
     }
+
+    #[test]
+    fn wide_checksum() {
+        let ptr = Pointer {
+            cluster: cluster::Pointer::new(0x0101010101010101).unwrap(),
+            offset: Some(3),
+            checksum: 0xDEADBEEFCCCCCCCC,
+        };
+
+        let mut buf = [0; WIDE_POINTER_SIZE];
+        ptr.encode(state_block::ChecksumWidth::Wide, &mut buf);
+        let decoded = Pointer::decode(state_block::ChecksumWidth::Wide, &buf);
+
+        assert_eq!(decoded, ptr);
+        assert_eq!(decoded.checksum, 0xDEADBEEFCCCCCCCC);
+    }
+
+    #[test]
+    fn narrow_checksum() {
+        let ptr = Pointer {
+            cluster: cluster::Pointer::new(0x0101010101010101).unwrap(),
+            offset: None,
+            checksum: state_block::ChecksumWidth::Narrow.truncate(0xDEADBEEFCCCCCCCC),
+        };
+
+        let mut buf = [0; NARROW_POINTER_SIZE];
+        ptr.encode(state_block::ChecksumWidth::Narrow, &mut buf);
+
+        assert_eq!(Pointer::decode(state_block::ChecksumWidth::Narrow, &buf).checksum, 0xCCCCCCCC);
+    }
+
+    #[test]
+    fn large_pointer() {
+        for &width in &[state_block::ChecksumWidth::Narrow, state_block::ChecksumWidth::Wide] {
+            let large = LargePointer {
+                first: Pointer {
+                    cluster: cluster::Pointer::new(0x0101010101010101).unwrap(),
+                    offset: None,
+                    checksum: width.truncate(0xDEADBEEFCCCCCCCC),
+                },
+                sectors: 0xABCD,
+            };
+
+            let mut buf = [0; WIDE_POINTER_SIZE + LARGE_SECTORS_SIZE];
+            large.encode(width, &mut buf);
+            assert_eq!(LargePointer::decode(width, &buf), large);
+
+            // The pointer to the first sector is encoded like any page pointer.
+            assert_eq!(Pointer::decode(width, &buf), large.first);
+            // Nothing is written past the encoded pointer.
+            assert!(buf[LargePointer::size(width)..].iter().all(|&x| x == 0));
+        }
+    }
 }
@@ -0,0 +1,162 @@
+//! Buffer pooling.
+//!
+//! Hot paths, such as reading and compressing pages, need temporary buffers. Allocating these
+//! anew on every call churns the allocator, so instead they are checked out of a pool and
+//! returned when done.
+
+use crossbeam::sync::SegQueue;
+use std::cmp;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{self, AtomicUsize};
+
+/// The atomic ordering used in the pool.
+const ORDERING: atomic::Ordering = atomic::Ordering::Relaxed;
+/// The maximal number of buffers kept in the pool.
+///
+/// Buffers returned to a full pool are deallocated, bounding the memory held by the pool.
+const MAX_BUFFERS: usize = 64;
+
+/// A pool of reusable buffers.
+#[derive(Default)]
+struct Pool {
+    /// The buffers available for checkout.
+    buffers: SegQueue<Vec<u8>>,
+    /// The number of buffers in `self.buffers`.
+    len: AtomicUsize,
+    /// The number of buffers the pool had to allocate.
+    ///
+    /// This measures how effective the pool is.
+    allocations: AtomicUsize,
+}
+
+impl Pool {
+    /// Create a pool with some preallocated buffers.
+    ///
+    /// This fills the pool with `buffers` buffers (bounded by the maximal size of the pool), each
+    /// with capacity `capacity`, so the first checkouts don't have to allocate.
+    pub fn with_buffers(buffers: usize, capacity: usize) -> Pool {
+        let pool = Pool::default();
+        for _ in 0..cmp::min(buffers, MAX_BUFFERS) {
+            pool.buffers.push(Vec::with_capacity(capacity));
+        }
+        pool.len.store(cmp::min(buffers, MAX_BUFFERS), ORDERING);
+
+        pool
+    }
+
+    /// Check out an empty buffer.
+    ///
+    /// This reuses a buffer from the pool if possible. Otherwise, a new buffer is allocated. The
+    /// buffer is returned to the pool when dropped.
+    pub fn get(&self) -> Buffer {
+        let buf = if let Some(mut buf) = self.buffers.try_pop() {
+            self.len.fetch_sub(1, ORDERING);
+            // Clear the old content, but keep the capacity.
+            buf.clear();
+
+            buf
+        } else {
+            // The pool was empty, so allocate a new buffer.
+            self.allocations.fetch_add(1, ORDERING);
+
+            Vec::new()
+        };
+
+        Buffer {
+            pool: self,
+            buf: buf,
+        }
+    }
+
+    /// Get the number of buffers available for checkout.
+    pub fn len(&self) -> usize {
+        self.len.load(ORDERING)
+    }
+
+    /// Get the number of buffers the pool has allocated.
+    pub fn allocations(&self) -> usize {
+        self.allocations.load(ORDERING)
+    }
+}
+
+/// A buffer checked out of a pool.
+///
+/// When dropped, the buffer is returned to the pool.
+struct Buffer<'a> {
+    /// The pool to return the buffer to.
+    pool: &'a Pool,
+    /// The inner buffer.
+    buf: Vec<u8>,
+}
+
+impl<'a> Deref for Buffer<'a> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl<'a> DerefMut for Buffer<'a> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl<'a> Drop for Buffer<'a> {
+    fn drop(&mut self) {
+        // Return the buffer to the pool, unless it is full.
+        if self.pool.len.fetch_add(1, ORDERING) < MAX_BUFFERS {
+            self.pool.buffers.push(mem::replace(&mut self.buf, Vec::new()));
+        } else {
+            self.pool.len.fetch_sub(1, ORDERING);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = Pool::default();
+
+        for _ in 0..100 {
+            let mut buf = pool.get();
+            assert!(buf.is_empty());
+            buf.extend_from_slice(&[1, 2, 3]);
+        }
+
+        assert_eq!(pool.allocations(), 1);
+    }
+
+    #[test]
+    fn bounded() {
+        let pool = Pool::default();
+
+        {
+            let bufs: Vec<_> = (0..MAX_BUFFERS * 2).map(|_| pool.get()).collect();
+            assert_eq!(pool.allocations(), MAX_BUFFERS * 2);
+        }
+
+        assert_eq!(pool.len.load(ORDERING), MAX_BUFFERS);
+    }
+
+    #[test]
+    fn preallocated() {
+        let pool = Pool::with_buffers(4, 512);
+        assert_eq!(pool.len(), 4);
+
+        {
+            let bufs: Vec<_> = (0..4).map(|_| pool.get()).collect();
+            assert!(bufs.iter().all(|buf| buf.capacity() >= 512));
+        }
+
+        // The preallocated buffers were used, so nothing had to be allocated.
+        assert_eq!(pool.allocations(), 0);
+        assert_eq!(pool.len(), 4);
+        // The preallocation is bounded by the size of the pool.
+        assert_eq!(Pool::with_buffers(MAX_BUFFERS * 2, 0).len(), MAX_BUFFERS);
+    }
+}
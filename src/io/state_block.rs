@@ -1,3 +1,8 @@
+/// The maximal number of reserved clusters.
+///
+/// The reserved clusters are listed in the state block, which bounds their number.
+pub const MAX_RESERVE: usize = 16;
+
 quick_error! {
     /// A state block parsing error.
     enum Error {
@@ -9,6 +14,46 @@ quick_error! {
         InvalidCompressionAlgorithm {
             description("Invalid compression algorithm option.")
         }
+        /// Invalid checksum width.
+        InvalidChecksumWidth {
+            description("Invalid checksum width option.")
+        }
+        /// Invalid deduplication policy.
+        InvalidDedupPolicy {
+            description("Invalid deduplication policy option.")
+        }
+        /// Invalid fill pattern.
+        InvalidFillPattern {
+            description("Invalid fill pattern option.")
+        }
+        /// Invalid compression profile.
+        InvalidCompressionProfile {
+            description("Invalid compression profile option.")
+        }
+        /// Invalid deduplication entropy threshold.
+        InvalidDedupEntropyThreshold {
+            description("Invalid deduplication entropy threshold option.")
+        }
+        /// Invalid cluster tagging option.
+        InvalidClusterTags {
+            description("Invalid cluster tagging option.")
+        }
+        /// Invalid discard option.
+        InvalidTrimOnFree {
+            description("Invalid discard option.")
+        }
+        /// Invalid secondary compression algorithm.
+        ///
+        /// The secondary algorithm cannot be chosen automatically.
+        InvalidSecondaryCompressionAlgorithm {
+            description("Invalid secondary compression algorithm option.")
+        }
+        /// Invalid reserve size.
+        ///
+        /// The reserve is larger than the state block can list.
+        InvalidReserve {
+            description("Invalid reserve size option.")
+        }
         /// The checksums doesn't match.
         ChecksumMismatch {
             /// The checksum of the data.
@@ -19,10 +64,69 @@ quick_error! {
             display("Mismatching checksums in the state block - expected {:x}, found {:x}.", expected, found)
             description("Mismatching checksum.")
         }
+        /// A disk error.
+        Disk(err: disk::Error) {
+            from()
+            description("Disk I/O error.")
+            display("Disk I/O error: {}", err)
+        }
+    }
+}
+
+quick_error! {
+    /// A conflict between configuration options.
+    ///
+    /// Each option might be valid on its own, but the combination is nonsensical or unsupported.
+    #[derive(PartialEq, Eq, Clone, Copy)]
+    enum ConfigError {
+        /// The secondary compression algorithm is set to automatic selection.
+        SecondaryCompressionAlgorithmAuto {
+            description("The secondary compression algorithm cannot be chosen automatically.")
+            display("`secondary_compression_algorithm` cannot be `Auto`.")
+        }
+        /// A secondary compression algorithm is set, but compression is disabled.
+        SecondaryCompressionWithoutCompression {
+            description("A secondary compression algorithm is set, but compression is disabled.")
+            display("`secondary_compression_algorithm` is set, but `compression_algorithm` is `Identity`.")
+        }
+        /// The secondary compression algorithm is the primary compression algorithm.
+        SecondaryCompressionAlgorithmIsPrimary {
+            description("The secondary compression algorithm is the primary one.")
+            display("`secondary_compression_algorithm` is the same as `compression_algorithm`.")
+        }
+        /// The deduplication entropy threshold is out of range.
+        DedupEntropyThresholdOutOfRange {
+            /// The threshold.
+            threshold: u16,
+        } {
+            description("The deduplication entropy threshold is out of range.")
+            display("`dedup_entropy_threshold` is {}, but at most 256.", threshold)
+        }
+        /// A deduplication entropy threshold is set, but deduplication is disabled.
+        DedupEntropyThresholdWithoutDedup {
+            description("A deduplication entropy threshold is set, but deduplication is disabled.")
+            display("`dedup_entropy_threshold` is set, but `dedup_policy` is `Disabled`.")
+        }
+        /// Freed clusters are both discarded and filled.
+        TrimOnFreeWithFill {
+            description("Freed clusters are both discarded and filled.")
+            display("`trim_on_free` is set, but `free_fill` is not `Keep`.")
+        }
+        /// The reserve is too large.
+        ReserveOutOfRange {
+            /// The number of reserved clusters.
+            clusters: u8,
+        } {
+            description("The reserve is too large.")
+            display("`reserve_clusters` is {}, but at most {}.", clusters, MAX_RESERVE)
+        }
     }
 }
 
 /// A compression algorithm configuration option.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 enum CompressionAlgorithm {
     /// Identity function/compression disabled.
     Identity = 0,
@@ -32,6 +136,15 @@ enum CompressionAlgorithm {
     /// based on streaming data reduplication. The details are described
     /// [here](http://ticki.github.io/blog/how-lz4-works/).
     Lz4 = 1,
+    /// Zstandard compression.
+    ///
+    /// Zstandard is slower than LZ4, but usually achieves a notably better compression ratio.
+    Zstd = 2,
+    /// Automatic selection.
+    ///
+    /// The algorithm is chosen for every cluster, when the first page is stored in it. The
+    /// chosen algorithm is tagged in the cluster itself.
+    Auto = 3,
 }
 
 impl TryFrom<u16> for CompressionAlgorithm {
@@ -41,12 +154,225 @@ impl TryFrom<u16> for CompressionAlgorithm {
         match from {
             0 => Ok(CompressionAlgorithm::Identity),
             1 => Ok(CompressionAlgorithm::Lz4),
+            2 => Ok(CompressionAlgorithm::Zstd),
+            3 => Ok(CompressionAlgorithm::Auto),
             0x8000...0xFFFF => Err(Error::UnknownCompressionAlgorithm),
             _ => Err(Error::InvalidCompressionAlgorithm),
         }
     }
 }
 
+/// A page pointer checksum width configuration option.
+///
+/// This is chosen on format time, and trades pointer size for stronger integrity: The wider the
+/// checksum, the less likely it is for corruption to go undetected (or for two distinct pages to
+/// be confused by the checksum-indexed deduplication table).
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+enum ChecksumWidth {
+    /// 32-bit checksums.
+    ///
+    /// This keeps the page pointer at 128 bits.
+    Narrow = 0,
+    /// 64-bit checksums.
+    ///
+    /// This extends the page pointer to 160 bits.
+    Wide = 1,
+}
+
+impl ChecksumWidth {
+    /// Truncate some checksum to this width.
+    pub fn truncate(self, cksum: u64) -> u64 {
+        match self {
+            // Take the 32 lowest bits (truncating cast).
+            ChecksumWidth::Narrow => cksum as u32 as u64,
+            // The checksum is already 64 bits wide.
+            ChecksumWidth::Wide => cksum,
+        }
+    }
+
+    /// The size (in bytes) of a page pointer with this checksum width.
+    pub fn pointer_size(self) -> usize {
+        match self {
+            ChecksumWidth::Narrow => page::NARROW_POINTER_SIZE,
+            ChecksumWidth::Wide => page::WIDE_POINTER_SIZE,
+        }
+    }
+}
+
+impl Default for ChecksumWidth {
+    fn default() -> ChecksumWidth {
+        // Narrow checksums are the default, to keep pointers compact.
+        ChecksumWidth::Narrow
+    }
+}
+
+impl TryFrom<u16> for ChecksumWidth {
+    type Err = Error;
+
+    fn try_from(from: u16) -> Result<ChecksumWidth, Error> {
+        match from {
+            0 => Ok(ChecksumWidth::Narrow),
+            1 => Ok(ChecksumWidth::Wide),
+            _ => Err(Error::InvalidChecksumWidth),
+        }
+    }
+}
+
+/// A deduplication policy configuration option.
+///
+/// Verifying a deduplication candidate costs a fingerprint calculation, which for write-heavy
+/// workloads can exceed the cost of simply storing the page again. The policy defines how the
+/// allocator reacts to this.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+enum DedupPolicy {
+    /// Always verify candidates.
+    Always = 0,
+    /// Adapt to the measured costs.
+    ///
+    /// When verification gets more expensive than storing a fresh page, the candidates are
+    /// trusted on the checksum alone if the checksums are wide, and deduplication is skipped
+    /// otherwise.
+    Adaptive = 1,
+    /// Never deduplicate.
+    Disabled = 2,
+}
+
+impl Default for DedupPolicy {
+    fn default() -> DedupPolicy {
+        DedupPolicy::Always
+    }
+}
+
+impl TryFrom<u16> for DedupPolicy {
+    type Err = Error;
+
+    fn try_from(from: u16) -> Result<DedupPolicy, Error> {
+        match from {
+            0 => Ok(DedupPolicy::Always),
+            1 => Ok(DedupPolicy::Adaptive),
+            2 => Ok(DedupPolicy::Disabled),
+            _ => Err(Error::InvalidDedupPolicy),
+        }
+    }
+}
+
+/// A fill pattern configuration option.
+///
+/// This defines what freed clusters are overwritten with, when they're pushed to the freelist.
+/// Overwriting avoids leaving freed data recoverable, at the cost of an extra write.
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+enum FillPattern {
+    /// Leave the data as-is.
+    Keep = 0,
+    /// Fill with zeros.
+    Zero = 1,
+    /// Fill with repeated `0xDEADBEEF`.
+    ///
+    /// This makes freed clusters easy to recognize when debugging.
+    DeadBeef = 2,
+}
+
+impl FillPattern {
+    /// Get the sector to overwrite freed clusters with.
+    ///
+    /// If the data shall be left as-is, `None` is returned.
+    pub fn sector(self) -> Option<disk::SectorBuf> {
+        match self {
+            FillPattern::Keep => None,
+            FillPattern::Zero => Some([0; disk::SECTOR_SIZE]),
+            FillPattern::DeadBeef => {
+                let mut buf = [0; disk::SECTOR_SIZE];
+                for chunk in buf.chunks_mut(4) {
+                    BigEndian::write_u32(chunk, 0xDEADBEEF);
+                }
+
+                Some(buf)
+            },
+        }
+    }
+}
+
+impl Default for FillPattern {
+    fn default() -> FillPattern {
+        // Leave the data as-is for performance.
+        FillPattern::Keep
+    }
+}
+
+impl TryFrom<u16> for FillPattern {
+    type Err = Error;
+
+    fn try_from(from: u16) -> Result<FillPattern, Error> {
+        match from {
+            0 => Ok(FillPattern::Keep),
+            1 => Ok(FillPattern::Zero),
+            2 => Ok(FillPattern::DeadBeef),
+            _ => Err(Error::InvalidFillPattern),
+        }
+    }
+}
+
+/// A compression profile configuration option.
+///
+/// Profiles are named presets mapping to a compression algorithm and level. They're resolved when
+/// the manager is set up, and override the compression algorithm option, unless the profile is
+/// `Custom`.
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+enum CompressionProfile {
+    /// Use the configured compression algorithm at its default level.
+    Custom = 0,
+    /// Favor speed over compression ratio.
+    Fastest = 1,
+    /// Balance speed and compression ratio.
+    Balanced = 2,
+    /// Favor compression ratio over speed.
+    Smallest = 3,
+}
+
+impl CompressionProfile {
+    /// Resolve the profile into a compression algorithm and level.
+    ///
+    /// `algorithm` is the configured compression algorithm, which is only used by `Custom`. The
+    /// level is ignored by algorithms without levels.
+    pub fn resolve(self, algorithm: CompressionAlgorithm) -> (CompressionAlgorithm, i32) {
+        match self {
+            CompressionProfile::Custom => (algorithm, 3),
+            CompressionProfile::Fastest => (CompressionAlgorithm::Lz4, 0),
+            CompressionProfile::Balanced => (CompressionAlgorithm::Zstd, 3),
+            CompressionProfile::Smallest => (CompressionAlgorithm::Zstd, 19),
+        }
+    }
+}
+
+impl Default for CompressionProfile {
+    fn default() -> CompressionProfile {
+        // Leave the choice of the algorithm to the compression algorithm option.
+        CompressionProfile::Custom
+    }
+}
+
+impl TryFrom<u16> for CompressionProfile {
+    type Err = Error;
+
+    fn try_from(from: u16) -> Result<CompressionProfile, Error> {
+        match from {
+            0 => Ok(CompressionProfile::Custom),
+            1 => Ok(CompressionProfile::Fastest),
+            2 => Ok(CompressionProfile::Balanced),
+            3 => Ok(CompressionProfile::Smallest),
+            _ => Err(Error::InvalidCompressionProfile),
+        }
+    }
+}
+
 /// The freelist head.
 ///
 /// The freelist chains some number of blocks containing pointers to free blocks. This allows for
@@ -82,9 +408,119 @@ struct StateBlock {
 }
 
 /// The configuration sub-block.
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct Config {
     /// The chosen compression algorithm.
     compression_algorithm: CompressionAlgorithm,
+    /// The width of the checksums stored in page pointers.
+    checksum_width: ChecksumWidth,
+    /// The deduplication policy.
+    dedup_policy: DedupPolicy,
+    /// The pattern to fill freed clusters with.
+    free_fill: FillPattern,
+    /// The compression profile.
+    compression_profile: CompressionProfile,
+    /// The entropy threshold of deduplication.
+    ///
+    /// Pages with more distinct byte values than this are deemed high-entropy, and skip the
+    /// deduplication lookup, as they rarely have duplicates. If zero, every page is looked up.
+    /// It is at most 256.
+    dedup_entropy_threshold: u16,
+    /// Tag the compressed clusters with their compression algorithm?
+    ///
+    /// If so, every compressed cluster records the algorithm it was compressed with, so the
+    /// compression algorithm can be changed without breaking the existing clusters. This is
+    /// implied by `CompressionAlgorithm::Auto`.
+    cluster_tags: bool,
+    /// The secondary compression algorithm.
+    ///
+    /// If a new cluster cannot be compressed with the chosen algorithm, this algorithm is tried,
+    /// before the page is stored uncompressed. `CompressionAlgorithm::Identity` disables it, and
+    /// `CompressionAlgorithm::Auto` is invalid. If enabled, it implies cluster tagging.
+    secondary_compression_algorithm: CompressionAlgorithm,
+    /// The compression level.
+    ///
+    /// This is used by the `Custom` compression profile, and ignored by algorithms without levels.
+    /// Zero means the default level of the algorithm. Out-of-range levels are clamped into the
+    /// range of the algorithm when compressing.
+    compression_level: u8,
+    /// Discard freed clusters?
+    ///
+    /// If so, the device is told that the sectors of clusters pushed to the freelist are unused,
+    /// which lets SSDs reclaim them. Devices without support for discarding ignore it. This
+    /// conflicts with filling freed clusters, since the fill would be written right after.
+    trim_on_free: bool,
+    /// The number of clusters to reserve for metaclusters.
+    ///
+    /// These clusters are set aside when formatting, and only ever used to hold new metaclusters
+    /// while pushing to the freelist. Freed clusters top up the reserve again. It is at most
+    /// `MAX_RESERVE`.
+    reserve_clusters: u8,
+}
+
+impl Config {
+    /// Resolve the compression profile.
+    ///
+    /// This sets the compression algorithm according to the profile, and returns the compression
+    /// level. The custom profile uses the configured compression level, if any.
+    pub fn resolve_compression(&mut self) -> i32 {
+        let (algorithm, level) = self.compression_profile.resolve(self.compression_algorithm);
+        self.compression_algorithm = algorithm;
+
+        if self.compression_profile == CompressionProfile::Custom && self.compression_level != 0 {
+            self.compression_level as i32
+        } else {
+            level
+        }
+    }
+
+    /// Check that the options are compatible with each other.
+    ///
+    /// If two options conflict, an error naming them is returned.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // Check the secondary compression algorithm against the primary one.
+        match self.secondary_compression_algorithm {
+            CompressionAlgorithm::Identity => (),
+            CompressionAlgorithm::Auto => return Err(ConfigError::SecondaryCompressionAlgorithmAuto),
+            _ if self.compression_algorithm == CompressionAlgorithm::Identity
+                => return Err(ConfigError::SecondaryCompressionWithoutCompression),
+            secondary if secondary == self.compression_algorithm
+                => return Err(ConfigError::SecondaryCompressionAlgorithmIsPrimary),
+            _ => (),
+        }
+
+        // Check the deduplication entropy threshold against the deduplication policy.
+        if self.dedup_entropy_threshold > 256 {
+            return Err(ConfigError::DedupEntropyThresholdOutOfRange {
+                threshold: self.dedup_entropy_threshold,
+            });
+        }
+        if self.dedup_entropy_threshold != 0 && self.dedup_policy == DedupPolicy::Disabled {
+            return Err(ConfigError::DedupEntropyThresholdWithoutDedup);
+        }
+
+        // Check the discard option against the fill pattern.
+        if self.trim_on_free && self.free_fill != FillPattern::Keep {
+            return Err(ConfigError::TrimOnFreeWithFill);
+        }
+
+        // Make sure that the reserve can be listed in the state block.
+        if self.reserve_clusters as usize > MAX_RESERVE {
+            return Err(ConfigError::ReserveOutOfRange {
+                clusters: self.reserve_clusters,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Are the compressed clusters tagged with their compression algorithm?
+    pub fn tags_clusters(&self) -> bool {
+        self.cluster_tags
+            || self.compression_algorithm == CompressionAlgorithm::Auto
+            || self.secondary_compression_algorithm != CompressionAlgorithm::Identity
+    }
 }
 
 /// The state sub-block.
@@ -95,6 +531,44 @@ struct State {
     ///
     /// If the freelist is empty, this is set to `None`.
     freelist_head: Option<FreelistHead>,
+    /// The number of metaclusters stored on the separate metadata device.
+    ///
+    /// The metaclusters on the metadata device form a stack following the state block, so this
+    /// is sufficient to find the next unused metadata cluster. It is zero if there is no separate
+    /// metadata device.
+    metaclusters: u64,
+    /// The current backup generation.
+    ///
+    /// Clusters written are stamped with this, so incremental backups can tell which clusters
+    /// changed since an earlier generation.
+    backup_generation: u64,
+    /// The generation of the current persisted live page index.
+    ///
+    /// This is zero, if the persisted index is stale (i.e. the live pages changed since it was
+    /// written), or if none was written.
+    live_index: u64,
+    /// The reserved clusters.
+    ///
+    /// These are neither free nor in use, but held back for new metaclusters. See
+    /// `Config::reserve_clusters`.
+    reserve: Vec<cluster::Pointer>,
+}
+
+/// Read the state block of some driver.
+///
+/// This reads and decodes (validating the checksum) only the state block of `driver`, without
+/// bringing the whole page manager online. This is useful for recovery tooling, as it works even
+/// if the freelist is damaged.
+///
+/// If a checksum migration was interrupted, the state block might still be checksummed under the
+/// previous algorithm, which is accepted as well.
+fn read(driver: &vdev::Driver) -> Result<StateBlock, Error> {
+    let buf = driver.read(driver.header.state_block_address)?;
+    let res = StateBlock::decode(&buf, driver.header.checksum_algorithm);
+    match driver.header.previous_checksum_algorithm {
+        Some(previous) if res.is_err() => StateBlock::decode(&buf, previous),
+        _ => res,
+    }
 }
 
 impl StateBlock {
@@ -110,14 +584,64 @@ impl StateBlock {
             });
         }
 
+        // Load the checksum width, which is needed for decoding the superpage pointer.
+        let checksum_width = ChecksumWidth::try_from(LittleEndian::read(&buf[10..]))?;
+
         Ok(StateBlock {
             config: Config {
                 // Load the compression algorithm config field.
                 compression_algorithm: CompressionAlgorithm::try_from(LittleEndian::read(buf[8..]))?,
+                // Load the checksum width config field.
+                checksum_width: checksum_width,
+                // Load the deduplication policy config field.
+                dedup_policy: DedupPolicy::try_from(LittleEndian::read(&buf[12..]))?,
+                // Load the fill pattern config field.
+                free_fill: FillPattern::try_from(LittleEndian::read(&buf[14..]))?,
+                // Load the compression profile config field.
+                compression_profile: CompressionProfile::try_from(LittleEndian::read(&buf[64..]))?,
+                // Load the deduplication entropy threshold config field.
+                dedup_entropy_threshold: match LittleEndian::read(&buf[74..]) {
+                    threshold @ 0...256 => threshold,
+                    _ => return Err(Error::InvalidDedupEntropyThreshold),
+                },
+                // Load the cluster tagging config field.
+                cluster_tags: match buf[76] {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(Error::InvalidClusterTags),
+                },
+                // Load the secondary compression algorithm config field.
+                secondary_compression_algorithm: match CompressionAlgorithm::try_from(LittleEndian::read(&buf[78..]))? {
+                    CompressionAlgorithm::Auto => return Err(Error::InvalidSecondaryCompressionAlgorithm),
+                    algorithm => algorithm,
+                },
+                // Load the compression level config field.
+                compression_level: buf[77],
+                // Load the discard config field.
+                trim_on_free: match buf[80] {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(Error::InvalidTrimOnFree),
+                },
+                // Load the reserve size config field.
+                reserve_clusters: match buf[81] {
+                    clusters if clusters as usize <= MAX_RESERVE => clusters,
+                    _ => return Err(Error::InvalidReserve),
+                },
             },
             state: State {
-                // Load the superpage pointer.
-                superpage: page::Pointer::new(LittleEndian::read(buf[16..])),
+                // Load the superpage pointer. The high checksum bits of wide pointers are stored
+                // separately in order to keep the layout of the freelist head fixed.
+                superpage: if LittleEndian::read::<u128>(&buf[16..]) == 0 {
+                    None
+                } else {
+                    let mut superpage = page::Pointer::from(LittleEndian::read(&buf[16..]));
+                    if checksum_width == ChecksumWidth::Wide {
+                        superpage.checksum |= (LittleEndian::read(&buf[52..]) as u32 as u64) << 32;
+                    }
+
+                    Some(superpage)
+                },
                 // Construct the freelist head metadata. If the pointer is 0, we return `None`.
                 freelist_head: cluster::Pointer::new(LittleEndian::read(&buf[32..])).map(|freelist_head| {
                     FreelistHead {
@@ -128,6 +652,18 @@ impl StateBlock {
                         counter: buf[48],
                     }
                 }),
+                // Load the number of metaclusters on the metadata device.
+                metaclusters: LittleEndian::read(&buf[56..]),
+                // Load the backup generation.
+                backup_generation: LittleEndian::read(&buf[66..]),
+                // Load the generation of the persisted live page index.
+                live_index: LittleEndian::read(&buf[88..]),
+                // Load the reserved clusters. The list ends at the first null pointer.
+                reserve: (0..MAX_RESERVE)
+                    .map(|n| LittleEndian::read(&buf[96 + n * cluster::POINTER_SIZE..]))
+                    .take_while(|&cluster| cluster != 0)
+                    .map(|cluster| cluster::Pointer::new(cluster).unwrap())
+                    .collect(),
             },
         })
     }
@@ -139,9 +675,35 @@ impl StateBlock {
 
         // Write the compression algorithm.
         LittleEndian::write(&mut buf[8..], self.config.compression_algorithm as u16);
+        // Write the checksum width.
+        LittleEndian::write(&mut buf[10..], self.config.checksum_width as u16);
+        // Write the deduplication policy.
+        LittleEndian::write(&mut buf[12..], self.config.dedup_policy as u16);
+        // Write the fill pattern.
+        LittleEndian::write(&mut buf[14..], self.config.free_fill as u16);
+        // Write the compression profile.
+        LittleEndian::write(&mut buf[64..], self.config.compression_profile as u16);
+        // Write the deduplication entropy threshold.
+        LittleEndian::write(&mut buf[74..], self.config.dedup_entropy_threshold);
+        // Write the cluster tagging option.
+        buf[76] = self.config.cluster_tags as u8;
+        // Write the compression level.
+        buf[77] = self.config.compression_level;
+        // Write the secondary compression algorithm.
+        LittleEndian::write(&mut buf[78..], self.config.secondary_compression_algorithm as u16);
+        // Write the discard option.
+        buf[80] = self.config.trim_on_free as u8;
+        // Write the reserve size.
+        buf[81] = self.config.reserve_clusters;
         // Write the superpage pointer. If no superpage is initialized, we simply write a null
         // pointer.
         LittleEndian::write(&mut buf[16..], self.state.superpage.map_or(0, |x| x.into()));
+        if let Some(superpage) = self.state.superpage {
+            if self.config.checksum_width == ChecksumWidth::Wide {
+                // Write the high checksum bits of the superpage pointer.
+                LittleEndian::write(&mut buf[52..], (superpage.checksum >> 32) as u32);
+            }
+        }
 
         if let Some(freelist_head) = self.state.freelist_head {
             // Write the freelist head pointer.
@@ -154,6 +716,17 @@ impl StateBlock {
         // If the free list was empty, both the checksum, counter, and pointer are zero, which
         // matching the buffer's current state.
 
+        // Write the number of metaclusters on the metadata device.
+        LittleEndian::write(&mut buf[56..], self.state.metaclusters);
+        // Write the backup generation.
+        LittleEndian::write(&mut buf[66..], self.state.backup_generation);
+        // Write the generation of the persisted live page index.
+        LittleEndian::write(&mut buf[88..], self.state.live_index);
+        // Write the reserved clusters. The rest of the list is left null.
+        for (n, &cluster) in self.state.reserve.iter().enumerate() {
+            LittleEndian::write(&mut buf[96 + n * cluster::POINTER_SIZE..], cluster);
+        }
+
         // Calculate and store the checksum.
         let cksum = checksum_algorithm.hash(&buf[8..]);
         LittleEndian::write(&mut buf, cksum);
@@ -174,6 +747,15 @@ mod tests {
         block.config.compression_algorithm = CompressionAlgorithm::Identity;
         assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
 
+        block.config.compression_algorithm = CompressionAlgorithm::Auto;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.config.cluster_tags = true;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.config.secondary_compression_algorithm = CompressionAlgorithm::Zstd;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
         block.state.superpage = 200;
         assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
 
@@ -183,6 +765,41 @@ mod tests {
             counter: 2,
         });
         assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.state.backup_generation = 7;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.state.live_index = 3;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.config.compression_level = 255;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.config.trim_on_free = true;
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+
+        block.config.reserve_clusters = MAX_RESERVE as u8;
+        block.state.reserve = (0..MAX_RESERVE as u64).map(|n| cluster::Pointer::new(n + 100).unwrap()).collect();
+        assert_eq!(StateBlock::decode(block.encode()).unwrap(), block);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_config() {
+        let mut block = StateBlock::default();
+        block.config.compression_algorithm = CompressionAlgorithm::Zstd;
+        block.config.checksum_width = ChecksumWidth::Wide;
+        block.config.free_fill = FillPattern::Zero;
+        let sector = block.encode();
+
+        // Round-trip the configuration through JSON.
+        let json = serde_json::to_string(&block.config).unwrap();
+        let config: Config = serde_json::from_str(&json).unwrap();
+        assert!(config == block.config);
+
+        // The on-disk encoding is unaffected.
+        block.config = config;
+        assert_eq!(block.encode(), sector);
     }
 
     #[test]
@@ -212,6 +829,48 @@ mod tests {
         assert_eq!(sector, block.encode());
     }
 
+    #[test]
+    fn validate_config() {
+        assert_eq!(Config::default().validate(), Ok(()));
+
+        let mut config = Config::default();
+        config.secondary_compression_algorithm = CompressionAlgorithm::Auto;
+        assert_eq!(config.validate(), Err(ConfigError::SecondaryCompressionAlgorithmAuto));
+
+        config.compression_algorithm = CompressionAlgorithm::Identity;
+        config.secondary_compression_algorithm = CompressionAlgorithm::Zstd;
+        assert_eq!(config.validate(), Err(ConfigError::SecondaryCompressionWithoutCompression));
+
+        config.compression_algorithm = CompressionAlgorithm::Zstd;
+        assert_eq!(config.validate(), Err(ConfigError::SecondaryCompressionAlgorithmIsPrimary));
+
+        config.compression_algorithm = CompressionAlgorithm::Lz4;
+        assert_eq!(config.validate(), Ok(()));
+
+        config.dedup_entropy_threshold = 300;
+        assert_eq!(config.validate(), Err(ConfigError::DedupEntropyThresholdOutOfRange { threshold: 300 }));
+
+        config.dedup_entropy_threshold = 200;
+        config.dedup_policy = DedupPolicy::Disabled;
+        assert_eq!(config.validate(), Err(ConfigError::DedupEntropyThresholdWithoutDedup));
+
+        config.dedup_entropy_threshold = 0;
+        assert_eq!(config.validate(), Ok(()));
+
+        config.trim_on_free = true;
+        config.free_fill = FillPattern::Zero;
+        assert_eq!(config.validate(), Err(ConfigError::TrimOnFreeWithFill));
+
+        config.free_fill = FillPattern::Keep;
+        assert_eq!(config.validate(), Ok(()));
+
+        config.reserve_clusters = MAX_RESERVE as u8 + 1;
+        assert_eq!(config.validate(), Err(ConfigError::ReserveOutOfRange { clusters: MAX_RESERVE as u8 + 1 }));
+
+        config.reserve_clusters = MAX_RESERVE as u8;
+        assert_eq!(config.validate(), Ok(()));
+    }
+
     #[test]
     fn mismatching_checksum() {
         let mut sector = StateBlock::default().encode();
@@ -227,5 +886,114 @@ mod tests {
 
         sector[8] = 0xFF;
         assert_eq!(StateBlock::decode(sector), Err(Error::InvalidCompression));
+
+        sector = StateBlock::default().encode();
+
+        sector[10] = 0xFF;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector), Err(Error::InvalidChecksumWidth));
+
+        sector = StateBlock::default().encode();
+
+        sector[12] = 0xFF;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector), Err(Error::InvalidDedupPolicy));
+
+        sector = StateBlock::default().encode();
+
+        sector[14] = 0xFF;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector), Err(Error::InvalidFillPattern));
+
+        sector = StateBlock::default().encode();
+
+        sector[64] = 0xFF;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector), Err(Error::InvalidCompressionProfile));
+
+        sector = StateBlock::default().encode();
+
+        sector[75] = 0xFF;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector), Err(Error::InvalidDedupEntropyThreshold));
+
+        sector = StateBlock::default().encode();
+
+        sector[76] = 2;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector), Err(Error::InvalidClusterTags));
+
+        sector = StateBlock::default().encode();
+
+        sector[78] = CompressionAlgorithm::Auto as u8;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector), Err(Error::InvalidSecondaryCompressionAlgorithm));
+
+        sector = StateBlock::default().encode();
+
+        sector[80] = 2;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector), Err(Error::InvalidTrimOnFree));
+
+        sector = StateBlock::default().encode();
+
+        sector[81] = MAX_RESERVE as u8 + 1;
+        LittleEndian::write(&mut sector, seahash::hash(sector[8..]));
+        assert_eq!(StateBlock::decode(sector), Err(Error::InvalidReserve));
+    }
+
+    #[test]
+    fn wide_superpage() {
+        let mut block = StateBlock::default();
+        block.config.checksum_width = ChecksumWidth::Wide;
+        block.state.superpage = Some(page::Pointer {
+            cluster: cluster::Pointer::new(200).unwrap(),
+            offset: Some(2),
+            checksum: 0xDEADBEEFCCCCCCCC,
+        });
+
+        let decoded = StateBlock::decode(block.encode()).unwrap();
+        assert_eq!(decoded, block);
+        assert_eq!(decoded.state.superpage.unwrap().checksum, 0xDEADBEEFCCCCCCCC);
+    }
+
+    #[test]
+    fn truncate_checksum() {
+        assert_eq!(ChecksumWidth::Narrow.truncate(0xDEADBEEFCCCCCCCC), 0xCCCCCCCC);
+        assert_eq!(ChecksumWidth::Wide.truncate(0xDEADBEEFCCCCCCCC), 0xDEADBEEFCCCCCCCC);
+    }
+
+    #[test]
+    fn compression_profiles() {
+        assert_eq!(CompressionProfile::Custom.resolve(CompressionAlgorithm::Lz4), (CompressionAlgorithm::Lz4, 3));
+        assert_eq!(CompressionProfile::Fastest.resolve(CompressionAlgorithm::Identity), (CompressionAlgorithm::Lz4, 0));
+        assert_eq!(CompressionProfile::Balanced.resolve(CompressionAlgorithm::Identity), (CompressionAlgorithm::Zstd, 3));
+        assert_eq!(CompressionProfile::Smallest.resolve(CompressionAlgorithm::Identity), (CompressionAlgorithm::Zstd, 19));
+
+        // The resolved configuration round-trips through the state block.
+        for &profile in &[CompressionProfile::Fastest, CompressionProfile::Balanced, CompressionProfile::Smallest] {
+            let mut block = StateBlock::default();
+            block.config.compression_profile = profile;
+            let level = block.config.resolve_compression();
+
+            let decoded = StateBlock::decode(block.encode()).unwrap();
+            assert_eq!(decoded, block);
+            assert_eq!(decoded.config.compression_profile, profile);
+            assert_eq!(decoded.config.clone().resolve_compression(), level);
+        }
+    }
+
+    #[test]
+    fn compression_level() {
+        let mut config = Config::default();
+        config.compression_algorithm = CompressionAlgorithm::Zstd;
+        // The default level of the algorithm is used, unless a level is configured.
+        assert_eq!(config.resolve_compression(), 3);
+        config.compression_level = 12;
+        assert_eq!(config.resolve_compression(), 12);
+
+        // Profiles other than `Custom` choose their own level.
+        config.compression_profile = CompressionProfile::Smallest;
+        assert_eq!(config.resolve_compression(), 19);
     }
 }
@@ -0,0 +1,17 @@
+//! Log subsystems.
+//!
+//! Every log record emitted by the I/O stack carries a `"subsystem"` key, holding one of the
+//! constants below. This allows operators to filter the logs on a per-subsystem basis through
+//! their drain, e.g. enabling debug logging of the freelist without the noise of the per-page
+//! allocation traces.
+
+/// The page allocator.
+pub const ALLOC: &str = "alloc";
+/// The freelist and its metaclusters.
+pub const FREELIST: &str = "freelist";
+/// Compression and decompression of clusters.
+pub const COMPRESSION: &str = "compression";
+/// The sector cache.
+pub const CACHE: &str = "cache";
+/// The virtual device driver.
+pub const VDEV: &str = "vdev";
@@ -58,6 +58,27 @@ impl Disk for Mirror {
             })
         }
     }
+
+    fn barrier(&mut self) -> Result<(), disk::Error> {
+        // Simply forward the call to the inner disk.
+        self.inner.barrier()
+    }
+
+    fn discard(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+        // Get the size of half of the inner disk.
+        let half = self.number_of_sectors();
+        // Check if in bound.
+        if sector < half {
+            // Discard both the sector and its mirror.
+            self.inner.discard(sector)?;
+            self.inner.discard(sector + half)
+        } else {
+            // Out of bounds sector; throw an error.
+            Err(disk::Error::OutOfBounds {
+                sector: sector,
+            })
+        }
+    }
 }
 
 /// A SPECK encryption vdev.
@@ -90,6 +111,16 @@ impl Disk for Speck {
         // Simply forward the call to the inner disk.
         self.inner.heal(sector)
     }
+
+    fn barrier(&mut self) -> Result<(), disk::Error> {
+        // Simply forward the call to the inner disk.
+        self.inner.barrier()
+    }
+
+    fn discard(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+        // Simply forward the call to the inner disk.
+        self.inner.discard(sector)
+    }
 }
 
 quick_error! {
@@ -147,28 +178,28 @@ impl Driver {
     /// disk to be in open state. If any encryption is enabled, `password` will be used as the
     /// password.
     fn open<T: Disk>(log: L, disk: T, password: &[u8]) -> Result<Driver, Error> {
-        info!(log, "initializing the driver");
+        info!(log, "initializing the driver"; "subsystem" => subsystem::VDEV);
 
         // Read the disk header.
-        debug!(log, "read the disk header");
+        debug!(log, "read the disk header"; "subsystem" => subsystem::VDEV);
         let mut header = DiskHeader::decode(disk.read(0)?)?;
 
         match header.state_flag {
             // Throw a warning if it wasn't properly shut down.
             StateFlag::Open => {
                 warn!(log, "the disk's state flag is still open, likely wasn't properly shut down \
-                            last time; beware of data loss");
+                            last time; beware of data loss"; "subsystem" => subsystem::VDEV);
             },
             // The state inconsistent; throw an error.
             StateFlag::Inconsistent => return Err(OpenError::InconsistentState),
         }
 
         // Set the state flag to open.
-        debug!(log, "setting the state flag to 'open'");
+        debug!(log, "setting the state flag to 'open'"; "subsystem" => subsystem::VDEV);
         header.state_flag = StateFlag::Open;
 
         // Update the version.
-        debug!(log, "updating the version number";
+        debug!(log, "updating the version number"; "subsystem" => subsystem::VDEV,
                "old version" => header.version_number,
                "new version" => VERSION_NUMBER);
         header.version_number = VERSION_NUMBER;
@@ -178,14 +209,15 @@ impl Driver {
         for i in header.vdev_stack {
             disk = match i {
                 Vdev::Mirror => {
-                    debug!(log, "appending a mirror vdev");
+                    debug!(log, "appending a mirror vdev"; "subsystem" => subsystem::VDEV);
 
                     Box::new(Mirror {
                         inner: disk,
                     })
                 },
                 Vdev::Speck { salt } => {
-                    debug!(log, "appending a SPECK encryption vdev"; "salt" => salt);
+                    debug!(log, "appending a SPECK encryption vdev"; "subsystem" => subsystem::VDEV,
+                           "salt" => salt);
 
                     Box::new(Speck {
                         inner: disk,
@@ -209,7 +241,7 @@ impl Driver {
 
     /// Flush the stored disk header.
     fn flush_header(&mut self) -> Result<(), disk::Error> {
-        debug!(self, "flushing the disk header");
+        debug!(self, "flushing the disk header"; "subsystem" => subsystem::VDEV);
 
         // Encode and write it to the disk.
         self.disk.write(0, &self.header.encode())
@@ -218,10 +250,10 @@ impl Driver {
 
 impl Drop for Driver {
     fn drop(&mut self) {
-        info!(self, "closing the driver");
+        info!(self, "closing the driver"; "subsystem" => subsystem::VDEV);
 
         // Set the state flag to close so we know that it was a proper shutdown.
-        debug!(self, "setting state flag to 'closed'");
+        debug!(self, "setting state flag to 'closed'"; "subsystem" => subsystem::VDEV);
         self.header.state_flag = StateFlag::Closed;
         // Flush the header.
         self.flush_header();
@@ -236,7 +268,7 @@ impl Disk for Driver {
     }
 
     fn write(&mut self, sector: Sector, buf: &[u8]) -> Result<(), Error> {
-        trace!(self, "writing data"; "sector" => sector);
+        trace!(self, "writing data"; "subsystem" => subsystem::VDEV, "sector" => sector);
 
         // Make sure it doesn't write to the null sector reserved for the disk header.
         assert_ne!(sector, 0, "Trying to write to the null sector.");
@@ -245,7 +277,7 @@ impl Disk for Driver {
         self.disk.write(sector, buf)
     }
     fn read_to(&mut self, sector: Sector, buf: &mut [u8]) -> Result<(), Error> {
-        trace!(self, "reading data"; "sector" => sector);
+        trace!(self, "reading data"; "subsystem" => subsystem::VDEV, "sector" => sector);
 
         // Make sure it doesn't write to the null sector reserved for the disk header.
         assert_ne!(sector, 0, "Trying to read from the null sector.");
@@ -255,9 +287,23 @@ impl Disk for Driver {
     }
 
     fn heal(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
-        debug!(self, "healing possibly corrupt sector"; "sector" => sector);
+        debug!(self, "healing possibly corrupt sector"; "subsystem" => subsystem::VDEV, "sector" => sector);
 
         // Forward the call to the inner disk.
         self.disk.heal(sector)
     }
+
+    fn barrier(&mut self) -> Result<(), disk::Error> {
+        trace!(self, "issuing write barrier"; "subsystem" => subsystem::VDEV);
+
+        // Forward the call to the inner disk.
+        self.disk.barrier()
+    }
+
+    fn discard(&mut self, sector: disk::Sector) -> Result<(), disk::Error> {
+        trace!(self, "discarding sector"; "subsystem" => subsystem::VDEV, "sector" => sector);
+
+        // Forward the call to the inner disk.
+        self.disk.discard(sector)
+    }
 }
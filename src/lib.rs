@@ -7,6 +7,17 @@
 extern crate slog;
 #[macro_use]
 extern crate quick_error;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 mod macros;
 mod io;
+
+#[cfg(feature = "testing")]
+pub use io::mem_sim::MemSim;
+#[cfg(feature = "testing")]
+pub use io::{ChecksumAlgorithm, ChecksumWidth, CompressionAlgorithm, Config, DedupPolicy, Disk, DiskHeader, Driver,
+             FreelistConstruction, Manager, SECTOR_SIZE, SectorBuf};